@@ -0,0 +1,262 @@
+//! Compares the throughput and CPU usage of the strategies for serving blobs:
+//!
+//! - `per-request-pool`: a thread of its own for each request, reading a
+//!   block at a time (how blobs used to be served, through a brand new
+//!   `FsPool`);
+//! - `pool`: reads through tokio's blocking pool, shared by all requests;
+//! - `mmap`: memory mapped blobs (how blobs are served).
+//!
+//! ```sh
+//! cargo bench --bench blob_serving
+//! ```
+//!
+//! The size of the blob (in MiB), the number of concurrent clients and the
+//! number of requests each client performs can be tuned through
+//! `BENCH_BLOB_SIZE_MB`, `BENCH_CLIENTS` and `BENCH_REQUESTS`.
+
+use std::convert::Infallible;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::{stream, TryStreamExt};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::Response;
+use hyper_util::rt::TokioIo;
+use tempfile::tempdir;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+
+use cartorio::blob_body::{BlobBodies, ResponseBody};
+
+
+const DIGEST: &str = "sha256:bench";
+
+
+#[derive(Clone, Copy)]
+enum Strategy {
+    PerRequestPool,
+    Pool,
+    Mmap,
+}
+
+impl Strategy {
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::PerRequestPool => "per-request-pool",
+            Strategy::Pool => "pool",
+            Strategy::Mmap => "mmap",
+        }
+    }
+}
+
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+
+/// Total CPU time (user + system) consumed by this process so far.
+///
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+
+    let to_duration = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+
+/// Streams the file at `path` from a thread started just for it, handing
+/// over a block (`st_blksize`) at a time, as a brand new `FsPool` per
+/// request used to.
+///
+fn per_request_pool(path: &Path) -> ResponseBody {
+    let mut file = std::fs::File::open(path).unwrap();
+    let block_size = file.metadata().unwrap().blksize() as usize;
+    let (sender, receiver) = mpsc::channel(1);
+
+    std::thread::spawn(move || loop {
+        let mut block = vec![0; block_size];
+
+        let chunk = match file.read(&mut block) {
+            Ok(0) => return,
+            Ok(n) => {
+                block.truncate(n);
+                Ok(Frame::data(Bytes::from(block)))
+            },
+            Err(err) => Err(err),
+        };
+
+        if sender.blocking_send(chunk).is_err() {
+            return;
+        }
+    });
+
+    let chunks = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk: io::Result<_>| (chunk, receiver))
+    });
+
+    StreamBody::new(chunks).boxed()
+}
+
+
+/// Streams the file at `path` through tokio's blocking pool.
+///
+async fn pool(path: &Path) -> ResponseBody {
+    let file = tokio::fs::File::open(path).await.unwrap();
+
+    StreamBody::new(ReaderStream::new(file).map_ok(Frame::data)).boxed()
+}
+
+
+/// Starts a server in the background that serves the blob at `path` using
+/// the supplied strategy, returning the address it listens on.
+///
+fn start_server(strategy: Strategy, path: PathBuf) -> SocketAddr {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let bodies = Arc::new(BlobBodies::new());
+    let path = Arc::new(path);
+
+    std::thread::spawn(move || runtime.block_on(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let bodies = bodies.clone();
+            let path = path.clone();
+
+            tokio::spawn(async move {
+                let service = service_fn(move |_req| {
+                    let bodies = bodies.clone();
+                    let path = path.clone();
+
+                    async move {
+                        let body = match strategy {
+                            Strategy::PerRequestPool => per_request_pool(&path),
+                            Strategy::Pool => pool(&path).await,
+                            Strategy::Mmap => bodies.mapped(DIGEST, &path).await.unwrap(),
+                        };
+
+                        Ok::<_, Infallible>(Response::new(body))
+                    }
+                });
+
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    }));
+
+    addr
+}
+
+
+/// Performs a GET against `addr`, discarding the body, returning the number
+/// of body bytes received.
+///
+fn fetch(addr: SocketAddr) -> u64 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /blob HTTP/1.1\r\nhost: bench\r\nconnection: close\r\n\r\n")
+        .unwrap();
+
+    let mut reader = BufReader::with_capacity(1 << 20, stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+
+        if line == "\r\n" {
+            break;
+        }
+    }
+
+    let mut buf = vec![0; 1 << 20];
+    let mut received = 0;
+
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+
+        if n == 0 {
+            return received;
+        }
+
+        received += n as u64;
+    }
+}
+
+
+fn run(strategy: Strategy, path: &Path, clients: usize, requests: usize) {
+    let addr = start_server(strategy, path.to_owned());
+
+    // warm up the page cache (and, for `mmap`, the mapping).
+    //
+    fetch(addr);
+
+    let started_cpu = cpu_time();
+    let started = Instant::now();
+
+    let handles: Vec<_> = (0..clients)
+        .map(|_| std::thread::spawn(move || (0..requests).map(|_| fetch(addr)).sum::<u64>()))
+        .collect();
+
+    let received: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+    let elapsed = started.elapsed();
+    let cpu = cpu_time() - started_cpu;
+    let mib = received as f64 / (1 << 20) as f64;
+
+    println!(
+        "{:<18} {:>10.1} MiB/s {:>10.2} s cpu {:>10.2} ms cpu/GiB",
+        strategy.name(),
+        mib / elapsed.as_secs_f64(),
+        cpu.as_secs_f64(),
+        cpu.as_secs_f64() * 1000.0 / (mib / 1024.0),
+    );
+}
+
+
+fn main() {
+    let size_mb = env_or("BENCH_BLOB_SIZE_MB", 256);
+    let clients = env_or("BENCH_CLIENTS", 4);
+    let requests = env_or("BENCH_REQUESTS", 4);
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join(DIGEST);
+
+    let chunk: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let mut file = std::fs::File::create(&path).unwrap();
+
+    for _ in 0..size_mb {
+        file.write_all(&chunk).unwrap();
+    }
+
+    println!(
+        "blob: {} MiB, clients: {}, requests per client: {}",
+        size_mb, clients, requests,
+    );
+
+    for strategy in &[Strategy::PerRequestPool, Strategy::Pool, Strategy::Mmap] {
+        run(*strategy, &path, clients, requests);
+    }
+}
@@ -24,16 +24,29 @@ pull_from_cartorio () {
 }
 
 cartorio_serve () {
-	cartorio serve --blobstore=$TMP_DIR &
+	cartorio serve --blobstore=$TMP_DIR --shutdown-timeout=30 &
 	echo "$!" > $CARTORIO_PID_FILE
-	sleep 1
+
+	for _ in $(seq 1 30); do
+		if curl -sf http://localhost:5000/_ready > /dev/null; then
+			return
+		fi
+
+		sleep 1
+	done
+
+	echo "cartorio did not become ready" >&2
+	exit 1
 }
 
 cleanup () {
-	local cartorio_pid=$(cat $CARTORIO_PID_FILE)
+	local cartorio_pid=$(cat $CARTORIO_PID_FILE 2> /dev/null || true)
 
 	if [[ -n $cartorio_pid ]]; then
+		# SIGTERM makes cartorio stop accepting connections and
+		# wait for in-flight downloads before exiting.
 		kill -s SIGTERM $cartorio_pid
+		wait $cartorio_pid || true
 	fi
 }
 
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use crate::error::Result;
+use crate::file_watch::{FileWatch, WATCH_INTERVAL};
+
+
+/// The realm advertised in authentication challenges.
+///
+pub const REALM: &str = "cartorio";
+
+
+/// Users and their bcrypt password hashes as found in an `htpasswd` file.
+///
+/// ```txt
+/// # comments and blank lines are ignored
+/// alice:$2y$05$6W5qnUjO0Nq9Q0nOkxj8tOBqVt4S2x1zaVJ4Pw4cL0pA2fGkqGxJW
+/// ```
+///
+pub struct HtpasswdEntries {
+    users: HashMap<String, String>,
+}
+
+
+impl FromStr for HtpasswdEntries {
+
+    type Err = failure::Error;
+
+    /// Parses the contents of an `htpasswd` file, accepting only bcrypt
+    /// entries (`htpasswd -B`).
+    ///
+    fn from_str(content: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = match line.find(':') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => {
+                    return Err(failure::format_err!("line {}: expected `user:hash`", idx + 1));
+                },
+            };
+
+            if !["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+                return Err(failure::format_err!(
+                    "line {}: only bcrypt entries are supported (see `htpasswd -B`)",
+                    idx + 1,
+                ));
+            }
+
+            users.insert(user.to_owned(), hash.to_owned());
+        }
+
+        Ok(HtpasswdEntries { users })
+    }
+
+}
+
+
+/// An `htpasswd` file that gets re-read whenever it changes.
+///
+/// As bcrypt is purposely expensive and clients send their credentials on
+/// every single request, credentials that have already been verified are
+/// remembered (by their digest) until the file changes.
+///
+pub struct Htpasswd {
+    path: PathBuf,
+    entries: RwLock<HtpasswdEntries>,
+    verified: Mutex<HashSet<String>>,
+}
+
+
+impl Htpasswd {
+
+    /// Loads the `htpasswd` file at `path`.
+    ///
+    pub fn new(path: &Path) -> Result<Htpasswd> {
+        Ok(Htpasswd {
+            path: path.to_owned(),
+            entries: RwLock::new(read_entries(path)?),
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+
+    /// Re-reads the file from disk, keeping the current entries if it
+    /// can't be loaded.
+    ///
+    pub fn reload(&self) -> Result<()> {
+        let entries = read_entries(&self.path)?;
+
+        *self.entries.write().unwrap() = entries;
+        self.verified.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+
+    /// Reloads the file whenever it changes, never returning.
+    ///
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut file = FileWatch::new(vec![self.path.clone()]);
+
+        loop {
+            interval.tick().await;
+
+            if !file.changed() {
+                continue;
+            }
+
+            let htpasswd = self.clone();
+
+            match tokio::task::spawn_blocking(move || htpasswd.reload()).await {
+                Ok(Ok(())) => info!(path = ?self.path, "reloaded htpasswd file"),
+                Ok(Err(err)) => error!(path = ?self.path, error = %err, "failed to reload htpasswd file"),
+                Err(err) => error!(path = ?self.path, error = %err, "failed to reload htpasswd file"),
+            }
+        }
+    }
+
+
+    /// Verifies that `password` is the password of `user`.
+    ///
+    /// # Remarks
+    ///
+    /// This is CPU intensive (bcrypt) when the credentials haven't been
+    /// verified before, so it should not be called from the reactor.
+    ///
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let credentials_digest = hex::encode(
+            Sha256::digest(format!("{}:{}", user, password).as_bytes()).as_slice(),
+        );
+
+        if self.verified.lock().unwrap().contains(&credentials_digest) {
+            return true;
+        }
+
+        let hash = match self.entries.read().unwrap().users.get(user) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+
+        if !bcrypt::verify(password, &hash).unwrap_or(false) {
+            return false;
+        }
+
+        self.verified.lock().unwrap().insert(credentials_digest);
+        true
+    }
+
+}
+
+
+fn read_entries(path: &Path) -> Result<HtpasswdEntries> {
+    std::fs::read_to_string(path)?
+        .parse()
+        .map_err(|err| failure::format_err!("{:?}: {}", path, err))
+}
+
+
+/// HTTP basic authentication (RFC 7617) against an `htpasswd` file.
+///
+#[derive(Clone)]
+pub struct BasicAuth {
+    htpasswd: Arc<Htpasswd>,
+}
+
+
+impl BasicAuth {
+
+    pub fn new(htpasswd: Arc<Htpasswd>) -> BasicAuth {
+        BasicAuth { htpasswd }
+    }
+
+
+    /// Authenticates a request by the credentials in its `Authorization`
+    /// header, returning the name of the user if they are valid.
+    ///
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let (user, password) = parse_basic_credentials(headers)?;
+        let htpasswd = self.htpasswd.clone();
+        let username = user.clone();
+
+        let valid = tokio::task::spawn_blocking(move || htpasswd.verify(&username, &password))
+            .await
+            .unwrap_or(false);
+
+        if valid {
+            Some(user)
+        } else {
+            None
+        }
+    }
+
+
+    /// Value of the `WWW-Authenticate` header sent along with responses to
+    /// requests that failed to authenticate.
+    ///
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\"", REALM)
+    }
+
+}
+
+
+/// Extracts the user and password from an `Authorization: Basic` header.
+///
+pub fn parse_basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next()?;
+    let encoded = parts.next()?.trim();
+
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let pos = decoded.find(':')?;
+
+    Some((decoded[..pos].to_owned(), decoded[pos + 1..].to_owned()))
+}
+
+
+#[cfg(test)]
+mod auth_tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn parses_htpasswd() {
+        let entries: HtpasswdEntries = "\n# comment\nalice:$2y$05$abc\nbob:$2b$05$def\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(entries.users.len(), 2);
+        assert_eq!(entries.users["alice"], "$2y$05$abc");
+    }
+
+    #[test]
+    fn rejects_non_bcrypt_htpasswd_entries() {
+        let err = "alice:$2y$05$abc\nbob:{SHA}abc"
+            .parse::<HtpasswdEntries>()
+            .err()
+            .unwrap();
+
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn parses_basic_credentials() {
+        let mut headers = HeaderMap::new();
+        assert!(parse_basic_credentials(&headers).is_none());
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic YWxpY2U6czNjcjM6dA=="));
+        assert_eq!(
+            parse_basic_credentials(&headers),
+            Some(("alice".to_owned(), "s3cr3:t".to_owned()))
+        );
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer YWxpY2U6czNjcjM6dA=="));
+        assert!(parse_basic_credentials(&headers).is_none());
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use memmap2::{Advice, Mmap};
+use tracing::{instrument, Span};
+
+use crate::blobstore::BlobExtent;
+use crate::error::Result;
+
+
+/// The body of every response sent by the server.
+///
+pub type ResponseBody = BoxBody<Bytes, io::Error>;
+
+
+/// Creates a body with the whole content already in memory.
+///
+pub fn full(content: impl Into<Bytes>) -> ResponseBody {
+    Full::new(content.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+
+/// Creates a body without any content.
+///
+pub fn empty() -> ResponseBody {
+    Empty::new()
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+
+/// Wraps a body so that `on_done` gets called with the number of bytes
+/// sent once it's dropped, be it because it's been fully sent or because
+/// the client went away in the middle.
+///
+pub fn metered(body: ResponseBody, on_done: impl FnOnce(u64) + Send + Sync + 'static) -> ResponseBody {
+    Metered {
+        inner: body,
+        sent: 0,
+        on_done: Some(Box::new(on_done)),
+    }
+    .boxed()
+}
+
+
+type OnDone = Box<dyn FnOnce(u64) + Send + Sync>;
+
+
+struct Metered {
+    inner: ResponseBody,
+    sent: u64,
+    on_done: Option<OnDone>,
+}
+
+
+impl Body for Metered {
+
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<std::result::Result<Frame<Bytes>, io::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.sent += data.len() as u64;
+            }
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+
+}
+
+
+impl Drop for Metered {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.sent);
+        }
+    }
+}
+
+
+/// Produces response bodies for the files that live in the blobstore.
+///
+/// Blobs are served straight from memory mappings of the files in the
+/// bucket: the body handed to hyper points at the mapped pages, so the
+/// content goes from the page cache to the socket without ever being
+/// copied through an intermediate userspace buffer. Blobs served in place
+/// from tarballs are slices of mappings of the tarballs instead.
+///
+///
+/// # Remarks
+///
+/// Responses that serve the same blob at the same time share a single
+/// mapping, which gets unmapped once the last of them is done - mappings
+/// don't outlive the responses using them, so files deleted or replaced in
+/// the meantime aren't kept around.
+///
+#[derive(Default)]
+pub struct BlobBodies {
+
+    /// Mappings of the blobs being served, keyed by their digest.
+    ///
+    mappings: Mutex<HashMap<String, Mapping>>,
+}
+
+
+/// A mapping shared by the responses serving a blob.
+///
+struct Mapping {
+
+    /// The mapped file, alive for as long as any response still uses it.
+    ///
+    mmap: Weak<Mmap>,
+
+    /// Range of the blob within the mapped file.
+    ///
+    range: Range<usize>,
+
+    /// Size and modification time of the tarball mapped, for blobs served
+    /// in place.
+    ///
+    tarball: Option<(u64, SystemTime)>,
+}
+
+
+/// Owner of a mapping, for it to back `Bytes`.
+///
+struct SharedMmap(Arc<Mmap>);
+
+
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+
+impl BlobBodies {
+
+    /// Instantiates `BlobBodies` without any blob mapped.
+    ///
+    pub fn new() -> BlobBodies {
+        BlobBodies::default()
+    }
+
+
+    /// Creates a body backed by a memory mapping of the blob identified
+    /// by `digest`, found at `path`.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - digest of the blob (e.g., `sha256:abcdef`).
+    /// * `path` - location of the blob in the filesystem.
+    ///
+    #[instrument(skip(self, path))]
+    pub async fn mapped(&self, digest: &str, path: &Path) -> Result<ResponseBody> {
+        if let Some(contents) = self.shared(digest, None) {
+            return Ok(full(contents));
+        }
+
+        let path = path.to_owned();
+        let span = Span::current();
+
+        let contents = match tokio::task::spawn_blocking(move || span.in_scope(|| map(path))).await?? {
+            Some(mmap) => {
+                let range = 0..mmap.len();
+                self.share(digest, mmap, range, None)
+            },
+            None => Bytes::new(),
+        };
+
+        Ok(full(contents))
+    }
+
+
+    /// Creates a body backed by the range of a memory mapping of the
+    /// tarball that the blob identified by `digest` is served in place
+    /// from.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - digest of the blob (e.g., `sha256:abcdef`).
+    /// * `extent` - location of the blob within the tarball.
+    ///
+    ///
+    /// # Errors
+    ///
+    /// Fails with `io::ErrorKind::NotFound` if the tarball is gone or
+    /// changed since the extent got recorded.
+    ///
+    #[instrument(skip(self, extent))]
+    pub async fn extent(&self, digest: &str, extent: &BlobExtent) -> Result<ResponseBody> {
+        let metadata = tokio::fs::metadata(&extent.tarball).await?;
+
+        if !extent.is_current(metadata.len(), metadata.modified()?) {
+            return Err(changed(extent).into());
+        }
+
+        let tarball = Some((extent.tarball_size, extent.tarball_modified));
+
+        if let Some(contents) = self.shared(digest, tarball) {
+            return Ok(full(contents));
+        }
+
+        let extent = extent.clone();
+        let span = Span::current();
+
+        let contents = match tokio::task::spawn_blocking(move || span.in_scope(|| map_extent(extent))).await?? {
+            Some((mmap, range)) => self.share(digest, mmap, range, tarball),
+            None => Bytes::new(),
+        };
+
+        Ok(full(contents))
+    }
+
+
+    /// The contents of the blob identified by `digest`, if a response is
+    /// serving it out of a mapping of the same file already.
+    ///
+    fn shared(&self, digest: &str, tarball: Option<(u64, SystemTime)>) -> Option<Bytes> {
+        let mappings = self.mappings.lock().unwrap();
+        let mapping = mappings.get(digest).filter(|mapping| mapping.tarball == tarball)?;
+
+        let mmap = mapping.mmap.upgrade()?;
+
+        Some(Bytes::from_owner(SharedMmap(mmap)).slice(mapping.range.clone()))
+    }
+
+
+    /// Makes `mmap` be shared by the responses serving the blob identified
+    /// by `digest` from now on, forgetting the mappings no longer in use.
+    ///
+    fn share(&self, digest: &str, mmap: Arc<Mmap>, range: Range<usize>, tarball: Option<(u64, SystemTime)>) -> Bytes {
+        let mut mappings = self.mappings.lock().unwrap();
+
+        mappings.retain(|_, mapping| mapping.mmap.strong_count() > 0);
+        mappings.insert(digest.to_owned(), Mapping {
+            mmap: Arc::downgrade(&mmap),
+            range: range.clone(),
+            tarball,
+        });
+
+        Bytes::from_owner(SharedMmap(mmap)).slice(range)
+    }
+
+}
+
+
+/// Maps the file at `path` into memory, unless it's empty.
+///
+fn map(path: PathBuf) -> io::Result<Option<Arc<Mmap>>> {
+    let file = File::open(path)?;
+
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+
+    // Safety: blobs in the bucket are never modified after having been
+    // moved there, only replaced, which leaves the mapped file untouched.
+    //
+    let mmap = unsafe { Mmap::map(&file)? };
+    mmap.advise(Advice::Sequential)?;
+
+    Ok(Some(Arc::new(mmap)))
+}
+
+
+/// Maps the tarball that `extent` points into, along with the range of the
+/// blob within it, unless the blob is empty.
+///
+fn map_extent(extent: BlobExtent) -> io::Result<Option<(Arc<Mmap>, Range<usize>)>> {
+    let file = File::open(&extent.tarball)?;
+    let metadata = file.metadata()?;
+
+    if !extent.is_current(metadata.len(), metadata.modified()?) {
+        return Err(changed(&extent));
+    }
+
+    if extent.size == 0 {
+        return Ok(None);
+    }
+
+    // Safety: tarballs served in place must not be modified while served,
+    // only replaced, which leaves the mapped file untouched; the check above
+    // makes sure that the file mapped is still the one that got indexed.
+    //
+    let mmap = unsafe { Mmap::map(&file)? };
+    mmap.advise(Advice::Sequential)?;
+
+    let start = extent.offset as usize;
+    let end = start + extent.size as usize;
+
+    if end > mmap.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("extent out of the bounds of {}", extent.tarball.display()),
+        ));
+    }
+
+    Ok(Some((Arc::new(mmap), start..end)))
+}
+
+
+/// The error for tarballs that changed since `extent` got recorded.
+///
+fn changed(extent: &BlobExtent) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} changed since it got loaded", extent.tarball.display()),
+    )
+}
+
+
+
+#[cfg(test)]
+mod blob_body_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    const DIGEST: &str = "sha256:abc";
+
+    async fn collect(body: ResponseBody) -> Bytes {
+        body.collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_shares_mappings_only_while_in_use() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blob");
+        std::fs::write(&path, b"content").unwrap();
+
+        let bodies = BlobBodies::new();
+
+        let body = bodies.mapped(DIGEST, &path).await.unwrap();
+        assert_eq!(bodies.shared(DIGEST, None).unwrap(), &b"content"[..]);
+
+        assert_eq!(collect(body).await, &b"content"[..]);
+        assert!(bodies.shared(DIGEST, None).is_none());
+
+        assert_eq!(collect(bodies.mapped(DIGEST, &path).await.unwrap()).await, &b"content"[..]);
+        assert_eq!(bodies.mappings.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_checks_extents_on_every_request() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, b"headerBLOBtrailer").unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let extent = BlobExtent {
+            tarball: path.clone(),
+            offset: 6,
+            size: 4,
+            tarball_size: metadata.len(),
+            tarball_modified: metadata.modified().unwrap(),
+        };
+
+        let bodies = BlobBodies::new();
+
+        let body = bodies.extent(DIGEST, &extent).await.unwrap();
+        assert_eq!(bodies.shared(DIGEST, Some((extent.tarball_size, extent.tarball_modified))).unwrap(), &b"BLOB"[..]);
+
+        // replaced while the mapping is still in use.
+        //
+        let replacement = dir.path().join("image.tar.new");
+        std::fs::write(&replacement, b"something else entirely").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+
+        let err = bodies.extent(DIGEST, &extent).await.unwrap_err();
+        assert!(err.downcast_ref::<io::Error>().unwrap().kind() == io::ErrorKind::NotFound, "{}", err);
+
+        assert_eq!(collect(body).await, &b"BLOB"[..]);
+    }
+}
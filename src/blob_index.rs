@@ -0,0 +1,247 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use tracing::{instrument, warn};
+
+use crate::blobstore::BlobStore;
+use crate::error::Result;
+
+
+/// The blobs that each repository references, so that a blob is only
+/// served under the repositories whose manifests reach it.
+///
+/// ```txt
+///
+///    manifests
+///    ├── public
+///    │   └── latest -> ../../bucket/sha256:aaa    { config: sha256:c1, layers: [ sha256:l1 ] }
+///    └── private
+///        └── latest -> ../../bucket/sha256:bbb    { config: sha256:c2, layers: [ sha256:l2 ] }
+///
+///
+///    public   ==> { sha256:aaa, sha256:c1, sha256:l1 }
+///    private  ==> { sha256:bbb, sha256:c2, sha256:l2 }
+///
+/// ```
+///
+/// As images are loaded by a process other than the one serving them, a
+/// repository is re-read whenever a lookup misses and its directory of
+/// manifests changed since it was last read.
+///
+pub struct BlobIndex {
+    blobstore: BlobStore,
+    repositories: RwLock<HashMap<String, Membership>>,
+}
+
+
+/// The blobs referenced by the manifests of a repository at the time its
+/// directory had been last modified.
+///
+struct Membership {
+    modified: Option<SystemTime>,
+    blobs: HashSet<String>,
+}
+
+
+impl BlobIndex {
+
+    /// Builds the index from all of the manifests in `blobstore`.
+    ///
+    #[instrument(skip_all)]
+    pub fn new(blobstore: BlobStore) -> Result<BlobIndex> {
+        let mut repositories = HashMap::new();
+
+        for repository in blobstore.list_repositories()? {
+            let membership = read_membership(&blobstore, &repository)?;
+            repositories.insert(repository, membership);
+        }
+
+        Ok(BlobIndex {
+            blobstore,
+            repositories: RwLock::new(repositories),
+        })
+    }
+
+
+    /// Whether `digest` is reachable from the manifests of `repository`, as
+    /// far as the index knows.
+    ///
+    pub fn contains(&self, repository: &str, digest: &str) -> bool {
+        self.repositories
+            .read()
+            .unwrap()
+            .get(repository)
+            .is_some_and(|membership| membership.blobs.contains(digest))
+    }
+
+
+    /// Re-reads the manifests of `repository` if its directory changed
+    /// since they were last read.
+    ///
+    /// # Remarks
+    ///
+    /// This performs blocking filesystem operations, so it should not be
+    /// called from the reactor.
+    ///
+    #[instrument(skip(self))]
+    pub fn refresh(&self, repository: &str) -> Result<()> {
+        let modified = modified(&self.blobstore.manifests_dir.join(repository))?;
+
+        let unchanged = self
+            .repositories
+            .read()
+            .unwrap()
+            .get(repository)
+            .map_or(modified.is_none(), |membership| membership.modified == modified);
+
+        if unchanged {
+            return Ok(());
+        }
+
+        let membership = read_membership(&self.blobstore, repository)?;
+
+        self.repositories
+            .write()
+            .unwrap()
+            .insert(repository.to_owned(), membership);
+
+        Ok(())
+    }
+
+}
+
+
+/// Modification time of `path`, if it exists.
+///
+fn modified(path: &Path) -> Result<Option<SystemTime>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+
+/// Reads the manifests tagged under `repository`, gathering the digests of
+/// the manifests themselves as well as everything they reference.
+///
+fn read_membership(blobstore: &BlobStore, repository: &str) -> Result<Membership> {
+    let dir = blobstore.manifests_dir.join(repository);
+
+    // taken before reading the entries so that anything tagged while
+    // they're being read triggers yet another read.
+    //
+    let modified = modified(&dir)?;
+    let mut blobs = HashSet::new();
+
+    if modified.is_none() {
+        return Ok(Membership { modified, blobs });
+    }
+
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        // a tag that can't be read (e.g., pointing at a manifest that's gone)
+        // shouldn't keep the rest of the repository from being served.
+        //
+        if let Err(err) = read_tag(&entry.path(), &mut blobs) {
+            warn!(repository, tag = ?entry.file_name(), error = %err, "skipping unreadable tag");
+        }
+    }
+
+    Ok(Membership { modified, blobs })
+}
+
+
+/// Gathers the digest of the manifest that the tag at `path` links to, as
+/// well as everything that it references, into `blobs`.
+///
+fn read_tag(path: &Path, blobs: &mut HashSet<String>) -> Result<()> {
+    let manifest_path = std::fs::read_link(path)?;
+
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|err| failure::format_err!("malformed manifest {:?} - {}", manifest_path, err))?;
+
+    if let Some(digest) = manifest_path.file_name().and_then(|name| name.to_str()) {
+        blobs.insert(digest.to_owned());
+    }
+
+    blobs.extend(referenced_digests(&manifest));
+
+    Ok(())
+}
+
+
+/// Digests of the descriptors in a manifest (config and layers) or in a
+/// manifest list.
+///
+fn referenced_digests(manifest: &serde_json::Value) -> Vec<String> {
+    let config = manifest.get("config").into_iter();
+
+    let lists = ["layers", "manifests"]
+        .iter()
+        .filter_map(|key| manifest.get(key).and_then(|list| list.as_array()))
+        .flatten();
+
+    config
+        .chain(lists)
+        .filter_map(|descriptor| descriptor.get("digest").and_then(|digest| digest.as_str()))
+        .map(|digest| digest.to_owned())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod blob_index_tests {
+    use super::*;
+
+    #[test]
+    fn test_referenced_digests() {
+        let manifest = serde_json::json!({
+            "config": { "digest": "sha256:c" },
+            "layers": [ { "digest": "sha256:l1" }, { "digest": "sha256:l2" } ],
+        });
+
+        assert_eq!(referenced_digests(&manifest), vec!["sha256:c", "sha256:l1", "sha256:l2"]);
+
+        let list = serde_json::json!({
+            "manifests": [ { "digest": "sha256:m1" } ],
+        });
+
+        assert_eq!(referenced_digests(&list), vec!["sha256:m1"]);
+    }
+
+    #[test]
+    fn test_skips_unreadable_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+
+        let manifest = blobstore
+            .add_raw_manifest(br#"{ "config": { "digest": "sha256:c" }, "layers": [] }"#)
+            .unwrap();
+        blobstore.tag_manifest(&manifest, "app", "latest").unwrap();
+
+        let malformed = blobstore.add_raw_manifest(b"not json").unwrap();
+        blobstore.tag_manifest(&malformed, "app", "malformed").unwrap();
+
+        std::os::unix::fs::symlink(
+            blobstore.get_blob("sha256:gone"),
+            blobstore.get_manifest("app", "dangling"),
+        )
+        .unwrap();
+
+        let index = BlobIndex::new(blobstore).unwrap();
+
+        assert!(index.contains("app", &manifest));
+        assert!(index.contains("app", "sha256:c"));
+        assert!(!index.contains("app", &malformed));
+        assert!(!index.contains("app", "sha256:gone"));
+    }
+}
@@ -1,9 +1,14 @@
-use std::fs::DirBuilder;
-use std::io::Write;
+use std::fs::{DirBuilder, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::fs::symlink;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::compression::LayerCompression;
 use crate::digest;
 use crate::error::Result;
 use crate::registry::Manifest;
@@ -38,6 +43,40 @@ pub struct BlobStore {
     /// Directory where manifests are put.
     ///
     pub manifests_dir: PathBuf,
+
+    /// Directory where the locations of blobs served in place from
+    /// tarballs are put.
+    ///
+    pub extents_dir: PathBuf,
+}
+
+
+/// The location of a blob within a tarball that it's served in place from
+/// (see `DockerSavedTarball::in_place`).
+///
+/// The size and modification time of the tarball at the time it got
+/// indexed are recorded so that a tarball that changed since then is not
+/// served from offsets that no longer hold the blob.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlobExtent {
+    pub tarball: PathBuf,
+    pub offset: u64,
+    pub size: u64,
+    pub tarball_size: u64,
+    pub tarball_modified: SystemTime,
+}
+
+
+impl BlobExtent {
+
+    /// Whether the tarball, with the given size and modification time,
+    /// is still the one that the extent got recorded from.
+    ///
+    pub fn is_current(&self, tarball_size: u64, tarball_modified: SystemTime) -> bool {
+        self.tarball_size == tarball_size && self.tarball_modified == tarball_modified
+    }
+
 }
 
 
@@ -45,6 +84,7 @@ impl BlobStore {
 
     const BUCKET_DIR_NAME: &'static str = "bucket";
     const MANIFESTS_DIR_NAME: &'static str = "manifests";
+    const EXTENTS_DIR_NAME: &'static str = "extents";
 
 
     /// Instantiates a blobstore - a place in the filesystem where all of
@@ -55,6 +95,7 @@ impl BlobStore {
         let blobstore = BlobStore {
             bucket_dir: root.join(BlobStore::BUCKET_DIR_NAME),
             manifests_dir: root.join(BlobStore::MANIFESTS_DIR_NAME),
+            extents_dir: root.join(BlobStore::EXTENTS_DIR_NAME),
         };
 
         DirBuilder::new()
@@ -65,10 +106,29 @@ impl BlobStore {
             .recursive(true)
             .create(&blobstore.manifests_dir)?;
 
+        DirBuilder::new()
+            .recursive(true)
+            .create(&blobstore.extents_dir)?;
+
         Ok(blobstore)
     }
 
 
+    /// Creates a temporary directory, next to the bucket, for files to be
+    /// put in before being moved to the bucket.
+    ///
+    /// Being in the same filesystem as the bucket, files can be moved from
+    /// there without being copied.
+    ///
+    pub fn staging_dir(&self) -> Result<tempfile::TempDir> {
+        let root = self.bucket_dir
+            .parent()
+            .ok_or_else(|| failure::format_err!("bucket {:?} has no parent", self.bucket_dir))?;
+
+        Ok(tempfile::Builder::new().prefix(".staging-").tempdir_in(root)?)
+    }
+
+
     /// Retrieves the path in the filesystem to the desired blob.
     ///
     ///
@@ -83,7 +143,67 @@ impl BlobStore {
     /// require making use of blocking syscalls.
     ///
     pub fn get_blob(&self, name: &str) -> PathBuf {
-        self.bucket_dir.join(&name)
+        self.bucket_dir.join(name)
+    }
+
+
+    /// Retrieves the path in the filesystem to the extent of the desired
+    /// blob, for blobs served in place from tarballs.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: name of the blob with the digest scheme (e.g., `sha256:abcdef`).
+    ///
+    ///
+    /// # Remarks
+    ///
+    /// This method WILL NOT check if the file exists or not as this would
+    /// require making use of blocking syscalls.
+    ///
+    pub fn get_blob_extent(&self, name: &str) -> PathBuf {
+        self.extents_dir.join(name)
+    }
+
+
+    /// Reads the contents of the desired blob, be it in the bucket or served
+    /// in place from a tarball.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: name of the blob with the digest scheme (e.g., `sha256:abcdef`).
+    ///
+    pub fn read_blob(&self, name: &str) -> Result<Vec<u8>> {
+        match std::fs::read(self.get_blob(name)) {
+            Ok(content) => return Ok(content),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        let extent: BlobExtent = match std::fs::read(self.get_blob_extent(name)) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(failure::format_err!("blob {} not found", name));
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut tarball = File::open(&extent.tarball)?;
+        let metadata = tarball.metadata()?;
+
+        if !extent.is_current(metadata.len(), metadata.modified()?) {
+            return Err(failure::format_err!(
+                "blob {} not found ({} changed since it got loaded)", name, extent.tarball.display(),
+            ));
+        }
+
+        let mut content = Vec::with_capacity(extent.size as usize);
+
+        tarball.seek(SeekFrom::Start(extent.offset))?;
+        tarball.take(extent.size).read_to_end(&mut content)?;
+
+        Ok(content)
     }
 
 
@@ -102,8 +222,57 @@ impl BlobStore {
     ///
     pub fn get_manifest(&self, name: &str, reference: &str) -> PathBuf {
         self.manifests_dir
-            .join(&name)
-            .join(&reference)
+            .join(name)
+            .join(reference)
+    }
+
+
+    /// Lists the names of the repositories that have manifests tagged in
+    /// the store, sorted.
+    ///
+    /// ```txt
+    ///    manifests
+    ///    ├── library
+    ///    │   └─ nginx
+    ///    │      └── latest -> ...        ==>   [ "library/nginx", "ubuntu" ]
+    ///    └── ubuntu
+    ///        └── 18.04 -> ...
+    /// ```
+    ///
+    #[instrument(skip(self))]
+    pub fn list_repositories(&self) -> Result<Vec<String>> {
+        let mut repositories = Vec::new();
+        let mut dirs = vec![self.manifests_dir.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            let mut has_manifests = false;
+
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+
+                if entry.file_type()?.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    has_manifests = true;
+                }
+            }
+
+            if !has_manifests {
+                continue;
+            }
+
+            if let Some(name) = dir
+                .strip_prefix(&self.manifests_dir)
+                .ok()
+                .and_then(|name| name.to_str())
+            {
+                repositories.push(name.to_owned());
+            }
+        }
+
+        repositories.sort();
+
+        Ok(repositories)
     }
 
 
@@ -135,14 +304,16 @@ impl BlobStore {
     ///
     /// * `blob` - path to the blob file in the filesystem.
     ///
+    #[instrument(skip(self))]
     pub fn add_blob(&self, blob: &Path) -> Result<()> {
         let blob_digest = digest::retrieve_or_compute_and_store(blob)?;
 
         self.add_blob_with_digest(blob, &blob_digest)
     }
 
+    #[instrument(skip(self))]
     pub fn add_blob_with_digest(&self, blob: &Path, digest: &str) -> Result<()> {
-        let blob_filename = digest::prepend_sha_scheme(&digest);
+        let blob_filename = digest::prepend_sha_scheme(digest);
         let blob_bucket_path = self.bucket_dir.join(blob_filename);
 
         std::fs::rename(
@@ -153,6 +324,61 @@ impl BlobStore {
         Ok(())
     }
 
+
+    /// Compresses the layer supplied by `reader` into the bucket, returning
+    /// the digest and size of the compressed blob.
+    ///
+    /// The layer gets compressed into a staging directory first, so that
+    /// the bucket never has a partially written blob.
+    ///
+    #[instrument(skip_all)]
+    pub fn add_compressed_blob(&self, reader: impl Read, compression: &LayerCompression) -> Result<(String, u64)> {
+        let staging_dir = self.staging_dir()?;
+        let staged = staging_dir.path().join("blob");
+
+        compression.compress(reader, File::create(&staged)?)?;
+
+        let blob_digest = digest::compute_for_file_and_store(&staged)?;
+        let blob_size = std::fs::metadata(&staged)?.len();
+
+        self.add_blob_with_digest(&staged, &blob_digest)?;
+
+        Ok((blob_digest, blob_size))
+    }
+
+    /// Records where, within a tarball, the blob identified by `digest`
+    /// is, so that it gets served from there instead of from the bucket.
+    ///
+    /// ```txt
+    ///
+    ///  add_blob_extent("4bc453b53", { tarball: /images/a.tar, offset: 3584, size: 2048, .. })
+    ///
+    ///         .
+    ///         └── blobstore
+    ///             ├── bucket
+    ///             ├── extents
+    ///             │   └── sha256:4bc453b53      { "tarball": "/images/a.tar", "offset": 3584, .. }
+    ///             └── manifests
+    ///
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `digest` - digest of the blob (without the `sha256:` scheme).
+    /// * `extent` - location of the blob.
+    ///
+    #[instrument(skip(self))]
+    pub fn add_blob_extent(&self, digest: &str, extent: &BlobExtent) -> Result<()> {
+        let extent_path = self.extents_dir.join(digest::prepend_sha_scheme(digest));
+        let extent_tmp_path = extent_path.with_extension("tmp");
+
+        std::fs::write(&extent_tmp_path, serde_json::to_vec(extent)?)?;
+        std::fs::rename(&extent_tmp_path, &extent_path)?;
+
+        Ok(())
+    }
+
+
     /// Writes an image manifest to the store.
     ///
     /// Given a [`Manifest`], this method will serialize the struct into
@@ -163,31 +389,49 @@ impl BlobStore {
     ///
     /// * `manifest` - the manifest to persist.
     ///
+    #[instrument(skip_all)]
     pub fn add_manifest(&self, manifest: &Manifest) -> Result<String> {
-
         let manifest_json = serde_json::to_string_pretty(&manifest).unwrap();
-        let manifest_json_digest = digest::compute_for_string(&manifest_json);
-        let manifest_filename = digest::prepend_sha_scheme(&manifest_json_digest);
+
+        self.add_raw_manifest(manifest_json.as_bytes())
+    }
+
+
+    /// Writes a manifest (or list of manifests) to the store exactly as
+    /// given, so that its digest stays the same as wherever it came from.
+    ///
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - the serialized manifest.
+    ///
+    #[instrument(skip_all)]
+    pub fn add_raw_manifest(&self, content: &[u8]) -> Result<String> {
+        let manifest_digest = digest::compute(content)?;
+        let manifest_filename = digest::prepend_sha_scheme(&manifest_digest);
         let manifest_bucket_path = self.bucket_dir.join(&manifest_filename);
 
-        let mut manifest_file = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&manifest_bucket_path)?;
+        // written aside and renamed over the bucket file rather than
+        // rewritten in place, as the server may have it mapped.
+        //
+        let staging_dir = self.staging_dir()?;
+        let manifest_staged_path = staging_dir.path().join(&manifest_filename);
 
-        manifest_file
-            .write_all(manifest_json.as_bytes())?;
+        std::fs::write(&manifest_staged_path, content)?;
 
         digest::store(
-            &manifest_bucket_path, 
-            &manifest_json_digest,
+            &manifest_staged_path, 
+            &manifest_digest,
         )?;
 
+        std::fs::rename(&manifest_staged_path, &manifest_bucket_path)?;
+
         Ok(manifest_filename)
     }
 
 
-    /// Links a manifest to a blob that represents it.
+    /// Links a manifest to a blob that represents it, replacing the link that
+    /// `reference` might already have (e.g., a tag being moved).
     ///
     /// ```txt
     ///
@@ -217,19 +461,29 @@ impl BlobStore {
     /// * `name` - name of the image
     /// * `reference` - reference (either digest or tag).
     ///
+    #[instrument(skip(self))]
     pub fn tag_manifest(&self, filename: &str, name: &str, reference: &str) -> Result<()> {
         let manifest_bucket_path = self.bucket_dir.join(filename);
 
+        let repository_dir = self.manifests_dir.join(name);
+
         DirBuilder::new()
             .recursive(true)
-            .create(self.manifests_dir.join(name))?;
+            .create(&repository_dir)?;
 
-        symlink(
-            &manifest_bucket_path,
-            self.manifests_dir
-                .join(name)
-                .join(reference),
-        )?;
+        // linking under a temporary name first and renaming it over the
+        // reference, so that it gets replaced atomically.
+        //
+        let link_tmp_path = repository_dir.join(format!(".{}.tmp", reference));
+
+        if std::fs::symlink_metadata(&link_tmp_path).is_ok() {
+            std::fs::remove_file(&link_tmp_path)?;
+        }
+
+        symlink(&manifest_bucket_path, &link_tmp_path)?;
+        std::fs::rename(&link_tmp_path, repository_dir.join(reference))?;
+
+        info!(name, reference, manifest = filename, "tagged manifest");
 
         Ok(())
     }
@@ -0,0 +1,461 @@
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::read::XzDecoder;
+
+use crate::error::Result;
+
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+
+/// How many bytes are needed to tell every supported format apart.
+///
+const MAGIC_LEN: usize = 6;
+
+
+/// Size of the chunks that layers get split into when compressed in
+/// parallel, each becoming a gzip member (or zstd frame) of its own.
+///
+const CHUNK_SIZE: usize = 4 << 20;
+
+
+/// The compression formats that archives can be in.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+
+impl Compression {
+
+    /// Detects the format of an archive from the first bytes of it.
+    ///
+    pub fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if magic.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+
+    /// Name of the format, as used in logs.
+    ///
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        }
+    }
+
+}
+
+
+impl FromStr for Compression {
+
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(failure::format_err!("unknown compression `{}`", s)),
+        }
+    }
+
+}
+
+
+/// How layers get compressed as they're ingested into the blobstore.
+///
+/// ```txt
+///
+///   layer.tar  (sha256:d1, the diff_id in the config)
+///
+///       ==> compress (gzip, level 6, 4 threads)
+///
+///   bucket/sha256:c1  (application/vnd.docker.image.rootfs.diff.tar.gzip)
+///
+/// ```
+///
+/// With more than one thread, layers bigger than a chunk get split into
+/// chunks that are compressed in parallel, with the output being the
+/// concatenation of the compressed chunks - still a valid gzip (multiple
+/// members) or zstd (multiple frames) stream.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerCompression {
+    compression: Compression,
+    level: u32,
+    threads: usize,
+}
+
+
+impl LayerCompression {
+
+    /// Instantiates `LayerCompression` with the default level of the
+    /// format, compressing in a single thread.
+    ///
+    /// # Errors
+    ///
+    /// Fails if layers can't be compressed with `compression` (only gzip
+    /// and zstd are supported by registries).
+    ///
+    pub fn new(compression: Compression) -> Result<LayerCompression> {
+        let level = match compression {
+            Compression::Gzip => 6,
+            Compression::Zstd => 3,
+            _ => return Err(failure::format_err!(
+                "layers can't be compressed with {}, only gzip or zstd", compression.name(),
+            )),
+        };
+
+        Ok(LayerCompression {
+            compression,
+            level,
+            threads: 1,
+        })
+    }
+
+
+    /// Sets the compression level (1 to 9 for gzip, 1 to 22 for zstd).
+    ///
+    pub fn level(mut self, level: u32) -> Result<LayerCompression> {
+        let max_level = match self.compression {
+            Compression::Zstd => 22,
+            _ => 9,
+        };
+
+        if level < 1 || level > max_level {
+            return Err(failure::format_err!(
+                "{} compression level must be between 1 and {}", self.compression.name(), max_level,
+            ));
+        }
+
+        self.level = level;
+        Ok(self)
+    }
+
+
+    /// Sets how many threads compress a layer at once.
+    ///
+    pub fn threads(mut self, threads: usize) -> LayerCompression {
+        self.threads = threads.max(1);
+        self
+    }
+
+
+    /// Format that layers get compressed with.
+    ///
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+
+    /// Media type of the layers compressed.
+    ///
+    pub fn media_type(&self) -> &'static str {
+        match self.compression {
+            Compression::Zstd => "application/vnd.oci.image.layer.v1.tar+zstd",
+            _ => "application/vnd.docker.image.rootfs.diff.tar.gzip",
+        }
+    }
+
+
+    /// Whether the layers compressed can only be referenced from OCI
+    /// manifests (Docker's have no media type for zstd layers).
+    ///
+    pub fn needs_oci_manifest(&self) -> bool {
+        self.compression == Compression::Zstd
+    }
+
+
+    /// Compresses the bytes supplied by `reader` into `writer`.
+    ///
+    pub fn compress(&self, reader: impl Read, writer: impl Write) -> Result<()> {
+        if self.threads == 1 {
+            return self.compress_stream(reader, writer);
+        }
+
+        self.compress_chunks(reader, writer)
+    }
+
+
+    fn compress_stream(&self, mut reader: impl Read, writer: impl Write) -> Result<()> {
+        match self.compression {
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, self.level as i32)?;
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            },
+            _ => {
+                let mut encoder = GzEncoder::new(writer, flate2::Compression::new(self.level));
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            },
+        }
+
+        Ok(())
+    }
+
+
+    /// Reads up to `threads` chunks at a time, compressing them in
+    /// parallel and writing them out in order.
+    ///
+    fn compress_chunks(&self, mut reader: impl Read, writer: impl Write) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        let mut written = false;
+
+        loop {
+            let mut chunks = Vec::with_capacity(self.threads);
+
+            while chunks.len() < self.threads {
+                let chunk = read_chunk(&mut reader)?;
+
+                if chunk.is_empty() {
+                    break;
+                }
+
+                chunks.push(chunk);
+            }
+
+            if chunks.is_empty() {
+                // even empty layers must be a valid stream.
+                //
+                if !written {
+                    self.compress_stream(io::empty(), &mut writer)?;
+                }
+
+                break;
+            }
+
+            let compressed = std::thread::scope(|scope| {
+                let handles: Vec<_> = chunks
+                    .iter()
+                    .map(|chunk| scope.spawn(move || {
+                        let mut compressed = Vec::with_capacity(chunk.len() / 2);
+                        self.compress_stream(&chunk[..], &mut compressed).map(|_| compressed)
+                    }))
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("compression thread panicked"))
+                    .collect::<Result<Vec<_>>>()
+            })?;
+
+            for chunk in compressed {
+                writer.write_all(&chunk)?;
+            }
+
+            written = true;
+
+            if chunks.len() < self.threads || chunks.last().unwrap().len() < CHUNK_SIZE {
+                break;
+            }
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+}
+
+
+/// Wraps `reader` so that the bytes read from it come out decompressed,
+/// with the format being detected from its first bytes.
+///
+/// Only the first bytes are consumed for detecting the format, thus
+/// `reader` can be something that can't be rewound (e.g., stdin).
+///
+/// ```txt
+///
+///    1f 8b 08 00 ...        ==>  (Gzip, MultiGzDecoder)
+///    28 b5 2f fd ...        ==>  (Zstd, zstd::Decoder)
+///    fd 37 7a 58 5a 00 ...  ==>  (Xz, XzDecoder)
+///    anything else          ==>  (None, reader)
+///
+/// ```
+///
+pub fn decompress<'a>(mut reader: impl Read + 'a) -> Result<(Compression, Box<dyn Read + 'a>)> {
+    let magic = read_magic(&mut reader)?;
+    let compression = Compression::detect(&magic);
+    let reader = Cursor::new(magic).chain(reader);
+
+    let decompressed: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    };
+
+    Ok((compression, decompressed))
+}
+
+
+/// Reads the first `MAGIC_LEN` bytes out of `reader`, or less if it ends
+/// before that.
+///
+fn read_magic(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+
+    reader
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut magic)?;
+
+    Ok(magic)
+}
+
+
+/// Reads up to `CHUNK_SIZE` bytes out of `reader`, less only if it ends
+/// before that.
+///
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+
+    reader
+        .take(CHUNK_SIZE as u64)
+        .read_to_end(&mut chunk)?;
+
+    Ok(chunk)
+}
+
+
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+
+    const CONTENT: &[u8] = b"some content that gets compressed";
+
+    fn decompressed(compressed: &[u8]) -> (Compression, Vec<u8>) {
+        let (compression, mut reader) = decompress(compressed).unwrap();
+        let mut content = Vec::new();
+
+        reader.read_to_end(&mut content).unwrap();
+
+        (compression, content)
+    }
+
+    #[test]
+    fn test_passes_uncompressed_content_through() {
+        assert_eq!(decompressed(CONTENT), (Compression::None, CONTENT.to_vec()));
+        assert_eq!(decompressed(b"ab"), (Compression::None, b"ab".to_vec()));
+        assert_eq!(decompressed(b""), (Compression::None, vec![]));
+    }
+
+    #[test]
+    fn test_decompresses_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(CONTENT).unwrap();
+
+        assert_eq!(
+            decompressed(&encoder.finish().unwrap()),
+            (Compression::Gzip, CONTENT.to_vec()),
+        );
+    }
+
+    #[test]
+    fn test_decompresses_zstd() {
+        let compressed = zstd::encode_all(CONTENT, 0).unwrap();
+
+        assert_eq!(decompressed(&compressed), (Compression::Zstd, CONTENT.to_vec()));
+    }
+
+    #[test]
+    fn test_decompresses_xz() {
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(CONTENT).unwrap();
+
+        assert_eq!(
+            decompressed(&encoder.finish().unwrap()),
+            (Compression::Xz, CONTENT.to_vec()),
+        );
+    }
+
+    #[test]
+    fn test_parses_names() {
+        assert_eq!("gzip".parse::<Compression>().unwrap(), Compression::Gzip);
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_only_compresses_layers_with_gzip_or_zstd() {
+        assert!(LayerCompression::new(Compression::Gzip).is_ok());
+        assert!(LayerCompression::new(Compression::Zstd).is_ok());
+        assert!(LayerCompression::new(Compression::Xz).is_err());
+        assert!(LayerCompression::new(Compression::None).is_err());
+    }
+
+    #[test]
+    fn test_checks_levels() {
+        let gzip = LayerCompression::new(Compression::Gzip).unwrap();
+        let zstd = LayerCompression::new(Compression::Zstd).unwrap();
+
+        assert!(gzip.clone().level(9).is_ok());
+        assert!(gzip.clone().level(0).is_err());
+        assert!(gzip.level(10).is_err());
+        assert!(zstd.clone().level(19).is_ok());
+        assert!(zstd.level(23).is_err());
+    }
+
+    #[test]
+    fn test_compresses_layers() {
+        // more than a few chunks, with the last one being partial.
+        //
+        let content: Vec<u8> = (0..CHUNK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect();
+
+        for compression in &[Compression::Gzip, Compression::Zstd] {
+            for threads in &[1, 2, 8] {
+                let mut compressed = Vec::new();
+
+                LayerCompression::new(*compression)
+                    .unwrap()
+                    .threads(*threads)
+                    .compress(&content[..], &mut compressed)
+                    .unwrap();
+
+                assert_eq!(decompressed(&compressed), (*compression, content.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compresses_empty_layers() {
+        for threads in &[1, 4] {
+            let mut compressed = Vec::new();
+
+            LayerCompression::new(Compression::Gzip)
+                .unwrap()
+                .threads(*threads)
+                .compress(&b""[..], &mut compressed)
+                .unwrap();
+
+            assert_eq!(decompressed(&compressed), (Compression::Gzip, vec![]));
+        }
+    }
+}
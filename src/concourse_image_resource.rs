@@ -2,14 +2,17 @@ use std::fs;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-use flate2::read::GzDecoder;
+use tracing::info;
 
 use crate::blobstore::BlobStore;
+use crate::compression::{self, LayerCompression};
 use crate::concourse_resource_metadata::ConcourseResourceMetadata;
 use crate::digest;
 use crate::error::Result;
-use crate::image_config::ImageConfig;
-use crate::registry::{ManifestDescriptor, Manifest};
+use crate::image_config::{ImageConfig, UnknownFields};
+use crate::image_reference::DEFAULT_TAG;
+use crate::registry::{ManifestDescriptor, Manifest, DOCKER_MANIFEST_MEDIA_TYPE};
+use crate::rootfs_image;
 
 pub struct ConcourseImageResource {
     /// Root directory of the image resource.
@@ -25,6 +28,24 @@ pub struct ConcourseImageResource {
     ///
     resource_metadata: ConcourseResourceMetadata,
 
+    /// How the rootfs gets compressed into the blobstore, if at all.
+    ///
+    layer_compression: Option<LayerCompression>,
+
+    /// Repository to tag the image under, defaulting to the `type` in
+    /// `resource_metadata.json`.
+    ///
+    name: Option<String>,
+
+    /// Tag to give to the image, defaulting to the `version` in
+    /// `resource_metadata.json`.
+    ///
+    tag: Option<String>,
+
+    /// Whether the image also gets tagged as `latest`.
+    ///
+    tag_latest: bool,
+
     /// The final owner of the blobs and manifests for the
     /// registry to serve.
     ///
@@ -43,23 +64,66 @@ impl ConcourseImageResource {
     /// * `blobstore` - a BlobStore to own the resulting image from such directory.
     ///
     pub fn new(dir: &Path, blobstore: BlobStore) -> Result<ConcourseImageResource> {
-        let metadata_content = fs::read_to_string(dir.join("resource_metadata.json"))?;
+        let metadata_path = dir.join("resource_metadata.json");
+        let metadata_content = fs::read_to_string(&metadata_path)
+            .map_err(|err| failure::format_err!("failed to read {}: {}", metadata_path.display(), err))?;
 
-        let metadata: ConcourseResourceMetadata = metadata_content.parse()?;
+        let metadata: ConcourseResourceMetadata = metadata_content
+            .parse()
+            .map_err(|err| failure::format_err!("invalid {}: {}", metadata_path.display(), err))?;
         let rootfs_tgz = dir.join("rootfs.tgz");
 
         if !rootfs_tgz.exists() {
-            return Err(failure::format_err!("no rootfs.tgz in dir %s"));
+            return Err(failure::format_err!("no rootfs.tgz in {}", dir.display()));
         }
 
         Ok(ConcourseImageResource {
-            blobstore: blobstore,
+            blobstore,
             resource_metadata: metadata,
+            layer_compression: None,
+            name: None,
+            tag: None,
+            tag_latest: false,
             root_dir: dir.to_owned(),
             rootfs_path: rootfs_tgz,
         })
     }
 
+    /// Makes the rootfs get compressed into the blobstore, instead of
+    /// being stored uncompressed.
+    ///
+    pub fn compress_layers(mut self, layer_compression: LayerCompression) -> ConcourseImageResource {
+        self.layer_compression = Some(layer_compression);
+        self
+    }
+
+    /// Sets the repository to tag the image under (e.g., `team/ci-image`),
+    /// instead of the `type` of the resource.
+    ///
+    pub fn name(mut self, name: &str) -> Result<ConcourseImageResource> {
+        rootfs_image::validate_name(name)?;
+
+        self.name = Some(name.to_owned());
+        Ok(self)
+    }
+
+    /// Sets the tag to give to the image, instead of the `version` of the
+    /// resource.
+    ///
+    pub fn tag(mut self, tag: &str) -> Result<ConcourseImageResource> {
+        rootfs_image::validate_tag(tag)?;
+
+        self.tag = Some(tag.to_owned());
+        Ok(self)
+    }
+
+    /// Makes the image also get tagged as `latest`.
+    ///
+    pub fn tag_latest(mut self) -> ConcourseImageResource {
+        self.tag_latest = true;
+        self
+    }
+
     /// Loads the contents found in the resource image directory into the blobstore.
     ///
     /// ```txt
@@ -70,11 +134,10 @@ impl ConcourseImageResource {
     ///
     /// ==>
     ///
-    /// 1. generate layer image configuration
-    ///     - diff_ids must be generated too
-    ///         --> ingest the layer
-    /// 2. ingest the layer
-    /// 3. generate manifest pointing to the layer and the config
+    /// 1. decompress the rootfs into the staging dir, computing its diff_id
+    /// 2. ingest the layer (compressing it if asked to)
+    /// 3. generate and ingest the image configuration
+    /// 4. generate manifest pointing to the layer and the config
     ///
     /// ==>
     ///
@@ -85,40 +148,74 @@ impl ConcourseImageResource {
     ///    │   └── sha256:ggghhhiii...  (manifest)
     ///    │
     ///    └── manifests
-    ///        └── library
-    ///            └─ $name (from `resource_metadata.json`)k
-    ///              ├── latest -> ../../bucket/sha256:ggghhhiii...
-    ///              ├── $version (from `resource_metadata.json`) -> ../../bucket/sha256:ggghhhiii...
-    ///              └── sha256:dbafff2... --> ../../bucket/ggghhhiii...
+    ///        └── $name (or `type` from `resource_metadata.json`)
+    ///            ├── latest -> ../../bucket/sha256:ggghhhiii...  (if asked to)
+    ///            ├── $tag (or `version` from `resource_metadata.json`) -> ../../bucket/sha256:ggghhhiii...
+    ///            └── sha256:ggghhhiii... --> ../../bucket/sha256:ggghhhiii...
     ///
     /// ```
     ///
     pub fn load(&self) -> Result<()> {
-        self.decompress_rootfs()?;
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                let name = &self.resource_metadata.image_type;
+                rootfs_image::validate_name(name)
+                    .map_err(|err| failure::format_err!("{} (type of the resource), set a name instead", err))?;
+
+                name.clone()
+            },
+        };
+
+        let tag = match &self.tag {
+            Some(tag) => tag.clone(),
+            None => {
+                let tag = &self.resource_metadata.version;
+                rootfs_image::validate_tag(tag)
+                    .map_err(|err| failure::format_err!("{} (version of the resource), set a tag instead", err))?;
+
+                tag.clone()
+            },
+        };
+
+        let staging_dir = self.blobstore.staging_dir()?;
 
-        let layer_descriptor = self.ingest_rootfs()?;
-        let image_config = self.generate_config(&layer_descriptor.digest)?;
-        let config_descriptor = self.ingest_config(&self.root_dir.join("config.json"))?;
+        let rootfs_tar = staging_dir.path().join("rootfs.tar");
+        self.decompress_rootfs(&rootfs_tar)?;
 
-        let manifest = Manifest {
+        let (layer_descriptor, diff_id) = self.ingest_rootfs(&rootfs_tar)?;
+
+        let config_path = staging_dir.path().join("config.json");
+        self.generate_config(&config_path, &diff_id)?;
+        let config_descriptor = self.ingest_config(&config_path)?;
+
+        let mut manifest = Manifest {
             schema_version: 2,
-            media_type: "application/vnd.docker.distribution.manifest.v2+json",
+            media_type: DOCKER_MANIFEST_MEDIA_TYPE.to_owned(),
             config: config_descriptor,
             layers: vec![layer_descriptor],
+            unknown: UnknownFields::new(),
         };
 
+        if self.layer_compression.as_ref().is_some_and(LayerCompression::needs_oci_manifest) {
+            manifest = manifest.into_oci();
+        }
+
         let manifest_filename = self.blobstore.add_manifest(&manifest)?;
 
-        self.blobstore.tag_manifest(
-            &manifest_filename, 
-            &self.resource_metadata.image_type, 
-            &manifest_filename,
-        );
+        self.blobstore.tag_manifest(&manifest_filename, &name, &manifest_filename)?;
+        self.blobstore.tag_manifest(&manifest_filename, &name, &tag)?;
+
+        if self.tag_latest {
+            self.blobstore.tag_manifest(&manifest_filename, &name, DEFAULT_TAG)?;
+        }
 
-        self.blobstore.tag_manifest(
-            &manifest_filename, 
-            &self.resource_metadata.image_type, 
-            &self.resource_metadata.version,
+        info!(
+            source = %self.root_dir.display(),
+            name = %name,
+            tag = %tag,
+            manifest = %manifest_filename,
+            "tagged image",
         );
 
         Ok(())
@@ -138,33 +235,46 @@ impl ConcourseImageResource {
 
         self.blobstore.add_blob(original_location)?;
 
-        Ok(ManifestDescriptor {
-            media_type: media_type,
+        let descriptor = ManifestDescriptor {
+            media_type: media_type.to_owned(),
             size: blob_size,
             digest: digest::prepend_sha_scheme(&blob_digest),
-        })
+            unknown: UnknownFields::new(),
+        };
+
+        info!(
+            source = %original_location.display(),
+            digest = %descriptor.digest,
+            size = descriptor.size,
+            media_type,
+            "ingested blob",
+        );
+
+        Ok(descriptor)
     }
 
-    fn decompress_rootfs(&self) -> Result<()> {
-        let tar_gz = fs::File::open(&self.rootfs_path)?;
-        let mut tar = GzDecoder::new(tar_gz);
+    fn decompress_rootfs(&self, rootfs_tar: &Path) -> Result<()> {
+        let (_, mut tar) = compression::decompress(fs::File::open(&self.rootfs_path)?)?;
         let mut tar_file = fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&self.root_dir.join("rootfs.tar"))?;
+            .truncate(true)
+            .open(rootfs_tar)?;
 
-        let _result = std::io::copy(&mut tar, &mut tar_file)?;
+        std::io::copy(&mut tar, &mut tar_file)
+            .map_err(|err| failure::format_err!("failed to decompress {}: {}", self.rootfs_path.display(), err))?;
 
         Ok(())
     }
 
-    fn generate_config(&self, layer_digest: &str) -> Result<ImageConfig> {
-        let config = ImageConfig::new(vec![layer_digest.to_owned()]);
+    fn generate_config(&self, config_path: &Path, diff_id: &str) -> Result<ImageConfig> {
+        let config = ImageConfig::new(vec![diff_id.to_owned()]);
 
         let mut config_file = fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&self.root_dir.join("config.json"))?;
+            .truncate(true)
+            .open(config_path)?;
 
         config_file.write_all(config.to_string().as_bytes())?;
 
@@ -178,10 +288,55 @@ impl ConcourseImageResource {
         )
     }
 
-    fn ingest_rootfs(&self) -> Result<ManifestDescriptor> {
-        self.ingest_blob(
-            &self.root_dir.join("rootfs.tar"),
-            "application/vnd.docker.image.rootfs.diff.tar",
-        )
+    /// Ingests the decompressed rootfs, returning its descriptor along with
+    /// the digest of the uncompressed layer (its `diff_id`).
+    ///
+    fn ingest_rootfs(&self, rootfs_tar: &Path) -> Result<(ManifestDescriptor, String)> {
+        let uncompressed_digest = digest::compute_for_file_and_store(rootfs_tar)?;
+        let diff_id = digest::prepend_sha_scheme(&uncompressed_digest);
+
+        let layer_compression = match &self.layer_compression {
+            Some(layer_compression) => layer_compression,
+            None => {
+                let descriptor = ManifestDescriptor {
+                    media_type: "application/vnd.docker.image.rootfs.diff.tar".to_owned(),
+                    size: fs::metadata(rootfs_tar)?.len(),
+                    digest: diff_id.clone(),
+                    unknown: UnknownFields::new(),
+                };
+
+                self.blobstore.add_blob_with_digest(rootfs_tar, &uncompressed_digest)?;
+
+                info!(
+                    source = %self.rootfs_path.display(),
+                    digest = %descriptor.digest,
+                    size = descriptor.size,
+                    media_type = %descriptor.media_type,
+                    "ingested blob",
+                );
+
+                return Ok((descriptor, diff_id));
+            },
+        };
+
+        let (blob_digest, blob_size) = self.blobstore.add_compressed_blob(fs::File::open(rootfs_tar)?, layer_compression)?;
+
+        let descriptor = ManifestDescriptor {
+            media_type: layer_compression.media_type().to_owned(),
+            size: blob_size,
+            digest: digest::prepend_sha_scheme(&blob_digest),
+            unknown: UnknownFields::new(),
+        };
+
+        info!(
+            source = %self.rootfs_path.display(),
+            digest = %descriptor.digest,
+            diff_id = %diff_id,
+            size = descriptor.size,
+            media_type = %descriptor.media_type,
+            "ingested compressed layer",
+        );
+
+        Ok((descriptor, diff_id))
     }
 }
@@ -27,13 +27,13 @@ impl FromStr for ConcourseResourceMetadata {
 mod concourse_resource_metadata_tests {
     use super::*;
 
-    const RESOURCE_METADATA_SAMPLE: &'static str = r#"{
+    const RESOURCE_METADATA_SAMPLE: &str = r#"{
   "type": "registry-image",
   "version": "v1.2.3"
 }"#;
 
     #[test]
     fn parses_metadata_json() {
-        let parsed: ConcourseResourceMetadata = RESOURCE_METADATA_SAMPLE.parse().unwrap();
+        let _parsed: ConcourseResourceMetadata = RESOURCE_METADATA_SAMPLE.parse().unwrap();
     }
 }
@@ -0,0 +1,801 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use toml::{Table, Value};
+use tracing_subscriber::EnvFilter;
+
+use crate::compression::{Compression, LayerCompression};
+use crate::error::Result;
+use crate::image_reference::RegistryHost;
+use crate::listener::ListenAddress;
+use crate::logging::LogFormat;
+use crate::server;
+use crate::tls::TlsOptions;
+use crate::token::TokenOptions;
+
+
+pub const DEFAULT_BLOBSTORE: &str = "/tmp/cartorio/blobstore";
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:5000";
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+pub const DEFAULT_TOKEN_SERVICE: &str = "cartorio";
+pub const DEFAULT_TOKEN_ISSUER: &str = "cartorio";
+
+
+/// Environment variable pointing at the configuration file, when not given
+/// through `--config`.
+///
+pub const CONFIG_ENV_VAR: &str = "CARTORIO_CONFIG";
+
+
+/// The settings of `cartorio serve` and `cartorio load`, gathered from a
+/// TOML file, `CARTORIO_*` environment variables and flags.
+///
+/// ```toml
+/// blobstore = "/var/lib/cartorio"
+///
+/// [log]
+/// level = "info"
+/// format = "json"
+///
+/// [serve]
+/// address = "0.0.0.0:5000"
+/// policy = "/etc/cartorio/policy.toml"
+///
+/// [serve.tls]
+/// cert = "/etc/cartorio/cert.pem"
+/// key = "/etc/cartorio/key.pem"
+///
+/// [load]
+/// compress = "zstd"
+/// registry_host = "strip"
+/// ```
+///
+/// Each setting can be overridden by an environment variable, which can
+/// in turn be overridden by a flag (see `SETTINGS`), with the defaults
+/// applying to whatever is left unset:
+///
+/// ```txt
+/// flags  >  environment variables  >  configuration file  >  defaults
+/// ```
+///
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub blobstore: Option<PathBuf>,
+
+    #[serde(default)]
+    pub log: LogConfig,
+
+    #[serde(default)]
+    pub serve: ServeConfig,
+
+    #[serde(default)]
+    pub load: LoadConfig,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default, deserialize_with = "log_filter")]
+    pub level: Option<String>,
+
+    pub format: Option<LogFormat>,
+
+    pub otlp_endpoint: Option<String>,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeConfig {
+    /// Either a single address or a list of them.
+    ///
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub address: Option<Vec<ListenAddress>>,
+
+    pub htpasswd: Option<PathBuf>,
+
+    pub policy: Option<PathBuf>,
+
+    pub global_blobs: Option<bool>,
+
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Seconds to wait for in-flight responses when shutting down.
+    ///
+    pub shutdown_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    #[serde(default)]
+    pub token: TokenConfig,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub realm: Option<String>,
+    pub service: Option<String>,
+    pub issuer: Option<String>,
+    pub public_key: Option<PathBuf>,
+    pub signing_key: Option<PathBuf>,
+}
+
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadConfig {
+    pub metrics_textfile: Option<PathBuf>,
+
+    /// How layers get compressed into the blobstore, if at all (also for
+    /// `mutate append`).
+    ///
+    #[serde(default, deserialize_with = "parsed")]
+    pub compress: Option<Compression>,
+
+    pub compression_level: Option<u32>,
+
+    pub compression_threads: Option<usize>,
+
+    #[serde(default, deserialize_with = "parsed")]
+    pub registry_host: Option<RegistryHost>,
+
+    /// Credentials for the registry that `--from-registry` pulls from.
+    ///
+    pub registry_username: Option<String>,
+
+    pub registry_password: Option<String>,
+}
+
+
+/// The type of the value of a setting, as environment variables and flags
+/// only carry strings.
+///
+/// Lists are given by repeating flags, or separating the values of
+/// environment variables with commas.
+///
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Bool,
+    Integer,
+    List,
+}
+
+
+/// A setting that can be given by an environment variable or a flag
+/// besides the configuration file.
+///
+/// Secrets (e.g., passwords) can't be given by flags, as those end up in
+/// the list of processes and the history of shells.
+///
+pub struct Setting {
+
+    /// Dotted path of the setting in the configuration file (e.g.,
+    /// `serve.tls.cert`).
+    ///
+    pub key: &'static str,
+
+    /// Name of the flag that sets it (e.g., `tls-cert`), also giving the
+    /// name of the environment variable (e.g., `CARTORIO_TLS_CERT`). Only
+    /// the latter exists for secrets.
+    ///
+    pub flag: &'static str,
+
+    kind: Kind,
+
+    secret: bool,
+}
+
+
+impl Setting {
+
+    /// Name of the environment variable that sets it.
+    ///
+    pub fn env_var(&self) -> String {
+        format!("CARTORIO_{}", self.flag.to_uppercase().replace('-', "_"))
+    }
+
+}
+
+
+const fn setting(key: &'static str, flag: &'static str, kind: Kind) -> Setting {
+    Setting { key, flag, kind, secret: false }
+}
+
+
+const fn secret(key: &'static str, flag: &'static str, kind: Kind) -> Setting {
+    Setting { key, flag, kind, secret: true }
+}
+
+
+/// Every setting that can be overridden.
+///
+pub const SETTINGS: &[Setting] = &[
+    setting("blobstore", "blobstore", Kind::String),
+    setting("log.level", "log-level", Kind::String),
+    setting("log.format", "log-format", Kind::String),
+    setting("log.otlp_endpoint", "otlp-endpoint", Kind::String),
+    setting("serve.address", "address", Kind::List),
+    setting("serve.htpasswd", "htpasswd", Kind::String),
+    setting("serve.policy", "policy", Kind::String),
+    setting("serve.global_blobs", "global-blobs", Kind::Bool),
+    setting("serve.metrics_address", "metrics-address", Kind::String),
+    setting("serve.shutdown_timeout", "shutdown-timeout", Kind::Integer),
+    setting("serve.tls.cert", "tls-cert", Kind::String),
+    setting("serve.tls.key", "tls-key", Kind::String),
+    setting("serve.tls.client_ca", "tls-client-ca", Kind::String),
+    setting("serve.token.realm", "token-realm", Kind::String),
+    setting("serve.token.service", "token-service", Kind::String),
+    setting("serve.token.issuer", "token-issuer", Kind::String),
+    setting("serve.token.public_key", "token-public-key", Kind::String),
+    setting("serve.token.signing_key", "token-signing-key", Kind::String),
+    setting("load.metrics_textfile", "metrics-textfile", Kind::String),
+    setting("load.compress", "compress", Kind::String),
+    setting("load.compression_level", "compression-level", Kind::Integer),
+    setting("load.compression_threads", "compression-threads", Kind::Integer),
+    setting("load.registry_host", "registry-host", Kind::String),
+    setting("load.registry_username", "registry-username", Kind::String),
+    secret("load.registry_password", "registry-password", Kind::String),
+];
+
+
+impl Config {
+
+    /// Gathers the configuration from all of its sources.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - configuration file to start from, if any.
+    /// * `env` - environment variables (e.g., `std::env::vars()`).
+    /// * `flag` - gives the values of a flag when it's been set.
+    ///
+    pub fn load(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+        flag: impl Fn(&str) -> Option<Vec<String>>,
+    ) -> Result<Config> {
+        let mut table = match file {
+            Some(path) => read_table(path)?,
+            None => Table::new(),
+        };
+
+        let env: HashMap<String, String> = env.into_iter().collect();
+
+        for setting in SETTINGS {
+            let env_var = setting.env_var();
+
+            if let Some(value) = env.get(&env_var) {
+                let values: Vec<String> = match setting.kind {
+                    Kind::List => value.split(',').map(|value| value.trim().to_owned()).collect(),
+                    _ => vec![value.clone()],
+                };
+
+                override_setting(&mut table, setting, &values)
+                    .map_err(|err| failure::format_err!("{}: {}", env_var, err))?;
+            }
+        }
+
+        for setting in SETTINGS.iter().filter(|setting| !setting.secret) {
+            if let Some(values) = flag(setting.flag) {
+                override_setting(&mut table, setting, &values)
+                    .map_err(|err| failure::format_err!("--{}: {}", setting.flag, err))?;
+            }
+        }
+
+        let config: Config = Value::Table(table).try_into()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+
+    /// Checks the settings that only make sense together.
+    ///
+    fn validate(&self) -> Result<()> {
+        let tls = &self.serve.tls;
+
+        if tls.cert.is_some() != tls.key.is_some() {
+            return Err(failure::format_err!("`serve.tls.cert` and `serve.tls.key` must be set together"));
+        }
+
+        if tls.client_ca.is_some() && tls.cert.is_none() {
+            return Err(failure::format_err!("`serve.tls.client_ca` requires `serve.tls.cert`"));
+        }
+
+        let token = &self.serve.token;
+
+        if (token.public_key.is_some() || token.signing_key.is_some()) && token.realm.is_none() {
+            return Err(failure::format_err!(
+                "`serve.token.public_key` and `serve.token.signing_key` require `serve.token.realm`"
+            ));
+        }
+
+        // a realm alone would leave the registry open rather than requiring
+        // tokens.
+        //
+        if token.realm.is_some() && token.public_key.is_none() && token.signing_key.is_none() {
+            return Err(failure::format_err!(
+                "`serve.token.realm` requires `serve.token.public_key` or `serve.token.signing_key`"
+            ));
+        }
+
+        let load = &self.load;
+
+        if (load.compression_level.is_some() || load.compression_threads.is_some()) && load.compress.is_none() {
+            return Err(failure::format_err!(
+                "`load.compression_level` and `load.compression_threads` require `load.compress`"
+            ));
+        }
+
+        self.layer_compression()?;
+
+        if self.load.registry_username.is_some() != self.load.registry_password.is_some() {
+            return Err(failure::format_err!(
+                "`load.registry_username` and `load.registry_password` must be set together"
+            ));
+        }
+
+        Ok(())
+    }
+
+
+    pub fn blobstore(&self) -> &Path {
+        self.blobstore
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_BLOBSTORE))
+    }
+
+
+    pub fn log_level(&self) -> &str {
+        self.log.level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)
+    }
+
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log.format.unwrap_or(LogFormat::Logfmt)
+    }
+
+
+    /// How layers get compressed into the blobstore, if at all, compressing
+    /// them with as many threads as there are CPUs by default.
+    ///
+    pub fn layer_compression(&self) -> Result<Option<LayerCompression>> {
+        let load = &self.load;
+
+        let compression = match load.compress {
+            Some(compression) => compression,
+            None => return Ok(None),
+        };
+
+        let mut layer_compression = LayerCompression::new(compression)?;
+
+        if let Some(level) = load.compression_level {
+            layer_compression = layer_compression.level(level)?;
+        }
+
+        let threads = load
+            .compression_threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |threads| threads.get()));
+
+        Ok(Some(layer_compression.threads(threads)))
+    }
+
+
+    pub fn registry_host(&self) -> RegistryHost {
+        self.load.registry_host.clone().unwrap_or_default()
+    }
+
+
+    pub fn addresses(&self) -> Vec<ListenAddress> {
+        self.serve
+            .address
+            .clone()
+            .unwrap_or_else(|| vec![DEFAULT_ADDRESS.parse().unwrap()])
+    }
+
+
+    /// The options of the server, as far as they can be configured.
+    ///
+    pub fn server_options(&self) -> server::Options {
+        let serve = &self.serve;
+
+        let tls = match (&serve.tls.cert, &serve.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsOptions {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: serve.tls.client_ca.clone(),
+            }),
+            _ => None,
+        };
+
+        let token = match &serve.token.realm {
+            Some(realm) if serve.token.public_key.is_some() || serve.token.signing_key.is_some() => {
+                Some(TokenOptions {
+                    realm: realm.clone(),
+                    service: serve.token.service.clone().unwrap_or_else(|| DEFAULT_TOKEN_SERVICE.to_owned()),
+                    issuer: serve.token.issuer.clone().unwrap_or_else(|| DEFAULT_TOKEN_ISSUER.to_owned()),
+                    public_key: serve.token.public_key.clone(),
+                    signing_key: serve.token.signing_key.clone(),
+                })
+            },
+            _ => None,
+        };
+
+        server::Options {
+            tls,
+            htpasswd: serve.htpasswd.clone(),
+            token,
+            policy: serve.policy.clone(),
+            global_blobs: serve.global_blobs.unwrap_or(false),
+            metrics_address: serve.metrics_address,
+            metrics: None,
+            shutdown_timeout: Some(Duration::from_secs(
+                serve.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            )),
+        }
+    }
+
+}
+
+
+/// Reads a configuration file, checking it on its own so that errors point
+/// at where in the file they are.
+///
+fn read_table(path: &Path) -> Result<Table> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| failure::format_err!("{}: {}", path.display(), err))?;
+
+    toml::from_str::<Config>(&content)
+        .map_err(|err| failure::format_err!("{}: {}", path.display(), err))?;
+
+    Ok(toml::from_str(&content)?)
+}
+
+
+/// Sets `setting` to `values` in `table` (only the last one for settings
+/// that aren't lists), checking that it's valid for the setting on its own.
+///
+fn override_setting(table: &mut Table, setting: &Setting, values: &[String]) -> Result<()> {
+    let value = match values.last() {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    let value = match setting.kind {
+        Kind::String => Value::String(value.to_owned()),
+        Kind::Bool => Value::Boolean(
+            value
+                .parse()
+                .map_err(|_| failure::format_err!("expected `true` or `false`, got `{}`", value))?,
+        ),
+        Kind::Integer => Value::Integer(
+            value
+                .parse()
+                .map_err(|_| failure::format_err!("expected an integer, got `{}`", value))?,
+        ),
+        Kind::List => Value::Array(values.iter().map(|value| Value::String(value.clone())).collect()),
+    };
+
+    let mut single = Table::new();
+    insert(&mut single, setting.key, value.clone());
+    Value::Table(single).try_into::<Config>()?;
+
+    insert(table, setting.key, value);
+
+    Ok(())
+}
+
+
+/// Inserts `value` under the dotted `key`, creating the tables in between.
+///
+fn insert(table: &mut Table, key: &str, value: Value) {
+    let mut table = table;
+    let mut components = key.split('.').peekable();
+
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            table.insert(component.to_owned(), value);
+            return;
+        }
+
+        let entry = table
+            .entry(component.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()));
+
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+
+        table = entry.as_table_mut().unwrap();
+    }
+}
+
+
+/// Takes either a single listen address or a list of them.
+///
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<ListenAddress>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct Addresses;
+
+    impl<'de> Visitor<'de> for Addresses {
+
+        type Value = Vec<ListenAddress>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an address or a list of addresses")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, address: &str) -> std::result::Result<Self::Value, E> {
+            Ok(vec![address.parse().map_err(E::custom)?])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+            let mut addresses = Vec::new();
+
+            while let Some(address) = seq.next_element()? {
+                addresses.push(address);
+            }
+
+            Ok(addresses)
+        }
+
+    }
+
+    deserializer.deserialize_any(Addresses).map(Some)
+}
+
+
+/// Takes a value out of its string representation.
+///
+fn parsed<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+
+fn log_filter<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let filter = String::deserialize(deserializer)?;
+
+    EnvFilter::try_new(&filter)
+        .map_err(|err| serde::de::Error::custom(format!("invalid log level `{}` - {}", filter, err)))?;
+
+    Ok(Some(filter))
+}
+
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    fn config_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn no_flags(_: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    #[test]
+    fn applies_defaults() {
+        let config = Config::load(None, env(&[]), no_flags).unwrap();
+
+        assert_eq!(config.blobstore(), Path::new(DEFAULT_BLOBSTORE));
+        assert_eq!(config.addresses(), vec![DEFAULT_ADDRESS.parse().unwrap()]);
+        assert_eq!(config.log_level(), "info");
+        assert_eq!(config.log_format(), LogFormat::Logfmt);
+
+        let options = config.server_options();
+        assert!(options.tls.is_none());
+        assert!(options.token.is_none());
+        assert_eq!(options.shutdown_timeout, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn flags_take_precedence_over_env_over_file() {
+        let file = config_file("blobstore = \"/from/file\"\n\n[serve]\naddress = \"127.0.0.1:1\"\npolicy = \"/from/file\"\n");
+
+        let config = Config::load(
+            Some(file.path()),
+            env(&[("CARTORIO_BLOBSTORE", "/from/env"), ("CARTORIO_ADDRESS", "127.0.0.1:2")]),
+            |flag| match flag {
+                "address" => Some(vec!["127.0.0.1:3".to_owned()]),
+                _ => None,
+            },
+        ).unwrap();
+
+        assert_eq!(config.blobstore(), Path::new("/from/env"));
+        assert_eq!(config.addresses(), vec!["127.0.0.1:3".parse().unwrap()]);
+        assert_eq!(config.serve.policy, Some(PathBuf::from("/from/file")));
+    }
+
+    #[test]
+    fn parses_typed_env_vars() {
+        let config = Config::load(
+            None,
+            env(&[("CARTORIO_GLOBAL_BLOBS", "true"), ("CARTORIO_SHUTDOWN_TIMEOUT", "5")]),
+            no_flags,
+        ).unwrap();
+
+        let options = config.server_options();
+        assert!(options.global_blobs);
+        assert_eq!(options.shutdown_timeout, Some(Duration::from_secs(5)));
+
+        let err = Config::load(None, env(&[("CARTORIO_SHUTDOWN_TIMEOUT", "soon")]), no_flags).unwrap_err();
+        assert!(err.to_string().starts_with("CARTORIO_SHUTDOWN_TIMEOUT:"), "{}", err);
+
+        let err = Config::load(None, env(&[("CARTORIO_ADDRESS", "nowhere")]), no_flags).unwrap_err();
+        assert!(err.to_string().starts_with("CARTORIO_ADDRESS:"), "{}", err);
+    }
+
+    #[test]
+    fn takes_one_or_many_addresses() {
+        let file = config_file("[serve]\naddress = [\"[::]:5000\", \"unix:/run/cartorio.sock\"]\n");
+        let config = Config::load(Some(file.path()), env(&[]), no_flags).unwrap();
+
+        assert_eq!(
+            config.addresses(),
+            vec![
+                ListenAddress::Tcp("[::]:5000".parse().unwrap()),
+                ListenAddress::Unix(PathBuf::from("/run/cartorio.sock")),
+            ],
+        );
+
+        let config = Config::load(None, env(&[("CARTORIO_ADDRESS", "127.0.0.1:1, systemd")]), no_flags).unwrap();
+        assert_eq!(config.addresses(), vec!["127.0.0.1:1".parse().unwrap(), ListenAddress::Systemd]);
+
+        let file = config_file("[serve]\naddress = [\"127.0.0.1:5000\", \"nowhere\"]\n");
+        let err = Config::load(Some(file.path()), env(&[]), no_flags).unwrap_err().to_string();
+
+        assert!(err.contains("line 2"), "{}", err);
+        assert!(err.contains("invalid listen address `nowhere`"), "{}", err);
+    }
+
+    #[test]
+    fn reports_where_file_errors_are() {
+        let file = config_file("[serve]\naddress = \"127.0.0.1:5000\"\nglobal_blob = true\n");
+        let err = Config::load(Some(file.path()), env(&[]), no_flags).unwrap_err().to_string();
+
+        assert!(err.contains("line 3, column 1"), "{}", err);
+        assert!(err.contains("global_blob"), "{}", err);
+
+        let file = config_file("[log]\nlevel = \"cartorio=loud\"\n");
+        let err = Config::load(Some(file.path()), env(&[]), no_flags).unwrap_err().to_string();
+
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn checks_settings_that_go_together() {
+        let err = Config::load(None, env(&[("CARTORIO_TLS_CERT", "/cert.pem")]), no_flags).unwrap_err();
+        assert!(err.to_string().contains("serve.tls.key"), "{}", err);
+
+        let err = Config::load(None, env(&[("CARTORIO_TOKEN_SIGNING_KEY", "/key.pem")]), no_flags).unwrap_err();
+        assert!(err.to_string().contains("serve.token.realm"), "{}", err);
+
+        let file = config_file("[serve.token]\nrealm = \"http://localhost:5000/token\"\n");
+        let config = Config::load(
+            Some(file.path()),
+            env(&[("CARTORIO_TOKEN_SIGNING_KEY", "/key.pem")]),
+            no_flags,
+        ).unwrap();
+
+        let token = config.server_options().token.unwrap();
+        assert_eq!(token.realm, "http://localhost:5000/token");
+        assert_eq!(token.service, DEFAULT_TOKEN_SERVICE);
+
+        let err = Config::load(None, env(&[("CARTORIO_REGISTRY_USERNAME", "alice")]), no_flags).unwrap_err();
+        assert!(err.to_string().contains("load.registry_password"), "{}", err);
+
+        let err = Config::load(None, env(&[("CARTORIO_TOKEN_REALM", "http://localhost/token")]), no_flags).unwrap_err();
+        assert!(err.to_string().contains("serve.token.public_key"), "{}", err);
+
+        let err = Config::load(None, env(&[("CARTORIO_COMPRESSION_LEVEL", "9")]), no_flags).unwrap_err();
+        assert!(err.to_string().contains("load.compress"), "{}", err);
+
+        let err = Config::load(
+            None,
+            env(&[("CARTORIO_COMPRESS", "gzip"), ("CARTORIO_COMPRESSION_LEVEL", "19")]),
+            no_flags,
+        ).unwrap_err();
+        assert!(err.to_string().contains("between 1 and 9"), "{}", err);
+    }
+
+    #[test]
+    fn configures_loading() {
+        let config = Config::load(None, env(&[]), no_flags).unwrap();
+        assert_eq!(config.layer_compression().unwrap(), None);
+        assert_eq!(config.registry_host(), RegistryHost::Keep);
+
+        let file = config_file("[load]\ncompress = \"zstd\"\ncompression_level = 19\nregistry_host = \"strip\"\n");
+        let config = Config::load(
+            Some(file.path()),
+            env(&[("CARTORIO_COMPRESSION_THREADS", "2")]),
+            no_flags,
+        ).unwrap();
+
+        let expected = LayerCompression::new(Compression::Zstd).unwrap().level(19).unwrap().threads(2);
+        assert_eq!(config.layer_compression().unwrap(), Some(expected));
+        assert_eq!(config.registry_host(), RegistryHost::Strip);
+
+        let config = Config::load(None, env(&[("CARTORIO_REGISTRY_HOST", "strip")]), |flag| match flag {
+            "registry-host" => Some(vec!["mirror.local".to_owned()]),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.registry_host(), RegistryHost::Rewrite("mirror.local".to_owned()));
+
+        let err = Config::load(None, env(&[("CARTORIO_COMPRESS", "lz4")]), no_flags).unwrap_err();
+        assert!(err.to_string().starts_with("CARTORIO_COMPRESS:"), "{}", err);
+    }
+
+    #[test]
+    fn takes_secrets_only_from_env_or_file() {
+        let flags = |flag: &str| match flag {
+            "registry-username" => Some(vec!["alice".to_owned()]),
+            "registry-password" => Some(vec!["hunter2".to_owned()]),
+            _ => None,
+        };
+
+        let err = Config::load(None, env(&[]), flags).unwrap_err();
+        assert!(err.to_string().contains("load.registry_password"), "{}", err);
+
+        let config = Config::load(None, env(&[("CARTORIO_REGISTRY_PASSWORD", "hunter2")]), flags).unwrap();
+        assert_eq!(config.load.registry_username.as_deref(), Some("alice"));
+        assert_eq!(config.load.registry_password.as_deref(), Some("hunter2"));
+
+        let file = config_file("[load]\nregistry_password = \"hunter2\"\n");
+        let config = Config::load(Some(file.path()), env(&[]), flags).unwrap();
+        assert_eq!(config.load.registry_password.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn maps_flags_to_env_vars() {
+        let names: Vec<String> = SETTINGS.iter().map(|setting| setting.env_var()).collect();
+
+        assert!(names.contains(&"CARTORIO_TLS_CLIENT_CA".to_owned()));
+        assert!(names.contains(&"CARTORIO_OTLP_ENDPOINT".to_owned()));
+    }
+}
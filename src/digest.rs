@@ -1,15 +1,32 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::path::{Path};
 
 use sha2::{Digest, Sha256};
+use tracing::instrument;
 
 use crate::error::Result;
 
 
 /// The xattr field to store the digest.
 ///
-const DIGEST_XATTR: &'static str = "digest";
+/// Linux only allows unprivileged processes to set attributes under the
+/// `user` namespace.
+///
+#[cfg(target_os = "linux")]
+const DIGEST_XATTR: &str = "user.digest";
+
+#[cfg(not(target_os = "linux"))]
+const DIGEST_XATTR: &str = "digest";
+
+
+/// The xattr field that digests used to be stored under on every platform.
+///
+/// Blobs of blobstores written before digests moved to `user.digest` on
+/// Linux are still looked up under it; those without either get their
+/// digest recomputed (and stored under `DIGEST_XATTR`) when loaded again.
+///
+const LEGACY_DIGEST_XATTR: &str = "digest";
 
 
 /// Stores digest information into a file.
@@ -44,13 +61,20 @@ pub fn store(filepath: &Path, digest: &str) -> Result<()> {
 /// The method might panic if the stored string is not utf8.
 ///
 pub fn retrieve(filepath: &Path) -> Result<Option<String>> {
-    let get_opt = xattr::get(filepath, DIGEST_XATTR)?;
+    let mut get_opt = xattr::get(filepath, DIGEST_XATTR)?;
+
+    // the kernel may refuse names outside of a namespace altogether, which
+    // just means there's no legacy digest to be found.
+    //
+    if get_opt.is_none() && DIGEST_XATTR != LEGACY_DIGEST_XATTR {
+        get_opt = xattr::get(filepath, LEGACY_DIGEST_XATTR).unwrap_or(None);
+    }
 
     match get_opt {
         Some(v) => {
             let value = std::str::from_utf8(&v).unwrap();
 
-            return Ok(Some(value.to_string()));
+            Ok(Some(value.to_string()))
         },
         None => Ok(None),
     }
@@ -63,7 +87,21 @@ pub fn retrieve(filepath: &Path) -> Result<Option<String>> {
 ///
 /// * `reader` - the supplier of bytes that we compute the hash against.
 ///
-pub fn compute(mut reader: impl Read) -> Result<String> {
+#[instrument(skip_all)]
+pub fn compute(reader: impl Read) -> Result<String> {
+    compute_and_copy(reader, io::sink())
+}
+
+
+/// Computes the digest of the bytes supplied by `reader` while copying
+/// them to `writer`.
+///
+/// # Arguments
+///
+/// * `reader` - the supplier of bytes that we compute the hash against.
+/// * `writer` - the destination of the bytes read.
+///
+pub fn compute_and_copy(mut reader: impl Read, mut writer: impl Write) -> Result<String> {
     let mut hasher = Sha256::new();
     let mut buf = [0; 1 << 12];
 
@@ -75,8 +113,11 @@ pub fn compute(mut reader: impl Read) -> Result<String> {
         }
 
         hasher.input(&buf[0..n]);
+        writer.write_all(&buf[0..n])?;
     }
 
+    writer.flush()?;
+
     Ok(hex::encode(hasher.result().as_slice()))
 }
 
@@ -95,6 +136,7 @@ pub fn compute_for_file(filepath: &Path) -> Result<String> {
 /// Computes the digest of a file and stores it in its xattr.
 ///
 ///
+#[instrument]
 pub fn compute_for_file_and_store(filepath: &Path) -> Result<String> {
     let digest = compute_for_file(filepath)?;
 
@@ -104,6 +146,7 @@ pub fn compute_for_file_and_store(filepath: &Path) -> Result<String> {
 }
 
 
+#[instrument]
 pub fn retrieve_or_compute_and_store(filepath: &Path) -> Result<String> {
     let digest_opt = retrieve(filepath)?;
 
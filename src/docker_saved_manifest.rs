@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 
 /// Represents the configuration exposed by `docker save`d  tarballs.
@@ -22,6 +22,10 @@ pub struct ImageManifest {
 
     /// List of tags associated with the image.
     ///
+    /// Images saved by ID rather than by name come with `null` instead,
+    /// taken as an empty list.
+    ///
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub repo_tags: Vec<String>,
 
     /// Image layers.
@@ -29,6 +33,15 @@ pub struct ImageManifest {
     pub layers: Vec<String>,
 }
 
+/// Deserializes a list that might be `null` into an empty one in such case.
+///
+fn null_as_empty<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
 pub struct DockerSavedManifest {
     pub images_manifests: Vec<ImageManifest>,
 }
@@ -49,7 +62,7 @@ impl FromStr for DockerSavedManifest {
         let images_manifests: Vec<ImageManifest> = serde_json::from_str(content)?;
 
         Ok(DockerSavedManifest{
-            images_manifests: images_manifests,
+            images_manifests,
         })
     }
 
@@ -84,4 +97,21 @@ mod docker_saved_manifest_tests {
             "48e2eeb489cdea15786d3622270750508d7385f3b684306703d17ffd50ecd34a.json"
         );
     }
+
+    #[test]
+    fn test_docker_saved_manifest_without_tags() {
+        let data = r#"[
+  {
+    "Config": "blobs/sha256/48e2eeb489cdea15786d3622270750508d7385f3b684306703d17ffd50ecd34a",
+    "RepoTags": null,
+    "Layers": [
+      "blobs/sha256/4dc05cb02b54b373232011f781f8a98905d3e10575f2a399094f704d14913a7d"
+    ]
+  }
+]"#;
+
+        let manifests: DockerSavedManifest = data.parse().unwrap();
+
+        assert!(manifests.images_manifests[0].repo_tags.is_empty());
+    }
 }
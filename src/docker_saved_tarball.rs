@@ -1,22 +1,58 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use tempfile::tempdir;
+use tracing::{info, warn};
 
-use crate::blobstore::BlobStore;
+use crate::blobstore::{BlobExtent, BlobStore};
+use crate::compression::{self, Compression, LayerCompression};
 use crate::digest;
 use crate::docker_saved_manifest::{DockerSavedManifest, ImageManifest};
 use crate::error::Result;
-use crate::registry::{Manifest, ManifestDescriptor};
+use crate::image_config::UnknownFields;
+use crate::image_reference::{ImageReference, RegistryHost, DEFAULT_TAG};
+use crate::registry::{ImageIndex, Manifest, ManifestDescriptor, DOCKER_MANIFEST_MEDIA_TYPE};
+use crate::tarball_index::{Member, TarballIndex};
+
+
+/// Where the files of a tarball are taken from.
+///
+enum Contents {
+
+    /// A temporary directory within the blobstore where the files of the
+    /// tarball have been staged to as the tarball got read, with the files
+    /// being moved to the bucket (see [`TarballIndex::stage`]).
+    ///
+    /// [`TarballIndex::stage`]: ../tarball_index/struct.TarballIndex.html#method.stage
+    ///
+    Staged {
+        dir: tempfile::TempDir,
+        index: TarballIndex,
+    },
+
+    /// The tarball itself, with the blobstore only recording where in the
+    /// tarball each file is (see [`TarballIndex`]).
+    ///
+    /// [`TarballIndex`]: ../tarball_index/struct.TarballIndex.html
+    ///
+    InPlace {
+        tarball: PathBuf,
+        index: TarballIndex,
+        size: u64,
+        modified: std::time::SystemTime,
+    },
+}
 
 /// A tarball that has been generated through `docker save`.
 ///
 pub struct DockerSavedTarball {
-    /// Directory where the tarball has been unpacked
-    /// (if so).
+    /// Where the files of the tarball are taken from: the directory
+    /// where they've been staged to, or the tarball itself.
     ///
-    unpacked_dir: tempfile::TempDir,
+    contents: Contents,
 
     /// The parsed verison of the `manifest.json` file that
     /// exists within a `docker save`d tarball containing the
@@ -24,6 +60,27 @@ pub struct DockerSavedTarball {
     ///
     parsed_manifest: DockerSavedManifest,
 
+    /// The parsed version of the `index.json` file that tarballs generated
+    /// by newer versions of Docker have, laid out as an OCI Image Layout
+    /// (with `manifest.json` pointing into `blobs/sha256`).
+    ///
+    oci_index: Option<ImageIndex>,
+
+    /// What to do with the registry hosts of the tags of the images (e.g.,
+    /// `localhost:5000/app:1.0`) when tagging them into the blobstore.
+    ///
+    registry_host: RegistryHost,
+
+    /// How the layers get compressed into the blobstore, if at all.
+    ///
+    layer_compression: Option<LayerCompression>,
+
+    /// Descriptors of the layers compressed so far, keyed by the digest of
+    /// the uncompressed layer, so that layers shared by more than one image
+    /// are only compressed once.
+    ///
+    compressed_layers: RefCell<HashMap<String, ManifestDescriptor>>,
+
     /// The final owner of the blobs and manifests for the
     /// registry to serve.
     ///
@@ -34,7 +91,8 @@ pub struct DockerSavedTarball {
 
 impl DockerSavedTarball {
     /// Creates a new instance of DockerSavedTarball holding a reference
-    /// to a temporary location to where the tarball gets extracted to.
+    /// to a temporary location to where the files of the tarball get
+    /// staged to.
     ///
     /// # Arguments
     ///
@@ -43,92 +101,412 @@ impl DockerSavedTarball {
     ///
     /// # Remarks
     ///
-    /// * This method *WILL* extract files from the tarball into the filesystem.
+    /// * This method *WILL* copy files from the tarball into the blobstore.
     /// * the temporary directory will be automatically removed once the object
     ///   goes out of scope.
     ///
     pub fn new(tarball: &Path, blobstore: BlobStore) -> Result<DockerSavedTarball> {
-        let tarball_tmp_dir = tempdir().unwrap();
-        let tarball_file = File::open(tarball)?;
+        DockerSavedTarball::from_reader(File::open(tarball)?, blobstore)
+    }
+
+    /// Creates a new instance of DockerSavedTarball from a tarball that
+    /// gets read only once, from start to end (e.g., from stdin).
+    ///
+    /// ```sh
+    /// docker save image | cartorio load --docker-save-tarball -
+    /// ```
+    ///
+    /// Each file gets hashed as it's copied to a temporary directory within
+    /// the blobstore, so that, once the whole tarball is read, the blobs
+    /// only need to be moved to the bucket, whichever position the
+    /// `manifest.json` had in the tarball.
+    ///
+    /// Tarballs compressed with gzip, zstd or xz are decompressed as they're
+    /// read.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - the supplier of the bytes of the tarball.
+    ///
+    pub fn from_reader(reader: impl Read, blobstore: BlobStore) -> Result<DockerSavedTarball> {
+        let (compression, reader) = compression::decompress(reader)?;
+
+        if compression != Compression::None {
+            info!(compression = compression.name(), "decompressing tarball");
+        }
+
+        let dir = blobstore.staging_dir()?;
+        let index = TarballIndex::stage(reader, dir.path())?;
+
+        DockerSavedTarball::with_contents(Contents::Staged { dir, index }, blobstore)
+    }
+
+    /// Creates a new instance of DockerSavedTarball that serves the blobs
+    /// straight from the tarball, without extracting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tarball` - location of the tarball in the filesystem.
+    ///
+    ///
+    /// # Remarks
+    ///
+    /// * The tarball is read once, to compute the digests of its files.
+    /// * Compressed tarballs are rejected, as their files don't lie in
+    ///   byte ranges of the tarball.
+    /// * The tarball must be kept where it is, unmodified, for as long as
+    ///   its images are served: replacing it (e.g., through `mv`) makes its
+    ///   blobs unknown until it gets loaded again.
+    ///
+    pub fn in_place(tarball: &Path, blobstore: BlobStore) -> Result<DockerSavedTarball> {
+        let tarball = fs::canonicalize(tarball)?;
+        let metadata = fs::metadata(&tarball)?;
+
+        let (compression, _) = compression::decompress(File::open(&tarball)?)?;
+
+        if compression != Compression::None {
+            return Err(failure::format_err!(
+                "{} is compressed with {}, thus can't be served in place",
+                tarball.display(), compression.name(),
+            ));
+        }
 
-        tar::Archive::new(tarball_file).unpack(tarball_tmp_dir.path())?;
+        let index = TarballIndex::new(&tarball)?;
 
-        let manifest_content = fs::read_to_string(tarball_tmp_dir.path().join("manifest.json"))?;
+        let contents = Contents::InPlace {
+            tarball,
+            index,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        };
+
+        DockerSavedTarball::with_contents(contents, blobstore)
+    }
+
+    /// Parses the `manifest.json` (and `index.json`, if any) out of the
+    /// contents of the tarball.
+    ///
+    fn with_contents(contents: Contents, blobstore: BlobStore) -> Result<DockerSavedTarball> {
+        let manifest_content = String::from_utf8(read_member(&contents, "manifest.json")?)?;
 
         let parsed_manifest: DockerSavedManifest = manifest_content.parse()?;
 
+        let oci_index = if index_of(&contents).member("index.json").is_ok() {
+            Some(serde_json::from_slice(&read_member(&contents, "index.json")?)?)
+        } else {
+            None
+        };
+
         Ok(DockerSavedTarball {
-            unpacked_dir: tarball_tmp_dir,
-            parsed_manifest: parsed_manifest,
-            blobstore: blobstore,
+            contents,
+            parsed_manifest,
+            oci_index,
+            registry_host: RegistryHost::Keep,
+            layer_compression: None,
+            compressed_layers: RefCell::new(HashMap::new()),
+            blobstore,
         })
     }
 
-    /// Ingests a blob, computing the necessary metadata and moving the
-    /// file to the blobstore.
+    /// Sets what to do with the registry hosts of the tags of the images
+    /// when tagging them into the blobstore (kept by default).
+    ///
+    pub fn registry_host(mut self, registry_host: RegistryHost) -> DockerSavedTarball {
+        self.registry_host = registry_host;
+        self
+    }
+
+    /// Makes the layers of images in the legacy `docker save` layout get
+    /// compressed into the blobstore, instead of being stored as they are
+    /// (uncompressed).
+    ///
+    /// Configs are left untouched, as their `diff_ids` are the digests of
+    /// the uncompressed layers already. Images in the OCI Image Layout are
+    /// always loaded as they are (warning about it), so that their digests
+    /// don't change.
+    ///
+    pub fn compress_layers(mut self, layer_compression: LayerCompression) -> DockerSavedTarball {
+        self.layer_compression = Some(layer_compression);
+        self
+    }
+
+    /// Ingests a blob, computing the necessary metadata and either moving
+    /// the file to the blobstore or recording where it is in the tarball.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - path of the file within the tarball.
+    /// * `media_type` - media type to describe the blob with.
     ///
     fn ingest_blob(
         &self,
-        original_location: &Path,
-        media_type: &'static str,
+        name: &str,
+        media_type: &str,
     ) -> Result<ManifestDescriptor> {
-        let blob_digest = digest::compute_for_file_and_store(original_location)?;
-        let blob_metadata = std::fs::metadata(original_location)?;
-        let blob_size = blob_metadata.len();
+        let (blob_digest, blob_size) = match &self.contents {
+            Contents::Staged { dir, index } => {
+                let member = index.member(name)?;
+                let staged = dir.path().join(digest::prepend_sha_scheme(&member.digest));
 
-        self.blobstore.add_blob(original_location)?;
+                // the same file might be referenced by more than one image,
+                // in which case it's already been moved.
+                //
+                if staged.exists() {
+                    digest::store(&staged, &member.digest)?;
+                    self.blobstore.add_blob_with_digest(&staged, &member.digest)?;
+                }
 
-        Ok(ManifestDescriptor {
-            media_type: media_type,
+                (member.digest.clone(), member.size)
+            },
+
+            Contents::InPlace { tarball, index, size, modified } => {
+                let member = index.member(name)?;
+
+                self.blobstore.add_blob_extent(&member.digest, &BlobExtent {
+                    tarball: tarball.clone(),
+                    offset: member.offset,
+                    size: member.size,
+                    tarball_size: *size,
+                    tarball_modified: *modified,
+                })?;
+
+                (member.digest.clone(), member.size)
+            },
+        };
+
+        let descriptor = ManifestDescriptor {
+            media_type: media_type.to_owned(),
             size: blob_size,
             digest: digest::prepend_sha_scheme(&blob_digest),
-        })
+            unknown: UnknownFields::new(),
+        };
+
+        info!(
+            source = name,
+            digest = %descriptor.digest,
+            size = descriptor.size,
+            media_type,
+            "ingested blob",
+        );
+
+        Ok(descriptor)
     }
 
-    fn ingest_config(&self, original_location: &Path) -> Result<ManifestDescriptor> {
+    fn ingest_config(&self, name: &str) -> Result<ManifestDescriptor> {
         self.ingest_blob(
-            original_location,
+            name,
             "application/vnd.docker.container.image.v1+json",
         )
     }
 
-    fn ingest_layer(&self, original_location: &Path) -> Result<ManifestDescriptor> {
-        self.ingest_blob(
-            original_location,
-            "application/vnd.docker.image.rootfs.diff.tar",
-        )
+    fn ingest_layer(&self, name: &str) -> Result<ManifestDescriptor> {
+        match &self.layer_compression {
+            Some(layer_compression) => self.ingest_compressed_layer(name, layer_compression),
+            None => self.ingest_blob(
+                name,
+                "application/vnd.docker.image.rootfs.diff.tar",
+            ),
+        }
+    }
+
+    /// Ingests a layer, compressing it into the blobstore.
+    ///
+    fn ingest_compressed_layer(&self, name: &str, layer_compression: &LayerCompression) -> Result<ManifestDescriptor> {
+        let (dir, member) = match &self.contents {
+            Contents::Staged { dir, index } => (dir, index.member(name)?),
+            Contents::InPlace { .. } => return Err(failure::format_err!(
+                "layers can't be compressed when served in place",
+            )),
+        };
+
+        if let Some(descriptor) = self.compressed_layers.borrow().get(&member.digest) {
+            return Ok(descriptor.clone());
+        }
+
+        let staged = dir.path().join(digest::prepend_sha_scheme(&member.digest));
+        let (blob_digest, blob_size) = self.blobstore.add_compressed_blob(File::open(&staged)?, layer_compression)?;
+
+        let descriptor = ManifestDescriptor {
+            media_type: layer_compression.media_type().to_owned(),
+            size: blob_size,
+            digest: digest::prepend_sha_scheme(&blob_digest),
+            unknown: UnknownFields::new(),
+        };
+
+        info!(
+            source = name,
+            digest = %descriptor.digest,
+            diff_id = %digest::prepend_sha_scheme(&member.digest),
+            size = descriptor.size,
+            uncompressed_size = member.size,
+            media_type = %descriptor.media_type,
+            "ingested compressed layer",
+        );
+
+        self.compressed_layers
+            .borrow_mut()
+            .insert(member.digest.clone(), descriptor.clone());
+
+        Ok(descriptor)
     }
 
     /// Loads a single image as described by a manifest.
     ///
     fn load_image(&self, manifest: &ImageManifest) -> Result<()> {
-        let config_descriptor =
-            self.ingest_config(&self.unpacked_dir.path().join(&manifest.config))?;
+        let config_descriptor = self.ingest_config(&manifest.config)?;
 
         let mut layers_descriptors: Vec<ManifestDescriptor> =
             Vec::with_capacity(manifest.layers.len() + 1);
 
         for layer in &manifest.layers {
-            layers_descriptors.push(self.ingest_layer(&self.unpacked_dir.path().join(&layer))?);
+            layers_descriptors.push(self.ingest_layer(layer)?);
         }
 
         let manifest_filename = self.ingest_manifest(config_descriptor, layers_descriptors)?;
 
-        for repo_tag in &manifest.repo_tags {
-            let mut repo_tag_splitted = repo_tag.split(':');
+        self.tag_image(&manifest_filename, &[], &manifest.repo_tags)
+    }
 
-            let name = repo_tag_splitted.next().unwrap();
-            let tag = repo_tag_splitted.next().unwrap();
+    /// Tags a manifest (along with the manifests it lists, if any, so that
+    /// they can be retrieved by digest) under each of `repo_tags`.
+    ///
+    fn tag_image(&self, manifest_filename: &str, listed: &[String], repo_tags: &[String]) -> Result<()> {
+        if repo_tags.is_empty() {
+            info!(manifest = manifest_filename, "image has no tags");
+        }
+
+        for repo_tag in repo_tags {
+            let reference: ImageReference = repo_tag.parse()?;
+            let name = reference.repository_in(&self.registry_host)?;
+
+            match &reference.tag {
+                Some(tag) => self.blobstore.tag_manifest(manifest_filename, &name, tag)?,
+                None if reference.digest.is_none() => self.blobstore.tag_manifest(manifest_filename, &name, DEFAULT_TAG)?,
+                None => (),
+            }
 
             self.blobstore
-                .tag_manifest(&manifest_filename, &name, &tag)?;
-            self.blobstore
-                .tag_manifest(&manifest_filename, &name, &manifest_filename)?;
+                .tag_manifest(manifest_filename, &name, manifest_filename)?;
+
+            for listed_filename in listed {
+                self.blobstore
+                    .tag_manifest(listed_filename, &name, listed_filename)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the images of a tarball laid out as an OCI Image Layout, taking
+    /// the manifests (and lists of manifests) as they are, along with their
+    /// media types, so that digests stay the same as the ones Docker had.
+    ///
+    /// ```txt
+    ///
+    ///   index.json                      manifest.json
+    ///   └── sha256:m1  (manifest)       └── { Config: blobs/sha256/c1, RepoTags: [ a:latest ] }
+    ///       ├── config: sha256:c1
+    ///       └── layers: [ sha256:l1 ]       ==>  a:latest -> sha256:m1
+    ///
+    /// ```
+    ///
+    /// Tags come from `manifest.json`, matching its entries to the
+    /// manifests in `index.json` through their configs.
+    ///
+    fn load_oci(&self, oci_index: &ImageIndex) -> Result<()> {
+        let mut images = Vec::with_capacity(oci_index.manifests.len());
+
+        for descriptor in &oci_index.manifests {
+            images.push(self.ingest_oci_manifest(descriptor)?);
+        }
+
+        for image_manifest in &self.parsed_manifest.images_manifests {
+            let config_digest = digest::prepend_sha_scheme(&self.member(&image_manifest.config)?.digest);
+
+            let image = images
+                .iter()
+                .find(|image| image.configs.contains(&config_digest))
+                .ok_or_else(|| failure::format_err!(
+                    "no manifest in index.json has {} as config", config_digest,
+                ))?;
+
+            self.tag_image(&image.manifest, &image.listed, &image_manifest.repo_tags)?;
         }
 
         Ok(())
     }
 
+    /// Ingests the manifest (or list of manifests) that `descriptor` points
+    /// at, along with everything it references.
+    ///
+    /// Manifests listed that aren't in the tarball (e.g., the ones of the
+    /// platforms that weren't pulled before `docker save`) are skipped.
+    ///
+    fn ingest_oci_manifest(&self, descriptor: &ManifestDescriptor) -> Result<OciImage> {
+        let content = read_member(&self.contents, &blob_member_name(&descriptor.digest))?;
+        let content_digest = digest::prepend_sha_scheme(&digest::compute(&content[..])?);
+
+        if content_digest != descriptor.digest {
+            return Err(failure::format_err!(
+                "manifest {} has digest {}", descriptor.digest, content_digest,
+            ));
+        }
+
+        let mut image = OciImage {
+            manifest: String::new(),
+            listed: vec![],
+            configs: vec![],
+        };
+
+        if descriptor.is_index() {
+            let index: ImageIndex = serde_json::from_slice(&content)?;
+
+            for listed in &index.manifests {
+                if self.member(&blob_member_name(&listed.digest)).is_err() {
+                    warn!(manifest = %listed.digest, "listed manifest not in tarball");
+                    continue;
+                }
+
+                let listed_image = self.ingest_oci_manifest(listed)?;
+
+                image.listed.push(listed_image.manifest);
+                image.listed.extend(listed_image.listed);
+                image.configs.extend(listed_image.configs);
+            }
+        } else {
+            let manifest: Manifest = serde_json::from_slice(&content)?;
+
+            for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+                let ingested = self.ingest_blob(&blob_member_name(&blob.digest), &blob.media_type)?;
+
+                if ingested.digest != blob.digest || ingested.size != blob.size {
+                    return Err(failure::format_err!(
+                        "blob {} ({} bytes) doesn't match the one in the tarball ({}, {} bytes)",
+                        blob.digest, blob.size, ingested.digest, ingested.size,
+                    ));
+                }
+            }
+
+            image.configs.push(manifest.config.digest);
+        }
+
+        image.manifest = self.blobstore.add_raw_manifest(&content)?;
+
+        info!(
+            digest = %image.manifest,
+            media_type = %descriptor.media_type,
+            "ingested manifest",
+        );
+
+        Ok(image)
+    }
+
+    /// Looks up the file at `name` in the tarball.
+    ///
+    fn member(&self, name: &str) -> Result<&Member> {
+        index_of(&self.contents).member(name)
+    }
+
     /// todo
     ///
     /// ```txt
@@ -143,13 +521,18 @@ impl DockerSavedTarball {
         config_desc: ManifestDescriptor,
         layers_descs: Vec<ManifestDescriptor>,
     ) -> Result<String> {
-        let manifest = Manifest {
+        let mut manifest = Manifest {
             schema_version: 2,
-            media_type: "application/vnd.docker.distribution.manifest.v2+json",
+            media_type: DOCKER_MANIFEST_MEDIA_TYPE.to_owned(),
             config: config_desc,
             layers: layers_descs,
+            unknown: UnknownFields::new(),
         };
 
+        if self.layer_compression.as_ref().is_some_and(LayerCompression::needs_oci_manifest) {
+            manifest = manifest.into_oci();
+        }
+
         let manifest_filename = self.blobstore.add_manifest(&manifest)?;
 
         Ok(manifest_filename)
@@ -190,16 +573,75 @@ impl DockerSavedTarball {
     /// # Arguments
     ///
     /// * `blobstore` - a [`Blobstore`] that represents the destination of
-    /// contents of this tarball.
+    ///   contents of this tarball.
     ///
     ///
     /// [`BlobStore`]: struct.BlobStore.html
     ///
     pub fn load(&self) -> Result<()> {
+        if let Some(oci_index) = &self.oci_index {
+            if self.layer_compression.is_some() {
+                warn!("layers of tarballs in the OCI Image Layout are loaded as they are, not compressed");
+            }
+
+            return self.load_oci(oci_index);
+        }
+
         for image_manifest in &self.parsed_manifest.images_manifests {
-            self.load_image(&image_manifest)?;
+            self.load_image(image_manifest)?;
         }
 
         Ok(())
     }
 }
+
+
+/// A manifest (or list of manifests) taken from a tarball laid out as an
+/// OCI Image Layout.
+///
+struct OciImage {
+
+    /// Name of the manifest in the bucket (e.g., `sha256:abc`).
+    ///
+    manifest: String,
+
+    /// Names of the manifests that it lists, if it's a list of manifests.
+    ///
+    listed: Vec<String>,
+
+    /// Digests of the configs of the images that it describes.
+    ///
+    configs: Vec<String>,
+}
+
+
+/// The index of the files of the tarball.
+///
+fn index_of(contents: &Contents) -> &TarballIndex {
+    match contents {
+        Contents::Staged { index, .. } | Contents::InPlace { index, .. } => index,
+    }
+}
+
+
+/// Reads the contents of the file at `name` in the tarball.
+///
+fn read_member(contents: &Contents, name: &str) -> Result<Vec<u8>> {
+    match contents {
+        Contents::Staged { dir, index } => {
+            let member = index.member(name)?;
+
+            Ok(fs::read(dir.path().join(digest::prepend_sha_scheme(&member.digest)))?)
+        },
+
+        Contents::InPlace { tarball, index, .. } => index.read(tarball, name),
+    }
+}
+
+
+/// Path, within a tarball laid out as an OCI Image Layout, of the blob
+/// identified by `digest` (e.g., `sha256:abc` => `blobs/sha256/abc`).
+///
+fn blob_member_name(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+
+/// How often watched files are checked for changes by those that poll a
+/// [`FileWatch`].
+///
+/// [`FileWatch`]: struct.FileWatch.html
+///
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+
+/// Detects changes to a set of files by keeping track of their
+/// modification times.
+///
+/// ```txt
+///
+///   let mut watch = FileWatch::new(vec![cert, key]);
+///
+///   watch.changed()   // false
+///   <file gets replaced>
+///   watch.changed()   // true
+///   watch.changed()   // false
+///
+/// ```
+///
+pub struct FileWatch {
+
+    /// Files being watched.
+    ///
+    paths: Vec<PathBuf>,
+
+    /// Last modification times observed for each file in `paths` (`None`
+    /// if the file could not be inspected).
+    ///
+    modified: Vec<Option<SystemTime>>,
+}
+
+
+impl FileWatch {
+
+    /// Starts watching `paths`, taking their current state as the
+    /// reference for future changes.
+    ///
+    pub fn new(paths: Vec<PathBuf>) -> FileWatch {
+        let modified = paths.iter().map(|p| modified(p)).collect();
+
+        FileWatch { paths, modified }
+    }
+
+
+    /// Tells whether any of the files changed since the last time that
+    /// this method (or `new`) was called.
+    ///
+    pub fn changed(&mut self) -> bool {
+        let modified: Vec<Option<SystemTime>> = self.paths.iter().map(|p| modified(p)).collect();
+
+        if modified == self.modified {
+            return false;
+        }
+
+        self.modified = modified;
+        true
+    }
+
+}
+
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+
+#[cfg(test)]
+mod file_watch_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn detects_modifications() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        std::fs::write(&path, "a").unwrap();
+
+        let mut watch = FileWatch::new(vec![path.clone()]);
+        assert!(!watch.changed());
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+
+        assert!(watch.changed());
+        assert!(!watch.changed());
+    }
+
+    #[test]
+    fn detects_removal() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        std::fs::write(&path, "a").unwrap();
+
+        let mut watch = FileWatch::new(vec![path.clone()]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(watch.changed());
+    }
+}
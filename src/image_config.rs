@@ -1,17 +1,27 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
+/// Fields of a JSON object that aren't modeled, kept so that they survive a round trip.
+///
+pub type UnknownFields = serde_json::Map<String, serde_json::Value>;
+
+/// A set of strings (e.g., ports or volumes), represented in JSON as an object whose values are
+/// empty objects (`{ "80/tcp": {} }`).
+///
+pub type EmptyObjectSet = BTreeMap<String, serde_json::Value>;
+
 /// References the layer content addresses used by the image, making the image config hash depend
 /// on the filesystem hash.
 ///
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ImageConfigRootfs {
     /// Must be set to `layers`.
     ///
     #[serde(rename = "type")]
-    pub rootfsType: String,
+    pub rootfs_type: String,
 
     /// An array of layer content hashes in order from first to last.
     ///
@@ -22,8 +32,155 @@ pub struct ImageConfigRootfs {
     pub diff_ids: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// The execution parameters to use when running a container from the image.
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfigContainer {
+    /// The username or UID (optionally followed by a group, as in `user:group`) that the process
+    /// runs as.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Ports to expose from a container (e.g., `80/tcp`).
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposed_ports: Option<EmptyObjectSet>,
+
+    /// Environment variables, in the form `VARNAME=VARVALUE`.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+
+    /// Arguments of the command to execute when the container starts.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// Default arguments to the entrypoint of the container.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+
+    /// Directories where data volumes get mounted.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<EmptyObjectSet>,
+
+    /// The working directory of the entrypoint process in the container.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+
+    /// Arbitrary metadata for the container.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+
+    /// The system call signal sent to the container to exit (e.g., `SIGTERM`).
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+impl ImageConfigContainer {
+    /// Sets the environment variable `var` (in the form `VARNAME=VARVALUE`), replacing the value
+    /// that it might already have.
+    ///
+    pub fn set_env(&mut self, var: &str) {
+        let name = var.split('=').next().unwrap_or_default();
+        let env = self.env.get_or_insert_with(Vec::new);
+
+        match env.iter_mut().find(|existing| existing.split('=').next() == Some(name)) {
+            Some(existing) => *existing = var.to_owned(),
+            None => env.push(var.to_owned()),
+        }
+    }
+
+    /// Applies `changes` on top of the parameters: the ones that are single values (e.g.,
+    /// `Entrypoint` or `User`) get replaced, while environment variables, labels, ports and
+    /// volumes get added to the ones already there.
+    ///
+    pub fn merge(&mut self, changes: ImageConfigContainer) {
+        fn extend<K: Ord, V>(current: &mut Option<BTreeMap<K, V>>, changes: Option<BTreeMap<K, V>>) {
+            if let Some(changes) = changes {
+                current.get_or_insert_with(BTreeMap::new).extend(changes);
+            }
+        }
+
+        for var in changes.env.iter().flatten() {
+            self.set_env(var);
+        }
+
+        extend(&mut self.exposed_ports, changes.exposed_ports);
+        extend(&mut self.volumes, changes.volumes);
+        extend(&mut self.labels, changes.labels);
+
+        self.user = changes.user.or(self.user.take());
+        self.entrypoint = changes.entrypoint.or(self.entrypoint.take());
+        self.cmd = changes.cmd.or(self.cmd.take());
+        self.working_dir = changes.working_dir.or(self.working_dir.take());
+        self.stop_signal = changes.stop_signal.or(self.stop_signal.take());
+        self.unknown.extend(changes.unknown);
+    }
+}
+
+/// Describes the history of each layer, from first to last.
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageConfigHistory {
+    /// When the layer got created (RFC 3339).
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// The command which created the layer.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+
+    /// The author of the build point.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+
+    /// A custom message set when creating the layer.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// Whether the history item created a filesystem diff (i.e., has a layer) or not.
+    ///
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+/// The configuration of an image, as in the [OCI image spec][spec].
+///
+/// Fields that aren't modeled (e.g., `container_config` in configs generated by Docker) are kept
+/// in `unknown`, so that configs can be modified without losing anything.
+///
+/// [spec]: https://github.com/opencontainers/image-spec/blob/main/config.md
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ImageConfig {
+    /// When the image got created (RFC 3339).
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// The name and/or email address of the person or entity which created the image.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+
     /// The CPU architecture which the binaries in this image are built to run on.
     ///
     /// Values provided here should be according to [`GOARCH`][goarch].
@@ -40,7 +197,20 @@ pub struct ImageConfig {
     ///
     pub os: String,
 
+    /// The execution parameters to use when running a container from the image.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ImageConfigContainer>,
+
     pub rootfs: ImageConfigRootfs,
+
+    /// The history of each layer.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<ImageConfigHistory>>,
+
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
 impl ImageConfig {
@@ -51,12 +221,45 @@ impl ImageConfig {
         ImageConfig {
             architecture: "amd64".to_owned(),
             os: "linux".to_owned(),
+            config: None,
             rootfs: ImageConfigRootfs {
-                rootfsType: "layers".to_owned(),
-                diff_ids: diff_ids,
+                rootfs_type: "layers".to_owned(),
+                diff_ids,
             },
+            created: None,
+            author: None,
+            history: None,
+            unknown: UnknownFields::new(),
         }
     }
+
+    /// Adds a layer (by the digest of its uncompressed contents) on top of the others, along with
+    /// its entry in the history.
+    ///
+    /// Configs without history get an entry for each of the layers they already have first, so
+    /// that the entries keep lining up with the layers.
+    ///
+    pub fn push_layer(&mut self, diff_id: &str, history: ImageConfigHistory) {
+        self.push_history(history);
+        self.rootfs.diff_ids.push(diff_id.to_owned());
+    }
+
+    /// Adds an entry to the history, which must be an `empty_layer` one if no layer gets added
+    /// along with it (see `push_layer`).
+    ///
+    pub fn push_history(&mut self, history: ImageConfigHistory) {
+        let layers = self.rootfs.diff_ids.len();
+
+        self.history
+            .get_or_insert_with(|| vec![ImageConfigHistory::default(); layers])
+            .push(history);
+    }
+
+    /// The execution parameters of the image, created empty if there are none.
+    ///
+    pub fn container_mut(&mut self) -> &mut ImageConfigContainer {
+        self.config.get_or_insert_with(ImageConfigContainer::default)
+    }
 }
 
 impl FromStr for ImageConfig {
@@ -84,7 +287,7 @@ impl fmt::Display for ImageConfig {
 mod image_config_tests {
     use super::*;
 
-    const IMAGE_CONFIG_SAMPLE: &'static str = r#"{
+    const IMAGE_CONFIG_SAMPLE: &str = r#"{
   "architecture": "amd64",
   "os": "linux",
   "rootfs": {
@@ -105,11 +308,116 @@ mod image_config_tests {
 
     #[test]
     fn marshal() {
-        let mut diff_ids: Vec<String> = Vec::new();
-        diff_ids.push("id1".to_owned());
+        let diff_ids: Vec<String> = vec!["id1".to_owned()];
 
         let configuration = ImageConfig::new(diff_ids);
 
         assert_eq!(configuration.to_string(), IMAGE_CONFIG_SAMPLE);
     }
+
+    #[test]
+    fn marshal_container_config() {
+        let mut configuration = ImageConfig::new(vec!["id1".to_owned()]);
+
+        configuration.config = Some(ImageConfigContainer {
+            entrypoint: Some(vec!["/bin/sh".to_owned()]),
+            working_dir: Some("/app".to_owned()),
+            ..ImageConfigContainer::default()
+        });
+
+        let value: serde_json::Value = serde_json::from_str(&configuration.to_string()).unwrap();
+
+        assert_eq!(
+            value["config"],
+            serde_json::json!({ "Entrypoint": ["/bin/sh"], "WorkingDir": "/app" }),
+        );
+    }
+
+    #[test]
+    fn round_trips_unknown_fields() {
+        let content = r#"{
+  "architecture": "amd64",
+  "config": {
+    "Cmd": ["sh"],
+    "Env": ["PATH=/bin"],
+    "ExposedPorts": { "80/tcp": {} },
+    "Labels": { "a": "b" },
+    "OnBuild": null,
+    "StopSignal": "SIGQUIT",
+    "User": "1000"
+  },
+  "container_config": { "Hostname": "abc" },
+  "created": "2019-01-01T00:00:00Z",
+  "docker_version": "18.09.0",
+  "history": [{ "created_by": "/bin/sh -c #(nop) ADD file", "weird": 1 }, { "empty_layer": true }],
+  "os": "linux",
+  "rootfs": { "type": "layers", "diff_ids": ["id1"] }
+}"#;
+
+        let parsed: ImageConfig = content.parse().unwrap();
+
+        let container = parsed.config.as_ref().unwrap();
+        assert_eq!(container.cmd, Some(vec!["sh".to_owned()]));
+        assert_eq!(container.user, Some("1000".to_owned()));
+        assert_eq!(container.stop_signal, Some("SIGQUIT".to_owned()));
+        assert!(container.exposed_ports.as_ref().unwrap().contains_key("80/tcp"));
+        assert!(parsed.history.as_ref().unwrap()[1].empty_layer);
+
+        let original: serde_json::Value = serde_json::from_str(content).unwrap();
+        let round_tripped: serde_json::Value = serde_json::from_str(&parsed.to_string()).unwrap();
+
+        assert_eq!(round_tripped, original);
+    }
+
+    #[test]
+    fn sets_env() {
+        let mut container = ImageConfigContainer::default();
+
+        container.set_env("PATH=/bin");
+        container.set_env("LANG=C");
+        container.set_env("PATH=/usr/bin:/bin");
+
+        assert_eq!(container.env, Some(vec!["PATH=/usr/bin:/bin".to_owned(), "LANG=C".to_owned()]));
+    }
+
+    #[test]
+    fn merges_container_config() {
+        let mut container = ImageConfigContainer {
+            env: Some(vec!["PATH=/bin".to_owned()]),
+            cmd: Some(vec!["sh".to_owned()]),
+            user: Some("root".to_owned()),
+            labels: Some(vec![("a".to_owned(), "1".to_owned())].into_iter().collect()),
+            ..ImageConfigContainer::default()
+        };
+
+        container.merge(ImageConfigContainer {
+            env: Some(vec!["LANG=C".to_owned()]),
+            user: Some("1000".to_owned()),
+            labels: Some(vec![("b".to_owned(), "2".to_owned())].into_iter().collect()),
+            volumes: Some(vec![("/data".to_owned(), serde_json::json!({}))].into_iter().collect()),
+            ..ImageConfigContainer::default()
+        });
+
+        assert_eq!(container.env, Some(vec!["PATH=/bin".to_owned(), "LANG=C".to_owned()]));
+        assert_eq!(container.cmd, Some(vec!["sh".to_owned()]));
+        assert_eq!(container.user, Some("1000".to_owned()));
+        assert_eq!(container.labels.unwrap().len(), 2);
+        assert!(container.volumes.unwrap().contains_key("/data"));
+    }
+
+    #[test]
+    fn pushes_layers() {
+        let mut configuration = ImageConfig::new(vec!["id1".to_owned(), "id2".to_owned()]);
+
+        configuration.push_layer("id3", ImageConfigHistory {
+            created_by: Some("test".to_owned()),
+            ..ImageConfigHistory::default()
+        });
+
+        assert_eq!(configuration.rootfs.diff_ids, vec!["id1", "id2", "id3"]);
+
+        let history = configuration.history.unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].created_by, Some("test".to_owned()));
+    }
 }
@@ -0,0 +1,461 @@
+use std::fmt;
+use std::str::FromStr;
+
+use tracing::warn;
+
+use crate::error::Result;
+use crate::router::{is_valid_name_component, is_valid_reference};
+
+
+/// Registry that references without a registry host refer to.
+///
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+
+/// Namespace that single-component repositories in the default registry
+/// live under (e.g., `alpine` => `library/alpine`).
+///
+pub const DEFAULT_NAMESPACE: &str = "library";
+
+
+/// Tag that references without a tag or digest refer to.
+///
+pub const DEFAULT_TAG: &str = "latest";
+
+
+/// Maximum length of a repository name, registry host included.
+///
+const MAX_NAME_LEN: usize = 255;
+
+
+/// A reference to an image, as in `docker pull`.
+///
+/// ```txt
+///
+///   localhost:5000/team/app:1.0@sha256:abc
+///   └─────┬──────┘ └──┬───┘ └┬┘ └───┬────┘
+///      registry   repository tag  digest
+///
+/// ```
+///
+/// References are kept as written (e.g., `alpine` has no registry); see
+/// `normalize` for the fully qualified form (`docker.io/library/alpine:latest`).
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageReference {
+
+    /// Host (and port) of the registry, if any.
+    ///
+    pub registry: Option<String>,
+
+    /// Path of the repository within the registry (e.g., `team/app`).
+    ///
+    pub repository: String,
+
+    /// Tag, if any (e.g., `1.0`).
+    ///
+    pub tag: Option<String>,
+
+    /// Digest, if any (e.g., `sha256:abc`).
+    ///
+    pub digest: Option<String>,
+}
+
+
+impl ImageReference {
+
+    /// Host (and port) of the registry, `docker.io` if none.
+    ///
+    pub fn registry(&self) -> &str {
+        self.registry.as_deref().unwrap_or(DEFAULT_REGISTRY)
+    }
+
+
+    /// Whether the reference points at the default registry (`docker.io`).
+    ///
+    pub fn is_default_registry(&self) -> bool {
+        self.registry() == DEFAULT_REGISTRY
+    }
+
+
+    /// Path of the repository, with single-component repositories of the
+    /// default registry put under `library` (e.g., `alpine` => `library/alpine`).
+    ///
+    pub fn normalized_repository(&self) -> String {
+        if self.is_default_registry() && !self.repository.contains('/') {
+            format!("{}/{}", DEFAULT_NAMESPACE, self.repository)
+        } else {
+            self.repository.clone()
+        }
+    }
+
+
+    /// Path of the repository, without the `library` namespace of the default
+    /// registry (e.g., `library/alpine` => `alpine`).
+    ///
+    pub fn familiar_repository(&self) -> &str {
+        let prefix = format!("{}/", DEFAULT_NAMESPACE);
+
+        match self.repository.strip_prefix(&prefix) {
+            Some(repository) if self.is_default_registry() && !repository.contains('/') => repository,
+            _ => &self.repository,
+        }
+    }
+
+
+    /// The fully qualified form of the reference, with registry, normalized
+    /// repository and, if no digest is set, tag.
+    ///
+    /// ```txt
+    /// alpine                    ==> docker.io/library/alpine:latest
+    /// localhost:5000/app@sha256 ==> localhost:5000/app@sha256
+    /// ```
+    ///
+    pub fn normalize(&self) -> ImageReference {
+        let tag = match (&self.tag, &self.digest) {
+            (None, None) => Some(DEFAULT_TAG.to_owned()),
+            (tag, _) => tag.clone(),
+        };
+
+        ImageReference {
+            registry: Some(self.registry().to_owned()),
+            repository: self.normalized_repository(),
+            tag,
+            digest: self.digest.clone(),
+        }
+    }
+
+
+    /// Name of the repository to tag the image under in the blobstore,
+    /// with its registry host handled as `registry_host` dictates.
+    ///
+    /// ```txt
+    ///                                 keep                          strip           rewrite(mirror.local)
+    /// alpine                      ==> alpine                        alpine          mirror.local/library/alpine
+    /// ghcr.io/team/app            ==> ghcr.io/team/app              team/app        mirror.local/team/app
+    /// localhost:5000/app          ==> app (warning)                 app             mirror.local/app
+    /// ```
+    ///
+    /// Registry hosts that can't be kept as part of a repository name (e.g.,
+    /// those with ports) get stripped instead.
+    ///
+    pub fn repository_in(&self, registry_host: &RegistryHost) -> Result<String> {
+        let name = match registry_host {
+            RegistryHost::Keep => match &self.registry {
+                Some(registry) if !is_valid_name_component(registry) => {
+                    warn!(
+                        registry,
+                        repository = %self.repository,
+                        "registry host can't be part of a repository name, stripping it",
+                    );
+
+                    self.familiar_repository().to_owned()
+                },
+                Some(registry) => format!("{}/{}", registry, self.repository),
+                None => self.repository.clone(),
+            },
+            RegistryHost::Strip => self.familiar_repository().to_owned(),
+            RegistryHost::Rewrite(host) => format!("{}/{}", host, self.normalized_repository()),
+        };
+
+        if !name.split('/').all(is_valid_name_component) {
+            return Err(failure::format_err!(
+                "`{}` can't be served as a repository (strip or rewrite its registry host)", name,
+            ));
+        }
+
+        Ok(name)
+    }
+
+}
+
+
+impl FromStr for ImageReference {
+
+    type Err = failure::Error;
+
+    /// Parses a reference (e.g., `localhost:5000/team/app:1.0`), following
+    /// the grammar that `docker` uses.
+    ///
+    /// The first component is taken as the registry host only if it looks
+    /// like one: it has a `.` or a `:` (port), or is `localhost`.
+    ///
+    fn from_str(reference: &str) -> Result<ImageReference> {
+        let invalid = |reason: &str| failure::format_err!("invalid image reference `{}` - {}", reference, reason);
+
+        let (name, digest) = match reference.find('@') {
+            Some(idx) => (&reference[..idx], Some(&reference[idx + 1..])),
+            None => (reference, None),
+        };
+
+        if let Some(digest) = digest {
+            if !digest.contains(':') || !is_valid_reference(digest) {
+                return Err(invalid("malformed digest"));
+            }
+        }
+
+        let last_slash = name.rfind('/').map_or(0, |idx| idx + 1);
+
+        let (name, tag) = match name[last_slash..].find(':') {
+            Some(idx) => (&name[..last_slash + idx], Some(&name[last_slash + idx + 1..])),
+            None => (name, None),
+        };
+
+        if let Some(tag) = tag {
+            if tag.contains(':') || !is_valid_reference(tag) {
+                return Err(invalid("malformed tag"));
+            }
+        }
+
+        let (registry, repository) = match name.find('/') {
+            Some(idx) if is_registry_host(&name[..idx]) => (Some(&name[..idx]), &name[idx + 1..]),
+            _ => (None, name),
+        };
+
+        if let Some(registry) = registry {
+            if !is_valid_registry_host(registry) {
+                return Err(invalid("malformed registry host"));
+            }
+        }
+
+        if repository.is_empty() {
+            return Err(invalid("missing repository"));
+        }
+
+        if !repository.split('/').all(is_valid_name_component) {
+            return Err(invalid("repository must be made of lowercase alphanumeric components"));
+        }
+
+        if name.len() > MAX_NAME_LEN {
+            return Err(invalid("name too long"));
+        }
+
+        Ok(ImageReference {
+            registry: registry.map(str::to_owned),
+            repository: repository.to_owned(),
+            tag: tag.map(str::to_owned),
+            digest: digest.map(str::to_owned),
+        })
+    }
+
+}
+
+
+impl fmt::Display for ImageReference {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(registry) = &self.registry {
+            write!(f, "{}/", registry)?;
+        }
+
+        write!(f, "{}", self.repository)?;
+
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+
+/// What to do with the registry host of a reference when tagging an image
+/// into the blobstore.
+///
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum RegistryHost {
+
+    /// Keeps it as part of the repository name (e.g., `ghcr.io/team/app`).
+    ///
+    #[default]
+    Keep,
+
+    /// Drops it (e.g., `ghcr.io/team/app` => `team/app`).
+    ///
+    Strip,
+
+    /// Puts a different host in its place (e.g., `ghcr.io/team/app` =>
+    /// `mirror.local/team/app`).
+    ///
+    Rewrite(String),
+}
+
+
+impl FromStr for RegistryHost {
+
+    type Err = failure::Error;
+
+    /// Parses `keep`, `strip`, or the host to rewrite registry hosts to.
+    ///
+    fn from_str(value: &str) -> Result<RegistryHost> {
+        match value {
+            "keep" => Ok(RegistryHost::Keep),
+            "strip" => Ok(RegistryHost::Strip),
+            host if is_valid_registry_host(host) => Ok(RegistryHost::Rewrite(host.to_owned())),
+            _ => Err(failure::format_err!(
+                "invalid registry host `{}` (expected keep, strip or a host)", value,
+            )),
+        }
+    }
+
+}
+
+
+/// Whether the first component of a name is a registry host rather than
+/// the first component of the repository.
+///
+fn is_registry_host(component: &str) -> bool {
+    component.contains('.')
+        || component.contains(':')
+        || component == "localhost"
+        || component.chars().any(|c| c.is_ascii_uppercase())
+}
+
+
+/// Whether `host` is a hostname (or IPv4 address), optionally followed by a
+/// port (e.g., `localhost:5000`).
+///
+fn is_valid_registry_host(host: &str) -> bool {
+    let (hostname, port) = match host.rfind(':') {
+        Some(idx) => (&host[..idx], Some(&host[idx + 1..])),
+        None => (host, None),
+    };
+
+    let is_valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+
+    hostname.split('.').all(is_valid_label)
+        && port.is_none_or(|port| !port.is_empty() && port.len() <= 5 && port.chars().all(|c| c.is_ascii_digit()))
+}
+
+
+
+#[cfg(test)]
+mod image_reference_tests {
+    use super::*;
+
+    fn parse(reference: &str) -> ImageReference {
+        reference.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parses_references() {
+        assert_eq!(
+            parse("alpine"),
+            ImageReference {
+                registry: None,
+                repository: "alpine".to_owned(),
+                tag: None,
+                digest: None,
+            },
+        );
+
+        assert_eq!(
+            parse("localhost:5000/foo:1.0"),
+            ImageReference {
+                registry: Some("localhost:5000".to_owned()),
+                repository: "foo".to_owned(),
+                tag: Some("1.0".to_owned()),
+                digest: None,
+            },
+        );
+
+        assert_eq!(
+            parse("ghcr.io/team/app/api:v2@sha256:abc"),
+            ImageReference {
+                registry: Some("ghcr.io".to_owned()),
+                repository: "team/app/api".to_owned(),
+                tag: Some("v2".to_owned()),
+                digest: Some("sha256:abc".to_owned()),
+            },
+        );
+
+        assert_eq!(parse("localhost/foo").registry.as_deref(), Some("localhost"));
+        assert_eq!(parse("team/app").registry, None);
+        assert_eq!(parse("team/app").repository, "team/app");
+        assert_eq!(parse("127.0.0.1:5000/a@sha256:abc").tag, None);
+    }
+
+    #[test]
+    fn test_rejects_malformed_references() {
+        for reference in &[
+            "",
+            ":latest",
+            "Alpine",
+            "alpine:",
+            "alpine:a:b",
+            "alpine@abc",
+            "alpine@sha256:",
+            "ghcr.io/",
+            "ghcr.io/team//app",
+            "-host.io/app",
+            "host.io:port/app",
+            "app/../etc",
+        ] {
+            assert!(reference.parse::<ImageReference>().is_err(), "{} must be rejected", reference);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for reference in &["alpine", "localhost:5000/foo:1.0", "ghcr.io/a/b:c@sha256:abc", "a/b@sha256:abc"] {
+            assert_eq!(parse(reference).to_string(), *reference);
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(parse("alpine").normalize().to_string(), "docker.io/library/alpine:latest");
+        assert_eq!(parse("team/app:1").normalize().to_string(), "docker.io/team/app:1");
+        assert_eq!(parse("docker.io/alpine").normalize().to_string(), "docker.io/library/alpine:latest");
+        assert_eq!(parse("localhost:5000/a@sha256:abc").normalize().to_string(), "localhost:5000/a@sha256:abc");
+        assert_eq!(parse("ghcr.io/app").normalize().to_string(), "ghcr.io/app:latest");
+    }
+
+    #[test]
+    fn test_familiar_repository() {
+        assert_eq!(parse("docker.io/library/alpine").familiar_repository(), "alpine");
+        assert_eq!(parse("library/alpine").familiar_repository(), "alpine");
+        assert_eq!(parse("ghcr.io/library/alpine").familiar_repository(), "library/alpine");
+        assert_eq!(parse("library/a/b").familiar_repository(), "library/a/b");
+    }
+
+    #[test]
+    fn test_repository_in() {
+        let rewrite = RegistryHost::Rewrite("mirror.local".to_owned());
+
+        assert_eq!(parse("alpine:3").repository_in(&RegistryHost::Keep).unwrap(), "alpine");
+        assert_eq!(parse("alpine:3").repository_in(&RegistryHost::Strip).unwrap(), "alpine");
+        assert_eq!(parse("alpine:3").repository_in(&rewrite).unwrap(), "mirror.local/library/alpine");
+
+        assert_eq!(parse("ghcr.io/team/app").repository_in(&RegistryHost::Keep).unwrap(), "ghcr.io/team/app");
+        assert_eq!(parse("ghcr.io/team/app").repository_in(&RegistryHost::Strip).unwrap(), "team/app");
+        assert_eq!(parse("docker.io/library/alpine").repository_in(&RegistryHost::Strip).unwrap(), "alpine");
+
+        assert_eq!(parse("localhost:5000/foo").repository_in(&RegistryHost::Keep).unwrap(), "foo");
+        assert_eq!(parse("localhost:5000/foo").repository_in(&RegistryHost::Strip).unwrap(), "foo");
+        assert_eq!(parse("localhost:5000/foo").repository_in(&rewrite).unwrap(), "mirror.local/foo");
+    }
+
+    #[test]
+    fn test_parses_registry_host() {
+        assert_eq!("keep".parse::<RegistryHost>().unwrap(), RegistryHost::Keep);
+        assert_eq!("strip".parse::<RegistryHost>().unwrap(), RegistryHost::Strip);
+        assert_eq!(
+            "mirror.local:5000".parse::<RegistryHost>().unwrap(),
+            RegistryHost::Rewrite("mirror.local:5000".to_owned()),
+        );
+
+        assert!("not/a/host".parse::<RegistryHost>().is_err());
+        assert!("".parse::<RegistryHost>().is_err());
+    }
+}
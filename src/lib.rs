@@ -1,11 +1,30 @@
+pub mod auth;
+pub mod blob_body;
+pub mod blob_index;
 pub mod blobstore;
+pub mod compression;
 pub mod concourse_image_resource;
 pub mod concourse_resource_metadata;
+pub mod config;
 pub mod digest;
 pub mod docker_saved_manifest;
 pub mod docker_saved_tarball;
 pub mod error;
+pub mod file_watch;
 pub mod image_config;
+pub mod image_reference;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod mutate;
 pub mod oci_image_layout;
+pub mod policy;
 pub mod registry;
+pub mod remote_image;
+pub mod rootfs_image;
+pub mod router;
 pub mod server;
+pub mod tarball_index;
+pub mod telemetry;
+pub mod tls;
+pub mod token;
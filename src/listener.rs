@@ -0,0 +1,460 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::error::Result;
+
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+///
+const LISTEN_FDS_START: RawFd = 3;
+
+
+/// Where to listen for connections.
+///
+/// ```txt
+/// 0.0.0.0:5000           TCP over IPv4
+/// [::]:5000              TCP over IPv6 (and IPv4, unless the system is set up otherwise)
+/// unix:/run/cartorio     Unix domain socket
+/// systemd                sockets passed through socket activation (LISTEN_FDS)
+/// ```
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd,
+}
+
+
+impl FromStr for ListenAddress {
+
+    type Err = failure::Error;
+
+    fn from_str(address: &str) -> Result<ListenAddress> {
+        if address == "systemd" {
+            return Ok(ListenAddress::Systemd);
+        }
+
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(failure::format_err!("invalid listen address `{}` - missing socket path", address));
+            }
+
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+
+        address.parse().map(ListenAddress::Tcp).map_err(|_| {
+            failure::format_err!(
+                "invalid listen address `{}` (expected ip:port, [ipv6]:port, unix:/path or systemd)",
+                address,
+            )
+        })
+    }
+
+}
+
+
+impl fmt::Display for ListenAddress {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddress::Systemd => write!(f, "systemd"),
+        }
+    }
+
+}
+
+
+impl<'de> Deserialize<'de> for ListenAddress {
+
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+
+}
+
+
+/// A socket that connections get accepted from.
+///
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+
+impl From<TcpListener> for Listener {
+
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+
+}
+
+
+impl From<UnixListener> for Listener {
+
+    fn from(listener: UnixListener) -> Listener {
+        Listener::Unix(listener)
+    }
+
+}
+
+
+impl Listener {
+
+    /// Binds to `address`, giving back every socket that it stands for
+    /// (socket activation can pass more than one, taken from `activated`).
+    ///
+    /// Stale Unix domain sockets (e.g., left by a previous instance that
+    /// got killed) are replaced.
+    ///
+    pub async fn bind(address: &ListenAddress, activated: &mut Vec<RawFd>) -> Result<Vec<Listener>> {
+        let listeners = match address {
+            ListenAddress::Tcp(addr) => vec![Listener::Tcp(TcpListener::bind(addr).await?)],
+            ListenAddress::Unix(path) => {
+                remove_stale_socket(path)?;
+                vec![Listener::Unix(UnixListener::bind(path)?)]
+            },
+            ListenAddress::Systemd => from_systemd(activated)?,
+        };
+
+        Ok(listeners)
+    }
+
+
+    /// Accepts a connection, telling who's on the other side.
+    ///
+    pub async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(addr)))
+            },
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), Peer::Unix))
+            },
+        }
+    }
+
+
+    /// Where the socket is bound to, for telling where to connect to.
+    ///
+    pub fn local_address(&self) -> io::Result<ListenAddress> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddress::Tcp(listener.local_addr()?)),
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(ListenAddress::Unix(path.to_owned())),
+                None => Err(io::Error::other("unnamed unix domain socket")),
+            },
+        }
+    }
+
+}
+
+
+/// Removes the socket file at `path` if nothing's listening on it anymore.
+///
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(failure::format_err!("{:?} exists and is not a socket", path));
+    }
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(failure::format_err!("{:?} is already being listened on", path));
+    }
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+
+/// Takes the file descriptors of the sockets passed by systemd (`LISTEN_PID`
+/// and `LISTEN_FDS`) out of the environment, so that child processes don't
+/// take them too. There are none if they're meant for another process.
+///
+/// As changing the environment isn't safe once other threads are running,
+/// it must be called before any gets started (e.g., by the tokio runtime).
+///
+pub fn take_activated_fds() -> Result<Vec<RawFd>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+
+    let fds: RawFd = fds
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| failure::format_err!("invalid or missing LISTEN_FDS"))?;
+
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + fds).collect())
+}
+
+
+/// Takes ownership of the sockets passed by systemd (see
+/// `take_activated_fds`), which can only happen once.
+///
+fn from_systemd(activated: &mut Vec<RawFd>) -> Result<Vec<Listener>> {
+    if activated.is_empty() {
+        return Err(failure::format_err!("no sockets passed through socket activation"));
+    }
+
+    activated
+        .drain(..)
+        .map(from_fd)
+        .collect()
+}
+
+
+/// Takes ownership of a listening socket inherited as `fd`.
+///
+fn from_fd(fd: RawFd) -> Result<Listener> {
+    let listener = match socket_family(fd)? {
+        libc::AF_UNIX => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(listener)?)
+        },
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        },
+        family => return Err(failure::format_err!("unsupported socket family {} of fd {}", family, fd)),
+    };
+
+    Ok(listener)
+}
+
+
+fn socket_family(fd: RawFd) -> Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+
+    if ret != 0 {
+        return Err(failure::format_err!("fd {} is not a socket - {}", fd, io::Error::last_os_error()));
+    }
+
+    Ok(addr.ss_family as libc::c_int)
+}
+
+
+/// Who's on the other side of a connection.
+///
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+
+impl fmt::Display for Peer {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix"),
+        }
+    }
+
+}
+
+
+/// A connection accepted from a `Listener`.
+///
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+
+impl AsyncRead for Stream {
+
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+
+}
+
+
+impl AsyncWrite for Stream {
+
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod listener_tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(
+            "0.0.0.0:5000".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Tcp("0.0.0.0:5000".parse().unwrap()),
+        );
+
+        assert_eq!(
+            "[::1]:5000".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Tcp("[::1]:5000".parse().unwrap()),
+        );
+
+        assert_eq!(
+            "unix:/run/cartorio.sock".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Unix(PathBuf::from("/run/cartorio.sock")),
+        );
+
+        assert_eq!("systemd".parse::<ListenAddress>().unwrap(), ListenAddress::Systemd);
+    }
+
+    #[test]
+    fn rejects_invalid_listen_addresses() {
+        for address in &["", "5000", "localhost", "::1:5000", "unix:", "0.0.0.0:99999"] {
+            let err = address.parse::<ListenAddress>().unwrap_err();
+            assert!(err.to_string().contains("invalid listen address"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn takes_inherited_sockets() {
+        use std::os::unix::io::IntoRawFd;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+
+        match from_fd(tcp.into_raw_fd()).unwrap() {
+            Listener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            Listener::Unix(_) => panic!("must be taken as tcp"),
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cartorio.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        match from_fd(unix.into_raw_fd()).unwrap() {
+            Listener::Unix(listener) => {
+                assert_eq!(listener.local_addr().unwrap().as_pathname(), Some(path.as_path()))
+            },
+            Listener::Tcp(_) => panic!("must be taken as unix"),
+        }
+    }
+
+    #[test]
+    fn takes_activated_sockets_once() {
+        use std::os::unix::io::IntoRawFd;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut activated = vec![tcp.into_raw_fd()];
+
+        assert_eq!(from_systemd(&mut activated).unwrap().len(), 1);
+        assert!(activated.is_empty());
+
+        let err = from_systemd(&mut activated).err().unwrap();
+        assert!(err.to_string().contains("no sockets passed"), "{}", err);
+    }
+
+    #[test]
+    fn replaces_stale_sockets_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cartorio.sock");
+
+        let listening = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(remove_stale_socket(&path).is_err(), "must not steal a socket in use");
+
+        drop(listening);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(remove_stale_socket(&path).is_err(), "must not remove regular files");
+    }
+
+    #[test]
+    fn displays_as_parsed() {
+        for address in &["0.0.0.0:5000", "[::1]:5000", "unix:/run/cartorio.sock", "systemd"] {
+            assert_eq!(address.parse::<ListenAddress>().unwrap().to_string(), *address);
+        }
+    }
+}
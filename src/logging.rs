@@ -0,0 +1,106 @@
+use std::str::FromStr;
+
+use opentelemetry::trace::TracerProvider;
+use serde::Deserialize;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::Result;
+use crate::telemetry::{Telemetry, SERVICE_NAME};
+
+
+/// Target of the events that make up the access log, one per request.
+///
+pub const ACCESS_LOG_TARGET: &str = "cartorio::access";
+
+
+/// How log events get written out (to stderr).
+///
+/// ```txt
+/// logfmt:  ts=2019-05-01T10:00:00.000Z level=info target=cartorio::access method=GET status=200 ...
+/// json:    {"timestamp":"2019-05-01T10:00:00.000Z","level":"INFO","fields":{"method":"GET",...},...}
+/// ```
+///
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Logfmt,
+    Json,
+}
+
+
+impl FromStr for LogFormat {
+
+    type Err = failure::Error;
+
+    fn from_str(format: &str) -> Result<LogFormat> {
+        match format {
+            "logfmt" => Ok(LogFormat::Logfmt),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(failure::format_err!("unknown log format `{}` (expected `logfmt` or `json`)", format)),
+        }
+    }
+
+}
+
+
+/// Installs the global subscriber that writes log events out.
+///
+/// # Arguments
+///
+/// * `filter` - the minimum level of the events to log (e.g., `info`), or
+///   a filter in the format of `RUST_LOG` (e.g., `warn,cartorio::access=info`).
+/// * `format` - how to write the events out.
+/// * `otlp_endpoint` - OpenTelemetry collector to export spans to, if any
+///   (see `Telemetry`).
+///
+/// The filter applies to spans as well, so only those that it lets
+/// through get exported.
+///
+pub fn init(filter: &str, format: LogFormat, otlp_endpoint: Option<&str>) -> Result<Telemetry> {
+    let filter = EnvFilter::try_new(filter)
+        .map_err(|err| failure::format_err!("invalid log level `{}` - {}", filter, err))?;
+
+    let telemetry = Telemetry::new(otlp_endpoint)?;
+    let spans = telemetry
+        .provider()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    let registry = tracing_subscriber::registry().with(filter).with(spans);
+
+    let result = match format {
+        LogFormat::Logfmt => registry
+            .with(tracing_logfmt::builder().layer().with_writer(std::io::stderr))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(std::io::stderr),
+            )
+            .try_init(),
+    };
+
+    result.map_err(|err| failure::format_err!("failed to set up logging - {}", err))?;
+
+    Ok(telemetry)
+}
+
+
+#[cfg(test)]
+mod logging_tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!("logfmt".parse::<LogFormat>().unwrap(), LogFormat::Logfmt);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(init("cartorio=loud", LogFormat::Logfmt, None).is_err());
+    }
+}
@@ -1,18 +1,65 @@
-#[macro_use] extern crate clap;
-#[macro_use] extern crate failure;
-
 use cartorio::blobstore::BlobStore;
+use cartorio::compression::LayerCompression;
+use cartorio::config::{self, Config, CONFIG_ENV_VAR};
 use cartorio::docker_saved_tarball::DockerSavedTarball;
+use cartorio::image_config::ImageConfigContainer;
+use cartorio::image_reference::RegistryHost;
+use cartorio::listener;
+use cartorio::rootfs_image::RootfsImage;
 use cartorio::concourse_image_resource::ConcourseImageResource;
+use cartorio::logging;
+use cartorio::metrics;
+use cartorio::mutate::{AppendLayer, EditConfig};
+use cartorio::remote_image::RemoteImage;
 use cartorio::server;
-use clap::{App, AppSettings, Arg, SubCommand};
-use std::path::Path;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{error, info, info_span};
 
 fn main() {
+    let blobstore_help = format!(
+        "Directory where blobs, manifests and configurations are saved to [default: {}]",
+        config::DEFAULT_BLOBSTORE,
+    );
+
     let matches = App::new("cartorio")
         .version(env!("CARGO_PKG_VERSION"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(
+            "Settings are taken from flags, then CARTORIO_* environment variables (e.g., \
+             CARTORIO_TLS_CERT for --tls-cert), then the configuration file, then defaults.",
+        )
+        .args(&[
+            Arg::with_name("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .short("c")
+                .long("config")
+                .global(true)
+                .help("TOML configuration file (also taken from CARTORIO_CONFIG)"),
+            Arg::with_name("log-level")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .long("log-level")
+                .global(true)
+                .help("Minimum level of the events to log (error, warn, info, debug, trace), or a RUST_LOG-like filter [default: info]"),
+            Arg::with_name("log-format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["logfmt", "json"])
+                .long("log-format")
+                .global(true)
+                .help("Format of the events logged to stderr [default: logfmt]"),
+            Arg::with_name("otlp-endpoint")
+                .value_name("URL")
+                .takes_value(true)
+                .long("otlp-endpoint")
+                .global(true)
+                .help("OTLP/HTTP endpoint to export tracing spans to (e.g., http://localhost:4318/v1/traces)"),
+        ])
         .subcommand(
             SubCommand::with_name("load")
                 .about(
@@ -20,93 +67,757 @@ fn main() {
                 )
                 .args(&[
                     Arg::with_name("blobstore")
-                        .default_value("/tmp/cartorio/blobstore")
+                        .value_name("DIRECTORY")
+                        .takes_value(true)
                         .short("b")
                         .long("blobstore")
-                        .help("Directory where blobs, manifests and configurations are saved to"),
+                        .help(&blobstore_help),
                     Arg::with_name("docker-save-tarball")
                         .value_name("TARBALL")
                         .takes_value(true)
                         .long("docker-save-tarball")
-                        .help("Tarball to load into the registry"),
+                        .help("Tarball (possibly gzip, zstd or xz compressed) to load into the registry (- for stdin), or directory whose *.tar{,.gz,.zst,.xz} files are all loaded"),
+                    Arg::with_name("in-place")
+                        .long("in-place")
+                        .requires("docker-save-tarball")
+                        .conflicts_with("compress")
+                        .help("Serves blobs straight from the tarballs instead of copying them into the blobstore (tarballs must then be kept in place, unmodified)"),
+                    Arg::with_name("registry-host")
+                        .value_name("MODE")
+                        .takes_value(true)
+                        .long("registry-host")
+                        .requires("tagged-images")
+                        .help("What to do with the registry host of image tags (e.g., ghcr.io/team/app:1.0): keep (stripping those that can't be kept, like localhost:5000), strip, or a host to rewrite it to [default: keep]"),
+                    Arg::with_name("from-registry")
+                        .value_name("IMAGE")
+                        .takes_value(true)
+                        .long("from-registry")
+                        .conflicts_with("compress")
+                        .help("Image to pull from a registry (e.g., ghcr.io/team/app:1.0 or alpine:3, from Docker Hub)"),
+                    Arg::with_name("platform")
+                        .value_name("OS/ARCH[/VARIANT]")
+                        .takes_value(true)
+                        .long("platform")
+                        .requires("from-registry")
+                        .help("Platform to pick out of lists of manifests pulled with --from-registry (e.g., linux/arm64) [default: the one cartorio runs on]"),
+                    Arg::with_name("plain-http")
+                        .long("plain-http")
+                        .requires("from-registry")
+                        .help("Talks to the registry of --from-registry over plain HTTP rather than HTTPS"),
+                    Arg::with_name("registry-username")
+                        .value_name("USERNAME")
+                        .takes_value(true)
+                        .long("registry-username")
+                        .requires("from-registry")
+                        .help("Username to authenticate against the registry of --from-registry with (the password is taken from CARTORIO_REGISTRY_PASSWORD or the configuration file)"),
                     Arg::with_name("concourse-image-resource")
                         .value_name("DIRECTORY")
                         .takes_value(true)
                         .long("concourse-image-resource")
                         .help("Directory where a Concourse image resource exists"),
+                    Arg::with_name("rootfs")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .long("rootfs")
+                        .requires("name")
+                        .help("Root filesystem (directory or tarball) to build a single-layer image out of"),
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .long("name")
+                        .requires("single-image")
+                        .help("Repository to tag the image built out of --rootfs or --concourse-image-resource under (e.g., team/app) [default for --concourse-image-resource: the resource's type]"),
+                    Arg::with_name("tag")
+                        .value_name("TAG")
+                        .takes_value(true)
+                        .long("tag")
+                        .requires("single-image")
+                        .help("Tag of the image built out of --rootfs or --concourse-image-resource [default: latest for --rootfs, the resource's version for --concourse-image-resource]"),
+                    Arg::with_name("tag-latest")
+                        .long("tag-latest")
+                        .requires("concourse-image-resource")
+                        .help("Tags the image built out of --concourse-image-resource as latest too"),
+                    Arg::with_name("entrypoint")
+                        .value_name("COMMAND")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .long("entrypoint")
+                        .requires("rootfs")
+                        .help("Entrypoint of the image built out of --rootfs: a JSON array (e.g., [\"/bin/sh\", \"-c\"]) or whitespace-separated arguments"),
+                    Arg::with_name("env")
+                        .value_name("NAME=VALUE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .long("env")
+                        .requires("rootfs")
+                        .help("Environment variable of the image built out of --rootfs; can be repeated"),
+                    Arg::with_name("workdir")
+                        .value_name("DIRECTORY")
+                        .takes_value(true)
+                        .long("workdir")
+                        .requires("rootfs")
+                        .help("Working directory of the image built out of --rootfs"),
                     Arg::with_name("oci-image-layout")
                         .value_name("DIRECTORY")
                         .takes_value(true)
                         .long("oci-image-layout")
                         .help("Directory where an OCI Image Layout exists"),
-                ]),
+                    Arg::with_name("metrics-textfile")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("metrics-textfile")
+                        .help("File to write load metrics to, for the node exporter's textfile collector"),
+                ])
+                .args(&compression_args())
+                .group(ArgGroup::with_name("single-image").args(&["rootfs", "concourse-image-resource"]))
+                .group(ArgGroup::with_name("tagged-images").args(&["docker-save-tarball", "from-registry"])),
+        )
+        .subcommand(
+            SubCommand::with_name("mutate")
+                .about("Derives new images from the ones in the blobstore")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("append")
+                        .about("Appends a layer to an image, tagging the result as a new image")
+                        .args(&[
+                            Arg::with_name("blobstore")
+                                .value_name("DIRECTORY")
+                                .takes_value(true)
+                                .short("b")
+                                .long("blobstore")
+                                .help(&blobstore_help),
+                            Arg::with_name("from")
+                                .value_name("IMAGE")
+                                .takes_value(true)
+                                .required(true)
+                                .long("from")
+                                .help("Image to append the layer to (e.g., base:1.0 or base@sha256:...)"),
+                            Arg::with_name("layer")
+                                .value_name("PATH")
+                                .takes_value(true)
+                                .required(true)
+                                .long("layer")
+                                .help("Directory or tarball with the files of the layer"),
+                            Arg::with_name("to")
+                                .value_name("IMAGE")
+                                .takes_value(true)
+                                .required(true)
+                                .long("to")
+                                .help("Name and tag of the resulting image (e.g., app:1.0)"),
+                        ])
+                        .args(&compression_args()),
+                )
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .about("Changes the execution parameters of an image, tagging the result as a new image")
+                        .args(&[
+                            Arg::with_name("blobstore")
+                                .value_name("DIRECTORY")
+                                .takes_value(true)
+                                .short("b")
+                                .long("blobstore")
+                                .help(&blobstore_help),
+                            Arg::with_name("from")
+                                .value_name("IMAGE")
+                                .takes_value(true)
+                                .required(true)
+                                .long("from")
+                                .help("Image to change the config of (e.g., base:1.0 or base@sha256:...)"),
+                            Arg::with_name("to")
+                                .value_name("IMAGE")
+                                .takes_value(true)
+                                .required(true)
+                                .long("to")
+                                .help("Name and tag of the resulting image (e.g., app:1.0)"),
+                            Arg::with_name("entrypoint")
+                                .value_name("COMMAND")
+                                .takes_value(true)
+                                .allow_hyphen_values(true)
+                                .long("entrypoint")
+                                .help("Entrypoint: a JSON array (e.g., [\"/bin/sh\", \"-c\"]) or whitespace-separated arguments"),
+                            Arg::with_name("cmd")
+                                .value_name("COMMAND")
+                                .takes_value(true)
+                                .allow_hyphen_values(true)
+                                .long("cmd")
+                                .help("Default arguments to the entrypoint: a JSON array or whitespace-separated arguments"),
+                            Arg::with_name("env")
+                                .value_name("NAME=VALUE")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .long("env")
+                                .help("Environment variable to set, replacing the value it might have; can be repeated"),
+                            Arg::with_name("label")
+                                .value_name("KEY=VALUE")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .long("label")
+                                .help("Label to set; can be repeated"),
+                            Arg::with_name("user")
+                                .value_name("USER[:GROUP]")
+                                .takes_value(true)
+                                .long("user")
+                                .help("User (name or UID) that the process runs as"),
+                            Arg::with_name("workdir")
+                                .value_name("DIRECTORY")
+                                .takes_value(true)
+                                .long("workdir")
+                                .help("Working directory"),
+                            Arg::with_name("expose")
+                                .value_name("PORT[/PROTOCOL]")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .long("expose")
+                                .help("Port to expose (e.g., 8080 or 53/udp); can be repeated"),
+                            Arg::with_name("volume")
+                                .value_name("DIRECTORY")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .long("volume")
+                                .help("Directory to mount a volume at; can be repeated"),
+                            Arg::with_name("stop-signal")
+                                .value_name("SIGNAL")
+                                .takes_value(true)
+                                .long("stop-signal")
+                                .help("Signal that stops the container (e.g., SIGTERM)"),
+                        ]),
+                ),
         )
         .subcommand(
             SubCommand::with_name("serve")
                 .about("Serve loaded images as a Docker registry")
                 .args(&[
                     Arg::with_name("address")
-                        .default_value("0.0.0.0:5000")
+                        .value_name("ADDRESS")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
                         .short("a")
                         .long("address")
-                        .help("IP address to listen for requests"),
+                        .help("Address to listen for requests on: ip:port, [ipv6]:port, unix:/path or systemd (socket activation); can be repeated [default: 0.0.0.0:5000]"),
                     Arg::with_name("blobstore")
-                        .default_value("/tmp/cartorio/blobstore")
+                        .value_name("DIRECTORY")
+                        .takes_value(true)
                         .short("b")
                         .long("blobstore")
-                        .help("Directory where blobs, manifests and configurations are saved to"),
+                        .help(&blobstore_help),
+                    Arg::with_name("tls-cert")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("tls-cert")
+                        .help("PEM-encoded certificate chain to serve over TLS"),
+                    Arg::with_name("tls-key")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("tls-key")
+                        .help("PEM-encoded private key of the TLS certificate"),
+                    Arg::with_name("tls-client-ca")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("tls-client-ca")
+                        .help("PEM-encoded CA certificates that clients must present certificates signed by"),
+                    Arg::with_name("htpasswd")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("htpasswd")
+                        .help("htpasswd file (bcrypt) with the credentials that clients must authenticate with"),
+                    Arg::with_name("token-realm")
+                        .value_name("URL")
+                        .takes_value(true)
+                        .long("token-realm")
+                        .help("URL of the token issuer that clients get pointed at (e.g., https://localhost:5000/token)"),
+                    Arg::with_name("token-service")
+                        .value_name("SERVICE")
+                        .takes_value(true)
+                        .long("token-service")
+                        .help("Service that tokens must be issued for [default: cartorio]"),
+                    Arg::with_name("token-issuer")
+                        .value_name("ISSUER")
+                        .takes_value(true)
+                        .long("token-issuer")
+                        .help("Issuer that tokens must be issued by [default: cartorio]"),
+                    Arg::with_name("token-public-key")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("token-public-key")
+                        .help("PEM-encoded public key (RSA or ECDSA P-256) that tokens must be signed with"),
+                    Arg::with_name("token-signing-key")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("token-signing-key")
+                        .help("PEM-encoded private key (PKCS#8) for issuing tokens under /token"),
+                    Arg::with_name("policy")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .long("policy")
+                        .help("TOML file with the actions that users and groups can perform against each repository"),
+                    Arg::with_name("global-blobs")
+                        .long("global-blobs")
+                        .help("Serves any blob under any repository, even if none of its manifests reference it"),
+                    Arg::with_name("metrics-address")
+                        .value_name("ADDRESS")
+                        .takes_value(true)
+                        .long("metrics-address")
+                        .help("Address to serve Prometheus metrics under /metrics from (e.g., 127.0.0.1:9090)"),
+                    Arg::with_name("shutdown-timeout")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .long("shutdown-timeout")
+                        .help("How long to wait for in-flight responses to be sent after SIGTERM or SIGINT [default: 30]"),
                 ]),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Inspects the configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("validate")
+                        .about("Checks the configuration file and CARTORIO_* environment variables"),
+                ),
+        )
         .get_matches();
 
+    // socket activation fds are taken out of the environment when serving,
+    // before anything (e.g., the tracing exporter or the tokio runtime)
+    // starts threads, as changing the environment isn't safe afterwards.
+    // Other commands leave them alone.
+    //
+    let activated = match matches.subcommand_name() {
+        Some("serve") => listener::take_activated_fds(),
+        _ => Ok(Vec::new()),
+    };
+
+    let (_, subcommand_matches) = matches.subcommand();
+    let config_matches = subcommand_matches.unwrap_or(&matches);
+    let config_matches = match config_matches.subcommand() {
+        (_, Some(m)) => m,
+        _ => config_matches,
+    };
+
+    let config = match load_config(config_matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        },
+    };
+
+    if let ("config", Some(_)) = matches.subcommand() {
+        println!("configuration is valid");
+        return;
+    }
+
+    let telemetry = match logging::init(
+        config.log_level(),
+        config.log_format(),
+        config.log.otlp_endpoint.as_deref(),
+    ) {
+        Ok(telemetry) => telemetry,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        },
+    };
+
     match matches.subcommand() {
 
         ("load", Some(m)) => {
-            let blobstore = BlobStore::new(
-                Path::new(&value_t!(m, "blobstore", String).unwrap()),
-            ).unwrap();
+            let blobstore = BlobStore::new(config.blobstore()).unwrap();
 
-            if let Ok(docker_saved_tarball) = &value_t!(m, "docker-save-tarball", String) {
-                let loader = DockerSavedTarball::new(
-                    Path::new(docker_saved_tarball), blobstore,
-                ).unwrap();
+            let started = Instant::now();
 
-                if let Err(err) = loader.load() {
-                    panic!("failed to load docker tarball - {}", err);
-                }
+            let (source, result) = if let Some(docker_saved_tarball) = m.value_of("docker-save-tarball") {
+                let result = info_span!("load", source = "docker-save-tarball", path = %docker_saved_tarball)
+                    .in_scope(|| {
+                        load_docker_saved_tarballs(
+                            Path::new(docker_saved_tarball),
+                            &blobstore,
+                            m.is_present("in-place"),
+                            config.registry_host(),
+                            config.layer_compression()?,
+                        )
+                    });
 
-                return;
-            } else if let Ok(concourse_image_resource_dir) = &value_t!(m, "concourse-image-resource", String) {
-                let loader = ConcourseImageResource::new(
-                    Path::new(concourse_image_resource_dir), blobstore,
-                ).unwrap();
+                ("docker-save-tarball", result)
+            } else if let Some(concourse_image_resource_dir) = m.value_of("concourse-image-resource") {
+                let result = info_span!("load", source = "concourse-image-resource", path = %concourse_image_resource_dir)
+                    .in_scope(|| {
+                        let mut loader = ConcourseImageResource::new(Path::new(concourse_image_resource_dir), blobstore)?;
 
-                if let Err(err) = loader.load() {
-                    panic!("failed to concourse image resource - {}", err);
-                }
+                        if let Some(name) = m.value_of("name") {
+                            loader = loader.name(name)?;
+                        }
+
+                        if let Some(tag) = m.value_of("tag") {
+                            loader = loader.tag(tag)?;
+                        }
+
+                        if m.is_present("tag-latest") {
+                            loader = loader.tag_latest();
+                        }
+
+                        if let Some(layer_compression) = config.layer_compression()? {
+                            loader = loader.compress_layers(layer_compression);
+                        }
+
+                        loader.load()
+                    });
+
+                ("concourse-image-resource", result)
+            } else if let Some(reference) = m.value_of("from-registry") {
+                let result = info_span!("load", source = "from-registry", reference = %reference)
+                    .in_scope(|| {
+                        let mut remote_image = RemoteImage::new(reference, blobstore)?
+                            .registry_host(config.registry_host());
+
+                        if let Some(platform) = m.value_of("platform") {
+                            remote_image = remote_image.platform(platform.parse()?);
+                        }
+
+                        if m.is_present("plain-http") {
+                            remote_image = remote_image.plain_http();
+                        }
 
-                return;
-            } else if let Ok(oci_image_layout) = &value_t!(m, "oci-image-layout", String) {
+                        if let (Some(username), Some(password)) =
+                            (&config.load.registry_username, &config.load.registry_password)
+                        {
+                            remote_image = remote_image.credentials(username, password);
+                        }
+
+                        remote_image.load().map(|_| ())
+                    });
+
+                ("from-registry", result)
+            } else if let Some(rootfs) = m.value_of("rootfs") {
+                let result = info_span!("load", source = "rootfs", path = %rootfs)
+                    .in_scope(|| load_rootfs(Path::new(rootfs), m, blobstore, config.layer_compression()?));
+
+                ("rootfs", result)
+            } else if let Some(_oci_image_layout) = m.value_of("oci-image-layout") {
                 unimplemented!("TBD");
+            } else {
+                error!("must specify something to be loaded");
+                std::process::exit(1);
+            };
+
+            let duration = started.elapsed();
+
+            if let Some(textfile) = &config.load.metrics_textfile {
+                if let Err(err) = metrics::write_load_textfile(textfile, source, duration, result.is_ok()) {
+                    error!(path = %textfile.display(), error = %err, "failed to write metrics textfile");
+                }
             }
 
-            println!("error: must specify something to be loaded");
-            std::process::exit(1);
+            if let Err(err) = result {
+                error!(source, error = %err, "failed to load");
+                telemetry.shutdown();
+                std::process::exit(1);
+            }
+
+            info!(source, duration_ms = duration.as_secs_f64() * 1000.0, "loaded");
         }
 
 
-        ("serve", Some(m)) => {
-            let blobstore = BlobStore::new(
-                Path::new(&value_t!(m, "blobstore", String).unwrap()),
-            ).unwrap();
+        ("mutate", Some(m)) => {
+            let blobstore = BlobStore::new(config.blobstore()).unwrap();
+
+            let (command, result) = match m.subcommand() {
+                ("append", Some(m)) => ("append", info_span!("mutate", command = "append").in_scope(|| {
+                    let mut append = AppendLayer::new(
+                        m.value_of("from").unwrap_or_default(),
+                        Path::new(m.value_of("layer").unwrap_or_default()),
+                        m.value_of("to").unwrap_or_default(),
+                        blobstore,
+                    )?;
+
+                    if let Some(layer_compression) = config.layer_compression()? {
+                        append = append.compress_layers(layer_compression);
+                    }
+
+                    append.append()
+                })),
+
+                ("config", Some(m)) => ("config", info_span!("mutate", command = "config").in_scope(|| {
+                    EditConfig::new(
+                        m.value_of("from").unwrap_or_default(),
+                        m.value_of("to").unwrap_or_default(),
+                        config_changes(m)?,
+                        blobstore,
+                    )?
+                    .edit()
+                })),
+
+                _ => unreachable!(),
+            };
+
+            if let Err(err) = result {
+                error!(command, error = %err, "failed to mutate");
+                telemetry.shutdown();
+                std::process::exit(1);
+            }
+        }
+
 
-            server::serve(
-                &value_t!(m, "address", String).unwrap(),
+        ("serve", Some(_)) => {
+            let blobstore = BlobStore::new(config.blobstore()).unwrap();
+
+            if let Err(err) = activated.and_then(|activated| server::serve(
+                &config.addresses(),
+                activated,
                 blobstore,
-            );
+                config.server_options(),
+            )) {
+                error!(error = %err, "failed to serve");
+                telemetry.shutdown();
+                std::process::exit(1);
+            }
         }
 
 
         _ => unreachable!(),
     }
+
+    telemetry.shutdown();
+}
+
+
+/// Flags that set how layers get compressed (see `Config::layer_compression`).
+///
+fn compression_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("compress")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["gzip", "zstd"])
+            .long("compress")
+            .help("Compresses layers into the blobstore instead of storing them uncompressed"),
+        Arg::with_name("compression-level")
+            .value_name("LEVEL")
+            .takes_value(true)
+            .long("compression-level")
+            .help("Level to compress layers with: 1 to 9 for gzip, 1 to 22 for zstd [default: 6 for gzip, 3 for zstd]"),
+        Arg::with_name("compression-threads")
+            .value_name("THREADS")
+            .takes_value(true)
+            .long("compression-threads")
+            .help("How many threads compress each layer, in chunks [default: number of CPUs]"),
+    ]
+}
+
+
+/// Suffixes of the files taken as tarballs when loading a directory of
+/// them.
+///
+const TARBALL_SUFFIXES: &[&str] = &[".tar", ".tar.gz", ".tgz", ".tar.zst", ".tar.xz"];
+
+
+/// Loads the `docker save`d tarball at `path` (`-` for stdin) or, if it's a
+/// directory, all of the tarballs in it.
+///
+fn load_docker_saved_tarballs(
+    path: &Path,
+    blobstore: &BlobStore,
+    in_place: bool,
+    registry_host: RegistryHost,
+    layer_compression: Option<LayerCompression>,
+) -> cartorio::error::Result<()> {
+    let configure = |loader: DockerSavedTarball| {
+        let loader = loader.registry_host(registry_host.clone());
+
+        match &layer_compression {
+            Some(layer_compression) => loader.compress_layers(layer_compression.clone()),
+            None => loader,
+        }
+    };
+
+    if in_place && layer_compression.is_some() {
+        return Err(failure::format_err!("layers of tarballs served in place can't be compressed"));
+    }
+
+    if path == Path::new("-") {
+        if in_place {
+            return Err(failure::format_err!("tarballs read from stdin can't be served in place"));
+        }
+
+        return DockerSavedTarball::from_reader(std::io::stdin().lock(), blobstore.clone())
+            .and_then(|loader| configure(loader).load());
+    }
+
+    let tarballs = if path.is_dir() {
+        let mut tarballs = Vec::new();
+
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+
+            let is_tarball = entry_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| TARBALL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)));
+
+            if is_tarball {
+                tarballs.push(entry_path);
+            }
+        }
+
+        tarballs.sort();
+        tarballs
+    } else {
+        vec![path.to_owned()]
+    };
+
+    for tarball in tarballs {
+        let loader = if in_place {
+            DockerSavedTarball::in_place(&tarball, blobstore.clone())
+        } else {
+            DockerSavedTarball::new(&tarball, blobstore.clone())
+        };
+
+        loader
+            .and_then(|loader| configure(loader).load())
+            .map_err(|err| failure::format_err!("{}: {}", tarball.display(), err))?;
+    }
+
+    Ok(())
+}
+
+
+/// Builds the image out of the root filesystem at `rootfs`, as described by
+/// the flags in `m`.
+///
+fn load_rootfs(
+    rootfs: &Path,
+    m: &ArgMatches,
+    blobstore: BlobStore,
+    layer_compression: Option<LayerCompression>,
+) -> cartorio::error::Result<()> {
+    let name = m.value_of("name").unwrap_or_default();
+    let tag = m.value_of("tag").unwrap_or("latest");
+
+    let mut loader = RootfsImage::new(rootfs, name, tag, blobstore)?;
+
+    if let Some(entrypoint) = m.value_of("entrypoint") {
+        loader = loader.entrypoint(parse_command("entrypoint", entrypoint)?);
+    }
+
+    if let Some(env) = parse_env(m)? {
+        loader = loader.env(env);
+    }
+
+    if let Some(workdir) = m.value_of("workdir") {
+        loader = loader.workdir(workdir);
+    }
+
+    if let Some(layer_compression) = layer_compression {
+        loader = loader.compress_layers(layer_compression);
+    }
+
+    loader.load()
+}
+
+
+/// The execution parameters that `mutate config` sets, as set through its
+/// flags in `m`.
+///
+fn config_changes(m: &ArgMatches) -> cartorio::error::Result<ImageConfigContainer> {
+    let labels = match m.values_of("label") {
+        Some(labels) => {
+            let mut parsed = BTreeMap::new();
+
+            for label in labels {
+                match label.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => parsed.insert(key.to_owned(), value.to_owned()),
+                    _ => return Err(failure::format_err!("invalid label `{}` (must be KEY=VALUE)", label)),
+                };
+            }
+
+            Some(parsed)
+        },
+        None => None,
+    };
+
+    let exposed_ports = match m.values_of("expose") {
+        Some(ports) => {
+            let mut parsed = BTreeMap::new();
+
+            for port in ports {
+                let (number, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+
+                if number.parse::<u16>().is_err() || !["tcp", "udp", "sctp"].contains(&protocol) {
+                    return Err(failure::format_err!("invalid port `{}` (must be PORT[/tcp|udp|sctp])", port));
+                }
+
+                parsed.insert(format!("{}/{}", number, protocol), serde_json::json!({}));
+            }
+
+            Some(parsed)
+        },
+        None => None,
+    };
+
+    let volumes = m
+        .values_of("volume")
+        .map(|volumes| volumes.map(|volume| (volume.to_owned(), serde_json::json!({}))).collect());
+
+    Ok(ImageConfigContainer {
+        user: m.value_of("user").map(|user| user.to_owned()),
+        exposed_ports,
+        env: parse_env(m)?,
+        entrypoint: m.value_of("entrypoint").map(|value| parse_command("entrypoint", value)).transpose()?,
+        cmd: m.value_of("cmd").map(|value| parse_command("cmd", value)).transpose()?,
+        volumes,
+        working_dir: m.value_of("workdir").map(|workdir| workdir.to_owned()),
+        labels,
+        stop_signal: m.value_of("stop-signal").map(|signal| signal.to_owned()),
+        ..ImageConfigContainer::default()
+    })
+}
+
+
+/// Parses the value of `--entrypoint` or `--cmd`: either a JSON array
+/// (e.g., `["/bin/sh", "-c"]`) or whitespace-separated arguments.
+///
+fn parse_command(flag: &str, value: &str) -> cartorio::error::Result<Vec<String>> {
+    if value.trim_start().starts_with('[') {
+        serde_json::from_str(value).map_err(|err| failure::format_err!("invalid {} `{}`: {}", flag, value, err))
+    } else {
+        Ok(value.split_whitespace().map(|arg| arg.to_owned()).collect())
+    }
+}
+
+
+/// The environment variables set through `--env`, if any.
+///
+fn parse_env(m: &ArgMatches) -> cartorio::error::Result<Option<Vec<String>>> {
+    let env: Vec<String> = match m.values_of("env") {
+        Some(env) => env.map(|var| var.to_owned()).collect(),
+        None => return Ok(None),
+    };
+
+    if let Some(var) = env.iter().find(|var| !var.contains('=')) {
+        return Err(failure::format_err!("invalid environment variable `{}` (must be NAME=VALUE)", var));
+    }
+
+    Ok(Some(env))
+}
+
+
+/// Gathers the configuration from the file (`--config` or `CARTORIO_CONFIG`),
+/// the environment and the flags set in `m`.
+///
+fn load_config(m: &ArgMatches) -> cartorio::error::Result<Config> {
+    let file = m
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from));
+
+    Config::load(file.as_deref(), std::env::vars(), |flag| match m.values_of(flag) {
+        Some(values) => Some(values.map(|value| value.to_owned()).collect()),
+        None if m.is_present(flag) => Some(vec!["true".to_owned()]),
+        None => None,
+    })
 }
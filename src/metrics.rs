@@ -0,0 +1,353 @@
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+use tracing::{error, warn};
+
+use crate::blob_body::{full, ResponseBody};
+use crate::blobstore::BlobStore;
+use crate::error::Result;
+
+
+/// Upper bounds (in seconds) of the buckets of request durations, which
+/// go from tiny manifests served from the page cache to multi-gigabyte
+/// layers sent over slow links.
+///
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+
+/// Metrics about the requests served, exposed in the Prometheus text
+/// format.
+///
+/// ```txt
+/// cartorio_http_requests_total{endpoint="blob",status="200"} 12
+/// cartorio_http_request_duration_seconds_bucket{endpoint="blob",status="200",le="0.005"} 3
+/// cartorio_http_response_bytes_total{endpoint="blob"} 1048576
+/// cartorio_http_requests_in_flight 1
+/// cartorio_blobstore_blobs 42
+/// cartorio_blobstore_bytes 73400320
+/// ```
+///
+/// The blobstore figures are computed whenever metrics get gathered, as
+/// blobs are added by `cartorio load`, a different process.
+///
+pub struct Metrics {
+    registry: Registry,
+    blobstore: BlobStore,
+
+    requests: IntCounterVec,
+    durations: HistogramVec,
+    bytes: IntCounterVec,
+    in_flight: IntGauge,
+
+    blobstore_blobs: IntGauge,
+    blobstore_bytes: IntGauge,
+}
+
+
+impl Metrics {
+
+    pub fn new(blobstore: BlobStore) -> Result<Metrics> {
+        let registry = Registry::new_custom(Some("cartorio".to_owned()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests served, by endpoint and status."),
+            &["endpoint", "status"],
+        )?;
+
+        let durations = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from receiving a request to sending the last byte of its response.",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+            &["endpoint", "status"],
+        )?;
+
+        let bytes = IntCounterVec::new(
+            Opts::new("http_response_bytes_total", "Bytes of response bodies sent, by endpoint."),
+            &["endpoint"],
+        )?;
+
+        let in_flight = IntGauge::new(
+            "http_requests_in_flight",
+            "Requests being handled or having their responses sent.",
+        )?;
+
+        let blobstore_blobs = IntGauge::new("blobstore_blobs", "Blobs (including manifests) in the blobstore.")?;
+        let blobstore_bytes = IntGauge::new("blobstore_bytes", "Size of the blobs in the blobstore.")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(durations.clone()))?;
+        registry.register(Box::new(bytes.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(blobstore_blobs.clone()))?;
+        registry.register(Box::new(blobstore_bytes.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            blobstore,
+            requests,
+            durations,
+            bytes,
+            in_flight,
+            blobstore_blobs,
+            blobstore_bytes,
+        })
+    }
+
+
+    /// Accounts for a request that started being handled, for as long as
+    /// the returned guard is alive.
+    ///
+    /// The guard must be kept until the response has been sent (or
+    /// abandoned), be it along with its body or with the future handling
+    /// the request, in case the client goes away before there's a body.
+    ///
+    pub fn request_started(&self) -> InFlight {
+        self.in_flight.inc();
+        InFlight(self.in_flight.clone())
+    }
+
+
+    /// Accounts for a request whose response has been sent (or abandoned),
+    /// apart from it being in flight (see `request_started`).
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - name of the route that handled the request.
+    /// * `status` - status code of the response.
+    /// * `bytes` - bytes of the response body sent.
+    /// * `duration` - time since the request started being handled.
+    ///
+    pub fn request_finished(&self, endpoint: &str, status: u16, bytes: u64, duration: Duration) {
+        let status = status.to_string();
+
+        self.requests.with_label_values(&[endpoint, &status]).inc();
+        self.durations
+            .with_label_values(&[endpoint, &status])
+            .observe(duration.as_secs_f64());
+        self.bytes.with_label_values(&[endpoint]).inc_by(bytes);
+    }
+
+
+    /// Renders all of the metrics in the Prometheus text format.
+    ///
+    /// # Remarks
+    ///
+    /// This walks the blobstore, so it should not be called from the
+    /// reactor.
+    ///
+    pub fn render(&self) -> Result<String> {
+        let (blobs, bytes) = bucket_usage(&self.blobstore.bucket_dir)?;
+
+        self.blobstore_blobs.set(blobs as i64);
+        self.blobstore_bytes.set(bytes as i64);
+
+        encode(&self.registry)
+    }
+
+}
+
+
+/// A request being handled, which stops counting as in flight once
+/// dropped.
+///
+pub struct InFlight(IntGauge);
+
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+
+/// Counts the files in the bucket and sums their sizes.
+///
+fn bucket_usage(bucket_dir: &Path) -> Result<(u64, u64)> {
+    let mut blobs = 0;
+    let mut bytes = 0;
+
+    for entry in std::fs::read_dir(bucket_dir)? {
+        let metadata = entry?.metadata()?;
+
+        if metadata.is_file() {
+            blobs += 1;
+            bytes += metadata.len();
+        }
+    }
+
+    Ok((blobs, bytes))
+}
+
+
+fn encode(registry: &Registry) -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&registry.gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+
+/// Serves `GET /metrics` to the connections accepted by `listener`, kept
+/// apart from the registry so that it can be exposed to a different
+/// audience (e.g., only to the node's Prometheus).
+///
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(error = %err, "failed to accept metrics connection");
+                continue;
+            },
+        };
+
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let metrics = metrics.clone();
+
+                async move { Ok::<_, Infallible>(handle_metrics(req, metrics).await) }
+            });
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!(client = %client, error = %err, "metrics connection error");
+            }
+        });
+    }
+}
+
+
+async fn handle_metrics<B>(req: Request<B>, metrics: Arc<Metrics>) -> Response<ResponseBody> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full("not found"))
+            .unwrap();
+    }
+
+    let rendered = tokio::task::spawn_blocking(move || metrics.render())
+        .await
+        .map_err(failure::Error::from)
+        .and_then(|rendered| rendered);
+
+    match rendered {
+        Ok(rendered) => Response::builder()
+            .header("content-type", prometheus::TEXT_FORMAT)
+            .status(StatusCode::OK)
+            .body(full(rendered))
+            .unwrap(),
+        Err(err) => {
+            error!(error = %err, "failed to render metrics");
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(full(err.to_string()))
+                .unwrap()
+        },
+    }
+}
+
+
+/// Writes the outcome of a `cartorio load` to a file for the textfile
+/// collector of the node exporter to pick up.
+///
+/// ```txt
+/// cartorio_load_duration_seconds{source="docker-save-tarball"} 1.27
+/// cartorio_load_success{source="docker-save-tarball"} 1
+/// cartorio_load_last_run_timestamp_seconds{source="docker-save-tarball"} 1556704800
+/// ```
+///
+/// The file is replaced atomically so that the collector never reads a
+/// partially written one.
+///
+/// # Arguments
+///
+/// * `path` - file to write (conventionally ending in `.prom`).
+/// * `source` - kind of what's been loaded (e.g., `docker-save-tarball`).
+/// * `duration` - how long loading took.
+/// * `success` - whether loading succeeded.
+///
+pub fn write_load_textfile(path: &Path, source: &str, duration: Duration, success: bool) -> Result<()> {
+    let registry = Registry::new_custom(Some("cartorio".to_owned()), None)?;
+
+    let gauge = |name: &str, help: &str, value: f64| -> Result<()> {
+        let gauge = Gauge::with_opts(Opts::new(name, help).const_label("source", source))?;
+        gauge.set(value);
+        registry.register(Box::new(gauge))?;
+
+        Ok(())
+    };
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+
+    gauge("load_duration_seconds", "How long the last load took.", duration.as_secs_f64())?;
+    gauge("load_success", "Whether the last load succeeded.", if success { 1.0 } else { 0.0 })?;
+    gauge("load_last_run_timestamp_seconds", "When the last load finished.", now.as_secs() as f64)?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+
+    std::io::Write::write_all(&mut file, encode(&registry)?.as_bytes())?;
+    file.persist(path)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn renders_request_and_blobstore_metrics() {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+        std::fs::write(blobstore.bucket_dir.join("sha256:abc"), "content").unwrap();
+
+        let metrics = Metrics::new(blobstore).unwrap();
+        let _in_flight = metrics.request_started();
+        drop(metrics.request_started());
+        metrics.request_finished("blob", 200, 7, Duration::from_millis(3));
+
+        let rendered = metrics.render().unwrap();
+
+        assert!(rendered.contains("cartorio_http_requests_total{endpoint=\"blob\",status=\"200\"} 1"));
+        assert!(rendered.contains("cartorio_http_response_bytes_total{endpoint=\"blob\"} 7"));
+        assert!(rendered.contains("cartorio_http_requests_in_flight 1"));
+        assert!(rendered.contains("cartorio_blobstore_blobs 1"));
+        assert!(rendered.contains("cartorio_blobstore_bytes 7"));
+    }
+
+    #[test]
+    fn writes_load_textfile() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cartorio.prom");
+
+        write_load_textfile(&path, "docker-save-tarball", Duration::from_millis(1500), true).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("cartorio_load_duration_seconds{source=\"docker-save-tarball\"} 1.5"));
+        assert!(content.contains("cartorio_load_success{source=\"docker-save-tarball\"} 1"));
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::blobstore::BlobStore;
+use crate::compression::LayerCompression;
+use crate::digest;
+use crate::error::Result;
+use crate::image_config::{ImageConfig, ImageConfigContainer, ImageConfigHistory};
+use crate::image_reference::{ImageReference, RegistryHost, DEFAULT_TAG};
+use crate::registry::{self, Manifest, DOCKER_MANIFEST_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE};
+use crate::rootfs_image;
+
+
+/// What the history entries of appended layers are created by.
+///
+const APPEND_CREATED_BY: &str = "cartorio mutate append";
+
+
+/// What the history entries of edited configs are created by.
+///
+const CONFIG_CREATED_BY: &str = "cartorio mutate config";
+
+
+/// Appends a layer on top of an image already in the blobstore, tagging
+/// the result as a new image.
+///
+/// ```txt
+///
+///   base:1.0  (manifest m1)                  app:1.0  (manifest m2)
+///   ├── config c1                            ├── config c2  (c1 + diff_id of l3
+///   │   └── diff_ids [ d1, d2 ]      ==>     │              + history entry)
+///   └── layers [ l1, l2 ]                    └── layers [ l1, l2, l3 ]
+///
+/// ```
+///
+/// The layer is built the same way as the ones of root filesystems (see
+/// [`RootfsImage`]), with the layers of the base image being referenced
+/// as they are - neither read nor re-written.
+///
+/// [`RootfsImage`]: ../rootfs_image/struct.RootfsImage.html
+///
+pub struct AppendLayer {
+
+    /// Name and reference (tag or digest) of the base image.
+    ///
+    from: (String, String),
+
+    /// Directory or tarball with the files of the layer.
+    ///
+    layer: PathBuf,
+
+    /// Name and tag to give to the resulting image.
+    ///
+    to: (String, String),
+
+    /// How the layer gets compressed into the blobstore, if at all.
+    ///
+    layer_compression: Option<LayerCompression>,
+
+    /// The owner of both the base image and the resulting one.
+    ///
+    blobstore: BlobStore,
+}
+
+
+impl AppendLayer {
+
+    /// Instantiates a new AppendLayer.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - the base image (e.g., `base:1.0` or `base@sha256:abc`).
+    /// * `layer` - directory or tarball (possibly compressed) with the
+    ///   files to add.
+    /// * `to` - the name and tag of the resulting image (e.g., `app:1.0`).
+    /// * `blobstore` - the blobstore where the base image is.
+    ///
+    pub fn new(from: &str, layer: &Path, to: &str, blobstore: BlobStore) -> Result<AppendLayer> {
+        if !layer.exists() {
+            return Err(failure::format_err!("no layer at {}", layer.display()));
+        }
+
+        Ok(AppendLayer {
+            from: parse_from(from)?,
+            layer: layer.to_owned(),
+            to: parse_to(to)?,
+            layer_compression: None,
+            blobstore,
+        })
+    }
+
+
+    /// Makes the layer get compressed into the blobstore, instead of
+    /// being stored uncompressed.
+    ///
+    pub fn compress_layers(mut self, layer_compression: LayerCompression) -> AppendLayer {
+        self.layer_compression = Some(layer_compression);
+        self
+    }
+
+
+    /// Builds the layer and the config and manifest of the resulting image,
+    /// tagging it into the blobstore.
+    ///
+    /// Returns the name of the manifest in the bucket (e.g., `sha256:abc`).
+    ///
+    pub fn append(&self) -> Result<String> {
+        let mut base = BaseImage::read(&self.blobstore, &self.from)?;
+        let staging_dir = self.blobstore.staging_dir()?;
+
+        let (mut layer_descriptor, diff_id) = rootfs_image::ingest_layer(
+            &self.layer,
+            staging_dir.path(),
+            &self.blobstore,
+            self.layer_compression.as_ref(),
+        )?;
+
+        if base.media_type != OCI_MANIFEST_MEDIA_TYPE
+            && self.layer_compression.as_ref().is_some_and(LayerCompression::needs_oci_manifest)
+        {
+            base.media_type = OCI_MANIFEST_MEDIA_TYPE.to_owned();
+            base.manifest = base.manifest.into_oci();
+        }
+
+        if base.media_type == OCI_MANIFEST_MEDIA_TYPE {
+            layer_descriptor.media_type = registry::oci_layer_media_type(&layer_descriptor.media_type).to_owned();
+        }
+
+        base.config.push_layer(&diff_id, ImageConfigHistory {
+            created_by: Some(APPEND_CREATED_BY.to_owned()),
+            ..ImageConfigHistory::default()
+        });
+
+        base.manifest.layers.push(layer_descriptor);
+
+        let manifest_filename = base.derive(&self.blobstore, staging_dir.path(), &self.to)?;
+
+        info!(
+            from = %format!("{}:{}", self.from.0, self.from.1),
+            to = %format!("{}:{}", self.to.0, self.to.1),
+            manifest = %manifest_filename,
+            "appended layer",
+        );
+
+        Ok(manifest_filename)
+    }
+
+}
+
+
+/// Changes the execution parameters (entrypoint, environment, labels, ...)
+/// in the config of an image already in the blobstore, tagging the result
+/// as a new image.
+///
+/// ```txt
+///
+///   base:1.0  (manifest m1)                  app:1.0  (manifest m2)
+///   ├── config c1                    ==>     ├── config c2  (c1 + changes
+///   │   └── Env [ PATH=/bin ]                │   └── Env [ PATH=/bin, LANG=C ]
+///   └── layers [ l1, l2 ]                    └── layers [ l1, l2 ]
+///
+/// ```
+///
+/// Everything in the config that isn't changed is kept as it is, including
+/// fields that cartorio doesn't know about.
+///
+pub struct EditConfig {
+
+    /// Name and reference (tag or digest) of the base image.
+    ///
+    from: (String, String),
+
+    /// Name and tag to give to the resulting image.
+    ///
+    to: (String, String),
+
+    /// The execution parameters to merge into the ones of the base image
+    /// (see [`ImageConfigContainer::merge`]).
+    ///
+    /// [`ImageConfigContainer::merge`]: ../image_config/struct.ImageConfigContainer.html#method.merge
+    ///
+    changes: ImageConfigContainer,
+
+    /// The owner of both the base image and the resulting one.
+    ///
+    blobstore: BlobStore,
+}
+
+
+impl EditConfig {
+
+    /// Instantiates a new EditConfig.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - the base image (e.g., `base:1.0` or `base@sha256:abc`).
+    /// * `to` - the name and tag of the resulting image (e.g., `app:1.0`),
+    ///   which can be the same as `from`'s.
+    /// * `changes` - the execution parameters to set.
+    /// * `blobstore` - the blobstore where the base image is.
+    ///
+    pub fn new(from: &str, to: &str, changes: ImageConfigContainer, blobstore: BlobStore) -> Result<EditConfig> {
+        Ok(EditConfig {
+            from: parse_from(from)?,
+            to: parse_to(to)?,
+            changes,
+            blobstore,
+        })
+    }
+
+
+    /// Writes the config and manifest of the resulting image, tagging it
+    /// into the blobstore.
+    ///
+    /// Returns the name of the manifest in the bucket (e.g., `sha256:abc`).
+    ///
+    pub fn edit(&self) -> Result<String> {
+        let mut base = BaseImage::read(&self.blobstore, &self.from)?;
+        let staging_dir = self.blobstore.staging_dir()?;
+
+        base.config.container_mut().merge(self.changes.clone());
+
+        base.config.push_history(ImageConfigHistory {
+            created_by: Some(CONFIG_CREATED_BY.to_owned()),
+            empty_layer: true,
+            ..ImageConfigHistory::default()
+        });
+
+        let manifest_filename = base.derive(&self.blobstore, staging_dir.path(), &self.to)?;
+
+        info!(
+            from = %format!("{}:{}", self.from.0, self.from.1),
+            to = %format!("{}:{}", self.to.0, self.to.1),
+            manifest = %manifest_filename,
+            "edited config",
+        );
+
+        Ok(manifest_filename)
+    }
+
+}
+
+
+/// An image in the blobstore that others get derived from.
+///
+struct BaseImage {
+
+    /// Media type of the manifest (Docker's or OCI's).
+    ///
+    media_type: String,
+
+    manifest: Manifest,
+
+    config: ImageConfig,
+}
+
+
+impl BaseImage {
+
+    /// Reads the manifest and config of the image tagged as `name` and
+    /// `reference`.
+    ///
+    fn read(blobstore: &BlobStore, (name, reference): &(String, String)) -> Result<BaseImage> {
+        let content = match fs::read(blobstore.get_manifest(name, reference)) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(failure::format_err!("image {}:{} not found", name, reference));
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        let media_type = registry::manifest_media_type(&content);
+
+        if media_type != DOCKER_MANIFEST_MEDIA_TYPE && media_type != OCI_MANIFEST_MEDIA_TYPE {
+            return Err(failure::format_err!(
+                "{}:{} is a {}, refer to one of the manifests it lists (by digest) instead",
+                name, reference, media_type,
+            ));
+        }
+
+        let manifest: Manifest = serde_json::from_slice(&content)?;
+        let config: ImageConfig = serde_json::from_slice(&blobstore.read_blob(&manifest.config.digest)?)?;
+
+        Ok(BaseImage {
+            media_type,
+            manifest,
+            config,
+        })
+    }
+
+
+    /// Writes the (modified) config and manifest to the blobstore, tagging
+    /// the image as `name` and `tag`.
+    ///
+    fn derive(self, blobstore: &BlobStore, staging_dir: &Path, (name, tag): &(String, String)) -> Result<String> {
+        let config_path = staging_dir.join("config.json");
+        fs::write(&config_path, self.config.to_string())?;
+
+        let config_digest = digest::compute_for_file_and_store(&config_path)?;
+        let config_size = fs::metadata(&config_path)?.len();
+
+        blobstore.add_blob_with_digest(&config_path, &config_digest)?;
+
+        // the rest of the manifest (e.g., annotations, or the URLs of
+        // foreign layers) is kept as it was.
+        //
+        let mut manifest = self.manifest;
+        manifest.media_type = self.media_type;
+        manifest.config.size = config_size;
+        manifest.config.digest = digest::prepend_sha_scheme(&config_digest);
+
+        let manifest_filename = blobstore.add_manifest(&manifest)?;
+
+        blobstore.tag_manifest(&manifest_filename, name, tag)?;
+        blobstore.tag_manifest(&manifest_filename, name, &manifest_filename)?;
+
+        Ok(manifest_filename)
+    }
+
+}
+
+
+/// Name and reference of the image that `from` refers to, defaulting to
+/// the `latest` tag.
+///
+fn parse_from(from: &str) -> Result<(String, String)> {
+    let from: ImageReference = from.parse()?;
+
+    Ok((
+        from.repository_in(&RegistryHost::Keep)?,
+        from.digest.clone().or_else(|| from.tag.clone()).unwrap_or_else(|| DEFAULT_TAG.to_owned()),
+    ))
+}
+
+
+/// Name and tag that `to` refers to, which can't be a digest, defaulting
+/// to the `latest` tag.
+///
+fn parse_to(to: &str) -> Result<(String, String)> {
+    let to: ImageReference = to.parse()?;
+
+    if to.digest.is_some() {
+        return Err(failure::format_err!("`{}` must be tagged, not referenced by digest", to));
+    }
+
+    Ok((
+        to.repository_in(&RegistryHost::Keep)?,
+        to.tag.clone().unwrap_or_else(|| DEFAULT_TAG.to_owned()),
+    ))
+}
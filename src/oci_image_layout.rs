@@ -15,9 +15,9 @@ use crate::error::Result;
 ///
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct OciManifestPlatform {
-    architecture: String,
-    os: String,
+pub struct OciManifestPlatform {
+    pub architecture: String,
+    pub os: String,
 }
 
 
@@ -26,12 +26,12 @@ struct OciManifestPlatform {
 ///
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct OciImageIndexManifest {
-    media_type: String,
-    digest: String,
-    size: u64,
-    annotations: HashMap<String, String>,
-    platform: OciManifestPlatform,
+pub struct OciImageIndexManifest {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    pub annotations: HashMap<String, String>,
+    pub platform: OciManifestPlatform,
 }
 
 
@@ -46,8 +46,8 @@ struct OciImageIndexManifest {
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OciImageIndex {
-    schema_version: u8,
-    manifests: Vec<OciImageIndexManifest>,
+    pub schema_version: u8,
+    pub manifests: Vec<OciImageIndexManifest>,
 }
 
 impl FromStr for OciImageIndex {
@@ -123,14 +123,14 @@ impl OciImageLayout {
     /// * `name` - name of the image
     /// * `blobstore` - a BlobStore to own the images from such directory.
     ///
-    pub fn new(dir: &Path, name: &str, blobstore: BlobStore) -> Result<OciImageLayout> {
+    pub fn new(dir: &Path, _name: &str, blobstore: BlobStore) -> Result<OciImageLayout> {
         let index_content = fs::read_to_string(dir.join("index.json"))?;
 
         let image_index: OciImageIndex = index_content.parse()?;
 
         Ok(OciImageLayout{
-            blobstore: blobstore,
-            image_index: image_index,
+            blobstore,
+            image_index,
             root_dir: dir.to_owned(),
         })
     }
@@ -183,7 +183,7 @@ impl OciImageLayout {
 
         for manifest in &self.image_index.manifests {
             let manifest_name = digest::prepend_sha_scheme(&manifest.digest);
-            let manifest_path = self.blobstore.get_blob(&manifest_name);
+            let _manifest_path = self.blobstore.get_blob(&manifest_name);
 
             // tagging digest to the digest
             self.blobstore.tag_manifest(&manifest_name, "test", &manifest_name)?;
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::error::Result;
+use crate::file_watch::{FileWatch, WATCH_INTERVAL};
+use crate::token::Action;
+
+
+/// Who can do what against which repositories.
+///
+/// ```toml
+/// [groups]
+/// team-a = ["alice", "bob"]
+///
+/// [[rules]]
+/// subjects = ["group:team-a"]
+/// repositories = ["team-a/**"]
+/// actions = ["pull", "list"]
+///
+/// [[rules]]
+/// subjects = ["*"]
+/// repositories = ["library/*"]
+/// actions = ["pull", "list"]
+/// ```
+///
+/// Subjects are either user names, groups (`group:<name>`) or `*`, which
+/// matches anyone, including anonymous clients.
+///
+/// In repository globs, `*` matches any sequence of characters within a
+/// path component, while `**` spans across components.
+///
+/// Anything not granted by a rule is denied.
+///
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRules {
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    subjects: Vec<String>,
+    repositories: Vec<String>,
+    actions: Vec<Action>,
+}
+
+
+impl FromStr for PolicyRules {
+
+    type Err = failure::Error;
+
+    fn from_str(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+}
+
+
+impl PolicyRules {
+
+    /// Whether `user` (`None` if anonymous) can perform `action` against
+    /// `repository`.
+    ///
+    pub fn allows(&self, user: Option<&str>, repository: &str, action: Action) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.actions.contains(&action)
+                && rule.subjects.iter().any(|subject| self.subject_matches(subject, user))
+                && rule.repositories.iter().any(|glob| glob_matches(glob, repository))
+        })
+    }
+
+
+    fn subject_matches(&self, subject: &str, user: Option<&str>) -> bool {
+        if subject == "*" {
+            return true;
+        }
+
+        let user = match user {
+            Some(user) => user,
+            None => return false,
+        };
+
+        if let Some(group) = subject.strip_prefix("group:") {
+            return self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.iter().any(|member| member == user));
+        }
+
+        subject == user
+    }
+
+}
+
+
+/// A policy file that gets re-read whenever it changes.
+///
+pub struct Policy {
+    path: PathBuf,
+    rules: RwLock<PolicyRules>,
+}
+
+
+impl Policy {
+
+    /// Loads the policy file at `path`.
+    ///
+    pub fn new(path: &Path) -> Result<Policy> {
+        Ok(Policy {
+            path: path.to_owned(),
+            rules: RwLock::new(read_rules(path)?),
+        })
+    }
+
+
+    /// Re-reads the file from disk, keeping the current rules if it can't
+    /// be loaded.
+    ///
+    pub fn reload(&self) -> Result<()> {
+        let rules = read_rules(&self.path)?;
+
+        *self.rules.write().unwrap() = rules;
+
+        Ok(())
+    }
+
+
+    /// Reloads the file whenever it changes, never returning.
+    ///
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut file = FileWatch::new(vec![self.path.clone()]);
+
+        loop {
+            interval.tick().await;
+
+            if !file.changed() {
+                continue;
+            }
+
+            let policy = self.clone();
+
+            match tokio::task::spawn_blocking(move || policy.reload()).await {
+                Ok(Ok(())) => info!(path = ?self.path, "reloaded policy file"),
+                Ok(Err(err)) => error!(path = ?self.path, error = %err, "failed to reload policy file"),
+                Err(err) => error!(path = ?self.path, error = %err, "failed to reload policy file"),
+            }
+        }
+    }
+
+
+    /// Whether `user` (`None` if anonymous) can perform `action` against
+    /// `repository`.
+    ///
+    pub fn allows(&self, user: Option<&str>, repository: &str, action: Action) -> bool {
+        self.rules.read().unwrap().allows(user, repository, action)
+    }
+
+}
+
+
+fn read_rules(path: &Path) -> Result<PolicyRules> {
+    std::fs::read_to_string(path)?
+        .parse()
+        .map_err(|err| failure::format_err!("{:?}: {}", path, err))
+}
+
+
+/// Matches a repository name against a glob where `*` stays within a path
+/// component and `**` crosses them.
+///
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let pos = match glob.find('*') {
+        Some(pos) => pos,
+        None => return glob == name,
+    };
+
+    let (prefix, wildcard) = glob.split_at(pos);
+
+    if !name.starts_with(prefix) {
+        return false;
+    }
+
+    let name = &name[prefix.len()..];
+
+    let (rest, limit) = match wildcard.strip_prefix("**") {
+        Some(rest) => (rest, name.len()),
+        None => (&wildcard[1..], name.find('/').unwrap_or(name.len())),
+    };
+
+    (0..=limit).any(|idx| glob_matches(rest, &name[idx..]))
+}
+
+
+#[cfg(test)]
+mod policy_tests {
+    use super::*;
+
+    const RULES: &str = r#"
+[groups]
+team-a = ["alice", "bob"]
+
+[[rules]]
+subjects = ["group:team-a", "carol"]
+repositories = ["team-a/*"]
+actions = ["pull", "list"]
+
+[[rules]]
+subjects = ["*"]
+repositories = ["public/**"]
+actions = ["pull"]
+"#;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("a", "a"));
+        assert!(!glob_matches("a", "ab"));
+
+        assert!(glob_matches("team-a/*", "team-a/app"));
+        assert!(!glob_matches("team-a/*", "team-a/app/sub"));
+        assert!(!glob_matches("team-a/*", "team-b/app"));
+
+        assert!(glob_matches("team-a/**", "team-a/app/sub"));
+        assert!(glob_matches("*/app", "team-a/app"));
+        assert!(glob_matches("*", "a"));
+        assert!(!glob_matches("*", "a/b"));
+        assert!(glob_matches("**", "a/b"));
+    }
+
+    #[test]
+    fn allows_by_user_and_group() {
+        let rules: PolicyRules = RULES.parse().unwrap();
+
+        assert!(rules.allows(Some("alice"), "team-a/app", Action::Pull));
+        assert!(rules.allows(Some("carol"), "team-a/app", Action::List));
+        assert!(!rules.allows(Some("alice"), "team-a/app", Action::Push));
+        assert!(!rules.allows(Some("dave"), "team-a/app", Action::Pull));
+        assert!(!rules.allows(None, "team-a/app", Action::Pull));
+    }
+
+    #[test]
+    fn allows_anyone_through_wildcard_subjects() {
+        let rules: PolicyRules = RULES.parse().unwrap();
+
+        assert!(rules.allows(None, "public/a/b", Action::Pull));
+        assert!(rules.allows(Some("dave"), "public/a", Action::Pull));
+        assert!(!rules.allows(None, "public/a", Action::List));
+    }
+
+    #[test]
+    fn rejects_malformed_policies() {
+        let err = "[[rules]]\nsubjects = [\"*\"]\nrepositories = [\"*\"]\nactions = [\"fly\"]\n"
+            .parse::<PolicyRules>()
+            .err()
+            .unwrap();
+
+        assert!(err.to_string().contains("line 4"), "{}", err);
+    }
+}
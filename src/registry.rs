@@ -1,13 +1,46 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::image_config::UnknownFields;
+
+/// Media type of the manifests generated by cartorio, which is also the one
+/// assumed for manifests that don't state theirs.
+///
+pub const DOCKER_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+pub const DOCKER_MANIFEST_LIST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+pub const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+pub const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ManifestDescriptor {
-    pub media_type: &'static str,
+    pub media_type: String,
     pub size: u64,
     pub digest: String,
+
+    /// Anything else (e.g., `urls`, `annotations` or `platform`), so that
+    /// descriptors survive being rewritten.
+    ///
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
 }
 
+
+impl ManifestDescriptor {
+
+    /// Whether the descriptor points at a list of manifests (a Docker
+    /// manifest list or an OCI image index) rather than at a manifest.
+    ///
+    pub fn is_index(&self) -> bool {
+        self.media_type == DOCKER_MANIFEST_LIST_MEDIA_TYPE || self.media_type == OCI_INDEX_MEDIA_TYPE
+    }
+
+}
+
+
 /// A manifest that represents an image:
 /// - configuration + layers.
 ///
@@ -15,7 +48,238 @@ pub struct ManifestDescriptor {
 #[serde(rename_all = "camelCase")]
 pub struct Manifest {
     pub schema_version: u8,
-    pub media_type: &'static str,
+    #[serde(default)]
+    pub media_type: String,
     pub config: ManifestDescriptor,
     pub layers: Vec<ManifestDescriptor>,
+
+    /// Anything else (e.g., `annotations`), so that manifests of images
+    /// derived from others keep it.
+    ///
+    #[serde(flatten)]
+    pub unknown: UnknownFields,
+}
+
+
+impl Manifest {
+
+    /// Turns a Docker manifest into the equivalent OCI manifest, with the
+    /// media types of the config and layers changed to match.
+    ///
+    pub fn into_oci(mut self) -> Manifest {
+        self.media_type = OCI_MANIFEST_MEDIA_TYPE.to_owned();
+
+        if self.config.media_type == "application/vnd.docker.container.image.v1+json" {
+            self.config.media_type = "application/vnd.oci.image.config.v1+json".to_owned();
+        }
+
+        for layer in &mut self.layers {
+            layer.media_type = oci_layer_media_type(&layer.media_type).to_owned();
+        }
+
+        self
+    }
+
+}
+
+
+/// A list of manifests, each for a different platform: either a Docker
+/// manifest list or an OCI image index.
+///
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u8,
+    #[serde(default)]
+    pub media_type: String,
+    pub manifests: Vec<ManifestDescriptor>,
+}
+
+
+/// The media type of a manifest (or list of manifests), as stated in its
+/// `mediaType` field.
+///
+/// OCI manifests are allowed to leave it out, in which case it's told from
+/// the presence of the list of manifests.
+///
+pub fn manifest_media_type(content: &[u8]) -> String {
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MediaType {
+        media_type: Option<String>,
+        manifests: Option<serde::de::IgnoredAny>,
+        layers: Option<serde::de::IgnoredAny>,
+    }
+
+    match serde_json::from_slice::<MediaType>(content) {
+        Ok(MediaType { media_type: Some(media_type), .. }) => media_type,
+        Ok(MediaType { manifests: Some(_), .. }) => OCI_INDEX_MEDIA_TYPE.to_owned(),
+        Ok(MediaType { layers: Some(_), .. }) => OCI_MANIFEST_MEDIA_TYPE.to_owned(),
+        _ => DOCKER_MANIFEST_MEDIA_TYPE.to_owned(),
+    }
+}
+
+
+/// The media type that a layer described in a Docker manifest as
+/// `docker_media_type` has in an OCI manifest (e.g.,
+/// `application/vnd.docker.image.rootfs.diff.tar.gzip` =>
+/// `application/vnd.oci.image.layer.v1.tar+gzip`).
+///
+pub fn oci_layer_media_type(docker_media_type: &str) -> &str {
+    match docker_media_type {
+        "application/vnd.docker.image.rootfs.diff.tar" => "application/vnd.oci.image.layer.v1.tar",
+        "application/vnd.docker.image.rootfs.diff.tar.gzip" => "application/vnd.oci.image.layer.v1.tar+gzip",
+        "application/vnd.docker.image.rootfs.diff.tar.zstd" => "application/vnd.oci.image.layer.v1.tar+zstd",
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip" => {
+            "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+        },
+        media_type => media_type,
+    }
+}
+
+
+/// Error codes defined by the distribution spec for signaling failures
+/// to clients.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    BlobUnknown,
+    ManifestUnknown,
+    Unauthorized,
+    Denied,
+}
+
+
+impl ErrorCode {
+
+    /// The identifier of the code as sent over the wire.
+    ///
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BlobUnknown => "BLOB_UNKNOWN",
+            ErrorCode::ManifestUnknown => "MANIFEST_UNKNOWN",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::Denied => "DENIED",
+        }
+    }
+
+}
+
+
+/// A single error as sent in the body of a failed response.
+///
+#[derive(Serialize, Deserialize)]
+pub struct ErrorDescriptor {
+    pub code: String,
+    pub message: String,
+}
+
+
+/// The body of a failed response.
+///
+/// ```json
+/// {
+///   "errors": [
+///     { "code": "BLOB_UNKNOWN", "message": "blob unknown to registry" }
+///   ]
+/// }
+/// ```
+///
+#[derive(Serialize, Deserialize)]
+pub struct Errors {
+    pub errors: Vec<ErrorDescriptor>,
+}
+
+
+impl Errors {
+
+    /// Creates the body for a failure signaled through a single error.
+    ///
+    pub fn new(code: ErrorCode, message: &str) -> Errors {
+        Errors {
+            errors: vec![ErrorDescriptor {
+                code: code.as_str().to_owned(),
+                message: message.to_owned(),
+            }],
+        }
+    }
+
+}
+
+
+/// The body of the response to a catalog request.
+///
+/// ```json
+/// { "repositories": [ "library/nginx", "team-a/app" ] }
+/// ```
+///
+#[derive(Serialize, Deserialize)]
+pub struct Catalog {
+    pub repositories: Vec<String>,
+}
+
+
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_media_type() {
+        assert_eq!(
+            manifest_media_type(br#"{"mediaType": "application/vnd.oci.image.manifest.v1+json", "layers": []}"#),
+            OCI_MANIFEST_MEDIA_TYPE,
+        );
+
+        assert_eq!(manifest_media_type(br#"{"schemaVersion": 2, "manifests": []}"#), OCI_INDEX_MEDIA_TYPE);
+        assert_eq!(manifest_media_type(br#"{"schemaVersion": 2, "layers": []}"#), OCI_MANIFEST_MEDIA_TYPE);
+        assert_eq!(manifest_media_type(b"not json"), DOCKER_MANIFEST_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_manifest_into_oci() {
+        let descriptor = |media_type: &str| ManifestDescriptor {
+            media_type: media_type.to_owned(),
+            size: 1,
+            digest: "sha256:abc".to_owned(),
+            unknown: UnknownFields::new(),
+        };
+
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: DOCKER_MANIFEST_MEDIA_TYPE.to_owned(),
+            config: descriptor("application/vnd.docker.container.image.v1+json"),
+            layers: vec![
+                descriptor("application/vnd.docker.image.rootfs.diff.tar.gzip"),
+                descriptor("application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"),
+                descriptor("application/vnd.oci.image.layer.v1.tar+zstd"),
+            ],
+            unknown: UnknownFields::new(),
+        }
+        .into_oci();
+
+        assert_eq!(manifest.media_type, OCI_MANIFEST_MEDIA_TYPE);
+        assert_eq!(manifest.config.media_type, "application/vnd.oci.image.config.v1+json");
+        assert_eq!(
+            manifest.layers.iter().map(|layer| layer.media_type.as_str()).collect::<Vec<_>>(),
+            vec![
+                "application/vnd.oci.image.layer.v1.tar+gzip",
+                "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+                "application/vnd.oci.image.layer.v1.tar+zstd",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_oci_layer_media_type() {
+        assert_eq!(
+            oci_layer_media_type("application/vnd.docker.image.rootfs.diff.tar.gzip"),
+            "application/vnd.oci.image.layer.v1.tar+gzip",
+        );
+        assert_eq!(
+            oci_layer_media_type("application/vnd.oci.image.layer.v1.tar"),
+            "application/vnd.oci.image.layer.v1.tar",
+        );
+    }
 }
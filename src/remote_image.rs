@@ -0,0 +1,689 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::blobstore::BlobStore;
+use crate::digest;
+use crate::error::Result;
+use crate::image_reference::{ImageReference, RegistryHost, DEFAULT_REGISTRY, DEFAULT_TAG};
+use crate::registry::{
+    self, Errors, Manifest, ManifestDescriptor, DOCKER_MANIFEST_LIST_MEDIA_TYPE, DOCKER_MANIFEST_MEDIA_TYPE,
+    OCI_INDEX_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE,
+};
+
+
+/// Host that the API of the default registry (`docker.io`) is served from.
+///
+const DEFAULT_REGISTRY_API_HOST: &str = "registry-1.docker.io";
+
+
+/// Media types accepted when fetching manifests, lists of manifests
+/// included.
+///
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    DOCKER_MANIFEST_MEDIA_TYPE,
+    DOCKER_MANIFEST_LIST_MEDIA_TYPE,
+    OCI_MANIFEST_MEDIA_TYPE,
+    OCI_INDEX_MEDIA_TYPE,
+];
+
+
+/// The platform (os, architecture and, optionally, variant) that an image
+/// is built for, as in `linux/arm64/v8`.
+///
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Platform {
+    pub os: String,
+
+    pub architecture: String,
+
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+
+impl Platform {
+
+    /// The platform that cartorio runs on (e.g., `linux/amd64`).
+    ///
+    pub fn host() -> Platform {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "powerpc64" => "ppc64",
+            architecture => architecture,
+        };
+
+        Platform {
+            os: std::env::consts::OS.to_owned(),
+            architecture: architecture.to_owned(),
+            variant: None,
+        }
+    }
+
+
+    /// Whether an image for `other` can run on this platform, with any
+    /// variant matching when this one has none.
+    ///
+    fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+
+}
+
+
+impl FromStr for Platform {
+
+    type Err = failure::Error;
+
+    fn from_str(platform: &str) -> Result<Self> {
+        let parts: Vec<&str> = platform.split('/').collect();
+
+        match parts[..] {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => Ok(Platform {
+                os: os.to_owned(),
+                architecture: architecture.to_owned(),
+                variant: None,
+            }),
+            [os, architecture, variant] if !os.is_empty() && !architecture.is_empty() && !variant.is_empty() => {
+                Ok(Platform {
+                    os: os.to_owned(),
+                    architecture: architecture.to_owned(),
+                    variant: Some(variant.to_owned()),
+                })
+            },
+            _ => Err(failure::format_err!("invalid platform `{}` (must be os/architecture[/variant])", platform)),
+        }
+    }
+
+}
+
+
+impl fmt::Display for Platform {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+
+/// A manifest listed in a Docker manifest list or an OCI image index,
+/// along with the platform it's for.
+///
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatformManifest {
+    media_type: String,
+    digest: String,
+    platform: Option<Platform>,
+}
+
+
+#[derive(Deserialize)]
+struct PlatformManifests {
+    manifests: Vec<PlatformManifest>,
+}
+
+
+/// Pulls an image from a registry, as in `docker pull`, storing it in the
+/// blobstore.
+///
+/// ```txt
+///
+///   ghcr.io/team/app:1.0
+///
+///   GET /v2/team/app/manifests/1.0          (list of manifests?
+///   GET /v2/team/app/manifests/sha256:m1     pick the one for the platform)
+///   GET /v2/team/app/blobs/sha256:c1        (config)
+///   GET /v2/team/app/blobs/sha256:l1        (layers)
+///
+/// ==>
+///
+///   .
+///   ├── bucket
+///   │   ├── sha256:m1
+///   │   ├── sha256:c1
+///   │   └── sha256:l1
+///   └── manifests
+///       └── ghcr.io/team/app      (see `RegistryHost`)
+///           ├── 1.0 -> ../../bucket/sha256:m1
+///           └── sha256:m1 -> ../../bucket/sha256:m1
+///
+/// ```
+///
+/// Everything downloaded gets its digest verified before making it into the
+/// blobstore, with blobs already there not being downloaded again. Lists of
+/// manifests aren't stored - only the manifest for the platform picked out
+/// of them.
+///
+pub struct RemoteImage {
+
+    /// The image to pull (e.g., `ghcr.io/team/app:1.0`).
+    ///
+    reference: ImageReference,
+
+    /// What to do with the registry host when tagging the image.
+    ///
+    registry_host: RegistryHost,
+
+    /// The platform to pick out of lists of manifests.
+    ///
+    platform: Platform,
+
+    /// Username and password to authenticate against the registry (or its
+    /// token issuer) with, if any.
+    ///
+    credentials: Option<(String, String)>,
+
+    /// Whether to talk to the registry over plain HTTP rather than HTTPS.
+    ///
+    plain_http: bool,
+
+    /// The final owner of the blobs and manifests pulled.
+    ///
+    blobstore: BlobStore,
+}
+
+
+impl RemoteImage {
+
+    /// Instantiates a new RemoteImage.
+    ///
+    /// # Arguments
+    ///
+    /// * `reference` - the image to pull (e.g., `alpine:3`,
+    ///   `ghcr.io/team/app:1.0` or `localhost:5000/app@sha256:abc`).
+    /// * `blobstore` - where the image gets stored.
+    ///
+    pub fn new(reference: &str, blobstore: BlobStore) -> Result<RemoteImage> {
+        Ok(RemoteImage {
+            reference: reference.parse()?,
+            registry_host: RegistryHost::Keep,
+            platform: Platform::host(),
+            credentials: None,
+            plain_http: false,
+            blobstore,
+        })
+    }
+
+
+    /// Sets what to do with the registry host when tagging the image.
+    ///
+    pub fn registry_host(mut self, registry_host: RegistryHost) -> RemoteImage {
+        self.registry_host = registry_host;
+        self
+    }
+
+
+    /// Sets the platform to pick out of lists of manifests, instead of the
+    /// one that cartorio runs on.
+    ///
+    pub fn platform(mut self, platform: Platform) -> RemoteImage {
+        self.platform = platform;
+        self
+    }
+
+
+    /// Sets the credentials to authenticate with, be it through basic
+    /// authentication or to get bearer tokens.
+    ///
+    pub fn credentials(mut self, username: &str, password: &str) -> RemoteImage {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+
+    /// Makes the registry be talked to over plain HTTP.
+    ///
+    pub fn plain_http(mut self) -> RemoteImage {
+        self.plain_http = true;
+        self
+    }
+
+
+    /// Pulls the image into the blobstore, tagging it.
+    ///
+    /// Returns the name of the manifest in the bucket (e.g., `sha256:abc`).
+    ///
+    pub fn load(&self) -> Result<String> {
+        let name = self.reference.repository_in(&self.registry_host)?;
+
+        let registry = match self.reference.registry() {
+            DEFAULT_REGISTRY => DEFAULT_REGISTRY_API_HOST,
+            registry => registry,
+        };
+
+        let scheme = if self.plain_http { "http" } else { "https" };
+
+        let client = RegistryClient::new(
+            Url::parse(&format!("{}://{}/", scheme, registry))?,
+            &self.reference.normalized_repository(),
+            self.credentials.clone(),
+        )?;
+
+        let reference = self
+            .reference
+            .digest
+            .clone()
+            .or_else(|| self.reference.tag.clone())
+            .unwrap_or_else(|| DEFAULT_TAG.to_owned());
+
+        let mut content = client.manifest(&reference, self.reference.digest.as_deref())?;
+        let mut media_type = registry::manifest_media_type(&content);
+
+        if media_type == DOCKER_MANIFEST_LIST_MEDIA_TYPE || media_type == OCI_INDEX_MEDIA_TYPE {
+            let manifests: PlatformManifests = serde_json::from_slice(&content)?;
+
+            let picked = manifests
+                .manifests
+                .into_iter()
+                .find(|manifest| manifest.platform.as_ref().is_some_and(|platform| self.platform.matches(platform)))
+                .ok_or_else(|| failure::format_err!("{} has no manifest for {}", self.reference, self.platform))?;
+
+            content = client.manifest(&picked.digest, Some(&picked.digest))?;
+            media_type = picked.media_type;
+        }
+
+        if media_type != DOCKER_MANIFEST_MEDIA_TYPE && media_type != OCI_MANIFEST_MEDIA_TYPE {
+            return Err(failure::format_err!("{} has a manifest of unsupported type {}", self.reference, media_type));
+        }
+
+        let manifest: Manifest = serde_json::from_slice(&content)?;
+
+        for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            self.pull_blob(&client, descriptor)?;
+        }
+
+        let manifest_filename = self.blobstore.add_raw_manifest(&content)?;
+
+        self.blobstore.tag_manifest(&manifest_filename, &name, &manifest_filename)?;
+
+        if let Some(tag) = &self.reference.tag {
+            self.blobstore.tag_manifest(&manifest_filename, &name, tag)?;
+        } else if self.reference.digest.is_none() {
+            self.blobstore.tag_manifest(&manifest_filename, &name, DEFAULT_TAG)?;
+        }
+
+        info!(
+            reference = %self.reference,
+            name = %name,
+            manifest = %manifest_filename,
+            "pulled image",
+        );
+
+        Ok(manifest_filename)
+    }
+
+
+    /// Downloads the blob that `descriptor` points at into the blobstore,
+    /// unless it's already there.
+    ///
+    fn pull_blob(&self, client: &RegistryClient, descriptor: &ManifestDescriptor) -> Result<()> {
+        if self.blobstore.get_blob(&descriptor.digest).exists() {
+            return Ok(());
+        }
+
+        let expected_digest = descriptor
+            .digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| failure::format_err!("unsupported digest {}", descriptor.digest))?;
+
+        let staging_dir = self.blobstore.staging_dir()?;
+        let blob_path = staging_dir.path().join("blob");
+
+        let response = client.get(&format!("blobs/{}", descriptor.digest), &[])?;
+        let blob_digest = digest::compute_and_copy(response, fs::File::create(&blob_path)?)?;
+        let blob_size = fs::metadata(&blob_path)?.len();
+
+        if blob_digest != expected_digest || blob_size != descriptor.size {
+            return Err(failure::format_err!(
+                "blob {} ({} bytes) came in as sha256:{} ({} bytes)",
+                descriptor.digest, descriptor.size, blob_digest, blob_size,
+            ));
+        }
+
+        digest::store(&blob_path, &blob_digest)?;
+        self.blobstore.add_blob_with_digest(&blob_path, &blob_digest)?;
+
+        info!(
+            digest = %descriptor.digest,
+            size = descriptor.size,
+            media_type = %descriptor.media_type,
+            "pulled blob",
+        );
+
+        Ok(())
+    }
+
+}
+
+
+/// A client of the distribution API of a registry, scoped to a single
+/// repository.
+///
+/// Requests are first tried with whatever authorization was last obtained
+/// (none at first), answering to challenges (`WWW-Authenticate`) with basic
+/// authentication or by getting a bearer token from the issuer named in
+/// them.
+///
+struct RegistryClient {
+
+    http: Client,
+
+    /// Base of the URLs of the registry (e.g., `https://ghcr.io/`).
+    ///
+    base_url: Url,
+
+    /// Path of the repository within the registry (e.g., `team/app`).
+    ///
+    repository: String,
+
+    credentials: Option<(String, String)>,
+
+    /// Value of the `Authorization` header to send, once challenged.
+    ///
+    authorization: RefCell<Option<String>>,
+}
+
+
+impl RegistryClient {
+
+    fn new(base_url: Url, repository: &str, credentials: Option<(String, String)>) -> Result<RegistryClient> {
+        let http = Client::builder()
+            .user_agent(concat!("cartorio/", env!("CARGO_PKG_VERSION")))
+            .connect_timeout(Duration::from_secs(30))
+            .timeout(None)
+            .build()?;
+
+        Ok(RegistryClient {
+            http,
+            base_url,
+            repository: repository.to_owned(),
+            credentials,
+            authorization: RefCell::new(None),
+        })
+    }
+
+
+    /// Fetches the manifest (or list of manifests) that `reference` refers
+    /// to, verifying that its digest is `expected_digest` when given, or the
+    /// one that the registry states otherwise.
+    ///
+    fn manifest(&self, reference: &str, expected_digest: Option<&str>) -> Result<Vec<u8>> {
+        let response = self.get(&format!("manifests/{}", reference), MANIFEST_MEDIA_TYPES)?;
+
+        let stated_digest = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|digest| digest.to_str().ok())
+            .map(|digest| digest.to_owned());
+
+        let content = response.bytes()?.to_vec();
+        let content_digest = digest::prepend_sha_scheme(&digest::compute(&content[..])?);
+
+        for expected in expected_digest.into_iter().chain(stated_digest.as_deref()) {
+            if expected.starts_with("sha256:") && expected != content_digest {
+                return Err(failure::format_err!(
+                    "manifest {} came in as {} instead of {}",
+                    reference, content_digest, expected,
+                ));
+            }
+        }
+
+        Ok(content)
+    }
+
+
+    /// Sends a `GET` for `path` under the repository (e.g.,
+    /// `manifests/latest`), authorizing it if challenged to.
+    ///
+    fn get(&self, path: &str, accept: &[&str]) -> Result<Response> {
+        let url = self.base_url.join(&format!("v2/{}/{}", self.repository, path))?;
+
+        let send = || {
+            let mut request = self.http.get(url.clone());
+
+            if !accept.is_empty() {
+                request = request.header(ACCEPT, accept.join(", "));
+            }
+
+            if let Some(authorization) = self.authorization.borrow().as_ref() {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+
+            request.send()
+        };
+
+        let mut response = send()?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|challenge| challenge.to_str().ok())
+                .ok_or_else(|| failure::format_err!("{} requires authentication but sent no challenge", url))?
+                .to_owned();
+
+            let authorization = self.authorize(&challenge)?;
+            *self.authorization.borrow_mut() = Some(authorization);
+
+            response = send()?;
+        }
+
+        if !response.status().is_success() {
+            let status = response.status();
+
+            let message = match serde_json::from_slice::<Errors>(&response.bytes()?) {
+                Ok(errors) => errors
+                    .errors
+                    .iter()
+                    .map(|error| format!("{}: {}", error.code, error.message))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Err(_) => status.canonical_reason().unwrap_or_default().to_owned(),
+            };
+
+            return Err(failure::format_err!("GET {} failed with {} ({})", url, status.as_u16(), message));
+        }
+
+        Ok(response)
+    }
+
+
+    /// The `Authorization` header that answers to `challenge`.
+    ///
+    fn authorize(&self, challenge: &str) -> Result<String> {
+        let (scheme, params) = parse_challenge(challenge)
+            .ok_or_else(|| failure::format_err!("unsupported authentication challenge `{}`", challenge))?;
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let (username, password) = self.credentials.as_ref().ok_or_else(|| {
+                failure::format_err!("{} requires credentials", self.base_url)
+            })?;
+
+            return Ok(basic_authorization(username, password));
+        }
+
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(failure::format_err!("unsupported authentication scheme `{}`", scheme));
+        }
+
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| failure::format_err!("bearer challenge `{}` has no realm", challenge))?;
+
+        let mut token_url = Url::parse(realm)?;
+
+        {
+            let mut query = token_url.query_pairs_mut();
+
+            if let Some(service) = params.get("service") {
+                query.append_pair("service", service);
+            }
+
+            query.append_pair("scope", &format!("repository:{}:pull", self.repository));
+        }
+
+        let mut request = self.http.get(token_url.clone());
+
+        if let Some((username, password)) = &self.credentials {
+            request = request.header(AUTHORIZATION, basic_authorization(username, password));
+        }
+
+        let response = request.send()?;
+
+        if !response.status().is_success() {
+            return Err(failure::format_err!(
+                "GET {} (token) failed with {}",
+                token_url, response.status().as_u16(),
+            ));
+        }
+
+        #[derive(Deserialize)]
+        struct Token {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+
+        let token: Token = serde_json::from_slice(&response.bytes()?)?;
+
+        token
+            .token
+            .or(token.access_token)
+            .map(|token| format!("Bearer {}", token))
+            .ok_or_else(|| failure::format_err!("GET {} (token) sent no token", token_url))
+    }
+
+}
+
+
+fn basic_authorization(username: &str, password: &str) -> String {
+    use base64::Engine;
+
+    format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password)),
+    )
+}
+
+
+/// Splits the value of a `WWW-Authenticate` header into its scheme and
+/// parameters.
+///
+/// ```txt
+/// Bearer realm="https://auth.io/token",service="registry.io"
+///
+/// ==> ("Bearer", { realm: "https://auth.io/token", service: "registry.io" })
+/// ```
+///
+fn parse_challenge(challenge: &str) -> Option<(String, HashMap<String, String>)> {
+    let challenge = challenge.trim();
+    let (scheme, mut rest) = match challenge.split_once(' ') {
+        Some((scheme, rest)) => (scheme, rest.trim_start()),
+        None => (challenge, ""),
+    };
+
+    if scheme.is_empty() {
+        return None;
+    }
+
+    let mut params = HashMap::new();
+
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let key = key.trim().to_ascii_lowercase();
+
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            },
+            None => match after_key.find(',') {
+                Some(end) => (&after_key[..end], &after_key[end..]),
+                None => (after_key, ""),
+            },
+        };
+
+        params.insert(key, value.to_owned());
+        rest = after_value.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    Some((scheme.to_owned(), params))
+}
+
+
+
+#[cfg(test)]
+mod remote_image_tests {
+    use super::*;
+
+    #[test]
+    fn parses_platforms() {
+        assert_eq!(
+            "linux/arm64/v8".parse::<Platform>().unwrap(),
+            Platform { os: "linux".to_owned(), architecture: "arm64".to_owned(), variant: Some("v8".to_owned()) },
+        );
+        assert_eq!("linux/amd64".parse::<Platform>().unwrap().to_string(), "linux/amd64");
+
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v8".parse::<Platform>().is_err());
+        assert!("a/b/c/d".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn matches_platforms() {
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        let arm64_v8: Platform = "linux/arm64/v8".parse().unwrap();
+        let arm_v7: Platform = "linux/arm/v7".parse().unwrap();
+
+        assert!(arm64.matches(&arm64_v8));
+        assert!(arm64_v8.matches(&arm64_v8));
+        assert!(!arm64_v8.matches(&arm64));
+        assert!(!arm64.matches(&arm_v7));
+    }
+
+    #[test]
+    fn parses_challenges() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        )
+        .unwrap();
+
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+
+        let (scheme, params) = parse_challenge(r#"Basic realm="cartorio""#).unwrap();
+
+        assert_eq!(scheme, "Basic");
+        assert_eq!(params["realm"], "cartorio");
+
+        let (_, params) = parse_challenge("Bearer realm=https://auth.io/token, service=auth.io").unwrap();
+
+        assert_eq!(params["realm"], "https://auth.io/token");
+        assert_eq!(params["service"], "auth.io");
+
+        assert!(parse_challenge(r#"Bearer realm="unterminated"#).is_none());
+    }
+}
@@ -0,0 +1,636 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use tar::{EntryType, Header};
+use tracing::{info, warn};
+
+use crate::blobstore::BlobStore;
+use crate::compression::{self, Compression, LayerCompression};
+use crate::digest;
+use crate::error::Result;
+use crate::image_config::{ImageConfig, ImageConfigContainer, UnknownFields};
+use crate::registry::{Manifest, ManifestDescriptor, DOCKER_MANIFEST_MEDIA_TYPE};
+use crate::router::{is_valid_name_component, is_valid_reference};
+use crate::tarball_index::normalize;
+
+
+/// An image made out of a plain root filesystem - a directory (e.g., the
+/// result of `debootstrap`) or a tarball of one - turned into a single
+/// layer.
+///
+/// ```txt
+///
+///    rootfs/                            bucket
+///    ├── bin/sh             ==>         ├── sha256:l1  (layer, entries sorted,
+///    ├── etc/os-release                 │               mtimes and owners zeroed)
+///    └── usr/...                        ├── sha256:c1  (config: entrypoint, env, ..)
+///                                       └── sha256:m1  (manifest)
+///
+///                                       manifests/$name
+///                                       ├── $tag -> ../../bucket/sha256:m1
+///                                       └── sha256:m1 -> ../../bucket/sha256:m1
+///
+/// ```
+///
+/// The layer is deterministic: loading the same files (with the same
+/// modes) always results in the same digests, whenever and by whoever the
+/// files were created.
+///
+pub struct RootfsImage {
+
+    /// Location of the directory or tarball with the root filesystem.
+    ///
+    source: PathBuf,
+
+    /// Name of the repository to tag the image under.
+    ///
+    name: String,
+
+    /// Tag to give to the image.
+    ///
+    tag: String,
+
+    /// Execution parameters to put in the config of the image.
+    ///
+    container: ImageConfigContainer,
+
+    /// How the layer gets compressed into the blobstore, if at all.
+    ///
+    layer_compression: Option<LayerCompression>,
+
+    /// The final owner of the blobs and manifests for the
+    /// registry to serve.
+    ///
+    blobstore: BlobStore,
+}
+
+
+impl RootfsImage {
+
+    /// Instantiates a new RootfsImage after validating the name and tag that
+    /// the image is meant to be served as.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - directory or tarball (possibly compressed) with the
+    ///   root filesystem.
+    /// * `name` - repository name (e.g., `team/app`).
+    /// * `tag` - tag of the image (e.g., `1.0`).
+    /// * `blobstore` - a BlobStore to own the resulting image.
+    ///
+    pub fn new(source: &Path, name: &str, tag: &str, blobstore: BlobStore) -> Result<RootfsImage> {
+        if !source.exists() {
+            return Err(failure::format_err!("no rootfs at {}", source.display()));
+        }
+
+        validate_name(name)?;
+        validate_tag(tag)?;
+
+        Ok(RootfsImage {
+            source: source.to_owned(),
+            name: name.to_owned(),
+            tag: tag.to_owned(),
+            container: ImageConfigContainer::default(),
+            layer_compression: None,
+            blobstore,
+        })
+    }
+
+
+    /// Sets the command that containers of the image run.
+    ///
+    pub fn entrypoint(mut self, entrypoint: Vec<String>) -> RootfsImage {
+        self.container.entrypoint = Some(entrypoint);
+        self
+    }
+
+
+    /// Sets the environment variables (`VARNAME=VARVALUE`) of the
+    /// containers of the image.
+    ///
+    pub fn env(mut self, env: Vec<String>) -> RootfsImage {
+        self.container.env = Some(env);
+        self
+    }
+
+
+    /// Sets the working directory of the containers of the image.
+    ///
+    pub fn workdir(mut self, workdir: &str) -> RootfsImage {
+        self.container.working_dir = Some(workdir.to_owned());
+        self
+    }
+
+
+    /// Makes the layer get compressed into the blobstore, instead of
+    /// being stored uncompressed.
+    ///
+    pub fn compress_layers(mut self, layer_compression: LayerCompression) -> RootfsImage {
+        self.layer_compression = Some(layer_compression);
+        self
+    }
+
+
+    /// Builds the layer out of the root filesystem, along with a config and
+    /// a manifest for it, tagging the image into the blobstore.
+    ///
+    pub fn load(self) -> Result<()> {
+        let staging_dir = self.blobstore.staging_dir()?;
+
+        let (layer_descriptor, diff_id) = ingest_layer(
+            &self.source,
+            staging_dir.path(),
+            &self.blobstore,
+            self.layer_compression.as_ref(),
+        )?;
+
+        let mut config = ImageConfig::new(vec![diff_id]);
+
+        if self.container != ImageConfigContainer::default() {
+            config.config = Some(self.container);
+        }
+
+        let config_path = staging_dir.path().join("config.json");
+        fs::write(&config_path, config.to_string())?;
+
+        let config_digest = digest::compute_for_file_and_store(&config_path)?;
+        let config_size = fs::metadata(&config_path)?.len();
+
+        self.blobstore.add_blob_with_digest(&config_path, &config_digest)?;
+
+        let mut manifest = Manifest {
+            schema_version: 2,
+            media_type: DOCKER_MANIFEST_MEDIA_TYPE.to_owned(),
+            config: ManifestDescriptor {
+                media_type: "application/vnd.docker.container.image.v1+json".to_owned(),
+                size: config_size,
+                digest: digest::prepend_sha_scheme(&config_digest),
+                unknown: UnknownFields::new(),
+            },
+            layers: vec![layer_descriptor],
+            unknown: UnknownFields::new(),
+        };
+
+        if self.layer_compression.as_ref().is_some_and(LayerCompression::needs_oci_manifest) {
+            manifest = manifest.into_oci();
+        }
+
+        let manifest_filename = self.blobstore.add_manifest(&manifest)?;
+
+        self.blobstore.tag_manifest(&manifest_filename, &self.name, &self.tag)?;
+        self.blobstore.tag_manifest(&manifest_filename, &self.name, &manifest_filename)?;
+
+        info!(
+            name = %self.name,
+            tag = %self.tag,
+            manifest = %manifest_filename,
+            "tagged image",
+        );
+
+        Ok(())
+    }
+
+}
+
+
+/// Makes sure that `name` can be used as the name of a repository (e.g.,
+/// `team/app`).
+///
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    if !name.split('/').all(is_valid_name_component) {
+        return Err(failure::format_err!("invalid repository name `{}`", name));
+    }
+
+    Ok(())
+}
+
+
+/// Makes sure that `tag` can be used as a tag (and not as a digest).
+///
+pub(crate) fn validate_tag(tag: &str) -> Result<()> {
+    if tag.contains(':') || !is_valid_reference(tag) {
+        return Err(failure::format_err!("invalid tag `{}`", tag));
+    }
+
+    Ok(())
+}
+
+
+/// Builds a deterministic layer out of the directory or tarball at `source`
+/// in `staging_dir`, moving it (possibly compressed) to the blobstore.
+///
+/// Returns the descriptor of the layer along with the digest of the
+/// uncompressed layer (its `diff_id`).
+///
+pub(crate) fn ingest_layer(
+    source: &Path,
+    staging_dir: &Path,
+    blobstore: &BlobStore,
+    layer_compression: Option<&LayerCompression>,
+) -> Result<(ManifestDescriptor, String)> {
+    let layer_path = staging_dir.join("layer.tar");
+    let layer = BufWriter::new(File::create(&layer_path)?);
+
+    if source.is_dir() {
+        write_layer(&scan_dir(source)?, None, layer)?;
+    } else {
+        let tarball = uncompressed_tarball(source, staging_dir)?;
+
+        write_layer(&scan_tarball(&tarball)?, Some(&mut File::open(&tarball)?), layer)?;
+    }
+
+    let layer_digest = digest::compute_for_file(&layer_path)?;
+    let diff_id = digest::prepend_sha_scheme(&layer_digest);
+
+    let (blob_digest, blob_size, media_type) = match layer_compression {
+        Some(layer_compression) => {
+            let (blob_digest, blob_size) = blobstore.add_compressed_blob(File::open(&layer_path)?, layer_compression)?;
+
+            (blob_digest, blob_size, layer_compression.media_type())
+        },
+        None => {
+            let blob_size = fs::metadata(&layer_path)?.len();
+
+            digest::store(&layer_path, &layer_digest)?;
+            blobstore.add_blob_with_digest(&layer_path, &layer_digest)?;
+
+            (layer_digest, blob_size, "application/vnd.docker.image.rootfs.diff.tar")
+        },
+    };
+
+    let descriptor = ManifestDescriptor {
+        media_type: media_type.to_owned(),
+        size: blob_size,
+        digest: digest::prepend_sha_scheme(&blob_digest),
+        unknown: UnknownFields::new(),
+    };
+
+    info!(
+        source = %source.display(),
+        digest = %descriptor.digest,
+        diff_id = %diff_id,
+        size = descriptor.size,
+        media_type,
+        "ingested layer",
+    );
+
+    Ok((descriptor, diff_id))
+}
+
+
+/// A file (or directory, link, ...) to be put in the layer.
+///
+struct Node {
+
+    /// Normalized path within the root filesystem (e.g., `usr/bin/env`).
+    ///
+    path: String,
+
+    /// Permission bits (including setuid, setgid and sticky).
+    ///
+    mode: u32,
+
+    kind: NodeKind,
+}
+
+
+enum NodeKind {
+    Directory,
+    File {
+        size: u64,
+        data: NodeData,
+    },
+    Symlink(PathBuf),
+    HardLink(String),
+    CharDevice(u32, u32),
+    BlockDevice(u32, u32),
+    Fifo,
+}
+
+
+/// Where the contents of a file come from.
+///
+enum NodeData {
+
+    /// A file in the filesystem, along with its device and inode if it has
+    /// more than one link, so that hard links can be kept as such.
+    ///
+    Path(PathBuf, Option<(u64, u64)>),
+
+    /// Offset of the contents of the file within the source tarball.
+    ///
+    Offset(u64),
+}
+
+
+/// Gathers every file under `root`, recursively.
+///
+fn scan_dir(root: &Path) -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut dirs = vec![root.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry_path = entry?.path();
+            let metadata = fs::symlink_metadata(&entry_path)?;
+            let file_type = metadata.file_type();
+
+            let path = normalize(entry_path.strip_prefix(root)?)
+                .ok_or_else(|| failure::format_err!("unsupported file name {}", entry_path.display()))?;
+
+            let kind = if file_type.is_dir() {
+                dirs.push(entry_path.clone());
+                NodeKind::Directory
+            } else if file_type.is_file() {
+                let inode = if metadata.nlink() > 1 {
+                    Some((metadata.dev(), metadata.ino()))
+                } else {
+                    None
+                };
+
+                NodeKind::File {
+                    size: metadata.len(),
+                    data: NodeData::Path(entry_path.clone(), inode),
+                }
+            } else if file_type.is_symlink() {
+                NodeKind::Symlink(fs::read_link(&entry_path)?)
+            } else if file_type.is_char_device() {
+                NodeKind::CharDevice(device_major(metadata.rdev()), device_minor(metadata.rdev()))
+            } else if file_type.is_block_device() {
+                NodeKind::BlockDevice(device_major(metadata.rdev()), device_minor(metadata.rdev()))
+            } else if file_type.is_fifo() {
+                NodeKind::Fifo
+            } else {
+                warn!(path = %entry_path.display(), "skipping socket");
+                continue;
+            };
+
+            nodes.push(Node {
+                path,
+                mode: metadata.mode() & 0o7777,
+                kind,
+            });
+        }
+    }
+
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // files with more than one link become a regular file the first
+    // time they're seen, and hard links to it from then on.
+    //
+    let mut linked: HashMap<(u64, u64), String> = HashMap::new();
+
+    for node in &mut nodes {
+        if let NodeKind::File { data: NodeData::Path(_, Some(inode)), .. } = &node.kind {
+            match linked.get(inode) {
+                Some(target) => node.kind = NodeKind::HardLink(target.clone()),
+                None => {
+                    linked.insert(*inode, node.path.clone());
+                },
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+
+/// Gathers every entry of the tarball at `tarball`, with later entries
+/// replacing earlier ones with the same path.
+///
+fn scan_tarball(tarball: &Path) -> Result<Vec<Node>> {
+    let mut archive = tar::Archive::new(File::open(tarball)?);
+    let mut nodes: HashMap<String, Node> = HashMap::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+
+        let path = match normalize(&entry.path()?) {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let kind = match header.entry_type() {
+            EntryType::Directory => NodeKind::Directory,
+            EntryType::Regular | EntryType::Continuous => NodeKind::File {
+                size: entry.size(),
+                data: NodeData::Offset(entry.raw_file_position()),
+            },
+            EntryType::Symlink => NodeKind::Symlink(
+                entry.link_name()?.ok_or_else(|| failure::format_err!("symlink {} has no target", path))?.into_owned(),
+            ),
+            EntryType::Link => NodeKind::HardLink(
+                entry
+                    .link_name()?
+                    .as_deref()
+                    .and_then(normalize)
+                    .ok_or_else(|| failure::format_err!("hard link {} has no valid target", path))?,
+            ),
+            EntryType::Char => NodeKind::CharDevice(
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            ),
+            EntryType::Block => NodeKind::BlockDevice(
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            ),
+            EntryType::Fifo => NodeKind::Fifo,
+            EntryType::GNUSparse => return Err(failure::format_err!(
+                "sparse file {} not supported", path,
+            )),
+            entry_type => {
+                warn!(path = %path, entry_type = ?entry_type, "skipping entry");
+                continue;
+            },
+        };
+
+        let mode = header.mode()? & 0o7777;
+
+        nodes.insert(path.clone(), Node { path, mode, kind });
+    }
+
+    let mut nodes: Vec<Node> = nodes.into_values().collect();
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    relink(&mut nodes)?;
+
+    Ok(nodes)
+}
+
+
+/// Makes the first path (in sorted order) of every group of hard links
+/// carry the contents of the file they all link to, with the others
+/// (the file itself included) becoming hard links to it - just like
+/// `scan_dir` does - so that no link comes before its target.
+///
+fn relink(nodes: &mut [Node]) -> Result<()> {
+    let indexes: HashMap<String, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (node.path.clone(), index))
+        .collect();
+
+    // indexes of the links to each file, by the index of the file.
+    //
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+
+    for (index, node) in nodes.iter().enumerate() {
+        let mut target = match &node.kind {
+            NodeKind::HardLink(target) => target,
+            _ => continue,
+        };
+
+        // links to links end up at the file, while links to anything
+        // else (e.g., symlinks) are left as they are.
+        //
+        for _ in 0..nodes.len() {
+            let target_index = *indexes.get(target).ok_or_else(|| {
+                failure::format_err!("hard link {} points to {}, which isn't in the tarball", node.path, target)
+            })?;
+
+            match &nodes[target_index].kind {
+                NodeKind::File { .. } => {
+                    groups.entry(target_index).or_default().push(index);
+                    break;
+                },
+                NodeKind::HardLink(next) => target = next,
+                _ => break,
+            }
+        }
+    }
+
+    for (file_index, links) in groups {
+        let first = links.iter().copied().fold(file_index, usize::min);
+
+        if first != file_index {
+            let kind = std::mem::replace(&mut nodes[file_index].kind, NodeKind::Fifo);
+            nodes[first].kind = kind;
+            nodes[first].mode = nodes[file_index].mode;
+        }
+
+        let first_path = nodes[first].path.clone();
+
+        for index in links.into_iter().chain(std::iter::once(file_index)) {
+            if index != first {
+                nodes[index].kind = NodeKind::HardLink(first_path.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Writes `nodes` out as a tarball, with everything but the paths, modes,
+/// link targets and contents zeroed.
+///
+/// # Arguments
+///
+/// * `nodes` - the files to write, already sorted.
+/// * `tarball` - the tarball that `NodeData::Offset`s point into.
+/// * `writer` - where to write the layer to.
+///
+fn write_layer(nodes: &[Node], mut tarball: Option<&mut File>, writer: impl Write) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+
+    for node in nodes {
+        let mut header = Header::new_gnu();
+
+        header.set_mode(node.mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(0);
+
+        match &node.kind {
+            NodeKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                builder.append_data(&mut header, format!("{}/", node.path), io::empty())?;
+            },
+
+            NodeKind::File { size, data } => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(*size);
+
+                match data {
+                    NodeData::Path(path, _) => {
+                        builder.append_data(&mut header, &node.path, File::open(path)?.take(*size))?;
+                    },
+                    NodeData::Offset(offset) => {
+                        let tarball = tarball
+                            .as_mut()
+                            .ok_or_else(|| failure::format_err!("no tarball to read {} from", node.path))?;
+
+                        tarball.seek(SeekFrom::Start(*offset))?;
+                        builder.append_data(&mut header, &node.path, (&mut **tarball).take(*size))?;
+                    },
+                }
+            },
+
+            NodeKind::Symlink(target) => {
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(&mut header, &node.path, target)?;
+            },
+
+            NodeKind::HardLink(target) => {
+                header.set_entry_type(EntryType::Link);
+                builder.append_link(&mut header, &node.path, target)?;
+            },
+
+            NodeKind::CharDevice(major, minor) | NodeKind::BlockDevice(major, minor) => {
+                header.set_entry_type(match node.kind {
+                    NodeKind::CharDevice(..) => EntryType::Char,
+                    _ => EntryType::Block,
+                });
+                header.set_device_major(*major)?;
+                header.set_device_minor(*minor)?;
+                builder.append_data(&mut header, &node.path, io::empty())?;
+            },
+
+            NodeKind::Fifo => {
+                header.set_entry_type(EntryType::Fifo);
+                builder.append_data(&mut header, &node.path, io::empty())?;
+            },
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+
+/// The tarball at `source`, decompressed into `staging_dir` first if it's
+/// compressed.
+///
+fn uncompressed_tarball(source: &Path, staging_dir: &Path) -> Result<PathBuf> {
+    let (compression, mut reader) = compression::decompress(File::open(source)?)?;
+
+    if compression == Compression::None {
+        return Ok(source.to_owned());
+    }
+
+    info!(compression = compression.name(), "decompressing rootfs");
+
+    let tarball = staging_dir.join("rootfs.tar");
+    let mut writer = BufWriter::new(File::create(&tarball)?);
+
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+
+    Ok(tarball)
+}
+
+
+fn device_major(rdev: u64) -> u32 {
+    libc::major(rdev as libc::dev_t) as u32
+}
+
+
+fn device_minor(rdev: u64) -> u32 {
+    libc::minor(rdev as libc::dev_t) as u32
+}
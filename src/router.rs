@@ -0,0 +1,315 @@
+use hyper::Method;
+
+
+/// Represents a manifest path.
+///
+pub struct BlobPath {
+    pub name: String,
+    pub reference: String,
+}
+
+
+/// The endpoints served by the registry.
+///
+pub enum Route {
+
+    /// `GET /_live`
+    ///
+    Liveness,
+
+    /// `GET /_ready`
+    ///
+    Readiness,
+
+    /// `GET /v2/`
+    ///
+    VersionCheck,
+
+    /// `GET /v2/<name>/manifests/<reference>`
+    ///
+    Manifest(BlobPath),
+
+    /// `GET /v2/<name>/blobs/<digest>`
+    ///
+    Blob(BlobPath),
+
+    /// `GET /v2/_catalog`
+    ///
+    Catalog,
+
+    /// `GET /token?service=<service>&scope=<scope>`
+    ///
+    Token,
+}
+
+
+impl Route {
+
+    /// Resolves the route that a request with `method` and `path` should
+    /// be handled by, if any.
+    ///
+    pub fn resolve(method: &Method, path: &str) -> Option<Route> {
+        if method != Method::GET {
+            return None;
+        }
+
+        match path {
+            "/_live" => return Some(Route::Liveness),
+            "/_ready" => return Some(Route::Readiness),
+            "/v2" | "/v2/" => return Some(Route::VersionCheck),
+            "/v2/_catalog" => return Some(Route::Catalog),
+            "/token" => return Some(Route::Token),
+            _ => (),
+        }
+
+        if let Some(manifest) = parse_manifests_path(path) {
+            return Some(Route::Manifest(manifest));
+        }
+
+        if let Some(blob) = parse_blobs_path(path) {
+            return Some(Route::Blob(blob));
+        }
+
+        None
+    }
+
+
+    /// Name of the endpoint, as used in metrics.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Route::Liveness => "liveness",
+            Route::Readiness => "readiness",
+            Route::VersionCheck => "version_check",
+            Route::Manifest(_) => "manifest",
+            Route::Blob(_) => "blob",
+            Route::Catalog => "catalog",
+            Route::Token => "token",
+        }
+    }
+
+
+    /// The name of the repository targeted by the route, if any.
+    ///
+    pub fn repository(&self) -> Option<&str> {
+        match self {
+            Route::Manifest(path) | Route::Blob(path) => Some(&path.name),
+            _ => None,
+        }
+    }
+
+}
+
+
+/// Parses a path into a BlobPath.
+///
+/// Names and references that do not conform to the grammar defined by the
+/// distribution spec are rejected, making sure that they can't be used to
+/// reach outside of the blobstore (e.g., through `..`).
+///
+fn parse_generic_blob_path(path_type: &'static str, path: &str) -> Option<BlobPath> {
+    let splitted: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    if splitted.len() < 4 {
+        return None;
+    }
+
+    if splitted[0] != "v2" {
+        return None;
+    }
+
+    if splitted[splitted.len() - 2] != path_type {
+        return None;
+    }
+
+    let reference = splitted[splitted.len() - 1];
+    let name = &splitted[1..splitted.len() - 2];
+
+    if !name.iter().all(|component| is_valid_name_component(component)) {
+        return None;
+    }
+
+    if !is_valid_reference(reference) {
+        return None;
+    }
+
+    Some(BlobPath {
+        name: name.join("/"),
+        reference: reference.to_string(),
+    })
+}
+
+
+/// Detects whether the provided `path` is a `BlobPath` and,
+/// if so, returns a `BlobPath`.
+///
+fn parse_manifests_path(path: &str) -> Option<BlobPath> {
+    parse_generic_blob_path("manifests", path)
+}
+
+
+fn parse_blobs_path(path: &str) -> Option<BlobPath> {
+    parse_generic_blob_path("blobs", path)
+}
+
+
+/// Whether `component` is a valid path component of a repository name
+/// (`[a-z0-9]+(?:(?:[._]|__|[-]*)[a-z0-9]+)*`).
+///
+pub(crate) fn is_valid_name_component(component: &str) -> bool {
+    let is_alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+    component.starts_with(is_alphanumeric)
+        && component.ends_with(is_alphanumeric)
+        && component
+            .chars()
+            .all(|c| is_alphanumeric(c) || c == '.' || c == '_' || c == '-')
+        && !component.contains("..")
+}
+
+
+/// Whether `reference` is either a valid tag (`[\w][\w.-]{0,127}`) or a
+/// valid digest (`algorithm:encoded`).
+///
+pub(crate) fn is_valid_reference(reference: &str) -> bool {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    if let Some(idx) = reference.find(':') {
+        let (algorithm, encoded) = (&reference[..idx], &reference[idx + 1..]);
+
+        return !algorithm.is_empty()
+            && !encoded.is_empty()
+            && algorithm
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+._-".contains(c))
+            && encoded
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "=_-".contains(c));
+    }
+
+    reference.len() <= 128
+        && reference.starts_with(is_word)
+        && reference.chars().all(|c| is_word(c) || c == '.' || c == '-')
+}
+
+
+
+#[cfg(test)]
+mod parsing_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifests_path() {
+        assert!(
+            parse_manifests_path("xxx").is_none(),
+            "must have a `/v2` in the prefix`"
+        );
+
+        assert!(
+            parse_manifests_path("/v2/library/manifests").is_none(),
+            "must have enough fields"
+        );
+
+        assert!(
+            parse_manifests_path("/v2/library/wrong/tag").is_none(),
+            "must have `manifests` after name and before reference"
+        );
+
+        assert_eq!(
+            parse_manifests_path("/v2/library/manifests/tag")
+                .unwrap()
+                .name,
+            "library",
+        );
+
+        assert_eq!(
+            parse_manifests_path("/v2/library/manifests/tag")
+                .unwrap()
+                .reference,
+            "tag",
+        );
+
+        assert_eq!(
+            parse_manifests_path("/v2/library/nginx/manifests/tag")
+                .unwrap()
+                .name,
+            "library/nginx",
+        );
+
+        assert_eq!(
+            parse_manifests_path("/v2/library/nginx/manifests/sha256:7422e18d69adca5354c08f92dd18192fa142eda4cc891d093f22edbb38c4de1b")
+                .unwrap()
+                .reference,
+            "sha256:7422e18d69adca5354c08f92dd18192fa142eda4cc891d093f22edbb38c4de1b",
+        );
+    }
+
+    #[test]
+    fn test_parse_path_rejects_invalid_names() {
+        assert!(
+            parse_manifests_path("/v2/../../etc/manifests/tag").is_none(),
+            "must not allow traversing up the tree"
+        );
+
+        assert!(
+            parse_manifests_path("/v2/Library/manifests/tag").is_none(),
+            "must not allow uppercase names"
+        );
+
+        assert!(
+            parse_blobs_path("/v2/library/blobs/..").is_none(),
+            "must not allow traversing up from the bucket"
+        );
+
+        assert!(
+            parse_blobs_path("/v2/library/blobs/sha256:").is_none(),
+            "must not allow empty digests"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/_live"),
+            Some(Route::Liveness)
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/_ready"),
+            Some(Route::Readiness)
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/v2/"),
+            Some(Route::VersionCheck)
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/v2/a/blobs/sha256:abc"),
+            Some(Route::Blob(ref blob)) if blob.name == "a" && blob.reference == "sha256:abc"
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/v2/a/manifests/latest"),
+            Some(Route::Manifest(ref manifest)) if manifest.reference == "latest"
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/v2/_catalog"),
+            Some(Route::Catalog)
+        ));
+
+        assert!(matches!(
+            Route::resolve(&Method::GET, "/token"),
+            Some(Route::Token)
+        ));
+
+        assert_eq!(
+            Route::resolve(&Method::GET, "/v2/a/b/blobs/sha256:abc").unwrap().repository(),
+            Some("a/b")
+        );
+
+        assert!(Route::resolve(&Method::DELETE, "/v2/a/manifests/latest").is_none());
+        assert!(Route::resolve(&Method::GET, "/v2/a/tags/list").is_none());
+    }
+}
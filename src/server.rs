@@ -1,199 +1,1052 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use futures::Future;
-use futures_fs::FsPool;
-use hyper::service::service_fn_ok;
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use futures_util::future;
+use hyper::body::Incoming;
+use hyper::header::{HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, USER_AGENT, WWW_AUTHENTICATE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tracing::field::Empty;
+use tracing::{error, info, info_span, instrument, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::blobstore::BlobStore;
+use crate::auth::{BasicAuth, Htpasswd};
+use crate::blob_body::{empty, full, metered, BlobBodies, ResponseBody};
+use crate::blob_index::BlobIndex;
+use crate::blobstore::{BlobExtent, BlobStore};
+use crate::error::Result;
+use crate::listener::{ListenAddress, Listener, Peer};
+use crate::logging::ACCESS_LOG_TARGET;
+use crate::metrics::{self, Metrics};
+use crate::policy::Policy;
+use crate::registry::{self, Catalog, ErrorCode, Errors};
+use crate::router::{BlobPath, Route};
+use crate::telemetry;
+use crate::tls::{Tls, TlsOptions};
+use crate::token::{Access, Action, TokenAuth, TokenIssuer, TokenOptions};
 
 
 const BODY_NOT_FOUND: &str = "not found";
 
 
-/// Represents a manifest path.
+/// `Cache-Control` for content addressed by digest (blobs and manifests
+/// by digest), which can never change.
 ///
-struct BlobPath {
-    name: String,
-    reference: String,
+const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+
+/// `Cache-Control` for manifests addressed by tag, which might be moved to
+/// a different manifest by a subsequent `load`.
+///
+const CACHE_CONTROL_TAG: &str = "public, max-age=60";
+
+
+/// Whether `err` comes from something not being found in the filesystem.
+///
+fn is_not_found(err: &failure::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
 }
 
 
-/// Parses a path into a BlobPath.
+/// Whether a reference addresses content by digest (e.g., `sha256:abc`)
+/// rather than by tag.
+///
+/// Tags can't contain `:`, thus the presence of a scheme is enough.
 ///
-fn parse_generic_blob_path(path_type: &'static str, path: &str) -> Option<BlobPath> {
-    let splitted: Vec<&str> = path.trim_matches('/').split("/").collect();
+fn is_digest_reference(reference: &str) -> bool {
+    reference.contains(':')
+}
 
-    if splitted.len() < 4 {
-        return None;
-    }
 
-    if splitted[0] != "v2" {
-        return None;
-    }
+/// Formats a digest as a strong entity tag (RFC 7232, section 2.3).
+///
+fn quoted_etag(digest: &str) -> String {
+    format!("\"{}\"", digest)
+}
+
 
-    if splitted[splitted.len() - 2] != path_type {
-        return None;
+/// Evaluates `If-None-Match` and `If-Modified-Since` against the validators
+/// of the content to be served, telling whether a `304 Not Modified` should
+/// be sent instead.
+///
+/// As mandated by RFC 7232 (section 6), `If-Modified-Since` is only taken
+/// into account when no `If-None-Match` has been supplied.
+///
+/// # Arguments
+///
+/// * `headers` - headers of the incoming request.
+/// * `etag` - quoted entity tag of the content.
+/// * `last_modified` - modification time of the content.
+///
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: SystemTime) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        let if_none_match = match if_none_match.to_str() {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+
+        return if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim())
+            .map(|candidate| candidate.trim_start_matches("W/"))
+            .any(|candidate| candidate == "*" || candidate == etag);
     }
 
-    let reference = splitted[splitted.len() - 1];
-    let name = &splitted[1..splitted.len() - 2];
+    let if_modified_since = match headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    {
+        Some(v) => v,
+        None => return false,
+    };
 
-    Some(BlobPath {
-        name: name.join("/"),
-        reference: reference.to_string(),
-    })
+    // HTTP dates have a resolution of seconds, so anything finer than
+    // that on the filesystem must not make the content look newer.
+    //
+    let last_modified = httpdate::parse_http_date(&httpdate::fmt_http_date(last_modified))
+        .unwrap_or(last_modified);
+
+    last_modified <= if_modified_since
+}
+
+
+/// Builds the `304 Not Modified` response carrying the same validators
+/// and caching headers that a `200` would have.
+///
+fn not_modified_response(digest: &str, etag: &str, last_modified: &str, cache_control: &str) -> Response<ResponseBody> {
+    Response::builder()
+        .header("docker-content-digest", digest)
+        .header("etag", etag)
+        .header("last-modified", last_modified)
+        .header("cache-control", cache_control)
+        .header("docker-distribution-api-version", "registry/2.0")
+        .status(StatusCode::NOT_MODIFIED)
+        .body(empty())
+        .unwrap()
 }
 
 
-/// Detects whether the provided `path` is a `BlobPath` and,
-/// if so, returns a `BlobPath`.
+/// Builds a response for a failure as described by the distribution spec.
 ///
-fn parse_manifests_path(path: &str) -> Option<BlobPath> {
-    parse_generic_blob_path("manifests", path)
+fn error_response(status: StatusCode, code: ErrorCode, message: &str) -> Response<ResponseBody> {
+    let errors = serde_json::to_string(&Errors::new(code, message)).unwrap();
+
+    Response::builder()
+        .header("content-type", "application/json")
+        .header("docker-distribution-api-version", "registry/2.0")
+        .status(status)
+        .body(full(errors))
+        .unwrap()
 }
 
 
-fn parse_blobs_path(path: &str) -> Option<BlobPath> {
-    parse_generic_blob_path("blobs", path)
+/// Builds the response to a request that failed to authenticate.
+///
+fn unauthorized_response(challenge: &str) -> Response<ResponseBody> {
+    let mut resp = error_response(
+        StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "authentication required",
+    );
+
+    resp.headers_mut().insert(WWW_AUTHENTICATE, challenge.parse().unwrap());
+
+    resp
 }
 
 
-/// Starts an HTTP server for serving the registry's content.
+/// Settings that tweak how the server behaves.
+///
+#[derive(Clone, Default)]
+pub struct Options {
+
+    /// Terminates TLS for the connections accepted when set.
+    ///
+    pub tls: Option<TlsOptions>,
+
+    /// Requires clients to authenticate with the credentials in this
+    /// `htpasswd` file when set.
+    ///
+    /// With `token` set, these are the credentials that the built-in token
+    /// issuer requires instead.
+    ///
+    pub htpasswd: Option<PathBuf>,
+
+    /// Requires clients to present bearer tokens granting access to the
+    /// repositories they target when set, taking precedence over `htpasswd`.
+    ///
+    pub token: Option<TokenOptions>,
+
+    /// Restricts what authenticated (and anonymous) clients can do against
+    /// each repository to what's granted by this policy file when set.
+    ///
+    pub policy: Option<PathBuf>,
+
+    /// Serves any blob in the store under any repository rather than only
+    /// under those whose manifests reference it.
+    ///
+    pub global_blobs: bool,
+
+    /// Where to serve `/metrics` from, if anywhere (see `serve`).
+    ///
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Metrics that the requests served get accounted in, if any.
+    ///
+    pub metrics: Option<Arc<Metrics>>,
+
+    /// How long to wait for the responses in flight to be sent when
+    /// shutting down, waiting for as long as they take when not set.
+    ///
+    pub shutdown_timeout: Option<Duration>,
+}
+
+
+/// Starts an HTTP server for serving the registry's content, blocking
+/// until it gets shut down by `SIGTERM` or `SIGINT` (see `run_until`).
 ///
 /// # Arguments
 ///
-/// * `address` - IPV4 address to bind to listen for requests
+/// * `addresses` - where to listen for requests
+/// * `activated` - sockets passed through socket activation (see
+///   `listener::take_activated_fds`)
+/// * `blobstore` - where the content to serve lives
+/// * `options` - settings that tweak the server behavior
+///
+/// The Unix domain sockets created get removed once the server stops.
 ///
 /// See `loader`.
 ///
-pub fn serve(address: &str, blobstore: BlobStore) {
-    let addr: SocketAddr = address.parse().unwrap();
-
-    let routing_svc = move || {
-        let bstore = blobstore.clone();
-
-        service_fn_ok(move |req| {
-            if let Some(resp) = handle_liveness_check(&req) {
-                return resp;
-            } else if let Some(resp) = handle_registry_version_check(&req) {
-                return resp;
-            } else if let Some(resp) = handle_registry_manifests(&req, &bstore) {
-                return resp;
-            } else if let Some(resp) = handle_registry_blobs(&req, &bstore) {
-                return resp;
+pub fn serve(
+    addresses: &[ListenAddress],
+    activated: Vec<RawFd>,
+    blobstore: BlobStore,
+    options: Options,
+) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    runtime.block_on(async {
+        let mut options = options;
+        let mut activated = activated;
+        let mut listeners = Vec::new();
+
+        for address in addresses {
+            let bound = Listener::bind(address, &mut activated)
+                .await
+                .map_err(|err| failure::format_err!("failed to listen on {} - {}", address, err))?;
+
+            listeners.extend(bound);
+        }
+
+        for listener in &listeners {
+            info!(address = %listener.local_address()?, tls = options.tls.is_some(), "listening");
+        }
+
+        if let Some(metrics_address) = options.metrics_address {
+            let metrics = Arc::new(Metrics::new(blobstore.clone())?);
+            let metrics_listener = TcpListener::bind(metrics_address).await?;
+
+            info!(address = %format!("http://{}/metrics", metrics_address), "serving metrics");
+
+            tokio::spawn(metrics::serve(metrics_listener, metrics.clone()));
+            options.metrics = Some(metrics);
+        }
+
+        let result = run_until(listeners, blobstore, options, shutdown_signal()).await;
+
+        for address in addresses {
+            if let ListenAddress::Unix(path) = address {
+                if let Err(err) = std::fs::remove_file(path) {
+                    warn!(path = ?path, error = %err, "failed to remove socket");
+                }
             }
+        }
 
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::from(BODY_NOT_FOUND))
-                .unwrap()
-        })
+        result
+    })
+}
+
+
+/// Resolves once the process gets asked to terminate, either through
+/// `SIGTERM` (e.g., by the container runtime) or `SIGINT` (`^C`).
+///
+async fn shutdown_signal() {
+    let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            error!(error = %err, "failed to listen for SIGTERM");
+            return std::future::pending().await;
+        },
     };
 
-    let server = Server::bind(&addr)
-        .serve(routing_svc)
-        .map_err(|e| println!("server error: {}", e));
+    let signal = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    };
+
+    info!(signal, "shutting down");
+}
 
-    println!("listening on http://{}", address);
 
-    hyper::rt::run(server);
+/// Serves the registry's content to the connections accepted by
+/// `listener`, never returning unless it fails to start.
+///
+pub async fn run(listener: TcpListener, blobstore: BlobStore, options: Options) -> Result<()> {
+    run_until(vec![listener.into()], blobstore, options, std::future::pending()).await
 }
 
 
-/// Handles blob requests.
+/// Serves the registry's content to the connections accepted by any of
+/// `listeners` until `shutdown` resolves.
 ///
-/// Note: there are no permissions checking regarding the repository that the GET is performed
-/// against.
+/// Once it does, no more connections are accepted, and those open get
+/// closed as soon as the responses in flight through them have been sent,
+/// or once `shutdown_timeout` elapses.
 ///
-fn handle_registry_blobs(req: &Request<Body>, blobstore: &BlobStore) -> Option<Response<Body>> {
-    if req.method() != &Method::GET {
-        return None;
+pub async fn run_until(
+    listeners: Vec<Listener>,
+    blobstore: BlobStore,
+    options: Options,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    if listeners.is_empty() {
+        return Err(failure::format_err!("no sockets to listen on"));
     }
 
-    let blob_info = parse_blobs_path(req.uri().path())?;
+    let basic_auth = match options.htpasswd {
+        Some(path) => {
+            let htpasswd = Arc::new(Htpasswd::new(&path)?);
+            tokio::spawn(htpasswd.clone().watch());
+            Some(BasicAuth::new(htpasswd))
+        },
+        None => None,
+    };
 
-    let file_path = blobstore
-        .get_blob(&blob_info.reference);
+    let (token_auth, token_issuer) = match options.token {
+        Some(token_options) => {
+            let token_issuer = match token_options.signing_key {
+                Some(_) => Some(TokenIssuer::new(token_options.clone())?),
+                None => None,
+            };
 
-    let file_size = std::fs::metadata(&file_path)
-        .unwrap()
-        .len();
+            (Some(TokenAuth::new(token_options)?), token_issuer)
+        },
+        None => (None, None),
+    };
 
-    let file = FsPool::default()
-        .read(file_path, Default::default());
+    let policy = match options.policy {
+        Some(path) => {
+            let policy = Arc::new(Policy::new(&path)?);
+            tokio::spawn(policy.clone().watch());
+            Some(policy)
+        },
+        None => None,
+    };
 
+    let blob_index = if options.global_blobs {
+        None
+    } else {
+        let blobstore = blobstore.clone();
+        Some(Arc::new(tokio::task::spawn_blocking(move || BlobIndex::new(blobstore)).await??))
+    };
 
-    Some(
-        Response::builder()
-            .header("content-type", "application/octet-stream")
-            .header("docker-content-digest", blob_info.reference.as_bytes())
-            .header("content-length", file_size)
-            .header("etag", blob_info.reference.as_bytes())
-            .header("docker-distribution-api-version", "registry/2.0")
-            .status(StatusCode::OK)
-            .body(Body::wrap_stream(file))
-            .unwrap(),
-    )
+    let registry = Arc::new(Registry {
+        blobstore,
+        bodies: BlobBodies::new(),
+        basic_auth,
+        token_auth,
+        token_issuer,
+        policy,
+        blob_index,
+        metrics: options.metrics,
+    });
+
+    let tls = match options.tls {
+        Some(tls_options) => {
+            let tls = Arc::new(Tls::new(tls_options)?);
+            tokio::spawn(tls.clone().watch());
+            Some(tls)
+        },
+        None => None,
+    };
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepting = listeners.iter().map(|listener| Box::pin(listener.accept()));
+
+        let accepted = tokio::select! {
+            (accepted, _, _) = future::select_all(accepting) => accepted,
+            _ = &mut shutdown => break,
+        };
+
+        let (stream, client) = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(error = %err, "failed to accept connection");
+                continue;
+            },
+        };
+
+        let registry = registry.clone();
+        let tls = tls.clone();
+        let watcher = graceful.watcher();
+
+        tokio::spawn(async move {
+            match tls {
+                None => serve_connection(stream, client, registry, watcher).await,
+                Some(tls) => match tls.acceptor().accept(stream).await {
+                    Ok(stream) => serve_connection(stream, client, registry, watcher).await,
+                    Err(err) => warn!(client = %client, error = %err, "tls handshake failed"),
+                },
+            }
+        });
+    }
+
+    drop(listeners);
+
+    let connections = graceful.count();
+    info!(connections, "draining connections");
+
+    match options.shutdown_timeout {
+        None => graceful.shutdown().await,
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, graceful.shutdown()).await.is_err() {
+                warn!(timeout_ms = timeout.as_millis() as u64, "gave up draining connections");
+            }
+        },
+    }
+
+    Ok(())
 }
 
 
-/// Handles requests for manifests.
+/// Serves the HTTP requests that come through a connection with `client`,
+/// until `watcher` tells it to close once the response in flight (if any)
+/// has been sent.
 ///
-/// ```txt
-/// GET /v2/foo/bar/manifests/tag
-/// ```
-///
-/// Note: there are no permissions checking regarding the repository that the GET is performed
-/// against.
+async fn serve_connection<I>(io: I, client: Peer, registry: Arc<Registry>, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req: Request<Incoming>| {
+        let registry = registry.clone();
+
+        async move { Ok::<_, Infallible>(registry.handle(req, client).await) }
+    });
+
+    let conn = http1::Builder::new().serve_connection(TokioIo::new(io), service);
+
+    if let Err(err) = watcher.watch(conn).await {
+        warn!(client = %client, error = %err, "connection error");
+    }
+}
+
+
+/// The state shared by all of the requests served.
 ///
-fn handle_registry_manifests(req: &Request<Body>, blobstore: &BlobStore) -> Option<Response<Body>> {
-    if req.method() != &Method::GET {
-        return None;
+struct Registry {
+    blobstore: BlobStore,
+    bodies: BlobBodies,
+
+    /// Credentials required from clients, either for every request or,
+    /// with `token_auth` set, for getting tokens from `token_issuer`.
+    ///
+    basic_auth: Option<BasicAuth>,
+
+    /// Tokens required from clients, if any.
+    ///
+    token_auth: Option<TokenAuth>,
+
+    /// The built-in token issuer, if any.
+    ///
+    token_issuer: Option<TokenIssuer>,
+
+    /// Who can do what against which repositories, if restricted.
+    ///
+    policy: Option<Arc<Policy>>,
+
+    /// The blobs referenced by each repository, unless blobs are served
+    /// under any repository.
+    ///
+    blob_index: Option<Arc<BlobIndex>>,
+
+    metrics: Option<Arc<Metrics>>,
+}
+
+
+impl Registry {
+
+    /// Handles a request from `client`, logging it to the access log once
+    /// its response has been sent.
+    ///
+    /// The request is traced under a span that continues the trace that the
+    /// client propagated (if any), lasting until the response has been sent.
+    ///
+    async fn handle<B>(&self, req: Request<B>, client: Peer) -> Response<ResponseBody> {
+        let started = Instant::now();
+        let route = Route::resolve(req.method(), req.uri().path());
+        let endpoint = route.as_ref().map_or("unknown", |route| route.name());
+
+        // dropped along with the body, or with this future if the client
+        // goes away before there's one.
+        //
+        let in_flight = self.metrics.as_ref().map(|metrics| metrics.request_started());
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(|user_agent| user_agent.to_owned());
+
+        let (repository, reference) = match &route {
+            Some(Route::Manifest(path)) | Some(Route::Blob(path)) => {
+                (Some(path.name.clone()), Some(path.reference.clone()))
+            },
+            _ => (None, None),
+        };
+
+        let span = info_span!(
+            "request",
+            method = %method,
+            path = %path,
+            endpoint,
+            repository = repository.as_deref(),
+            reference = reference.as_deref(),
+            status = Empty,
+        );
+
+        // without a tracer to export spans there's no context to continue.
+        //
+        let _ = span.set_parent(telemetry::parent_context(req.headers()));
+
+        let resp = match route {
+            Some(route) => self.dispatch(req, route).instrument(span.clone()).await,
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(full(BODY_NOT_FOUND))
+                .unwrap(),
+        };
+
+        let status = resp.status().as_u16();
+        let metrics = self.metrics.clone();
+        let trace_id = telemetry::trace_id(&span);
+
+        span.record("status", status);
+        let sending = info_span!(parent: &span, "send_body", bytes = Empty);
+
+        resp.map(|body| {
+            metered(body, move |bytes| {
+                sending.record("bytes", bytes);
+                drop(sending);
+                drop(span);
+                drop(in_flight);
+
+                if let Some(metrics) = metrics {
+                    metrics.request_finished(endpoint, status, bytes, started.elapsed());
+                }
+
+                info!(
+                    target: ACCESS_LOG_TARGET,
+                    method = %method,
+                    path = %path,
+                    repository = repository.as_deref(),
+                    reference = reference.as_deref(),
+                    status,
+                    bytes,
+                    duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+                    client = %client,
+                    user_agent = user_agent.as_deref(),
+                    trace_id = trace_id.as_deref(),
+                    "request",
+                );
+            })
+        })
     }
 
-    let manifest_info = parse_manifests_path(req.uri().path())?;
 
-    let file_path = match std::fs::read_link(
-        blobstore.get_manifest(
-            &manifest_info.name,
-            &manifest_info.reference,
-        ),
-    ) {
-        Err(err) => {
-            panic!("oh damn, it probably doesn't exist - {}", err);
-        },
+    /// Routes a request to the handler of the endpoint it targets.
+    ///
+    async fn dispatch<B>(&self, req: Request<B>, route: Route) -> Response<ResponseBody> {
+        let user = match self.authenticate(&req, &route).await {
+            Ok(user) => user,
+            Err(resp) => return resp,
+        };
 
-        Ok(fp) => fp,
-    };
+        if let Some(resp) = self.authorize(&req, &route, user.as_deref()) {
+            return resp;
+        }
 
-    let manifest_digest = file_path
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
+        let result = match route {
+            Route::Liveness => Ok(handle_liveness_check()),
+            Route::Readiness => Ok(self.handle_readiness_check().await),
+            Route::VersionCheck => Ok(handle_registry_version_check()),
+            Route::Manifest(manifest) => self.handle_registry_manifests(&req, manifest).await,
+            Route::Blob(blob) => self.handle_registry_blobs(&req, blob).await,
+            Route::Catalog => self.handle_catalog(&req, user.as_deref()).await,
+            Route::Token => self.handle_token(&req).await,
+        };
 
-    let file_size = std::fs::metadata(&file_path)
-        .unwrap()
-        .len();
+        match result {
+            Ok(resp) => resp,
+            Err(err) => {
+                error!(method = %req.method(), uri = %req.uri(), error = %err, "failed to handle request");
 
-    let file = FsPool::default().read(
-        file_path,
-        Default::default(),
-    );
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(empty())
+                    .unwrap()
+            },
+        }
+    }
+
+
+    /// Authenticates a request, giving back the user that performs it
+    /// (`None` if anonymous) or the response to send to the client when it
+    /// fails to.
+    ///
+    /// With token authentication, the token must grant the action performed
+    /// against the repository targeted (if any), otherwise the challenge
+    /// tells the client which scope to ask the issuer for.
+    ///
+    /// The liveness and readiness checks are always let through so that
+    /// probes don't need credentials, as is the token issuer, which
+    /// authenticates on its own.
+    ///
+    #[instrument(skip_all)]
+    async fn authenticate<B>(&self, req: &Request<B>, route: &Route) -> std::result::Result<Option<String>, Response<ResponseBody>> {
+        if let Route::Liveness | Route::Readiness | Route::Token = route {
+            return Ok(None);
+        }
+
+        if let Some(token_auth) = &self.token_auth {
+            let required = match route {
+                Route::Catalog => Some(Access::catalog()),
+                _ => route
+                    .repository()
+                    .map(|name| Access::repository(name, Action::for_method(req.method()))),
+            };
+
+            let challenge = match token_auth.authenticate(req.headers()) {
+                Some(claims) => match &required {
+                    Some(required) if !claims.allows(required) => {
+                        token_auth.challenge(Some(required), true)
+                    },
+                    _ => return Ok(Some(claims.sub).filter(|sub| !sub.is_empty())),
+                },
+                None => token_auth.challenge(required.as_ref(), false),
+            };
+
+            return Err(unauthorized_response(&challenge));
+        }
+
+        let basic_auth = match &self.basic_auth {
+            Some(basic_auth) => basic_auth,
+            None => return Ok(None),
+        };
+
+        match basic_auth.authenticate(req.headers()).await {
+            Some(user) => Ok(Some(user)),
+            None => Err(unauthorized_response(&basic_auth.challenge())),
+        }
+    }
+
+
+    /// Checks that the policy lets `user` perform the request against the
+    /// repository it targets, giving back the response to send to the
+    /// client when it doesn't.
+    ///
+    /// With token authentication, `user` is the subject of the token, so
+    /// that tokens handed out by other issuers can't go past the policy
+    /// either.
+    ///
+    fn authorize<B>(&self, req: &Request<B>, route: &Route, user: Option<&str>) -> Option<Response<ResponseBody>> {
+        let policy = self.policy.as_ref()?;
+        let repository = route.repository()?;
+
+        if policy.allows(user, repository, Action::for_method(req.method())) {
+            return None;
+        }
+
+        Some(error_response(
+            StatusCode::FORBIDDEN, ErrorCode::Denied, "requested access to the resource is denied",
+        ))
+    }
+
+
+    /// Handles readiness checks, telling whether the blobstore can be read
+    /// from.
+    ///
+    /// ```txt
+    /// GET /_ready
+    /// ```
+    ///
+    #[instrument(skip_all)]
+    async fn handle_readiness_check(&self) -> Response<ResponseBody> {
+        for dir in &[&self.blobstore.bucket_dir, &self.blobstore.manifests_dir] {
+            if let Err(err) = tokio::fs::read_dir(dir).await {
+                warn!(dir = ?dir, error = %err, "blobstore not readable");
+
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(full(format!("blobstore not readable: {}", err)))
+                    .unwrap();
+            }
+        }
 
-    Some(
         Response::builder()
-            .header("content-length", file_size)
-            .header("content-type", "application/vnd.docker.distribution.manifest.v2+json")
-            .header("docker-distribution-api-version", "registry/2.0")
-            .header("docker-content-digest", manifest_digest.as_bytes())
-            .header("etag", manifest_digest.as_bytes())
             .status(StatusCode::OK)
-            .body(Body::wrap_stream(file))
-            .unwrap(),
-    )
+            .body(full("ready"))
+            .unwrap()
+    }
+
+
+    /// Handles requests for the list of repositories.
+    ///
+    /// ```txt
+    /// GET /v2/_catalog?n=10&last=library/nginx
+    /// ```
+    ///
+    /// Only the repositories that the policy lets `user` list are included.
+    ///
+    #[instrument(skip_all)]
+    async fn handle_catalog<B>(&self, req: &Request<B>, user: Option<&str>) -> Result<Response<ResponseBody>> {
+        let blobstore = self.blobstore.clone();
+        let span = Span::current();
+        let mut repositories = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| blobstore.list_repositories())
+        }).await??;
+
+        if let Some(policy) = &self.policy {
+            repositories.retain(|repository| policy.allows(user, repository, Action::List));
+        }
+
+        let mut n = None;
+        let mut last = None;
+
+        for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes()) {
+            match &*key {
+                "n" => n = value.parse::<usize>().ok(),
+                "last" => last = Some(value.into_owned()),
+                _ => (),
+            }
+        }
+
+        if let Some(last) = &last {
+            repositories.retain(|repository| repository > last);
+        }
+
+        let mut resp = Response::builder()
+            .header("content-type", "application/json")
+            .header("docker-distribution-api-version", "registry/2.0");
+
+        if let Some(n) = n {
+            if repositories.len() > n {
+                repositories.truncate(n);
+
+                // with `n=0` there's no last repository to carry on from,
+                // so no next page either.
+                //
+                if let Some(last) = repositories.last() {
+                    let next: String = form_urlencoded::Serializer::new(String::new())
+                        .append_pair("n", &n.to_string())
+                        .append_pair("last", last)
+                        .finish();
+
+                    resp = resp.header("link", format!("</v2/_catalog?{}>; rel=\"next\"", next));
+                }
+            }
+        }
+
+        Ok(
+            resp
+                .status(StatusCode::OK)
+                .body(full(serde_json::to_string(&Catalog { repositories })?))
+                .unwrap(),
+        )
+    }
+
+
+    /// Handles requests for tokens, issuing them for the scopes requested.
+    ///
+    /// ```txt
+    /// GET /token?service=cartorio&scope=repository:foo/bar:pull
+    /// ```
+    ///
+    /// Clients authenticate with the credentials in the `htpasswd` file
+    /// when configured, being let through anonymously otherwise.
+    ///
+    /// Only the actions that the policy lets the client perform are
+    /// granted, or just `pull` without a policy. Access to the catalog is
+    /// always granted, as it only lists what the policy lets the client see.
+    ///
+    #[instrument(skip_all)]
+    async fn handle_token<B>(&self, req: &Request<B>) -> Result<Response<ResponseBody>> {
+        let token_issuer = match &self.token_issuer {
+            Some(token_issuer) => token_issuer,
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(full(BODY_NOT_FOUND))
+                    .unwrap());
+            },
+        };
+
+        let user = match &self.basic_auth {
+            None => None,
+            Some(basic_auth) => match basic_auth.authenticate(req.headers()).await {
+                Some(user) => Some(user),
+                None => return Ok(unauthorized_response(&basic_auth.challenge())),
+            },
+        };
+
+        let query = req.uri().query().unwrap_or("");
+        let mut access = Vec::new();
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key != "scope" {
+                continue;
+            }
+
+            for scope in value.split(' ').filter(|scope| !scope.is_empty()) {
+                let mut requested: Access = match scope.parse() {
+                    Ok(requested) => requested,
+                    Err(err) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(full(err.to_string()))
+                            .unwrap());
+                    },
+                };
+
+                if requested == Access::catalog() {
+                    access.push(requested);
+                    continue;
+                }
+
+                if requested.resource_type != "repository" {
+                    continue;
+                }
+
+                let name = requested.name.clone();
+
+                // without a policy, everyone gets to pull but nothing else.
+                //
+                requested.actions.retain(|action| match (action.parse::<Action>(), &self.policy) {
+                    (Ok(action), Some(policy)) => policy.allows(user.as_deref(), &name, action),
+                    (Ok(action), None) => action == Action::Pull,
+                    (Err(_), _) => false,
+                });
+
+                access.push(requested);
+            }
+        }
+
+        let token = serde_json::to_string(&token_issuer.issue(user.as_deref().unwrap_or(""), access)?)?;
+
+        Ok(
+            Response::builder()
+                .header("content-type", "application/json")
+                .status(StatusCode::OK)
+                .body(full(token))
+                .unwrap(),
+        )
+    }
+
+
+    /// Handles blob requests.
+    ///
+    /// ```txt
+    /// GET /v2/foo/bar/blobs/sha256:abc
+    /// ```
+    ///
+    /// Blobs that the manifests of the repository don't reference are
+    /// answered as unknown (see `is_blob_reachable`).
+    ///
+    #[instrument(skip_all)]
+    async fn handle_registry_blobs<B>(&self, req: &Request<B>, blob_info: BlobPath) -> Result<Response<ResponseBody>> {
+        if !self.is_blob_reachable(&blob_info).await? {
+            return Ok(error_response(
+                StatusCode::NOT_FOUND, ErrorCode::BlobUnknown, "blob unknown to registry",
+            ));
+        }
+
+        let file_path = self.blobstore
+            .get_blob(&blob_info.reference);
+
+        let (file_size, modified, extent) = match tokio::fs::metadata(&file_path).instrument(info_span!("metadata")).await {
+            Ok(metadata) => (metadata.len(), metadata.modified()?, None),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                match self.read_blob_extent(&blob_info.reference).await? {
+                    Some(extent) => (extent.size, extent.tarball_modified, Some(extent)),
+                    None => {
+                        return Ok(error_response(
+                            StatusCode::NOT_FOUND, ErrorCode::BlobUnknown, "blob unknown to registry",
+                        ));
+                    },
+                }
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        let last_modified = httpdate::fmt_http_date(modified);
+        let etag = quoted_etag(&blob_info.reference);
+
+        if is_not_modified(req.headers(), &etag, modified) {
+            return Ok(not_modified_response(
+                &blob_info.reference, &etag, &last_modified, CACHE_CONTROL_IMMUTABLE,
+            ));
+        }
+
+        let file = match &extent {
+            None => self.bodies.mapped(&blob_info.reference, &file_path).await?,
+            Some(extent) => match self.bodies.extent(&blob_info.reference, extent).await {
+                Ok(body) => body,
+                Err(err) if is_not_found(&err) => {
+                    warn!(digest = %blob_info.reference, error = %err, "blob extent no longer valid");
+
+                    return Ok(error_response(
+                        StatusCode::NOT_FOUND, ErrorCode::BlobUnknown, "blob unknown to registry",
+                    ));
+                },
+                Err(err) => return Err(err),
+            },
+        };
+
+        Ok(
+            Response::builder()
+                .header("content-type", "application/octet-stream")
+                .header("docker-content-digest", blob_info.reference.as_bytes())
+                .header("content-length", file_size)
+                .header("etag", etag.as_bytes())
+                .header("last-modified", last_modified.as_bytes())
+                .header("cache-control", CACHE_CONTROL_IMMUTABLE)
+                .header("docker-distribution-api-version", "registry/2.0")
+                .status(StatusCode::OK)
+                .body(file)
+                .unwrap(),
+        )
+    }
+
+
+    /// Reads where, within a tarball, a blob that's served in place is, if
+    /// it's served that way.
+    ///
+    #[instrument(skip_all)]
+    async fn read_blob_extent(&self, digest: &str) -> Result<Option<BlobExtent>> {
+        match tokio::fs::read(self.blobstore.get_blob_extent(digest)).await {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+
+    /// Whether the blob is referenced by the manifests of the repository it's
+    /// requested under, re-reading them in case they changed when it isn't.
+    ///
+    #[instrument(skip_all)]
+    async fn is_blob_reachable(&self, blob_info: &BlobPath) -> Result<bool> {
+        let blob_index = match &self.blob_index {
+            Some(blob_index) => blob_index,
+            None => return Ok(true),
+        };
+
+        if blob_index.contains(&blob_info.name, &blob_info.reference) {
+            return Ok(true);
+        }
+
+        let index = blob_index.clone();
+        let repository = blob_info.name.clone();
+        let span = Span::current();
+
+        tokio::task::spawn_blocking(move || span.in_scope(|| index.refresh(&repository))).await??;
+
+        Ok(blob_index.contains(&blob_info.name, &blob_info.reference))
+    }
+
+
+    /// Handles requests for manifests.
+    ///
+    /// ```txt
+    /// GET /v2/foo/bar/manifests/tag
+    /// ```
+    ///
+    /// Access to the repository is checked beforehand (see `authorize`).
+    ///
+    #[instrument(skip_all)]
+    async fn handle_registry_manifests<B>(&self, req: &Request<B>, manifest_info: BlobPath) -> Result<Response<ResponseBody>> {
+        let file_path = match tokio::fs::read_link(
+            self.blobstore.get_manifest(
+                &manifest_info.name,
+                &manifest_info.reference,
+            ),
+        ).instrument(info_span!("read_link")).await {
+            Ok(fp) => fp,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(error_response(
+                    StatusCode::NOT_FOUND, ErrorCode::ManifestUnknown, "manifest unknown",
+                ));
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        let manifest_digest = file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| failure::format_err!("malformed manifest link {:?}", file_path))?
+            .to_owned();
+
+        let file_metadata = tokio::fs::metadata(&file_path).instrument(info_span!("metadata")).await?;
+
+        let modified = file_metadata.modified()?;
+        let last_modified = httpdate::fmt_http_date(modified);
+        let etag = quoted_etag(&manifest_digest);
+
+        let cache_control = if is_digest_reference(&manifest_info.reference) {
+            CACHE_CONTROL_IMMUTABLE
+        } else {
+            CACHE_CONTROL_TAG
+        };
+
+        if is_not_modified(req.headers(), &etag, modified) {
+            return Ok(not_modified_response(
+                &manifest_digest, &etag, &last_modified, cache_control,
+            ));
+        }
+
+        let content = tokio::fs::read(&file_path).instrument(info_span!("read")).await?;
+        let media_type = registry::manifest_media_type(&content);
+
+        Ok(
+            Response::builder()
+                .header("content-length", content.len())
+                .header("content-type", media_type)
+                .header("docker-distribution-api-version", "registry/2.0")
+                .header("docker-content-digest", manifest_digest.as_bytes())
+                .header("etag", etag.as_bytes())
+                .header("last-modified", last_modified.as_bytes())
+                .header("cache-control", cache_control)
+                .status(StatusCode::OK)
+                .body(full(content))
+                .unwrap(),
+        )
+    }
+
 }
 
 
@@ -206,92 +1059,107 @@ fn handle_registry_manifests(req: &Request<Body>, blobstore: &BlobStore) -> Opti
 /// Note: even though this serve doesn't implement the `push`-side of the distribution spec, we
 /// don't advertise that through the body as that's not very standardized.
 ///
-fn handle_registry_version_check(req: &Request<Body>) -> Option<Response<Body>> {
-    println!("path = {}", req.uri().path());
-
-    if req.method() != &Method::GET  {
-        return None;
-    }
-
-    match req.uri().path() {
-        "/v2" | "/v2/" => (),
-        _ => return None,
-    }
-
-    Some(
-        Response::builder()
-            .status(StatusCode::OK)
-            .header("docker-distribution-api-version", "registry/2.0")
-            .body(Body::empty())
-            .unwrap(),
-    )
+fn handle_registry_version_check() -> Response<ResponseBody> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("docker-distribution-api-version", "registry/2.0")
+        .body(empty())
+        .unwrap()
 }
 
 
 /// If 200, it's alive lol
 ///
-fn handle_liveness_check(req: &Request<Body>) -> Option<Response<Body>> {
-    if req.method() != &Method::GET || req.uri().path() != "/_live" {
-        return None;
-    }
-
-    Some(
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::from("alive"))
-            .unwrap(),
-    )
+fn handle_liveness_check() -> Response<ResponseBody> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(full("alive"))
+        .unwrap()
 }
 
 
 
 #[cfg(test)]
-mod parsing_tests {
+mod conditional_tests {
     use super::*;
 
+    use std::time::Duration;
+
+    use hyper::header::HeaderValue;
+
+    const ETAG: &str = "\"sha256:abc\"";
+
+    fn headers(name: hyper::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_quoted_etag() {
+        assert_eq!(quoted_etag("sha256:abc"), ETAG);
+    }
+
+    #[test]
+    fn test_is_digest_reference() {
+        assert!(is_digest_reference("sha256:abc"));
+        assert!(!is_digest_reference("latest"));
+    }
+
+    #[test]
+    fn test_is_not_modified_without_conditionals() {
+        assert!(!is_not_modified(&HeaderMap::new(), ETAG, SystemTime::now()));
+    }
+
     #[test]
-    fn test_parse_manifests_path() {
+    fn test_is_not_modified_if_none_match() {
+        let now = SystemTime::now();
+
+        assert!(is_not_modified(&headers(IF_NONE_MATCH, ETAG), ETAG, now));
+        assert!(is_not_modified(&headers(IF_NONE_MATCH, "*"), ETAG, now));
+        assert!(is_not_modified(&headers(IF_NONE_MATCH, "\"a\", W/\"sha256:abc\""), ETAG, now));
+
         assert!(
-            parse_manifests_path("xxx").is_none(),
-            "must have a `/v2` in the prefix`"
+            !is_not_modified(&headers(IF_NONE_MATCH, "\"sha256:def\""), ETAG, now),
+            "must not match a different entity tag"
         );
 
         assert!(
-            parse_manifests_path("/v2/library/manifests").is_none(),
-            "must have enough fields"
+            !is_not_modified(&headers(IF_NONE_MATCH, "sha256:abc"), ETAG, now),
+            "must not match an unquoted entity tag"
         );
+    }
+
+    #[test]
+    fn test_is_not_modified_if_modified_since() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let at = |t: SystemTime| headers(IF_MODIFIED_SINCE, &httpdate::fmt_http_date(t));
+
+        assert!(is_not_modified(&at(modified), ETAG, modified));
+        assert!(is_not_modified(&at(modified + Duration::from_secs(10)), ETAG, modified));
+        assert!(!is_not_modified(&at(modified - Duration::from_secs(10)), ETAG, modified));
 
         assert!(
-            parse_manifests_path("/v2/library/wrong/tag").is_none(),
-            "must have `manifests` after name and before reference"
+            is_not_modified(&at(modified), ETAG, modified + Duration::from_millis(500)),
+            "sub-second precision must be ignored"
         );
 
-        assert_eq!(
-            parse_manifests_path("/v2/library/manifests/tag")
-                .unwrap()
-                .name,
-            "library",
+        assert!(
+            !is_not_modified(&headers(IF_MODIFIED_SINCE, "garbage"), ETAG, modified),
+            "must ignore invalid dates"
         );
+    }
 
-        assert_eq!(
-            parse_manifests_path("/v2/library/manifests/tag")
-                .unwrap()
-                .reference,
-            "tag",
-        );
+    #[test]
+    fn test_if_none_match_takes_precedence() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
 
-        assert_eq!(
-            parse_manifests_path("/v2/library/nginx/manifests/tag")
-                .unwrap()
-                .name,
-            "library/nginx",
+        let mut headers = headers(IF_NONE_MATCH, "\"sha256:def\"");
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
         );
 
-        assert_eq!(
-            parse_manifests_path("/v2/library/nginx/manifests/sha256:7422e18d69adca5354c08f92dd18192fa142eda4cc891d093f22edbb38c4de1b")
-                .unwrap()
-                .reference,
-            "sha256:7422e18d69adca5354c08f92dd18192fa142eda4cc891d093f22edbb38c4de1b",
-        );
+        assert!(!is_not_modified(&headers, ETAG, modified));
     }
 }
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+
+use tar::EntryType;
+use tempfile::NamedTempFile;
+use tracing::instrument;
+
+use crate::digest;
+use crate::error::Result;
+
+
+/// How many links are followed when resolving a member before giving up.
+///
+const MAX_LINKS: usize = 16;
+
+
+/// A regular file within a tarball.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Member {
+
+    /// Offset, from the start of the tarball, where the contents of the
+    /// file begin.
+    ///
+    pub offset: u64,
+
+    /// Size of the contents of the file.
+    ///
+    pub size: u64,
+
+    /// Digest of the contents of the file (without the `sha256:` scheme).
+    ///
+    pub digest: String,
+}
+
+
+/// The location and digest of every regular file in a tarball, so that
+/// their contents can be served from byte ranges of the tarball itself,
+/// without extracting them, or taken from where they got staged to.
+///
+/// ```txt
+///
+///    image.tar
+///    ├── manifest.json                  { offset: 512,  size: 197,  digest: 9a1c.. }
+///    ├── 922f19e5.json                  { offset: 1536, size: 1192, digest: 922f.. }
+///    └── 2449afaa
+///        ├── layer.tar                  { offset: 3584, size: 2048, digest: 4bc4.. }
+///        └── ...
+///
+/// ```
+///
+pub struct TarballIndex {
+
+    /// Regular files, keyed by their normalized path in the tarball.
+    ///
+    members: HashMap<String, Member>,
+
+    /// Hard and symbolic links, keyed by their normalized path in the
+    /// tarball, pointing at the normalized path of their target.
+    ///
+    links: HashMap<String, String>,
+}
+
+
+impl TarballIndex {
+
+    /// Indexes the tarball at `path`, reading it once from start to end
+    /// to compute the digests of its files.
+    ///
+    ///
+    /// # Remarks
+    ///
+    /// * Sparse files can't be served from a contiguous byte range, thus
+    ///   tarballs containing them are rejected.
+    /// * Files under `blobs/sha256` (as in an OCI Image Layout) are named
+    ///   after their digest already, thus they're not hashed again.
+    ///
+    #[instrument]
+    pub fn new(path: &Path) -> Result<TarballIndex> {
+        TarballIndex::from_entries(File::open(path)?, |name, entry| {
+            if entry.header().entry_type() == EntryType::GNUSparse {
+                return Err(failure::format_err!(
+                    "{} is a sparse file, which can't be served in place", name,
+                ));
+            }
+
+            match digest_from_name(name) {
+                Some(digest) => Ok(digest.to_owned()),
+                None => digest::compute(entry),
+            }
+        })
+    }
+
+
+    /// Indexes a tarball as it gets read from `reader` (e.g., a pipe),
+    /// copying each of its files to `dir` under the name of its digest
+    /// (e.g., `sha256:abcdef`) as they're hashed.
+    ///
+    /// ```txt
+    ///
+    ///    stdin                              dir
+    ///    ├── 922f19e5.json         ==>      ├── sha256:922f19e5...
+    ///    ├── 2449afaa/layer.tar             ├── sha256:4bc453b5...
+    ///    └── manifest.json                  └── sha256:9a1c3e0d...
+    ///
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - the supplier of the bytes of the tarball.
+    /// * `dir` - where to copy the files to.
+    ///
+    #[instrument(skip(reader))]
+    pub fn stage(reader: impl Read, dir: &Path) -> Result<TarballIndex> {
+        TarballIndex::from_entries(reader, |_, entry| {
+            let mut staged = NamedTempFile::new_in(dir)?;
+            let digest = digest::compute_and_copy(entry, &mut staged)?;
+
+            staged
+                .persist(dir.join(digest::prepend_sha_scheme(&digest)))
+                .map_err(|err| err.error)?;
+
+            Ok(digest)
+        })
+    }
+
+
+    /// Indexes the entries of the tarball supplied by `reader`, having the
+    /// digest of each regular file computed by `digest_of`.
+    ///
+    fn from_entries<R: Read>(
+        reader: R,
+        mut digest_of: impl FnMut(&str, &mut tar::Entry<R>) -> Result<String>,
+    ) -> Result<TarballIndex> {
+        let mut archive = tar::Archive::new(reader);
+        let mut members = HashMap::new();
+        let mut links = HashMap::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = match normalize(&entry.path()?) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                    let offset = entry.raw_file_position();
+                    let size = entry.size();
+                    let digest = digest_of(&name, &mut entry)?;
+
+                    members.insert(name, Member { offset, size, digest });
+                },
+
+                EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .and_then(|target| normalize(&target))
+                        .ok_or_else(|| failure::format_err!("invalid link target for {}", name))?;
+
+                    links.insert(name, target);
+                },
+
+                EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .and_then(|target| normalize(&Path::new(&name).with_file_name(target.as_os_str())))
+                        .ok_or_else(|| failure::format_err!("invalid link target for {}", name))?;
+
+                    links.insert(name, target);
+                },
+
+                _ => (),
+            }
+        }
+
+        Ok(TarballIndex {
+            members,
+            links,
+        })
+    }
+
+
+    /// Looks up the regular file at `name`, following links.
+    ///
+    pub fn member(&self, name: &str) -> Result<&Member> {
+        let mut name = normalize(Path::new(name))
+            .ok_or_else(|| failure::format_err!("invalid member path {}", name))?;
+
+        for _ in 0..MAX_LINKS {
+            if let Some(member) = self.members.get(&name) {
+                return Ok(member);
+            }
+
+            name = match self.links.get(&name) {
+                Some(target) => target.clone(),
+                None => break,
+            };
+        }
+
+        Err(failure::format_err!("{} not found in the tarball", name))
+    }
+
+
+    /// Reads the contents of the regular file at `name` from the tarball
+    /// at `tarball`.
+    ///
+    pub fn read(&self, tarball: &Path, name: &str) -> Result<Vec<u8>> {
+        let member = self.member(name)?;
+        let mut file = File::open(tarball)?;
+        let mut contents = Vec::with_capacity(member.size as usize);
+
+        file.seek(SeekFrom::Start(member.offset))?;
+        file.take(member.size).read_to_end(&mut contents)?;
+
+        Ok(contents)
+    }
+
+}
+
+
+/// The digest that a file under `blobs/sha256` is named after, if it is
+/// one (e.g., `blobs/sha256/abc...` => `abc...`).
+///
+fn digest_from_name(name: &str) -> Option<&str> {
+    name.strip_prefix("blobs/sha256/")
+        .filter(|digest| digest.len() == 64 && digest.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)))
+}
+
+
+/// Normalizes a path within a tarball (e.g., `./a/../b/c` into `b/c`),
+/// returning `None` if it escapes the root of the tarball.
+///
+pub(crate) fn normalize(path: &Path) -> Option<String> {
+    let mut components: Vec<&str> = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?),
+            Component::ParentDir => {
+                components.pop()?;
+            },
+            Component::CurDir | Component::RootDir => (),
+            Component::Prefix(_) => return None,
+        }
+    }
+
+    if components.is_empty() {
+        return None;
+    }
+
+    Some(components.join("/"))
+}
+
+
+
+#[cfg(test)]
+mod tarball_index_tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    fn tarball(build: impl FnOnce(&mut tar::Builder<&mut NamedTempFile>)) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+
+        {
+            let mut builder = tar::Builder::new(&mut file);
+            build(&mut builder);
+            builder.finish().unwrap();
+        }
+
+        file.flush().unwrap();
+        file
+    }
+
+    fn append_file(builder: &mut tar::Builder<&mut NamedTempFile>, path: &str, contents: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        builder.append_data(&mut header, path, contents).unwrap();
+    }
+
+    fn append_link(builder: &mut tar::Builder<&mut NamedTempFile>, entry_type: EntryType, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mode(0o777);
+
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    #[test]
+    fn test_digest_from_name() {
+        let digest = "e4f8be873d750c0f729a43cac89e1c07a347690c3a8464f35c83109b82b0aa09";
+
+        assert_eq!(digest_from_name(&format!("blobs/sha256/{}", digest)), Some(digest));
+        assert!(digest_from_name(&format!("blobs/sha512/{}", digest)).is_none());
+        assert!(digest_from_name("blobs/sha256/abc").is_none());
+        assert!(digest_from_name(&format!("{}/layer.tar", digest)).is_none());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("./a/b")).unwrap(), "a/b");
+        assert_eq!(normalize(Path::new("a/../b/./c")).unwrap(), "b/c");
+        assert!(normalize(Path::new("../a")).is_none());
+        assert!(normalize(Path::new(".")).is_none());
+    }
+
+    #[test]
+    fn test_indexes_the_contents_of_members() {
+        let file = tarball(|builder| {
+            append_file(builder, "a.json", b"{}");
+            append_file(builder, "dir/layer.tar", b"layer");
+        });
+
+        let index = TarballIndex::new(file.path()).unwrap();
+        let member = index.member("dir/layer.tar").unwrap();
+
+        assert_eq!(member.size, 5);
+        assert_eq!(member.digest, digest::compute(&b"layer"[..]).unwrap());
+        assert_eq!(index.read(file.path(), "./dir/layer.tar").unwrap(), b"layer");
+        assert_eq!(index.read(file.path(), "a.json").unwrap(), b"{}");
+
+        assert!(index.member("missing").is_err());
+    }
+
+    #[test]
+    fn test_follows_links() {
+        let file = tarball(|builder| {
+            append_file(builder, "a/layer.tar", b"layer");
+            append_link(builder, EntryType::Symlink, "b/layer.tar", "../a/layer.tar");
+            append_link(builder, EntryType::Link, "c/layer.tar", "b/layer.tar");
+        });
+
+        let index = TarballIndex::new(file.path()).unwrap();
+
+        assert_eq!(index.member("b/layer.tar").unwrap(), index.member("a/layer.tar").unwrap());
+        assert_eq!(index.read(file.path(), "c/layer.tar").unwrap(), b"layer");
+    }
+
+    #[test]
+    fn test_rejects_link_cycles() {
+        let file = tarball(|builder| {
+            append_link(builder, EntryType::Symlink, "a", "b");
+            append_link(builder, EntryType::Symlink, "b", "a");
+        });
+
+        let index = TarballIndex::new(file.path()).unwrap();
+
+        assert!(index.member("a").is_err());
+    }
+
+    #[test]
+    fn test_stages_members_as_they_are_read() {
+        let file = tarball(|builder| {
+            append_file(builder, "a/layer.tar", b"layer");
+            append_link(builder, EntryType::Symlink, "b/layer.tar", "../a/layer.tar");
+            append_file(builder, "manifest.json", b"[]");
+        });
+
+        let dir = tempdir().unwrap();
+        let index = TarballIndex::stage(File::open(file.path()).unwrap(), dir.path()).unwrap();
+
+        let member = index.member("b/layer.tar").unwrap();
+        let staged = dir.path().join(digest::prepend_sha_scheme(&member.digest));
+
+        assert_eq!(std::fs::read(staged).unwrap(), b"layer");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,176 @@
+use hyper::header::HeaderMap;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::error::Result;
+
+
+/// Name that spans get exported under.
+///
+pub const SERVICE_NAME: &str = "cartorio";
+
+
+/// Exports the spans recorded by the process to an OpenTelemetry
+/// collector, if any, until shut down.
+///
+/// ```txt
+///
+///    request  GET /v2/library/nginx/blobs/sha256:abc     (traceparent: 00-4bf9...-00f0...-01)
+///    ├── authenticate
+///    ├── handle_registry_blobs
+///    │   ├── is_blob_reachable
+///    │   ├── metadata
+///    │   └── mapped
+///    └── send_body
+///
+/// ```
+///
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+
+impl Telemetry {
+
+    /// Sets up exporting spans over OTLP (HTTP/protobuf) to `endpoint`
+    /// (e.g., `http://localhost:4318/v1/traces`), or not exporting them at
+    /// all when `None`.
+    ///
+    pub fn new(endpoint: Option<&str>) -> Result<Telemetry> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(Telemetry { provider: None }),
+        };
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| failure::format_err!("invalid otlp endpoint `{}` - {}", endpoint, err))?;
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build();
+
+        Ok(Telemetry { provider: Some(provider) })
+    }
+
+
+    /// The provider of the tracer that spans get recorded with, if they're
+    /// exported at all.
+    ///
+    pub fn provider(&self) -> Option<&SdkTracerProvider> {
+        self.provider.as_ref()
+    }
+
+
+    /// Flushes the spans not yet exported, waiting for them to be sent.
+    ///
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("error: failed to flush spans - {}", err);
+            }
+        }
+    }
+
+}
+
+
+/// Gives the propagator access to the headers of a request.
+///
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+
+impl Extractor for HeaderExtractor<'_> {
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+
+}
+
+
+/// The context that a request got sent within, as propagated by the client
+/// through the W3C Trace Context headers (`traceparent` and `tracestate`).
+///
+/// ```txt
+/// traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+/// ```
+///
+/// Requests without (valid) headers give back an empty context, making
+/// their spans the roots of new traces.
+///
+pub fn parent_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+
+/// The trace that `span` belongs to, if it's being recorded.
+///
+pub fn trace_id(span: &Span) -> Option<String> {
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+
+    if span_context.is_valid() {
+        Some(span_context.trace_id().to_string())
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod telemetry_tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_str(traceparent).unwrap());
+        headers
+    }
+
+    #[test]
+    fn extracts_the_parent_from_traceparent() {
+        let context = parent_context(&headers("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        let span_context = context.span().span_context().clone();
+
+        assert!(span_context.is_remote());
+        assert!(span_context.is_sampled());
+        assert_eq!(span_context.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn ignores_missing_or_malformed_traceparent() {
+        assert!(!parent_context(&HeaderMap::new()).span().span_context().is_valid());
+        assert!(!parent_context(&headers("00-xyz-00f067aa0ba902b7-01")).span().span_context().is_valid());
+        assert!(
+            !parent_context(&headers("00-00000000000000000000000000000000-00f067aa0ba902b7-01"))
+                .span()
+                .span_context()
+                .is_valid(),
+            "must reject the all-zeros trace id"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_endpoints() {
+        assert!(Telemetry::new(None).unwrap().provider().is_none());
+        assert!(Telemetry::new(Some("not a url")).is_err());
+    }
+}
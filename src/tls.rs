@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+use crate::error::Result;
+use crate::file_watch::{FileWatch, WATCH_INTERVAL};
+
+
+/// Files that configure TLS termination.
+///
+#[derive(Clone)]
+pub struct TlsOptions {
+
+    /// PEM file with the certificate chain to present to clients (leaf
+    /// first).
+    ///
+    pub cert: PathBuf,
+
+    /// PEM file with the private key of the leaf certificate.
+    ///
+    pub key: PathBuf,
+
+    /// PEM file with the certificate authorities that client certificates
+    /// must be signed by.
+    ///
+    /// When set, clients that do not present a valid certificate are
+    /// refused (mutual TLS).
+    ///
+    pub client_ca: Option<PathBuf>,
+}
+
+
+/// TLS termination for the connections accepted by the server.
+///
+/// The configuration gets rebuilt from the files in [`TlsOptions`]
+/// whenever [`reload`] is called, which [`watch`] does on `SIGHUP` or once
+/// any of those files change. Connections already established keep the
+/// configuration they were accepted with.
+///
+/// [`TlsOptions`]: struct.TlsOptions.html
+/// [`reload`]: struct.Tls.html#method.reload
+/// [`watch`]: struct.Tls.html#method.watch
+///
+pub struct Tls {
+    options: TlsOptions,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+
+impl Tls {
+
+    /// Loads the certificates and keys referenced by `options`.
+    ///
+    pub fn new(options: TlsOptions) -> Result<Tls> {
+        let config = server_config(&options)?;
+
+        Ok(Tls {
+            options,
+            config: RwLock::new(Arc::new(config)),
+        })
+    }
+
+
+    /// Acceptor for new connections, using the current configuration.
+    ///
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+
+    /// Reloads the certificates and keys from disk.
+    ///
+    /// If they can't be loaded, the current configuration is kept.
+    ///
+    pub fn reload(&self) -> Result<()> {
+        let config = server_config(&self.options)?;
+
+        *self.config.write().unwrap() = Arc::new(config);
+
+        Ok(())
+    }
+
+
+    /// Reloads the configuration on `SIGHUP` and whenever the files it's
+    /// made of change, never returning.
+    ///
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        let mut paths = vec![self.options.cert.clone(), self.options.key.clone()];
+        paths.extend(self.options.client_ca.clone());
+
+        let mut files = FileWatch::new(paths);
+
+        loop {
+            tokio::select! {
+                _ = hangups.recv() => (),
+                _ = interval.tick() => {
+                    if !files.changed() {
+                        continue;
+                    }
+                },
+            }
+
+            match self.reload() {
+                Ok(()) => info!("reloaded tls certificates"),
+                Err(err) => error!(error = %err, "failed to reload tls certificates"),
+            }
+        }
+    }
+
+}
+
+
+/// Builds the configuration for terminating TLS as described by `options`.
+///
+fn server_config(options: &TlsOptions) -> Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let certs = CertificateDer::pem_file_iter(&options.cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| failure::format_err!("failed to read {:?} - {}", options.cert, err))?;
+
+    if certs.is_empty() {
+        return Err(failure::format_err!("no certificates found in {:?}", options.cert));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&options.key)
+        .map_err(|err| failure::format_err!("failed to read {:?} - {}", options.key, err))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &options.client_ca {
+        None => builder.with_no_client_auth(),
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in CertificateDer::pem_file_iter(client_ca)
+                .map_err(|err| failure::format_err!("failed to read {:?} - {}", client_ca, err))?
+            {
+                roots.add(cert.map_err(|err| failure::format_err!("failed to read {:?} - {}", client_ca, err))?)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()?;
+
+            builder.with_client_cert_verifier(verifier)
+        },
+    };
+
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
@@ -0,0 +1,547 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use hyper::Method;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+
+/// For how long tokens issued by [`TokenIssuer`] are valid, in seconds.
+///
+/// [`TokenIssuer`]: struct.TokenIssuer.html
+///
+pub const TOKEN_EXPIRATION: u64 = 300;
+
+
+/// Settings for the Docker token authentication flow.
+///
+/// ```txt
+///
+///   client ---- GET /v2/foo/manifests/latest ---------------> cartorio
+///          <--- 401 WWW-Authenticate: Bearer realm=$realm,
+///                   service=$service,
+///                   scope="repository:foo:pull" ----------
+///
+///          ---- GET $realm?service=$service&scope=... ------> token issuer
+///          <--- { "token": "$jwt" } -------------------------
+///
+///          ---- GET /v2/foo/manifests/latest ---------------> cartorio
+///               Authorization: Bearer $jwt
+///
+/// ```
+///
+#[derive(Clone, Default)]
+pub struct TokenOptions {
+
+    /// URL of the token issuer that clients are pointed at.
+    ///
+    pub realm: String,
+
+    /// Name of this registry as a service, which tokens must be issued for
+    /// (`aud`).
+    ///
+    pub service: String,
+
+    /// Who tokens must be issued by (`iss`).
+    ///
+    pub issuer: String,
+
+    /// PEM file with the public key (RSA or ECDSA P-256) that tokens must
+    /// be signed with.
+    ///
+    /// When not set, the public key of `signing_key` is used.
+    ///
+    pub public_key: Option<PathBuf>,
+
+    /// PEM file with a PKCS#8 private key (RSA or ECDSA P-256) used to sign
+    /// the tokens issued by cartorio itself under `/token`.
+    ///
+    pub signing_key: Option<PathBuf>,
+}
+
+
+/// Actions that can be performed against a repository.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Pull,
+    Push,
+    Delete,
+
+    /// Seeing that the repository exists through the catalog.
+    ///
+    List,
+}
+
+
+impl Action {
+
+    /// The action that a request with `method` performs.
+    ///
+    pub fn for_method(method: &Method) -> Action {
+        match *method {
+            Method::DELETE => Action::Delete,
+            Method::PUT | Method::POST | Method::PATCH => Action::Push,
+            _ => Action::Pull,
+        }
+    }
+
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Pull => "pull",
+            Action::Push => "push",
+            Action::Delete => "delete",
+            Action::List => "list",
+        }
+    }
+
+}
+
+
+impl FromStr for Action {
+
+    type Err = failure::Error;
+
+    fn from_str(action: &str) -> Result<Action> {
+        match action {
+            "pull" => Ok(Action::Pull),
+            "push" => Ok(Action::Push),
+            "delete" => Ok(Action::Delete),
+            "list" => Ok(Action::List),
+            _ => Err(failure::format_err!("unknown action `{}`", action)),
+        }
+    }
+
+}
+
+
+/// Access to a resource, both as requested by clients through scopes
+/// (`repository:foo/bar:pull,push`) and as granted in tokens.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+
+    pub name: String,
+
+    pub actions: Vec<String>,
+}
+
+
+impl Access {
+
+    /// Access to perform `action` against the repository `name`.
+    ///
+    pub fn repository(name: &str, action: Action) -> Access {
+        Access {
+            resource_type: "repository".to_owned(),
+            name: name.to_owned(),
+            actions: vec![action.as_str().to_owned()],
+        }
+    }
+
+
+    /// Access to list the repositories through `/v2/_catalog`.
+    ///
+    pub fn catalog() -> Access {
+        Access {
+            resource_type: "registry".to_owned(),
+            name: "catalog".to_owned(),
+            actions: vec!["*".to_owned()],
+        }
+    }
+
+
+    /// Whether this access covers everything that `requested` asks for.
+    ///
+    pub fn allows(&self, requested: &Access) -> bool {
+        self.resource_type == requested.resource_type
+            && self.name == requested.name
+            && requested
+                .actions
+                .iter()
+                .all(|action| self.actions.iter().any(|granted| granted == action || granted == "*"))
+    }
+
+}
+
+
+impl FromStr for Access {
+
+    type Err = failure::Error;
+
+    /// Parses a scope (`type:name:action[,action]`).
+    ///
+    /// As names might contain a registry host with a port, the name is
+    /// everything between the first and the last `:`.
+    ///
+    fn from_str(scope: &str) -> Result<Access> {
+        let first = scope.find(':');
+        let last = scope.rfind(':');
+
+        match (first, last) {
+            (Some(first), Some(last)) if first < last => Ok(Access {
+                resource_type: scope[..first].to_owned(),
+                name: scope[first + 1..last].to_owned(),
+                actions: scope[last + 1..]
+                    .split(',')
+                    .filter(|action| !action.is_empty())
+                    .map(|action| action.to_owned())
+                    .collect(),
+            }),
+            _ => Err(failure::format_err!("malformed scope `{}`", scope)),
+        }
+    }
+
+}
+
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.resource_type, self.name, self.actions.join(","))
+    }
+}
+
+
+/// The claims carried by a token.
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: u64,
+    pub nbf: u64,
+    pub iat: u64,
+    pub jti: String,
+
+    #[serde(default)]
+    pub access: Vec<Access>,
+}
+
+
+impl Claims {
+
+    /// Whether the token grants `requested`.
+    ///
+    pub fn allows(&self, requested: &Access) -> bool {
+        self.access.iter().any(|granted| granted.allows(requested))
+    }
+
+}
+
+
+/// Validates tokens presented by clients through `Authorization: Bearer`.
+///
+pub struct TokenAuth {
+    options: TokenOptions,
+    key: DecodingKey,
+    algorithm: Algorithm,
+}
+
+
+impl TokenAuth {
+
+    /// Loads the public key that tokens are verified against.
+    ///
+    pub fn new(options: TokenOptions) -> Result<TokenAuth> {
+        let (path, public_key_pem) = match (&options.public_key, &options.signing_key) {
+            (Some(public_key), _) => (public_key, std::fs::read_to_string(public_key)?),
+            (None, Some(signing_key)) => {
+                let public_key_pem = rcgen::KeyPair::from_pem(&std::fs::read_to_string(signing_key)?)
+                    .map_err(|err| failure::format_err!("{:?}: {}", signing_key, err))?
+                    .public_key_pem();
+
+                (signing_key, public_key_pem)
+            },
+            (None, None) => {
+                return Err(failure::format_err!("token auth requires a public or a signing key"));
+            },
+        };
+
+        let (key, algorithm) = if let Ok(key) = DecodingKey::from_ec_pem(public_key_pem.as_bytes()) {
+            check_es256_curve(path, public_key_pem.as_bytes())?;
+            (key, Algorithm::ES256)
+        } else if let Ok(key) = DecodingKey::from_rsa_pem(public_key_pem.as_bytes()) {
+            (key, Algorithm::RS256)
+        } else {
+            return Err(failure::format_err!("public key must be either RSA or ECDSA P-256"));
+        };
+
+        Ok(TokenAuth { options, key, algorithm })
+    }
+
+
+    /// Validates the token in the `Authorization` header, returning its
+    /// claims if it's been issued for this service and is still valid.
+    ///
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<Claims> {
+        let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+
+        let mut parts = value.splitn(2, ' ');
+        let scheme = parts.next()?;
+        let token = parts.next()?.trim();
+
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_audience(&[&self.options.service]);
+        validation.set_issuer(&[&self.options.issuer]);
+        validation.set_required_spec_claims(&["exp", "nbf", "aud", "iss"]);
+        validation.validate_nbf = true;
+
+        jsonwebtoken::decode::<Claims>(token, &self.key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+
+
+    /// Value of the `WWW-Authenticate` header sent along with responses to
+    /// requests that lack a token granting `scope`.
+    ///
+    pub fn challenge(&self, scope: Option<&Access>, insufficient: bool) -> String {
+        let mut challenge = format!(
+            "Bearer realm={},service={}",
+            quoted(&self.options.realm), quoted(&self.options.service),
+        );
+
+        if let Some(scope) = scope {
+            challenge.push_str(&format!(",scope={}", quoted(&scope.to_string())));
+        }
+
+        if insufficient {
+            challenge.push_str(",error=\"insufficient_scope\"");
+        }
+
+        challenge
+    }
+
+}
+
+
+/// The body of the response to a token request.
+///
+#[derive(Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+
+/// A minimal token issuer, signing tokens for the access requested by
+/// clients.
+///
+pub struct TokenIssuer {
+    options: TokenOptions,
+    key: EncodingKey,
+    algorithm: Algorithm,
+}
+
+
+impl TokenIssuer {
+
+    /// Loads the private key that tokens get signed with.
+    ///
+    pub fn new(options: TokenOptions) -> Result<TokenIssuer> {
+        let signing_key = options
+            .signing_key
+            .clone()
+            .ok_or_else(|| failure::format_err!("token issuer requires a signing key"))?;
+
+        let (key, algorithm) = read_signing_key(&signing_key)?;
+
+        Ok(TokenIssuer { options, key, algorithm })
+    }
+
+
+    /// Issues a token to `subject` granting `access`.
+    ///
+    pub fn issue(&self, subject: &str, access: Vec<Access>) -> Result<TokenResponse> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let claims = Claims {
+            iss: self.options.issuer.clone(),
+            sub: subject.to_owned(),
+            aud: self.options.service.clone(),
+            exp: now + TOKEN_EXPIRATION,
+            nbf: now,
+            iat: now,
+            jti: hex::encode(rand_bytes()?),
+            access,
+        };
+
+        let token = jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.key)?;
+
+        Ok(TokenResponse {
+            token: token.clone(),
+            access_token: token,
+            expires_in: TOKEN_EXPIRATION,
+        })
+    }
+
+}
+
+
+fn read_signing_key(path: &Path) -> Result<(EncodingKey, Algorithm)> {
+    let pem = std::fs::read(path)?;
+
+    if let Ok(key) = EncodingKey::from_ec_pem(&pem) {
+        check_es256_curve(path, &pem)?;
+        return Ok((key, Algorithm::ES256));
+    }
+
+    if let Ok(key) = EncodingKey::from_rsa_pem(&pem) {
+        return Ok((key, Algorithm::RS256));
+    }
+
+    Err(failure::format_err!("{:?}: signing key must be either RSA or ECDSA P-256", path))
+}
+
+
+/// OID of the P-256 curve (`prime256v1`), DER encoded.
+///
+const P256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+
+/// Makes sure that the ECDSA key in `pem` (read from `path`) is on P-256,
+/// the only curve that tokens can be signed on with ES256 - keys on other
+/// curves would otherwise fail to verify every token.
+///
+fn check_es256_curve(path: &Path, pem: &[u8]) -> Result<()> {
+    let base64: String = String::from_utf8_lossy(pem)
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    let der = base64::engine::general_purpose::STANDARD
+        .decode(base64.trim())
+        .map_err(|err| failure::format_err!("{:?}: {}", path, err))?;
+
+    if !der.windows(P256_OID.len()).any(|window| window == P256_OID) {
+        return Err(failure::format_err!(
+            "{:?}: ECDSA keys must be on the P-256 curve, as tokens are signed with ES256", path,
+        ));
+    }
+
+    Ok(())
+}
+
+
+/// `value` as a quoted string of a header parameter, with `"` and `\`
+/// escaped.
+///
+fn quoted(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+
+/// Random bytes for token identifiers.
+///
+fn rand_bytes() -> Result<[u8; 16]> {
+    let mut bytes = [0; 16];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+        .map_err(|_| failure::format_err!("failed to generate random bytes"))?;
+
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod token_tests {
+    use super::*;
+
+    #[test]
+    fn parses_scopes() {
+        let access: Access = "repository:library/nginx:pull,push".parse().unwrap();
+
+        assert_eq!(access.resource_type, "repository");
+        assert_eq!(access.name, "library/nginx");
+        assert_eq!(access.actions, vec!["pull", "push"]);
+
+        let access: Access = "repository:localhost:5000/foo:pull".parse().unwrap();
+        assert_eq!(access.name, "localhost:5000/foo");
+
+        assert!("repository".parse::<Access>().is_err());
+        assert_eq!(
+            "repository:a:pull".parse::<Access>().unwrap().to_string(),
+            "repository:a:pull"
+        );
+    }
+
+    #[test]
+    fn access_allows() {
+        let granted: Access = "repository:a:pull,push".parse().unwrap();
+
+        assert!(granted.allows(&Access::repository("a", Action::Pull)));
+        assert!(granted.allows(&"repository:a:pull,push".parse().unwrap()));
+        assert!(!granted.allows(&Access::repository("a", Action::Delete)));
+        assert!(!granted.allows(&Access::repository("b", Action::Pull)));
+
+        let wildcard: Access = "repository:a:*".parse().unwrap();
+        assert!(wildcard.allows(&Access::repository("a", Action::Delete)));
+    }
+
+    #[test]
+    fn refuses_ecdsa_keys_not_on_p256() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+
+        std::fs::write(dir.path().join("signing.pem"), key.serialize_pem()).unwrap();
+        std::fs::write(dir.path().join("public.pem"), key.public_key_pem()).unwrap();
+
+        let options = TokenOptions {
+            signing_key: Some(dir.path().join("signing.pem")),
+            ..TokenOptions::default()
+        };
+
+        let err = TokenIssuer::new(options.clone()).err().unwrap();
+        assert!(err.to_string().contains("P-256"), "{}", err);
+
+        let err = TokenAuth::new(options.clone()).err().unwrap();
+        assert!(err.to_string().contains("P-256"), "{}", err);
+
+        let err = TokenAuth::new(TokenOptions {
+            public_key: Some(dir.path().join("public.pem")),
+            ..options
+        }).err().unwrap();
+        assert!(err.to_string().contains("P-256"), "{}", err);
+
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        std::fs::write(dir.path().join("public.pem"), key.public_key_pem()).unwrap();
+
+        TokenAuth::new(TokenOptions {
+            public_key: Some(dir.path().join("public.pem")),
+            ..TokenOptions::default()
+        }).unwrap();
+    }
+
+    #[test]
+    fn escapes_challenge_parameters() {
+        assert_eq!(quoted("cartorio"), r#""cartorio""#);
+        assert_eq!(quoted(r#"a "quoted\" name"#), r#""a \"quoted\\\" name""#);
+    }
+}
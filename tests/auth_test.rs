@@ -0,0 +1,523 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tempfile::{tempdir, TempDir};
+use tokio::net::{TcpListener, TcpStream};
+
+use cartorio::blobstore::BlobStore;
+use cartorio::server;
+
+mod common;
+
+use common::{start_server, write_htpasswd};
+
+async fn get(addr: SocketAddr, path: &str, authorization: Option<&str>) -> (Response<hyper::body::Incoming>, Bytes) {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+
+    tokio::spawn(conn);
+
+    let mut req = Request::get(path).header("host", addr.to_string());
+    if let Some(authorization) = authorization {
+        req = req.header("authorization", authorization);
+    }
+
+    let mut resp = sender
+        .send_request(req.body(Empty::<Bytes>::new()).unwrap())
+        .await
+        .unwrap();
+
+    let body = resp.body_mut().collect().await.unwrap().to_bytes();
+
+    (resp, body)
+}
+
+fn basic(user: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+    )
+}
+
+mod basic {
+    use super::*;
+
+    async fn start_basic_auth_server(dir: &Path) -> (SocketAddr, TempDir) {
+        let htpasswd = dir.join("htpasswd");
+        write_htpasswd(&htpasswd, &[("alice", "s3cr3t")]);
+
+        start_server(server::Options {
+            htpasswd: Some(htpasswd),
+            ..Default::default()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn challenges_requests_without_credentials() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_basic_auth_server(dir.path()).await;
+
+        for path in &["/v2/", "/v2/a/manifests/latest"] {
+            let (resp, body) = get(addr, path, None).await;
+
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                resp.headers()["www-authenticate"],
+                "Basic realm=\"cartorio\""
+            );
+            assert!(String::from_utf8_lossy(&body).contains("UNAUTHORIZED"));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_invalid_credentials() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_basic_auth_server(dir.path()).await;
+
+        let (resp, _) = get(addr, "/v2/", Some(&basic("alice", "wrong"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let (resp, _) = get(addr, "/v2/", Some(&basic("bob", "s3cr3t"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn serves_authenticated_requests() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_basic_auth_server(dir.path()).await;
+
+        let (resp, _) = get(addr, "/v2/", Some(&basic("alice", "s3cr3t"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&basic("alice", "s3cr3t"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn lets_liveness_checks_through() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_basic_auth_server(dir.path()).await;
+
+        let (resp, _) = get(addr, "/_live", None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (resp, _) = get(addr, "/_ready", None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rereads_htpasswd_when_it_changes() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_basic_auth_server(dir.path()).await;
+
+        write_htpasswd(&dir.path().join("htpasswd"), &[("bob", "hunter2")]);
+
+        for _ in 0..30 {
+            let (resp, _) = get(addr, "/v2/", Some(&basic("bob", "hunter2"))).await;
+
+            if resp.status() == StatusCode::OK {
+                let (resp, _) = get(addr, "/v2/", Some(&basic("alice", "s3cr3t"))).await;
+                assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "must forget removed users");
+
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        panic!("htpasswd file was not re-read");
+    }
+
+    #[tokio::test]
+    async fn fails_to_start_with_invalid_htpasswd() {
+        let dir = tempdir().unwrap();
+        let htpasswd = dir.path().join("htpasswd");
+        std::fs::write(&htpasswd, "alice:plaintext").unwrap();
+
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let result = server::run(listener, blobstore, server::Options {
+            htpasswd: Some(htpasswd),
+            ..Default::default()
+        })
+        .await;
+
+        assert!(result.is_err());
+    }
+}
+
+mod token {
+    use super::*;
+
+    use cartorio::token::{Access, Claims, TokenIssuer, TokenOptions, TokenResponse};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use rcgen::KeyPair;
+
+    const REALM: &str = "https://auth.example.com/token";
+
+    fn token_options(dir: &Path) -> TokenOptions {
+        let key = KeyPair::generate().unwrap();
+        std::fs::write(dir.join("signing.pem"), key.serialize_pem()).unwrap();
+
+        TokenOptions {
+            realm: REALM.to_owned(),
+            service: "cartorio".to_owned(),
+            issuer: "cartorio".to_owned(),
+            public_key: None,
+            signing_key: Some(dir.join("signing.pem")),
+        }
+    }
+
+    async fn start_token_auth_server(dir: &Path) -> (SocketAddr, TempDir) {
+        let htpasswd = dir.join("htpasswd");
+        write_htpasswd(&htpasswd, &[("alice", "s3cr3t")]);
+
+        start_server(server::Options {
+            htpasswd: Some(htpasswd),
+            token: Some(token_options(dir)),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn fetch_token(addr: SocketAddr, scope: &str) -> String {
+        let path = format!("/token?service=cartorio&scope={}", scope);
+        let (resp, body) = get(addr, &path, Some(&basic("alice", "s3cr3t"))).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token: TokenResponse = serde_json::from_slice(&body).unwrap();
+        format!("Bearer {}", token.token)
+    }
+
+    #[tokio::test]
+    async fn challenges_requests_without_tokens() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_token_auth_server(dir.path()).await;
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", None).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers()["www-authenticate"],
+            "Bearer realm=\"https://auth.example.com/token\",service=\"cartorio\",scope=\"repository:a:pull\""
+        );
+
+        let (resp, _) = get(addr, "/v2/", None).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers()["www-authenticate"],
+            "Bearer realm=\"https://auth.example.com/token\",service=\"cartorio\""
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_requests_with_tokens_granting_access() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_token_auth_server(dir.path()).await;
+
+        let token = fetch_token(addr, "repository:a:pull").await;
+
+        let (resp, _) = get(addr, "/v2/", Some(&token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn refuses_tokens_for_other_repositories() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_token_auth_server(dir.path()).await;
+
+        let token = fetch_token(addr, "repository:b:pull").await;
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers()["www-authenticate"]
+            .to_str()
+            .unwrap()
+            .ends_with("scope=\"repository:a:pull\",error=\"insufficient_scope\""));
+    }
+
+    #[tokio::test]
+    async fn issues_tokens_only_for_pulling_without_policy() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_server(server::Options {
+            token: Some(token_options(dir.path())),
+            ..Default::default()
+        })
+        .await;
+
+        let (resp, body) = get(addr, "/token?service=cartorio&scope=repository:a:pull,push,delete", None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token: TokenResponse = serde_json::from_slice(&body).unwrap();
+        let claims = token.token.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(claims).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            claims["access"],
+            serde_json::json!([{ "type": "repository", "name": "a", "actions": ["pull"] }])
+        );
+    }
+
+    #[tokio::test]
+    async fn issues_tokens_only_to_authenticated_users() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_token_auth_server(dir.path()).await;
+
+        let (resp, _) = get(addr, "/token?service=cartorio&scope=repository:a:pull", None).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["www-authenticate"], "Basic realm=\"cartorio\"");
+
+        let (resp, _) = get(
+            addr,
+            "/token?service=cartorio&scope=repository:a:pull",
+            Some(&basic("alice", "wrong")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refuses_expired_and_foreign_tokens() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_token_auth_server(dir.path()).await;
+
+        let claims = |exp: u64| Claims {
+            iss: "cartorio".to_owned(),
+            sub: "alice".to_owned(),
+            aud: "cartorio".to_owned(),
+            exp,
+            nbf: 0,
+            iat: 0,
+            jti: "jti".to_owned(),
+            access: vec!["repository:a:pull".parse::<Access>().unwrap()],
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let key = EncodingKey::from_ec_pem(&std::fs::read(dir.path().join("signing.pem")).unwrap()).unwrap();
+        let expired = jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims(now - 3600), &key).unwrap();
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&format!("Bearer {}", expired))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "must refuse expired tokens");
+
+        let foreign_key = EncodingKey::from_ec_pem(KeyPair::generate().unwrap().serialize_pem().as_bytes()).unwrap();
+        let foreign = jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims(now + 3600), &foreign_key).unwrap();
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&format!("Bearer {}", foreign))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "must refuse tokens signed by other keys");
+    }
+
+    #[tokio::test]
+    async fn validates_tokens_from_external_issuers() {
+        let dir = tempdir().unwrap();
+        let issuer_options = token_options(dir.path());
+
+        let key = KeyPair::from_pem(&std::fs::read_to_string(dir.path().join("signing.pem")).unwrap()).unwrap();
+        std::fs::write(dir.path().join("public.pem"), key.public_key_pem()).unwrap();
+
+        let (addr, _blobstore) = start_server(server::Options {
+            token: Some(TokenOptions {
+                public_key: Some(dir.path().join("public.pem")),
+                signing_key: None,
+                ..issuer_options.clone()
+            }),
+            ..Default::default()
+        })
+        .await;
+
+        let (resp, _) = get(addr, "/token?service=cartorio&scope=repository:a:pull", None).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "must not issue tokens without a signing key");
+
+        let token = TokenIssuer::new(issuer_options)
+            .unwrap()
+            .issue("alice", vec!["repository:a:pull".parse().unwrap()])
+            .unwrap();
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&format!("Bearer {}", token.token))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+mod policy {
+    use super::*;
+
+    use cartorio::token::{TokenIssuer, TokenOptions, TokenResponse};
+    use rcgen::KeyPair;
+
+    const POLICY: &str = r#"
+[groups]
+team-a = ["alice"]
+
+[[rules]]
+subjects = ["group:team-a"]
+repositories = ["team-a/*"]
+actions = ["pull", "list"]
+
+[[rules]]
+subjects = ["*"]
+repositories = ["a"]
+actions = ["pull"]
+"#;
+
+    /// Starts a server with the fixture tagged under `team-a/app` and
+    /// `team-b/app` too, letting `alice` and `bob` in.
+    ///
+    async fn start_policy_server(dir: &Path, token: Option<TokenOptions>) -> (SocketAddr, TempDir) {
+        let htpasswd = dir.join("htpasswd");
+        write_htpasswd(&htpasswd, &[("alice", "s3cr3t"), ("bob", "hunter2")]);
+        std::fs::write(dir.join("policy.toml"), POLICY).unwrap();
+
+        let (addr, blobstore_root_dir) = start_server(server::Options {
+            htpasswd: Some(htpasswd),
+            policy: Some(dir.join("policy.toml")),
+            token,
+            ..Default::default()
+        })
+        .await;
+
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+        let manifest = std::fs::read_link(blobstore.get_manifest("a", "latest")).unwrap();
+        let manifest = manifest.file_name().unwrap().to_str().unwrap();
+
+        blobstore.tag_manifest(manifest, "team-a/app", "latest").unwrap();
+        blobstore.tag_manifest(manifest, "team-b/app", "latest").unwrap();
+
+        (addr, blobstore_root_dir)
+    }
+
+    #[tokio::test]
+    async fn enforces_policy_on_repositories() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_policy_server(dir.path(), None).await;
+
+        let alice = basic("alice", "s3cr3t");
+        let bob = basic("bob", "hunter2");
+
+        let (resp, _) = get(addr, "/v2/team-a/app/manifests/latest", Some(&alice)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (resp, body) = get(addr, "/v2/team-b/app/manifests/latest", Some(&alice)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(String::from_utf8_lossy(&body).contains("DENIED"));
+
+        let (resp, _) = get(addr, "/v2/team-a/app/manifests/latest", Some(&bob)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", Some(&bob)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn hides_repositories_from_catalog() {
+        let dir = tempdir().unwrap();
+        let (addr, _blobstore) = start_policy_server(dir.path(), None).await;
+
+        let (_, body) = get(addr, "/v2/_catalog", Some(&basic("alice", "s3cr3t"))).await;
+        assert_eq!(body, r#"{"repositories":["team-a/app"]}"#);
+
+        let (_, body) = get(addr, "/v2/_catalog", Some(&basic("bob", "hunter2"))).await;
+        assert_eq!(body, r#"{"repositories":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn issues_tokens_only_for_what_policy_allows() {
+        let dir = tempdir().unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        std::fs::write(dir.path().join("signing.pem"), key.serialize_pem()).unwrap();
+
+        let token_options = TokenOptions {
+            realm: "https://auth.example.com/token".to_owned(),
+            service: "cartorio".to_owned(),
+            issuer: "cartorio".to_owned(),
+            public_key: None,
+            signing_key: Some(dir.path().join("signing.pem")),
+        };
+
+        let (addr, _blobstore) = start_policy_server(dir.path(), Some(token_options)).await;
+
+        let path = "/token?service=cartorio&scope=repository:team-a/app:pull,push&scope=registry:catalog:*";
+        let (resp, body) = get(addr, path, Some(&basic("alice", "s3cr3t"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token: TokenResponse = serde_json::from_slice(&body).unwrap();
+        let claims = token.token.split('.').nth(1).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(claims).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            claims["access"],
+            serde_json::json!([
+                { "type": "repository", "name": "team-a/app", "actions": ["pull"] },
+                { "type": "registry", "name": "catalog", "actions": ["*"] },
+            ])
+        );
+
+        let bearer = format!("Bearer {}", token.token);
+
+        let (resp, _) = get(addr, "/v2/team-a/app/manifests/latest", Some(&bearer)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (_, body) = get(addr, "/v2/_catalog", Some(&bearer)).await;
+        assert_eq!(body, r#"{"repositories":["team-a/app"]}"#);
+    }
+
+    #[tokio::test]
+    async fn enforces_policy_on_tokens_from_external_issuers() {
+        let dir = tempdir().unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        std::fs::write(dir.path().join("signing.pem"), key.serialize_pem()).unwrap();
+        std::fs::write(dir.path().join("public.pem"), key.public_key_pem()).unwrap();
+
+        let issuer_options = TokenOptions {
+            realm: "https://auth.example.com/token".to_owned(),
+            service: "cartorio".to_owned(),
+            issuer: "cartorio".to_owned(),
+            public_key: None,
+            signing_key: Some(dir.path().join("signing.pem")),
+        };
+
+        let token_options = TokenOptions {
+            public_key: Some(dir.path().join("public.pem")),
+            signing_key: None,
+            ..issuer_options.clone()
+        };
+
+        let (addr, _blobstore) = start_policy_server(dir.path(), Some(token_options)).await;
+        let issuer = TokenIssuer::new(issuer_options).unwrap();
+
+        let bearer = |user: &str| {
+            let token = issuer.issue(user, vec!["repository:team-a/app:pull".parse().unwrap()]).unwrap();
+            format!("Bearer {}", token.token)
+        };
+
+        let (resp, _) = get(addr, "/v2/team-a/app/manifests/latest", Some(&bearer("alice"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let (resp, body) = get(addr, "/v2/team-a/app/manifests/latest", Some(&bearer("bob"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "must not let tokens go past the policy");
+        assert!(String::from_utf8_lossy(&body).contains("DENIED"));
+    }
+}
@@ -37,3 +37,75 @@ fn test_blobstore_add_blob() {
         bucket_dir_entries.next().is_none(),
     );
 }
+
+
+#[test]
+fn test_blobstore_tag_manifest_moves_tags() {
+    let root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(root_dir.path()).unwrap();
+
+    let first = blobstore.add_raw_manifest(b"{\"first\": true}").unwrap();
+    let second = blobstore.add_raw_manifest(b"{\"second\": true}").unwrap();
+
+    blobstore.tag_manifest(&first, "name", "latest").unwrap();
+    blobstore.tag_manifest(&second, "name", "latest").unwrap();
+
+    assert_eq!(
+        fs::read_link(blobstore.get_manifest("name", "latest")).unwrap(),
+        blobstore.get_blob(&second),
+    );
+
+    // nothing but the tag is left behind.
+    //
+    assert_eq!(fs::read_dir(blobstore.manifests_dir.join("name")).unwrap().count(), 1);
+}
+
+
+#[test]
+fn test_blobstore_read_blob() {
+    let root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(root_dir.path()).unwrap();
+    let blob_path = root_dir.path().join("file.txt");
+
+    fs::write(&blob_path, "something").unwrap();
+    blobstore.add_blob(&blob_path).unwrap();
+
+    assert_eq!(
+        blobstore.read_blob("sha256:3fc9b689459d738f8c88a3a48aa9e33542016b7a4052e001aaa536fca74813cb").unwrap(),
+        b"something",
+    );
+
+    assert!(blobstore.read_blob("sha256:missing").is_err());
+}
+
+
+#[test]
+fn test_blobstore_add_raw_manifest_replaces_rather_than_rewrites() {
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+
+    let root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(root_dir.path()).unwrap();
+
+    let filename = blobstore.add_raw_manifest(b"{\"first\": true}").unwrap();
+
+    let mut opened = fs::File::open(blobstore.get_blob(&filename)).unwrap();
+    let inode = opened.metadata().unwrap().ino();
+
+    assert_eq!(blobstore.add_raw_manifest(b"{\"first\": true}").unwrap(), filename);
+
+    // whoever has the old file open (or mapped) still sees all of it.
+    //
+    let mut content = Vec::new();
+    opened.read_to_end(&mut content).unwrap();
+
+    assert_eq!(content, b"{\"first\": true}");
+    assert_ne!(fs::metadata(blobstore.get_blob(&filename)).unwrap().ino(), inode);
+    assert_eq!(fs::read(blobstore.get_blob(&filename)).unwrap(), b"{\"first\": true}");
+
+    // and no staging directory is left behind.
+    //
+    assert!(fs::read_dir(root_dir.path())
+        .unwrap()
+        .all(|entry| !entry.unwrap().file_name().to_str().unwrap().starts_with(".staging-")));
+}
@@ -0,0 +1,91 @@
+//! Helpers shared by the integration tests, each of which uses only some
+//! of them.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use tempfile::{tempdir, TempDir};
+use tokio::net::TcpListener;
+
+use cartorio::blobstore::BlobStore;
+use cartorio::docker_saved_tarball::DockerSavedTarball;
+use cartorio::registry::Manifest;
+use cartorio::server;
+
+
+/// Writes an htpasswd file at `path` with the bcrypt hashes of the
+/// passwords of `users`.
+///
+pub fn write_htpasswd(path: &Path, users: &[(&str, &str)]) {
+    let content: Vec<String> = users
+        .iter()
+        .map(|(user, password)| format!("{}:{}", user, bcrypt::hash(password, 4).unwrap()))
+        .collect();
+
+    fs::write(path, content.join("\n")).unwrap();
+}
+
+
+/// A blobstore that contains the `small-image` fixture (tagged as
+/// `a:latest`).
+///
+pub fn load_blobstore() -> (BlobStore, TempDir) {
+    let blobstore_root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+    let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let tarball_path = repository_root.join("tests/fixtures/small-image/image.tar");
+
+    DockerSavedTarball::new(&tarball_path, blobstore.clone())
+        .unwrap()
+        .load()
+        .unwrap();
+
+    (blobstore, blobstore_root_dir)
+}
+
+
+/// Starts a server in the background serving the blobstore of
+/// `load_blobstore`.
+///
+pub async fn start_server(options: server::Options) -> (SocketAddr, TempDir) {
+    let (blobstore, blobstore_root_dir) = load_blobstore();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(server::run(listener, blobstore, options));
+
+    (addr, blobstore_root_dir)
+}
+
+
+pub fn read_manifest(blobstore: &BlobStore, name: &str, reference: &str) -> Manifest {
+    serde_json::from_slice(&fs::read(blobstore.get_manifest(name, reference)).unwrap()).unwrap()
+}
+
+
+/// The filename (i.e., the digest) of the manifest that `name:reference`
+/// points at.
+///
+pub fn manifest_filename(blobstore: &BlobStore, name: &str, reference: &str) -> String {
+    fs::read_link(blobstore.get_manifest(name, reference))
+        .unwrap()
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+
+/// The configuration of the image of `manifest`, either as an `ImageConfig`
+/// or as a `serde_json::Value` to look at what cartorio doesn't model.
+///
+pub fn read_config<T: DeserializeOwned>(blobstore: &BlobStore, manifest: &Manifest) -> T {
+    serde_json::from_slice(&blobstore.read_blob(&manifest.config.digest).unwrap()).unwrap()
+}
@@ -5,12 +5,26 @@ use std::path::PathBuf;
 use tempfile::tempdir;
 
 use cartorio::blobstore::BlobStore;
+use cartorio::compression::{Compression, LayerCompression};
 use cartorio::concourse_image_resource::ConcourseImageResource;
+use cartorio::image_config::ImageConfig;
 
-const SAMPLE_RESOURCE_METADATA: &'static str = r#"{
-  "type": "registry-image",
-  "version": "v1.2.3"
-}"#;
+mod common;
+
+use common::{read_config, read_manifest};
+
+/// A copy of the resource fixture, in a directory of its own.
+///
+fn resource_dir() -> tempfile::TempDir {
+    let original_resource_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/resource");
+    let resource_dir = tempdir().unwrap();
+
+    for name in &["resource_metadata.json", "rootfs.tgz"] {
+        fs::copy(original_resource_dir.join(name), resource_dir.path().join(name)).unwrap();
+    }
+
+    resource_dir
+}
 
 mod load {
     use super::*;
@@ -37,10 +51,158 @@ mod load {
         )
         .unwrap();
 
-        let loader = ConcourseImageResource::new(resource_dir.path(), blobstore).unwrap();
+        let loader = ConcourseImageResource::new(resource_dir.path(), blobstore.clone()).unwrap();
         assert!(loader.load().is_ok());
 
-        // TODO read the config file
+        let manifest = read_manifest(&blobstore, "registry-image", "v1.2.3");
+        let config: ImageConfig = read_config(&blobstore, &manifest);
+
+        let layer = &manifest.layers[0];
+        assert_eq!(layer.media_type, "application/vnd.docker.image.rootfs.diff.tar");
+
+        let uncompressed = fs::read(blobstore.get_blob(&layer.digest)).unwrap();
+
+        assert_eq!(
+            config.rootfs.diff_ids,
+            vec![cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&uncompressed[..]).unwrap())],
+        );
+
+        // nothing gets written to the resource's directory.
+        //
+        let mut entries: Vec<_> = fs::read_dir(resource_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+
+        assert_eq!(entries, vec!["resource_metadata.json", "rootfs.tgz"]);
+        assert!(fs::symlink_metadata(blobstore.get_manifest("registry-image", "latest")).is_err());
+    }
+
+    #[test]
+    fn tags_under_the_name_and_tag_given() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let resource_dir = resource_dir();
+
+        ConcourseImageResource::new(resource_dir.path(), blobstore.clone())
+            .unwrap()
+            .name("team/ci-image")
+            .unwrap()
+            .tag("1.0")
+            .unwrap()
+            .tag_latest()
+            .load()
+            .unwrap();
+
+        let manifest_path = fs::read_link(blobstore.get_manifest("team/ci-image", "1.0")).unwrap();
+
+        assert_eq!(fs::read_link(blobstore.get_manifest("team/ci-image", "latest")).unwrap(), manifest_path);
+        assert!(fs::symlink_metadata(blobstore.get_manifest("registry-image", "v1.2.3")).is_err());
+    }
+
+    #[test]
+    fn fails_when_version_is_not_a_tag() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let resource_dir = resource_dir();
+
+        fs::write(
+            resource_dir.path().join("resource_metadata.json"),
+            br#"{"type": "registry-image", "version": "sha256:abc"}"#,
+        )
+        .unwrap();
+
+        let err = ConcourseImageResource::new(resource_dir.path(), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("set a tag instead"));
+
+        ConcourseImageResource::new(resource_dir.path(), blobstore)
+            .unwrap()
+            .tag("pinned")
+            .unwrap()
+            .load()
+            .unwrap();
+    }
+
+    #[test]
+    fn compresses_rootfs_keeping_uncompressed_diff_id() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let resource_dir = resource_dir();
+
+        ConcourseImageResource::new(resource_dir.path(), blobstore.clone())
+            .unwrap()
+            .compress_layers(LayerCompression::new(Compression::Zstd).unwrap())
+            .load()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "registry-image", "v1.2.3");
+        let config: ImageConfig = read_config(&blobstore, &manifest);
+
+        let layer = &manifest.layers[0];
+        assert_eq!(manifest.media_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(layer.media_type, "application/vnd.oci.image.layer.v1.tar+zstd");
+
+        let mut uncompressed = Vec::new();
+        cartorio::compression::decompress(fs::File::open(blobstore.get_blob(&layer.digest)).unwrap())
+            .unwrap()
+            .1
+            .read_to_end(&mut uncompressed)
+            .unwrap();
+
+        assert_eq!(
+            config.rootfs.diff_ids,
+            vec![cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&uncompressed[..]).unwrap())],
+        );
+        assert!(!resource_dir.path().join("rootfs.tar").exists());
+    }
+
+    #[test]
+    fn decompresses_every_gzip_member_of_rootfs() {
+        use flate2::read::MultiGzDecoder;
+        use flate2::write::GzEncoder;
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let resource_dir = resource_dir();
+        let rootfs_path = resource_dir.path().join("rootfs.tgz");
+
+        let mut tar = Vec::new();
+        MultiGzDecoder::new(fs::File::open(&rootfs_path).unwrap())
+            .read_to_end(&mut tar)
+            .unwrap();
+
+        // as written by parallel compressors (e.g., pigz), one member per
+        // chunk.
+        //
+        let mut rootfs = Vec::new();
+        for chunk in tar.chunks(tar.len() / 2 + 1) {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(chunk).unwrap();
+            rootfs.extend(encoder.finish().unwrap());
+        }
+        fs::write(&rootfs_path, rootfs).unwrap();
+
+        ConcourseImageResource::new(resource_dir.path(), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "registry-image", "v1.2.3");
+        let config: ImageConfig = read_config(&blobstore, &manifest);
+
+        let diff_id = cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&tar[..]).unwrap());
+        assert_eq!(config.rootfs.diff_ids, vec![diff_id.clone()]);
+        assert_eq!(manifest.layers[0].digest, diff_id);
+        assert_eq!(manifest.layers[0].size, tar.len() as u64);
     }
 
 }
@@ -68,7 +230,8 @@ mod new {
         let mut metadata_file = fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&resource_dir.path().join("resource_metadata.json"))
+            .truncate(true)
+            .open(resource_dir.path().join("resource_metadata.json"))
             .unwrap();
 
         metadata_file.write_all(b"ahuah").unwrap();
@@ -76,6 +239,17 @@ mod new {
         assert!(ConcourseImageResource::new(resource_dir.path(), blobstore,).is_err());
     }
 
+    #[test]
+    fn fails_with_invalid_name_or_tag() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let resource_dir = resource_dir();
+
+        assert!(ConcourseImageResource::new(resource_dir.path(), blobstore.clone()).unwrap().name("Team/CI").is_err());
+        assert!(ConcourseImageResource::new(resource_dir.path(), blobstore).unwrap().tag("sha256:abc").is_err());
+    }
+
     #[test]
     fn fails_without_rootfs() {
         let blobstore_root_dir = tempdir().unwrap();
@@ -21,6 +21,18 @@ fn test_compute_for_file() {
     );
 }
 
+#[test]
+fn test_compute_and_copy() {
+    let mut copy = Vec::new();
+
+    assert_eq!(
+        digest::compute_and_copy(&b"hello world"[..], &mut copy).unwrap(),
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+    );
+
+    assert_eq!(copy, b"hello world");
+}
+
 #[test]
 fn test_store_and_retrieve () {
     let dir = tempdir().unwrap();
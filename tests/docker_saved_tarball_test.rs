@@ -1,7 +1,7 @@
 use tempfile::tempdir;
 use std::path::{PathBuf};
 use cartorio::docker_saved_tarball::DockerSavedTarball;
-use cartorio::blobstore::BlobStore;
+use cartorio::blobstore::{BlobExtent, BlobStore};
 
 #[test]
 fn test_docker_saved_tarball() {
@@ -24,3 +24,452 @@ fn test_docker_saved_tarball() {
 
 
 }
+
+#[test]
+fn test_docker_saved_tarball_in_place() {
+    let blobstore_root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+    let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let tarball_path = repository_root.join("tests/fixtures/small-image/image.tar");
+
+    DockerSavedTarball::in_place(&tarball_path, blobstore.clone())
+        .unwrap()
+        .load()
+        .unwrap();
+
+    // only the manifest gets written to the bucket, with the config and
+    // the layer being left in the tarball.
+    //
+    assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 1);
+    assert_eq!(std::fs::read_dir(&blobstore.extents_dir).unwrap().count(), 2);
+
+    let tarball = std::fs::read(&tarball_path).unwrap();
+
+    for entry in std::fs::read_dir(&blobstore.extents_dir).unwrap() {
+        let entry = entry.unwrap();
+        let extent: BlobExtent = serde_json::from_slice(&std::fs::read(entry.path()).unwrap()).unwrap();
+
+        let start = extent.offset as usize;
+        let contents = &tarball[start..start + extent.size as usize];
+
+        assert_eq!(
+            entry.file_name().to_str().unwrap(),
+            cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(contents).unwrap()),
+        );
+    }
+
+    assert!(std::fs::symlink_metadata(blobstore.get_manifest("a", "latest")).is_ok());
+}
+
+#[test]
+fn test_docker_saved_tarball_from_reader() {
+    let blobstore_root_dir = tempdir().unwrap();
+    let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+    let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let tarball = std::fs::read(repository_root.join("tests/fixtures/small-image/image.tar")).unwrap();
+
+    DockerSavedTarball::from_reader(&tarball[..], blobstore.clone())
+        .unwrap()
+        .load()
+        .unwrap();
+
+    // the config, the layer and the manifest end up in the bucket, with
+    // nothing else left behind.
+    //
+    assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 3);
+    assert_eq!(std::fs::read_dir(blobstore_root_dir.path()).unwrap().count(), 3);
+
+    for entry in std::fs::read_dir(&blobstore.bucket_dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        assert_eq!(
+            path.file_name().unwrap().to_str().unwrap(),
+            cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute_for_file(&path).unwrap()),
+        );
+    }
+
+    assert!(std::fs::symlink_metadata(blobstore.get_manifest("a", "latest")).is_ok());
+}
+
+mod compressed {
+    use super::*;
+
+    use std::io::Write;
+
+    fn fixture() -> Vec<u8> {
+        let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+        std::fs::read(repository_root.join("tests/fixtures/small-image/image.tar")).unwrap()
+    }
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(content: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn assert_loads_from_file(compressed: &[u8]) {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(&dir.path().join("blobstore")).unwrap();
+        let tarball_path = dir.path().join("image.tar.compressed");
+
+        std::fs::write(&tarball_path, compressed).unwrap();
+
+        DockerSavedTarball::new(&tarball_path, blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 3);
+        assert!(std::fs::symlink_metadata(blobstore.get_manifest("a", "latest")).is_ok());
+    }
+
+    fn assert_loads_from_reader(compressed: &[u8]) {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+
+        DockerSavedTarball::from_reader(compressed, blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_loads_gzip() {
+        assert_loads_from_file(&gzip(&fixture()));
+        assert_loads_from_reader(&gzip(&fixture()));
+    }
+
+    #[test]
+    fn test_loads_zstd() {
+        assert_loads_from_file(&zstd::encode_all(&fixture()[..], 0).unwrap());
+        assert_loads_from_reader(&zstd::encode_all(&fixture()[..], 0).unwrap());
+    }
+
+    #[test]
+    fn test_loads_xz() {
+        assert_loads_from_file(&xz(&fixture()));
+        assert_loads_from_reader(&xz(&fixture()));
+    }
+
+    #[test]
+    fn test_rejects_serving_compressed_tarballs_in_place() {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(&dir.path().join("blobstore")).unwrap();
+        let tarball_path = dir.path().join("image.tar.gz");
+
+        std::fs::write(&tarball_path, gzip(&fixture())).unwrap();
+
+        let err = DockerSavedTarball::in_place(&tarball_path, blobstore)
+            .err()
+            .unwrap();
+
+        assert!(err.to_string().contains("compressed with gzip"));
+    }
+}
+
+mod oci_layout {
+    use super::*;
+
+    fn fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/oci-image/image.tar")
+    }
+
+    /// Digests of the manifests listed in the `index.json` of the fixture.
+    ///
+    fn index_digests() -> Vec<String> {
+        let index = cartorio::tarball_index::TarballIndex::new(&fixture()).unwrap();
+        let index: serde_json::Value =
+            serde_json::from_slice(&index.read(&fixture(), "index.json").unwrap()).unwrap();
+
+        index["manifests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|descriptor| descriptor["digest"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn tagged(blobstore: &BlobStore, name: &str, reference: &str) -> Option<String> {
+        std::fs::read_link(blobstore.get_manifest(name, reference))
+            .ok()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+    }
+
+    fn assert_loaded_as_saved(blobstore: &BlobStore) {
+        let digests = index_digests();
+
+        // the image index is tagged as it came, with the manifest of the
+        // platform that's in the tarball being reachable by digest.
+        //
+        assert_eq!(tagged(blobstore, "b", "latest").unwrap(), digests[0]);
+
+        let image_index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(blobstore.get_blob(&digests[0])).unwrap()).unwrap();
+
+        let amd64 = image_index["manifests"][0]["digest"].as_str().unwrap();
+        let arm64 = image_index["manifests"][1]["digest"].as_str().unwrap();
+
+        assert!(tagged(blobstore, "b", amd64).is_some());
+        assert!(tagged(blobstore, "b", arm64).is_none());
+
+        // the untagged image gets loaded nonetheless.
+        //
+        assert!(blobstore.get_blob(&digests[1]).exists());
+
+        for digest in &digests {
+            let content = std::fs::read(blobstore.get_blob(digest)).unwrap();
+
+            assert_eq!(
+                digest,
+                &cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&content[..]).unwrap()),
+            );
+        }
+    }
+
+    #[test]
+    fn test_loads_oci_layout() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture(), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_loaded_as_saved(&blobstore);
+
+        // image index, two manifests, two configs and a layer.
+        //
+        assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 6);
+    }
+
+    #[test]
+    fn test_loads_oci_layout_in_place() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::in_place(&fixture(), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_loaded_as_saved(&blobstore);
+
+        assert_eq!(std::fs::read_dir(&blobstore.bucket_dir).unwrap().count(), 3);
+        assert_eq!(std::fs::read_dir(&blobstore.extents_dir).unwrap().count(), 3);
+    }
+}
+
+mod repo_tags {
+    use super::*;
+
+    use cartorio::image_reference::RegistryHost;
+
+    /// The `small-image` fixture with its `manifest.json` tagging the image
+    /// as `repo_tags`.
+    ///
+    fn retagged_fixture(repo_tags: &[&str]) -> Vec<u8> {
+        let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let fixture = std::fs::File::open(repository_root.join("tests/fixtures/small-image/image.tar")).unwrap();
+
+        let mut archive = tar::Archive::new(fixture);
+        let mut builder = tar::Builder::new(Vec::new());
+
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut header = entry.header().clone();
+
+            if entry.path().unwrap().to_str() == Some("manifest.json") {
+                let mut manifest: serde_json::Value = serde_json::from_reader(&mut entry).unwrap();
+                manifest[0]["RepoTags"] = serde_json::json!(repo_tags);
+
+                let content = serde_json::to_vec(&manifest).unwrap();
+                header.set_size(content.len() as u64);
+                header.set_cksum();
+
+                builder.append(&header, &content[..]).unwrap();
+            } else {
+                builder.append(&header, &mut entry).unwrap();
+            }
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn load(repo_tags: &[&str], registry_host: RegistryHost) -> (BlobStore, tempfile::TempDir, cartorio::error::Result<()>) {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let result = DockerSavedTarball::from_reader(&retagged_fixture(repo_tags)[..], blobstore.clone())
+            .unwrap()
+            .registry_host(registry_host)
+            .load();
+
+        (blobstore, blobstore_root_dir, result)
+    }
+
+    fn is_tagged(blobstore: &BlobStore, name: &str, reference: &str) -> bool {
+        std::fs::symlink_metadata(blobstore.get_manifest(name, reference)).is_ok()
+    }
+
+    #[test]
+    fn test_tags_with_registry_host_kept() {
+        let (blobstore, _dir, result) = load(&["ghcr.io/team/app:1.0", "other"], RegistryHost::Keep);
+
+        result.unwrap();
+
+        assert!(is_tagged(&blobstore, "ghcr.io/team/app", "1.0"));
+        assert!(is_tagged(&blobstore, "other", "latest"));
+    }
+
+    #[test]
+    fn test_strips_registry_host_with_port_instead_of_keeping_it() {
+        let (blobstore, _dir, result) = load(&["localhost:5000/foo:1.0", "ghcr.io/team/app:1.0"], RegistryHost::Keep);
+
+        result.unwrap();
+
+        assert!(is_tagged(&blobstore, "foo", "1.0"));
+        assert!(is_tagged(&blobstore, "ghcr.io/team/app", "1.0"));
+    }
+
+    #[test]
+    fn test_tags_with_registry_host_stripped() {
+        let (blobstore, _dir, result) = load(&["localhost:5000/foo:1.0"], RegistryHost::Strip);
+
+        result.unwrap();
+
+        assert!(is_tagged(&blobstore, "foo", "1.0"));
+    }
+
+    #[test]
+    fn test_tags_with_registry_host_rewritten() {
+        let (blobstore, _dir, result) = load(
+            &["localhost:5000/foo:1.0", "alpine:3"],
+            RegistryHost::Rewrite("mirror.local".to_owned()),
+        );
+
+        result.unwrap();
+
+        assert!(is_tagged(&blobstore, "mirror.local/foo", "1.0"));
+        assert!(is_tagged(&blobstore, "mirror.local/library/alpine", "3"));
+    }
+
+    #[test]
+    fn test_rejects_malformed_tags() {
+        let (_, _dir, result) = load(&["Not A Tag"], RegistryHost::Keep);
+
+        assert!(result.unwrap_err().to_string().contains("invalid image reference"));
+    }
+}
+
+mod compressed_layers {
+    use super::*;
+
+    use std::io::Read;
+
+    use cartorio::compression::{Compression, LayerCompression};
+    use cartorio::registry::Manifest;
+
+    fn load(layer_compression: LayerCompression) -> (BlobStore, tempfile::TempDir) {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let tarball_path = repository_root.join("tests/fixtures/small-image/image.tar");
+
+        DockerSavedTarball::new(&tarball_path, blobstore.clone())
+            .unwrap()
+            .compress_layers(layer_compression)
+            .load()
+            .unwrap();
+
+        (blobstore, blobstore_root_dir)
+    }
+
+    /// Checks that the layers of the image are compressed with
+    /// `compression`, with the config still having the digests of the
+    /// uncompressed layers as diff_ids.
+    ///
+    fn assert_layers_compressed(blobstore: &BlobStore, compression: Compression, media_type: &str) {
+        let manifest: Manifest = serde_json::from_slice(
+            &std::fs::read(blobstore.get_manifest("a", "latest")).unwrap(),
+        ).unwrap();
+
+        let config: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(blobstore.get_blob(&manifest.config.digest)).unwrap(),
+        ).unwrap();
+
+        assert_eq!(manifest.layers.len(), config["rootfs"]["diff_ids"].as_array().unwrap().len());
+
+        for (layer, diff_id) in manifest.layers.iter().zip(config["rootfs"]["diff_ids"].as_array().unwrap()) {
+            let blob = std::fs::read(blobstore.get_blob(&layer.digest)).unwrap();
+
+            assert_eq!(layer.media_type, media_type);
+            assert_eq!(layer.size, blob.len() as u64);
+            assert_eq!(
+                layer.digest,
+                cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&blob[..]).unwrap()),
+            );
+
+            let (detected, mut reader) = cartorio::compression::decompress(&blob[..]).unwrap();
+            let mut uncompressed = Vec::new();
+            reader.read_to_end(&mut uncompressed).unwrap();
+
+            assert_eq!(detected, compression);
+            assert_eq!(
+                diff_id.as_str().unwrap(),
+                cartorio::digest::prepend_sha_scheme(&cartorio::digest::compute(&uncompressed[..]).unwrap()),
+            );
+        }
+    }
+
+    #[test]
+    fn test_compresses_layers_with_gzip() {
+        let (blobstore, _dir) = load(LayerCompression::new(Compression::Gzip).unwrap());
+
+        assert_layers_compressed(&blobstore, Compression::Gzip, "application/vnd.docker.image.rootfs.diff.tar.gzip");
+    }
+
+    #[test]
+    fn test_compresses_layers_with_zstd_in_parallel() {
+        let (blobstore, _dir) = load(
+            LayerCompression::new(Compression::Zstd).unwrap().level(19).unwrap().threads(4),
+        );
+
+        assert_layers_compressed(&blobstore, Compression::Zstd, "application/vnd.oci.image.layer.v1.tar+zstd");
+
+        let manifest = std::fs::read(blobstore.get_manifest("a", "latest")).unwrap();
+        let manifest: Manifest = serde_json::from_slice(&manifest).unwrap();
+
+        assert_eq!(manifest.media_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(manifest.config.media_type, "application/vnd.oci.image.config.v1+json");
+    }
+
+    #[test]
+    fn test_rejects_compressing_layers_in_place() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let repository_root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let tarball_path = repository_root.join("tests/fixtures/small-image/image.tar");
+
+        let err = DockerSavedTarball::in_place(&tarball_path, blobstore)
+            .unwrap()
+            .compress_layers(LayerCompression::new(Compression::Gzip).unwrap())
+            .load()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("served in place"));
+    }
+}
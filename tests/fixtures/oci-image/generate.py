@@ -0,0 +1,97 @@
+#!/usr/bin/env python3
+#
+# Generates `image.tar`, laid out the way `docker save` does since Docker 25
+# (OCI Image Layout + legacy `manifest.json`), out of the config and layer of
+# the `small-image` fixture:
+#
+#   - b:latest  -> image index listing a linux/amd64 manifest that's in the
+#                  tarball and a linux/arm64 one that isn't
+#   - (untagged) -> manifest with a different config, `RepoTags: null`
+#
+import hashlib
+import io
+import json
+import os
+import tarfile
+
+here = os.path.dirname(os.path.abspath(__file__))
+small_image = os.path.join(here, "..", "small-image", "image.tar")
+
+with tarfile.open(small_image) as source:
+    layer = source.extractfile(
+        "2449afaa7c622303c49bbf83cdc41b03de6222859e85db4ad4464afc1d2c4f89/layer.tar").read()
+    config = source.extractfile(
+        "922f19e5e8f8e734b76618a3e1fe4312c9f07f8d5f83b32c7f33dd9ac38decf7.json").read()
+
+other_config = json.dumps(dict(json.loads(config), author="someone else")).encode()
+
+blobs = {}
+
+
+def blob(content):
+    digest = hashlib.sha256(content).hexdigest()
+    blobs[digest] = content
+    return {"digest": "sha256:" + digest, "size": len(content)}
+
+
+def manifest(config_content):
+    return json.dumps({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": dict(blob(config_content), mediaType="application/vnd.oci.image.config.v1+json"),
+        "layers": [dict(blob(layer), mediaType="application/vnd.oci.image.layer.v1.tar")],
+    }, indent=2).encode()
+
+
+amd64 = dict(blob(manifest(config)), mediaType="application/vnd.oci.image.manifest.v1+json",
+             platform={"architecture": "amd64", "os": "linux"})
+
+arm64 = {"mediaType": "application/vnd.oci.image.manifest.v1+json",
+         "digest": "sha256:" + hashlib.sha256(b"not in the tarball").hexdigest(),
+         "size": 1234, "platform": {"architecture": "arm64", "os": "linux"}}
+
+image_index = json.dumps({
+    "schemaVersion": 2,
+    "mediaType": "application/vnd.oci.image.index.v1+json",
+    "manifests": [amd64, arm64],
+}, indent=2).encode()
+
+untagged = blob(manifest(other_config))
+
+index = {
+    "schemaVersion": 2,
+    "mediaType": "application/vnd.oci.image.index.v1+json",
+    "manifests": [
+        dict(blob(image_index), mediaType="application/vnd.oci.image.index.v1+json",
+             annotations={"io.containerd.image.name": "docker.io/library/b:latest",
+                          "org.opencontainers.image.ref.name": "latest"}),
+        dict(untagged, mediaType="application/vnd.oci.image.manifest.v1+json"),
+    ],
+}
+
+layer_digest = hashlib.sha256(layer).hexdigest()
+
+legacy_manifest = [
+    {"Config": "blobs/sha256/" + hashlib.sha256(config).hexdigest(),
+     "RepoTags": ["b:latest"],
+     "Layers": ["blobs/sha256/" + layer_digest]},
+    {"Config": "blobs/sha256/" + hashlib.sha256(other_config).hexdigest(),
+     "RepoTags": None,
+     "Layers": ["blobs/sha256/" + layer_digest]},
+]
+
+
+def add(tar, name, content):
+    info = tarfile.TarInfo(name)
+    info.size = len(content)
+    info.mode = 0o644
+    tar.addfile(info, io.BytesIO(content))
+
+
+with tarfile.open(os.path.join(here, "image.tar"), "w", format=tarfile.USTAR_FORMAT) as tar:
+    for digest in sorted(blobs):
+        add(tar, "blobs/sha256/" + digest, blobs[digest])
+
+    add(tar, "index.json", json.dumps(index).encode())
+    add(tar, "manifest.json", json.dumps(legacy_manifest).encode())
+    add(tar, "oci-layout", b'{"imageLayoutVersion": "1.0.0"}')
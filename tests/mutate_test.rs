@@ -0,0 +1,409 @@
+use std::fs;
+use std::path::PathBuf;
+
+use tempfile::tempdir;
+
+use cartorio::blobstore::BlobStore;
+use cartorio::compression::{Compression, LayerCompression};
+use cartorio::docker_saved_tarball::DockerSavedTarball;
+use cartorio::image_config::ImageConfigContainer;
+use cartorio::mutate::{AppendLayer, EditConfig};
+
+mod common;
+
+use common::{read_config, read_manifest};
+
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+
+/// Tags `a:latest` again as `a:annotated`, with annotations on the manifest
+/// and URLs on its first layer (as foreign layers have).
+///
+fn tag_annotated_image(blobstore: &BlobStore) {
+    let mut manifest = read_manifest(blobstore, "a", "latest");
+
+    manifest.unknown.insert("annotations".to_owned(), serde_json::json!({ "team": "core" }));
+    manifest.layers[0].unknown.insert("urls".to_owned(), serde_json::json!(["https://example.com/layer"]));
+
+    let filename = blobstore.add_manifest(&manifest).unwrap();
+    blobstore.tag_manifest(&filename, "a", "annotated").unwrap();
+}
+
+
+/// Checks that the manifest of `name:reference` kept what
+/// `tag_annotated_image` added.
+///
+fn assert_annotations_kept(blobstore: &BlobStore, name: &str, reference: &str) {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(blobstore.get_manifest(name, reference)).unwrap()).unwrap();
+
+    assert_eq!(manifest["annotations"], serde_json::json!({ "team": "core" }));
+    assert_eq!(manifest["layers"][0]["urls"], serde_json::json!(["https://example.com/layer"]));
+}
+
+
+/// A directory with the files of a layer to append.
+///
+fn layer_dir() -> tempfile::TempDir {
+    let dir = tempdir().unwrap();
+
+    fs::create_dir(dir.path().join("etc")).unwrap();
+    fs::write(dir.path().join("etc/app.conf"), b"debug = true\n").unwrap();
+
+    dir
+}
+
+
+mod append {
+    use super::*;
+
+    #[test]
+    fn appends_a_layer_on_top_of_the_base_image() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let layer = layer_dir();
+
+        let manifest_filename = AppendLayer::new("a:latest", layer.path(), "a:with-conf", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        let base_manifest = read_manifest(&blobstore, "a", "latest");
+        let manifest = read_manifest(&blobstore, "a", "with-conf");
+
+        assert!(fs::symlink_metadata(blobstore.get_manifest("a", &manifest_filename)).is_ok());
+
+        // the base layers are referenced as they are.
+        //
+        assert_eq!(manifest.layers.len(), base_manifest.layers.len() + 1);
+
+        for (layer, base_layer) in manifest.layers.iter().zip(&base_manifest.layers) {
+            assert_eq!(layer.digest, base_layer.digest);
+        }
+
+        let base_config: serde_json::Value = read_config(&blobstore, &base_manifest);
+        let config: serde_json::Value = read_config(&blobstore, &manifest);
+
+        let diff_ids = config["rootfs"]["diff_ids"].as_array().unwrap();
+        let appended = manifest.layers.last().unwrap();
+
+        assert_eq!(diff_ids.len(), manifest.layers.len());
+        assert_eq!(diff_ids[diff_ids.len() - 1], appended.digest.as_str());
+        assert_eq!(
+            config["history"].as_array().unwrap().last().unwrap()["created_by"],
+            "cartorio mutate append",
+        );
+        assert_eq!(config["architecture"], base_config["architecture"]);
+        assert_eq!(config["config"]["Env"], base_config["config"]["Env"]);
+    }
+
+    #[test]
+    fn moves_existing_tags() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let layer = layer_dir();
+
+        let first = AppendLayer::new("a", layer.path(), "a:dev", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        fs::write(layer.path().join("etc/app.conf"), b"debug = false\n").unwrap();
+
+        let second = AppendLayer::new("a", layer.path(), "a:dev", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(
+            fs::read_link(blobstore.get_manifest("a", "dev")).unwrap().file_name().unwrap().to_str().unwrap(),
+            second,
+        );
+    }
+
+    #[test]
+    fn appends_to_images_served_in_place() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::in_place(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let layer = layer_dir();
+
+        AppendLayer::new("a:latest", layer.path(), "a:with-conf", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "a", "with-conf");
+
+        let config: serde_json::Value = read_config(&blobstore, &manifest);
+
+        assert_eq!(config["rootfs"]["diff_ids"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn appends_oci_layers_to_oci_manifests() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("oci-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let layer = layer_dir();
+
+        // `b:latest` is a list of manifests, which layers can't be
+        // appended to.
+        //
+        let err = AppendLayer::new("b:latest", layer.path(), "b:with-conf", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("refer to one of the manifests it lists"));
+
+        let image_index: serde_json::Value =
+            serde_json::from_slice(&fs::read(blobstore.get_manifest("b", "latest")).unwrap()).unwrap();
+        let amd64 = image_index["manifests"][0]["digest"].as_str().unwrap();
+
+        AppendLayer::new(&format!("b@{}", amd64), layer.path(), "b:with-conf", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "b", "with-conf");
+
+        assert_eq!(manifest.media_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(manifest.layers.last().unwrap().media_type, "application/vnd.oci.image.layer.v1.tar");
+    }
+
+    #[test]
+    fn turns_docker_manifests_into_oci_ones_for_zstd_layers() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let layer = layer_dir();
+
+        AppendLayer::new("a:latest", layer.path(), "a:with-conf", blobstore.clone())
+            .unwrap()
+            .compress_layers(LayerCompression::new(Compression::Zstd).unwrap())
+            .append()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "a", "with-conf");
+
+        assert_eq!(manifest.media_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(manifest.config.media_type, "application/vnd.oci.image.config.v1+json");
+        assert_eq!(manifest.layers[0].media_type, "application/vnd.oci.image.layer.v1.tar");
+        assert_eq!(manifest.layers.last().unwrap().media_type, "application/vnd.oci.image.layer.v1.tar+zstd");
+    }
+
+    #[test]
+    fn keeps_the_rest_of_the_manifest() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        tag_annotated_image(&blobstore);
+
+        let layer = layer_dir();
+
+        AppendLayer::new("a:annotated", layer.path(), "a:with-conf", blobstore.clone())
+            .unwrap()
+            .append()
+            .unwrap();
+
+        assert_annotations_kept(&blobstore, "a", "with-conf");
+    }
+}
+
+
+mod config {
+    use super::*;
+
+    #[test]
+    fn changes_the_execution_parameters_keeping_the_layers() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let changes = ImageConfigContainer {
+            entrypoint: Some(vec!["/bin/app".to_owned()]),
+            env: Some(vec!["PATH=/app/bin".to_owned(), "LANG=C".to_owned()]),
+            labels: Some(vec![("team".to_owned(), "core".to_owned())].into_iter().collect()),
+            user: Some("1000:1000".to_owned()),
+            ..ImageConfigContainer::default()
+        };
+
+        let manifest_filename = EditConfig::new("a", "a:configured", changes, blobstore.clone())
+            .unwrap()
+            .edit()
+            .unwrap();
+
+        let base_manifest = read_manifest(&blobstore, "a", "latest");
+        let manifest = read_manifest(&blobstore, "a", "configured");
+
+        assert!(fs::symlink_metadata(blobstore.get_manifest("a", &manifest_filename)).is_ok());
+        assert_eq!(
+            manifest.layers.iter().map(|layer| &layer.digest).collect::<Vec<_>>(),
+            base_manifest.layers.iter().map(|layer| &layer.digest).collect::<Vec<_>>(),
+        );
+        assert_ne!(manifest.config.digest, base_manifest.config.digest);
+
+        let base_config: serde_json::Value = read_config(&blobstore, &base_manifest);
+        let config: serde_json::Value = read_config(&blobstore, &manifest);
+
+        assert_eq!(config["config"]["Entrypoint"], serde_json::json!(["/bin/app"]));
+        assert_eq!(config["config"]["Env"], serde_json::json!(["PATH=/app/bin", "LANG=C"]));
+        assert_eq!(config["config"]["Labels"], serde_json::json!({"team": "core"}));
+        assert_eq!(config["config"]["User"], "1000:1000");
+
+        // what wasn't changed is kept, including fields cartorio doesn't
+        // know about.
+        //
+        assert_eq!(config["config"]["Hostname"], base_config["config"]["Hostname"]);
+        assert_eq!(config["container_config"], base_config["container_config"]);
+        assert_eq!(config["rootfs"], base_config["rootfs"]);
+
+        let history = config["history"].as_array().unwrap().last().unwrap();
+
+        assert_eq!(history["created_by"], "cartorio mutate config");
+        assert_eq!(history["empty_layer"], true);
+    }
+
+    #[test]
+    fn keeps_the_rest_of_the_manifest() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        tag_annotated_image(&blobstore);
+
+        let changes = ImageConfigContainer {
+            user: Some("1000:1000".to_owned()),
+            ..ImageConfigContainer::default()
+        };
+
+        EditConfig::new("a:annotated", "a:configured", changes, blobstore.clone())
+            .unwrap()
+            .edit()
+            .unwrap();
+
+        assert_annotations_kept(&blobstore, "a", "configured");
+    }
+
+    #[test]
+    fn retags_the_image_in_place() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        DockerSavedTarball::new(&fixture("small-image/image.tar"), blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let changes = ImageConfigContainer {
+            working_dir: Some("/srv".to_owned()),
+            ..ImageConfigContainer::default()
+        };
+
+        let manifest_filename = EditConfig::new("a:latest", "a:latest", changes, blobstore.clone())
+            .unwrap()
+            .edit()
+            .unwrap();
+
+        assert_eq!(
+            fs::read_link(blobstore.get_manifest("a", "latest")).unwrap().file_name().unwrap().to_str().unwrap(),
+            manifest_filename,
+        );
+
+        let manifest = read_manifest(&blobstore, "a", "latest");
+
+        let config: serde_json::Value = read_config(&blobstore, &manifest);
+
+        assert_eq!(config["config"]["WorkingDir"], "/srv");
+    }
+
+    #[test]
+    fn fails_without_base_image() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let err = EditConfig::new("a", "a:1", ImageConfigContainer::default(), blobstore)
+            .unwrap()
+            .edit()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("not found"));
+    }
+}
+
+
+mod new {
+    use super::*;
+
+    #[test]
+    fn fails_with_invalid_references() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let layer = layer_dir();
+
+        assert!(AppendLayer::new("Not Valid", layer.path(), "a:1", blobstore.clone()).is_err());
+        assert!(AppendLayer::new("a", layer.path(), "a@sha256:abc", blobstore.clone()).is_err());
+        assert!(AppendLayer::new("a", &layer.path().join("nope"), "a:1", blobstore).is_err());
+    }
+
+    #[test]
+    fn fails_without_base_image() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let layer = layer_dir();
+
+        let err = AppendLayer::new("a", layer.path(), "a:1", blobstore)
+            .unwrap()
+            .append()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("not found"));
+    }
+}
@@ -3,7 +3,7 @@ use cartorio::blobstore::BlobStore;
 
 use tempfile::tempdir; 
 
-const OCI_IMAGE_INDEX_SAMPLE: &'static str = r#"{
+const OCI_IMAGE_INDEX_SAMPLE: &str = r#"{
   "schemaVersion": 2,
   "manifests": [
     {
@@ -24,7 +24,7 @@ const OCI_IMAGE_INDEX_SAMPLE: &'static str = r#"{
 
 #[test]
 fn parses_image_index() {
-    let parsed: OciImageIndex = OCI_IMAGE_INDEX_SAMPLE.parse().unwrap();
+    let _parsed: OciImageIndex = OCI_IMAGE_INDEX_SAMPLE.parse().unwrap();
 }
 
 
@@ -0,0 +1,286 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use tempfile::{tempdir, TempDir};
+
+use cartorio::blobstore::BlobStore;
+use cartorio::docker_saved_tarball::DockerSavedTarball;
+use cartorio::image_reference::RegistryHost;
+use cartorio::registry::Manifest;
+use cartorio::remote_image::RemoteImage;
+use cartorio::server;
+
+mod common;
+
+use common::{manifest_filename, write_htpasswd};
+
+/// A cartorio serving the `docker save`d tarball `fixture` in the
+/// background, to pull images from.
+///
+struct Upstream {
+    addr: SocketAddr,
+    blobstore: BlobStore,
+    _blobstore_root_dir: TempDir,
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl Upstream {
+    fn start(fixture: &str, options: impl FnOnce(SocketAddr) -> server::Options) -> Upstream {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let tarball_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(fixture);
+
+        DockerSavedTarball::new(&tarball_path, blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        runtime.spawn(server::run(listener, blobstore.clone(), options(addr)));
+
+        Upstream {
+            addr,
+            blobstore,
+            _blobstore_root_dir: blobstore_root_dir,
+            _runtime: runtime,
+        }
+    }
+
+    /// Reference to the image `image` (e.g., `a:latest`) in this registry.
+    ///
+    fn reference(&self, image: &str) -> String {
+        format!("{}/{}", self.addr, image)
+    }
+}
+
+fn pull(reference: &str, blobstore: &BlobStore) -> RemoteImage {
+    RemoteImage::new(reference, blobstore.clone())
+        .unwrap()
+        .registry_host(RegistryHost::Strip)
+        .plain_http()
+}
+
+mod load {
+    use super::*;
+
+    #[test]
+    fn pulls_images_anonymously() {
+        let upstream = Upstream::start("small-image/image.tar", |_| Default::default());
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let filename = pull(&upstream.reference("a:latest"), &blobstore).load().unwrap();
+
+        // same manifest, byte for byte.
+        //
+        assert_eq!(filename, manifest_filename(&upstream.blobstore, "a", "latest"));
+        assert_eq!(manifest_filename(&blobstore, "a", "latest"), filename);
+        assert_eq!(manifest_filename(&blobstore, "a", &filename), filename);
+
+        let manifest: Manifest = serde_json::from_slice(&fs::read(blobstore.get_manifest("a", "latest")).unwrap()).unwrap();
+
+        for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            assert_eq!(
+                fs::read(blobstore.get_blob(&descriptor.digest)).unwrap(),
+                upstream.blobstore.read_blob(&descriptor.digest).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn pulls_by_digest_without_tagging() {
+        let upstream = Upstream::start("small-image/image.tar", |_| Default::default());
+        let digest = manifest_filename(&upstream.blobstore, "a", "latest");
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        pull(&upstream.reference(&format!("a@{}", digest)), &blobstore).load().unwrap();
+
+        assert_eq!(manifest_filename(&blobstore, "a", &digest), digest);
+        assert!(fs::symlink_metadata(blobstore.get_manifest("a", "latest")).is_err());
+    }
+
+    #[test]
+    fn picks_the_manifest_for_the_platform() {
+        let upstream = Upstream::start("oci-image/image.tar", |_| Default::default());
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let filename = pull(&upstream.reference("b:latest"), &blobstore)
+            .platform("linux/amd64".parse().unwrap())
+            .load()
+            .unwrap();
+
+        assert_eq!(filename, "sha256:999c1f41973bb74a48fedb7ee2052f3114c50652413a4187f2f4026ef4a84260");
+        assert_eq!(manifest_filename(&blobstore, "b", "latest"), filename);
+
+        // the arm64 manifest is listed but wasn't saved along, and there's
+        // none at all for s390x.
+        //
+        let err = pull(&upstream.reference("b:latest"), &blobstore)
+            .platform("linux/arm64".parse().unwrap())
+            .load()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("MANIFEST_UNKNOWN"), "{}", err);
+
+        let err = pull(&upstream.reference("b:latest"), &blobstore)
+            .platform("linux/s390x".parse().unwrap())
+            .load()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("has no manifest for linux/s390x"), "{}", err);
+    }
+
+    #[test]
+    fn verifies_digests() {
+        let upstream = Upstream::start("small-image/image.tar", |_| Default::default());
+
+        let manifest: Manifest =
+            serde_json::from_slice(&fs::read(upstream.blobstore.get_manifest("a", "latest")).unwrap()).unwrap();
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let err = pull(&upstream.reference("a@sha256:0000000000000000000000000000000000000000000000000000000000000000"), &blobstore)
+            .load()
+            .unwrap_err();
+
+        assert!(err.to_string().contains("MANIFEST_UNKNOWN"), "{}", err);
+
+        // a layer that got tampered with upstream.
+        //
+        let layer_path = upstream.blobstore.get_blob(&manifest.layers[0].digest);
+        let mut layer = fs::read(&layer_path).unwrap();
+        layer[0] ^= 0xff;
+        fs::write(&layer_path, layer).unwrap();
+
+        let err = pull(&upstream.reference("a:latest"), &blobstore).load().unwrap_err();
+
+        assert!(err.to_string().contains(&format!("blob {}", manifest.layers[0].digest)), "{}", err);
+        assert!(!blobstore.get_blob(&manifest.layers[0].digest).exists());
+        assert!(fs::symlink_metadata(blobstore.get_manifest("a", "latest")).is_err());
+
+        // and a manifest that did.
+        //
+        let digest = manifest_filename(&upstream.blobstore, "a", "latest");
+        let manifest_path = upstream.blobstore.get_blob(&digest);
+        let mut content = fs::read(&manifest_path).unwrap();
+        content.push(b'\n');
+        fs::write(&manifest_path, content).unwrap();
+
+        let err = pull(&upstream.reference(&format!("a@{}", digest)), &blobstore).load().unwrap_err();
+
+        assert!(err.to_string().contains(&format!("instead of {}", digest)), "{}", err);
+    }
+
+    #[test]
+    fn authenticates_with_basic_credentials() {
+        let dir = tempdir().unwrap();
+        let htpasswd = dir.path().join("htpasswd");
+        write_htpasswd(&htpasswd, &[("alice", "s3cr3t")]);
+
+        let upstream = Upstream::start("small-image/image.tar", |_| server::Options {
+            htpasswd: Some(htpasswd),
+            ..Default::default()
+        });
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let err = pull(&upstream.reference("a:latest"), &blobstore).load().unwrap_err();
+        assert!(err.to_string().contains("requires credentials"), "{}", err);
+
+        let err = pull(&upstream.reference("a:latest"), &blobstore)
+            .credentials("alice", "wrong")
+            .load()
+            .unwrap_err();
+        assert!(err.to_string().contains("401"), "{}", err);
+
+        pull(&upstream.reference("a:latest"), &blobstore)
+            .credentials("alice", "s3cr3t")
+            .load()
+            .unwrap();
+
+        assert_eq!(manifest_filename(&blobstore, "a", "latest"), manifest_filename(&upstream.blobstore, "a", "latest"));
+    }
+
+    #[test]
+    fn authenticates_with_bearer_tokens() {
+        let dir = tempdir().unwrap();
+        let htpasswd = dir.path().join("htpasswd");
+        write_htpasswd(&htpasswd, &[("alice", "s3cr3t")]);
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        fs::write(dir.path().join("signing.pem"), key.serialize_pem()).unwrap();
+
+        let upstream = Upstream::start("small-image/image.tar", |addr| server::Options {
+            htpasswd: Some(htpasswd),
+            token: Some(cartorio::token::TokenOptions {
+                realm: format!("http://{}/token", addr),
+                service: "cartorio".to_owned(),
+                issuer: "cartorio".to_owned(),
+                public_key: None,
+                signing_key: Some(dir.path().join("signing.pem")),
+            }),
+            ..Default::default()
+        });
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        let err = pull(&upstream.reference("a:latest"), &blobstore).load().unwrap_err();
+        assert!(err.to_string().contains("(token) failed with 401"), "{}", err);
+
+        pull(&upstream.reference("a:latest"), &blobstore)
+            .credentials("alice", "s3cr3t")
+            .load()
+            .unwrap();
+
+        assert_eq!(manifest_filename(&blobstore, "a", "latest"), manifest_filename(&upstream.blobstore, "a", "latest"));
+    }
+}
+
+mod registry_host {
+    use super::*;
+
+    #[test]
+    fn strips_registry_hosts_that_cant_be_kept() {
+        let upstream = Upstream::start("small-image/image.tar", |_| Default::default());
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        // `127.0.0.1:port/a` can't be kept as a repository name.
+        //
+        let filename = RemoteImage::new(&upstream.reference("a:latest"), blobstore.clone())
+            .unwrap()
+            .plain_http()
+            .load()
+            .unwrap();
+
+        assert_eq!(manifest_filename(&blobstore, "a", "latest"), filename);
+    }
+}
+
+mod new {
+    use super::*;
+
+    #[test]
+    fn fails_with_invalid_references() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        assert!(RemoteImage::new("Not Valid", blobstore.clone()).is_err());
+        assert!(RemoteImage::new("a:", blobstore).is_err());
+    }
+}
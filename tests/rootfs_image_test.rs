@@ -0,0 +1,298 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use tempfile::tempdir;
+
+use cartorio::blobstore::BlobStore;
+use cartorio::compression::{Compression, LayerCompression};
+use cartorio::image_config::ImageConfig;
+use cartorio::rootfs_image::RootfsImage;
+
+mod common;
+
+use common::{manifest_filename, read_manifest};
+
+
+/// Creates a small root filesystem under `dir`.
+///
+fn create_rootfs(dir: &Path) {
+    fs::create_dir_all(dir.join("bin")).unwrap();
+    fs::create_dir_all(dir.join("etc")).unwrap();
+
+    fs::write(dir.join("bin/app"), b"#!/bin/sh\necho hi\n").unwrap();
+    fs::set_permissions(dir.join("bin/app"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    fs::write(dir.join("etc/os-release"), b"ID=test\n").unwrap();
+    fs::set_permissions(dir.join("etc/os-release"), fs::Permissions::from_mode(0o644)).unwrap();
+
+    for subdir in &["bin", "etc"] {
+        fs::set_permissions(dir.join(subdir), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    std::os::unix::fs::symlink("../bin/app", dir.join("etc/app")).unwrap();
+}
+
+
+mod load {
+    use super::*;
+
+    #[test]
+    fn builds_the_same_image_out_of_the_same_files() {
+        let rootfs_dir = tempdir().unwrap();
+        create_rootfs(rootfs_dir.path());
+        fs::hard_link(rootfs_dir.path().join("etc/os-release"), rootfs_dir.path().join("etc/os-release.bak")).unwrap();
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(rootfs_dir.path(), "team/app", "1.0", blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        // same files, created at a different time.
+        //
+        let other_rootfs_dir = tempdir().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        create_rootfs(other_rootfs_dir.path());
+        fs::hard_link(other_rootfs_dir.path().join("etc/os-release"), other_rootfs_dir.path().join("etc/os-release.bak")).unwrap();
+
+        let other_blobstore_root_dir = tempdir().unwrap();
+        let other_blobstore = BlobStore::new(other_blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(other_rootfs_dir.path(), "team/app", "1.0", other_blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let filename = manifest_filename(&blobstore, "team/app", "1.0");
+        let other_filename = manifest_filename(&other_blobstore, "team/app", "1.0");
+        let manifest = read_manifest(&blobstore, "team/app", "1.0");
+
+        assert_eq!(filename, other_filename);
+        assert!(fs::symlink_metadata(blobstore.get_manifest("team/app", &filename)).is_ok());
+
+        let layer = fs::File::open(blobstore.get_blob(&manifest.layers[0].digest)).unwrap();
+        let mut archive = tar::Archive::new(layer);
+
+        let entries: Vec<(String, tar::EntryType, u64, u64, u32)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+
+                (
+                    entry.path().unwrap().to_str().unwrap().to_owned(),
+                    header.entry_type(),
+                    header.mtime().unwrap(),
+                    header.uid().unwrap(),
+                    header.mode().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("bin/".to_owned(), tar::EntryType::Directory, 0, 0, 0o755),
+                ("bin/app".to_owned(), tar::EntryType::Regular, 0, 0, 0o755),
+                ("etc/".to_owned(), tar::EntryType::Directory, 0, 0, 0o755),
+                ("etc/app".to_owned(), tar::EntryType::Symlink, 0, 0, 0o777),
+                ("etc/os-release".to_owned(), tar::EntryType::Regular, 0, 0, 0o644),
+                ("etc/os-release.bak".to_owned(), tar::EntryType::Link, 0, 0, 0o644),
+            ],
+        );
+    }
+
+    #[test]
+    fn builds_the_same_image_out_of_a_tarball() {
+        let rootfs_dir = tempdir().unwrap();
+        create_rootfs(rootfs_dir.path());
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(rootfs_dir.path(), "app", "latest", blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        // a gzipped tarball of it, with owners and mtimes of its own.
+        //
+        let tarball_dir = tempdir().unwrap();
+        let tarball_path = tarball_dir.path().join("rootfs.tgz");
+
+        let encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&tarball_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", rootfs_dir.path()).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let other_blobstore_root_dir = tempdir().unwrap();
+        let other_blobstore = BlobStore::new(other_blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(&tarball_path, "app", "latest", other_blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "app", "latest");
+        let other_manifest = read_manifest(&other_blobstore, "app", "latest");
+
+        assert_eq!(manifest.layers[0].digest, other_manifest.layers[0].digest);
+        assert_eq!(manifest.config.digest, other_manifest.config.digest);
+    }
+
+    #[test]
+    fn keeps_hard_links_after_their_targets() {
+        let rootfs_dir = tempdir().unwrap();
+        fs::create_dir(rootfs_dir.path().join("bin")).unwrap();
+        fs::set_permissions(rootfs_dir.path().join("bin"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(rootfs_dir.path().join("bin/busybox"), b"busybox").unwrap();
+        fs::set_permissions(rootfs_dir.path().join("bin/busybox"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::hard_link(rootfs_dir.path().join("bin/busybox"), rootfs_dir.path().join("bin/[")).unwrap();
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(rootfs_dir.path(), "busybox", "latest", blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        // `bin/[` sorts before `bin/busybox`, which the tarball has first.
+        //
+        let tarball_dir = tempdir().unwrap();
+        let tarball_path = tarball_dir.path().join("rootfs.tar");
+
+        let mut builder = tar::Builder::new(fs::File::create(&tarball_path).unwrap());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder.append_data(&mut header, "bin/", std::io::empty()).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o755);
+        header.set_size(7);
+        builder.append_data(&mut header, "bin/busybox", &b"busybox"[..]).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder.append_link(&mut header, "bin/[", "bin/busybox").unwrap();
+
+        builder.into_inner().unwrap();
+
+        let other_blobstore_root_dir = tempdir().unwrap();
+        let other_blobstore = BlobStore::new(other_blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(&tarball_path, "busybox", "latest", other_blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "busybox", "latest");
+        let other_manifest = read_manifest(&other_blobstore, "busybox", "latest");
+
+        assert_eq!(manifest.layers[0].digest, other_manifest.layers[0].digest);
+
+        let layer = fs::File::open(other_blobstore.get_blob(&other_manifest.layers[0].digest)).unwrap();
+
+        let entries: Vec<(String, tar::EntryType, Option<String>)> = tar::Archive::new(layer)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+
+                (
+                    entry.path().unwrap().to_str().unwrap().to_owned(),
+                    entry.header().entry_type(),
+                    entry.link_name().unwrap().map(|target| target.to_str().unwrap().to_owned()),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("bin/".to_owned(), tar::EntryType::Directory, None),
+                ("bin/[".to_owned(), tar::EntryType::Regular, None),
+                ("bin/busybox".to_owned(), tar::EntryType::Link, Some("bin/[".to_owned())),
+            ],
+        );
+
+        let unpacked_dir = tempdir().unwrap();
+        let layer = fs::File::open(other_blobstore.get_blob(&other_manifest.layers[0].digest)).unwrap();
+        tar::Archive::new(layer).unpack(unpacked_dir.path()).unwrap();
+
+        assert_eq!(fs::read(unpacked_dir.path().join("bin/busybox")).unwrap(), b"busybox");
+        assert_eq!(fs::read(unpacked_dir.path().join("bin/[")).unwrap(), b"busybox");
+    }
+
+    #[test]
+    fn writes_the_execution_parameters_to_the_config() {
+        let rootfs_dir = tempdir().unwrap();
+        create_rootfs(rootfs_dir.path());
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        RootfsImage::new(rootfs_dir.path(), "app", "1.0", blobstore.clone())
+            .unwrap()
+            .entrypoint(vec!["/bin/app".to_owned()])
+            .env(vec!["PATH=/bin".to_owned()])
+            .workdir("/etc")
+            .compress_layers(LayerCompression::new(Compression::Gzip).unwrap())
+            .load()
+            .unwrap();
+
+        let manifest = read_manifest(&blobstore, "app", "1.0");
+
+        let config: ImageConfig = fs::read_to_string(blobstore.get_blob(&manifest.config.digest))
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let container = config.config.unwrap();
+
+        assert_eq!(container.entrypoint, Some(vec!["/bin/app".to_owned()]));
+        assert_eq!(container.env, Some(vec!["PATH=/bin".to_owned()]));
+        assert_eq!(container.working_dir, Some("/etc".to_owned()));
+
+        assert_eq!(manifest.layers[0].media_type, "application/vnd.docker.image.rootfs.diff.tar.gzip");
+        assert_ne!(config.rootfs.diff_ids[0], manifest.layers[0].digest);
+    }
+}
+
+
+mod new {
+    use super::*;
+
+    #[test]
+    fn fails_without_rootfs() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        assert!(RootfsImage::new(&blobstore_root_dir.path().join("nope"), "app", "1.0", blobstore).is_err());
+    }
+
+    #[test]
+    fn fails_with_invalid_name_or_tag() {
+        let rootfs_dir = tempdir().unwrap();
+
+        let blobstore_root_dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(blobstore_root_dir.path()).unwrap();
+
+        assert!(RootfsImage::new(rootfs_dir.path(), "Team/App", "1.0", blobstore.clone()).is_err());
+        assert!(RootfsImage::new(rootfs_dir.path(), "app", "sha256:abc", blobstore.clone()).is_err());
+        assert!(RootfsImage::new(rootfs_dir.path(), "app", "-1", blobstore).is_err());
+    }
+}
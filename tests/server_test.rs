@@ -0,0 +1,668 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tempfile::{tempdir, TempDir};
+use tokio::net::{TcpListener, TcpStream};
+
+use cartorio::blobstore::BlobStore;
+use cartorio::docker_saved_tarball::DockerSavedTarball;
+use cartorio::image_config::UnknownFields;
+use cartorio::registry::{Manifest, ManifestDescriptor};
+use cartorio::server;
+
+mod common;
+
+use common::{load_blobstore, start_server};
+
+async fn get(addr: SocketAddr, path: &str, headers: &[(&str, &str)]) -> (Response<hyper::body::Incoming>, Bytes) {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+
+    tokio::spawn(conn);
+
+    let mut req = Request::get(path).header("host", addr.to_string());
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+
+    let mut resp = sender
+        .send_request(req.body(Empty::<Bytes>::new()).unwrap())
+        .await
+        .unwrap();
+
+    let body = resp.body_mut().collect().await.unwrap().to_bytes();
+
+    (resp, body)
+}
+
+fn header<'a>(resp: &'a Response<hyper::body::Incoming>, name: &str) -> &'a str {
+    resp.headers().get(name).unwrap().to_str().unwrap()
+}
+
+mod routes {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_liveness_check() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, body) = get(addr, "/_live", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, "alive");
+    }
+
+    #[tokio::test]
+    async fn serves_readiness_check() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, body) = get(addr, "/_ready", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, "ready");
+    }
+
+    #[tokio::test]
+    async fn answers_unavailable_when_blobstore_is_unreadable() {
+        let (addr, dir) = start_server(Default::default()).await;
+
+        let (resp, _) = get(addr, "/_ready", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(dir.path().join("bucket")).unwrap();
+
+        let (resp, _) = get(addr, "/_ready", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn serves_version_check() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, _) = get(addr, "/v2/", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "docker-distribution-api-version"), "registry/2.0");
+    }
+
+    #[tokio::test]
+    async fn answers_not_found_for_unknown_routes() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, _) = get(addr, "/v2/a/tags/list", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_catalog() {
+        let (addr, dir) = start_server(Default::default()).await;
+
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+        let manifest = std::fs::read_link(blobstore.get_manifest("a", "latest")).unwrap();
+        let manifest = manifest.file_name().unwrap().to_str().unwrap();
+
+        blobstore.tag_manifest(manifest, "b/c", "latest").unwrap();
+
+        let (resp, body) = get(addr, "/v2/_catalog", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, r#"{"repositories":["a","b/c"]}"#);
+
+        let (resp, body) = get(addr, "/v2/_catalog?n=1", &[]).await;
+        assert_eq!(body, r#"{"repositories":["a"]}"#);
+        assert_eq!(header(&resp, "link"), "</v2/_catalog?n=1&last=a>; rel=\"next\"");
+
+        let (resp, body) = get(addr, "/v2/_catalog?n=1&last=a", &[]).await;
+        assert_eq!(body, r#"{"repositories":["b/c"]}"#);
+        assert!(resp.headers().get("link").is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_empty_catalog_pages() {
+        let (addr, _dir) = start_server(Default::default()).await;
+
+        let (resp, body) = get(addr, "/v2/_catalog?n=0", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, r#"{"repositories":[]}"#);
+        assert!(resp.headers().get("link").is_none());
+
+        // the server is still up.
+        //
+        let (resp, _) = get(addr, "/v2/_catalog", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+mod shutdown {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    async fn start_stoppable_server() -> (SocketAddr, TempDir, oneshot::Sender<()>, JoinHandle<cartorio::error::Result<()>>) {
+        let (blobstore, dir) = load_blobstore();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = server::Options {
+            shutdown_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        let server = tokio::spawn(server::run_until(vec![listener.into()], blobstore, options, async move {
+            let _ = stopped.await;
+        }));
+
+        (addr, dir, stop, server)
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_connections() {
+        let (addr, _dir, stop, server) = start_stoppable_server().await;
+
+        let (resp, _) = get(addr, "/_ready", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        stop.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server must stop")
+            .unwrap()
+            .unwrap();
+
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_responses_in_flight() {
+        let (addr, _dir, stop, server) = start_stoppable_server().await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+
+        tokio::spawn(conn);
+
+        let req = Request::get("/v2/a/manifests/latest")
+            .header("host", addr.to_string())
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let mut resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        stop.send(()).unwrap();
+
+        let length: usize = header(&resp, "content-length").parse().unwrap();
+        let body = resp.body_mut().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), length);
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server must stop once the response is sent")
+            .unwrap()
+            .unwrap();
+    }
+}
+
+mod listeners {
+    use super::*;
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    #[tokio::test]
+    async fn serves_over_tcp_and_unix_sockets_at_once() {
+        let (blobstore, dir) = load_blobstore();
+        let socket = dir.path().join("cartorio.sock");
+
+        // over IPv6 unless the host lacks it.
+        //
+        let tcp = match TcpListener::bind("[::1]:0").await {
+            Ok(tcp) => tcp,
+            Err(_) => TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        };
+        let addr = tcp.local_addr().unwrap();
+        let unix = UnixListener::bind(&socket).unwrap();
+
+        tokio::spawn(server::run_until(
+            vec![tcp.into(), unix.into()],
+            blobstore,
+            Default::default(),
+            std::future::pending(),
+        ));
+
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let stream = UnixStream::connect(&socket).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+
+        tokio::spawn(conn);
+
+        let req = Request::get("/v2/a/manifests/latest")
+            .header("host", "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn fails_without_listeners() {
+        let (blobstore, _dir) = load_blobstore();
+        let result = server::run_until(vec![], blobstore, Default::default(), std::future::pending()).await;
+
+        assert!(result.is_err());
+    }
+}
+
+mod manifests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_manifest_by_tag_and_digest() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, body) = get(addr, "/v2/a/manifests/latest", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            header(&resp, "content-type"),
+            "application/vnd.docker.distribution.manifest.v2+json"
+        );
+        assert_eq!(header(&resp, "cache-control"), "public, max-age=60");
+
+        let digest = header(&resp, "docker-content-digest").to_owned();
+        assert_eq!(header(&resp, "etag"), format!("\"{}\"", digest));
+        assert_eq!(
+            digest,
+            format!("sha256:{}", cartorio::digest::compute(&body[..]).unwrap())
+        );
+
+        let (resp, by_digest) = get(addr, &format!("/v2/a/manifests/{}", digest), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(by_digest, body);
+        assert_eq!(
+            header(&resp, "cache-control"),
+            "public, max-age=31536000, immutable"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_not_modified_for_matching_etag() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, _) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        let etag = header(&resp, "etag").to_owned();
+
+        let (resp, body) = get(addr, "/v2/a/manifests/latest", &[("if-none-match", &etag)]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_manifest_unknown() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, body) = get(addr, "/v2/a/manifests/nope", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(String::from_utf8_lossy(&body).contains("MANIFEST_UNKNOWN"));
+    }
+}
+
+mod blobs {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_blobs_referenced_by_manifest() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (_, manifest) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+
+        let layer = &manifest["layers"][0];
+        let digest = layer["digest"].as_str().unwrap();
+
+        let (resp, body) = get(addr, &format!("/v2/a/blobs/{}", digest), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body.len() as u64, layer["size"].as_u64().unwrap());
+        assert_eq!(header(&resp, "docker-content-digest"), digest);
+        assert_eq!(
+            digest,
+            format!("sha256:{}", cartorio::digest::compute(&body[..]).unwrap())
+        );
+    }
+
+    /// Tags a manifest that references nothing but a config blob as `c`,
+    /// and gives back the digest of the first layer of `a:latest`.
+    ///
+    async fn tag_unrelated_repository(addr: SocketAddr, dir: &TempDir) -> String {
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: "application/vnd.docker.distribution.manifest.v2+json".to_owned(),
+            config: ManifestDescriptor {
+                media_type: "application/vnd.docker.container.image.v1+json".to_owned(),
+                size: 2,
+                digest: "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a".to_owned(),
+                unknown: UnknownFields::new(),
+            },
+            layers: vec![],
+            unknown: UnknownFields::new(),
+        };
+
+        let filename = blobstore.add_manifest(&manifest).unwrap();
+        blobstore.tag_manifest(&filename, "c", "latest").unwrap();
+
+        let (_, manifest) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+
+        manifest["layers"][0]["digest"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn answers_blob_unknown_for_blobs_of_other_repositories() {
+        let (addr, dir) = start_server(Default::default()).await;
+        let digest = tag_unrelated_repository(addr, &dir).await;
+
+        let (resp, body) = get(addr, &format!("/v2/c/blobs/{}", digest), &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(String::from_utf8_lossy(&body).contains("BLOB_UNKNOWN"));
+
+        let (resp, _) = get(addr, &format!("/v2/nonexistent/blobs/{}", digest), &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_blobs_of_repositories_tagged_after_start() {
+        let (addr, dir) = start_server(Default::default()).await;
+        let digest = tag_unrelated_repository(addr, &dir).await;
+
+        let (resp, _) = get(addr, &format!("/v2/c/blobs/{}", digest), &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+        let manifest = std::fs::read_link(blobstore.get_manifest("a", "latest")).unwrap();
+        blobstore
+            .tag_manifest(manifest.file_name().unwrap().to_str().unwrap(), "c", "other")
+            .unwrap();
+
+        let (resp, _) = get(addr, &format!("/v2/c/blobs/{}", digest), &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_blobs_under_any_repository_when_global() {
+        let (addr, dir) = start_server(server::Options {
+            global_blobs: true,
+            ..Default::default()
+        })
+        .await;
+
+        let digest = tag_unrelated_repository(addr, &dir).await;
+
+        let (resp, _) = get(addr, &format!("/v2/c/blobs/{}", digest), &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn answers_blob_unknown() {
+        let (addr, _dir) = start_server(Default::default()).await;
+        let (resp, body) = get(addr, "/v2/a/blobs/sha256:abc", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(String::from_utf8_lossy(&body).contains("BLOB_UNKNOWN"));
+    }
+}
+
+mod in_place {
+    use super::*;
+
+    /// Starts a server in the background serving the `small-image` fixture
+    /// in place from a copy of its tarball.
+    ///
+    async fn start_in_place_server() -> (SocketAddr, TempDir) {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(&dir.path().join("blobstore")).unwrap();
+        let tarball_path = dir.path().join("image.tar");
+
+        std::fs::copy(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/small-image/image.tar"),
+            &tarball_path,
+        )
+        .unwrap();
+
+        DockerSavedTarball::in_place(&tarball_path, blobstore.clone())
+            .unwrap()
+            .load()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(server::run(listener, blobstore, Default::default()));
+
+        (addr, dir)
+    }
+
+    async fn layer_digest(addr: SocketAddr) -> (String, u64) {
+        let (_, manifest) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+
+        let layer = &manifest["layers"][0];
+
+        (layer["digest"].as_str().unwrap().to_owned(), layer["size"].as_u64().unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_blobs_from_the_tarball() {
+        let (addr, _dir) = start_in_place_server().await;
+        let (digest, size) = layer_digest(addr).await;
+
+        let (resp, body) = get(addr, &format!("/v2/a/blobs/{}", digest), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body.len() as u64, size);
+        assert_eq!(header(&resp, "content-length"), size.to_string());
+        assert_eq!(
+            digest,
+            format!("sha256:{}", cartorio::digest::compute(&body[..]).unwrap())
+        );
+
+        let etag = header(&resp, "etag").to_owned();
+        let (resp, _) = get(addr, &format!("/v2/a/blobs/{}", digest), &[("if-none-match", &etag)]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn answers_blob_unknown_once_the_tarball_changed() {
+        let (addr, dir) = start_in_place_server().await;
+        let (digest, _) = layer_digest(addr).await;
+
+        let replacement = dir.path().join("replacement.tar");
+        std::fs::write(&replacement, b"something else").unwrap();
+        std::fs::rename(&replacement, dir.path().join("image.tar")).unwrap();
+
+        let (resp, body) = get(addr, &format!("/v2/a/blobs/{}", digest), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(String::from_utf8_lossy(&body).contains("BLOB_UNKNOWN"));
+    }
+}
+
+mod oci_layout {
+    use super::*;
+
+    /// Starts a server in the background serving the `oci-image` fixture
+    /// (`b:latest` being an image index).
+    ///
+    async fn start_oci_server() -> (SocketAddr, TempDir) {
+        let dir = tempdir().unwrap();
+        let blobstore = BlobStore::new(dir.path()).unwrap();
+
+        DockerSavedTarball::new(
+            &PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/oci-image/image.tar"),
+            blobstore.clone(),
+        )
+        .unwrap()
+        .load()
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(server::run(listener, blobstore, Default::default()));
+
+        (addr, dir)
+    }
+
+    #[tokio::test]
+    async fn serves_manifests_with_their_own_media_types() {
+        let (addr, _dir) = start_oci_server().await;
+        let (resp, body) = get(addr, "/v2/b/manifests/latest", &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "content-type"), "application/vnd.oci.image.index.v1+json");
+        assert_eq!(
+            header(&resp, "docker-content-digest"),
+            format!("sha256:{}", cartorio::digest::compute(&body[..]).unwrap())
+        );
+
+        let index: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let amd64 = index["manifests"][0]["digest"].as_str().unwrap();
+
+        let (resp, body) = get(addr, &format!("/v2/b/manifests/{}", amd64), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header(&resp, "content-type"), "application/vnd.oci.image.manifest.v1+json");
+
+        let manifest: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let layer = &manifest["layers"][0];
+
+        assert_eq!(layer["mediaType"], "application/vnd.oci.image.layer.v1.tar");
+
+        let (resp, _) = get(addr, &format!("/v2/b/blobs/{}", layer["digest"].as_str().unwrap()), &[]).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
+
+mod metrics {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use cartorio::metrics::{self, Metrics};
+
+    #[tokio::test]
+    async fn accounts_requests_in_metrics() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new(BlobStore::new(blobstore_root_dir.path()).unwrap()).unwrap());
+
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = metrics_listener.local_addr().unwrap();
+        tokio::spawn(metrics::serve(metrics_listener, metrics.clone()));
+
+        let (addr, _dir) = start_server(server::Options {
+            metrics: Some(metrics),
+            ..Default::default()
+        })
+        .await;
+
+        let (_, manifest) = get(addr, "/v2/a/manifests/latest", &[]).await;
+        let (_, not_found) = get(addr, "/v2/a/manifests/nonexistent", &[]).await;
+
+        let expected = [
+            "cartorio_http_requests_total{endpoint=\"manifest\",status=\"200\"} 1".to_owned(),
+            "cartorio_http_requests_total{endpoint=\"manifest\",status=\"404\"} 1".to_owned(),
+            format!("cartorio_http_response_bytes_total{{endpoint=\"manifest\"}} {}", manifest.len() + not_found.len()),
+            "cartorio_http_requests_in_flight 0".to_owned(),
+        ];
+
+        for _ in 0..20 {
+            let (resp, body) = get(metrics_addr, "/metrics", &[]).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let body = String::from_utf8_lossy(&body);
+
+            if expected.iter().all(|line| body.contains(line.as_str())) {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("metrics do not account for the requests served");
+    }
+
+    #[tokio::test]
+    async fn stops_counting_requests_abandoned_before_responses() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempdir().unwrap();
+        let htpasswd = dir.path().join("htpasswd");
+        // checking a password against it takes a while, whatever the password.
+        //
+        std::fs::write(&htpasswd, "alice:$2b$12$abcdefghijklmnopqrstuuabcdefghijklmnopqrstuvwxyzABCDE").unwrap();
+
+        let metrics = Arc::new(Metrics::new(BlobStore::new(dir.path()).unwrap()).unwrap());
+
+        let (addr, _dir) = start_server(server::Options {
+            htpasswd: Some(htpasswd),
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        })
+        .await;
+
+        // the client goes away while its password is still being checked.
+        //
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /v2/ HTTP/1.1\r\nhost: cartorio\r\nauthorization: Basic YWxpY2U6czNjcjN0\r\n\r\n")
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(metrics.render().unwrap().contains("cartorio_http_requests_in_flight 1"));
+
+        drop(stream);
+
+        for _ in 0..10 {
+            if metrics.render().unwrap().contains("cartorio_http_requests_in_flight 0") {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("abandoned request still counted as in flight");
+    }
+
+    #[tokio::test]
+    async fn serves_only_metrics() {
+        let blobstore_root_dir = tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new(BlobStore::new(blobstore_root_dir.path()).unwrap()).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics::serve(listener, metrics));
+
+        let (resp, _) = get(addr, "/v2/", &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
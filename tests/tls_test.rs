@@ -0,0 +1,217 @@
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tempfile::tempdir;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use cartorio::server;
+use cartorio::tls::{Tls, TlsOptions};
+
+mod common;
+
+use common::start_server;
+
+struct Authority {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Authority {
+    fn new() -> Authority {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        Authority {
+            cert: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    /// Issues a certificate, returning its PEM-encoded chain and key.
+    ///
+    fn issue(&self, purpose: ExtendedKeyUsagePurpose) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        params.extended_key_usages = vec![purpose];
+
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+        (cert.pem(), key.serialize_pem())
+    }
+}
+
+/// Writes a server certificate issued by `ca` to `dir`, returning options
+/// that point at it.
+///
+fn write_server_cert(dir: &Path, ca: &Authority) -> TlsOptions {
+    let (cert, key) = ca.issue(ExtendedKeyUsagePurpose::ServerAuth);
+
+    std::fs::write(dir.join("cert.pem"), cert).unwrap();
+    std::fs::write(dir.join("key.pem"), key).unwrap();
+
+    TlsOptions {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+        client_ca: None,
+    }
+}
+
+fn tls_options(tls: TlsOptions) -> server::Options {
+    server::Options {
+        tls: Some(tls),
+        ..Default::default()
+    }
+}
+
+/// Performs `GET /v2/` over TLS trusting only `ca`, optionally presenting
+/// a client certificate.
+///
+async fn version_check(addr: SocketAddr, ca: &Authority, client: Option<&Authority>) -> Result<StatusCode, String> {
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.cert.der().clone()).unwrap();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+
+    let config = match client {
+        None => builder.with_no_client_auth(),
+        Some(client_ca) => {
+            let (cert, key) = client_ca.issue(ExtendedKeyUsagePurpose::ClientAuth);
+            let certs = CertificateDer::pem_slice_iter(cert.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap();
+
+            builder.with_client_auth_cert(certs, key).unwrap()
+        },
+    };
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    tokio::spawn(conn);
+
+    let resp = sender
+        .send_request(
+            Request::get("/v2/")
+                .header("host", "localhost")
+                .body(Empty::<Bytes>::new())
+                .unwrap(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(resp.status())
+}
+
+#[tokio::test]
+async fn serves_over_tls() {
+    let dir = tempdir().unwrap();
+    let ca = Authority::new();
+
+    let (addr, _blobstore) = start_server(tls_options(write_server_cert(dir.path(), &ca))).await;
+
+    assert_eq!(version_check(addr, &ca, None).await.unwrap(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn requires_client_certificates_when_client_ca_set() {
+    let dir = tempdir().unwrap();
+    let ca = Authority::new();
+    let clients_ca = Authority::new();
+    let other_ca = Authority::new();
+
+    std::fs::write(dir.path().join("clients.pem"), clients_ca.cert.pem()).unwrap();
+
+    let options = TlsOptions {
+        client_ca: Some(dir.path().join("clients.pem")),
+        ..write_server_cert(dir.path(), &ca)
+    };
+
+    let (addr, _blobstore) = start_server(tls_options(options)).await;
+
+    assert_eq!(
+        version_check(addr, &ca, Some(&clients_ca)).await.unwrap(),
+        StatusCode::OK
+    );
+
+    assert!(
+        version_check(addr, &ca, None).await.is_err(),
+        "must refuse clients without certificates"
+    );
+
+    assert!(
+        version_check(addr, &ca, Some(&other_ca)).await.is_err(),
+        "must refuse clients with certificates from unknown authorities"
+    );
+}
+
+#[tokio::test]
+async fn reloads_certificates_when_files_change() {
+    let dir = tempdir().unwrap();
+    let ca = Authority::new();
+    let new_ca = Authority::new();
+
+    let (addr, _blobstore) = start_server(tls_options(write_server_cert(dir.path(), &ca))).await;
+
+    assert!(version_check(addr, &new_ca, None).await.is_err());
+
+    write_server_cert(dir.path(), &new_ca);
+
+    for _ in 0..30 {
+        if version_check(addr, &new_ca, None).await.is_ok() {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    panic!("certificates were not reloaded");
+}
+
+#[test]
+fn reload_keeps_configuration_on_failure() {
+    let dir = tempdir().unwrap();
+    let ca = Authority::new();
+
+    let tls = Tls::new(write_server_cert(dir.path(), &ca)).unwrap();
+
+    std::fs::write(dir.path().join("key.pem"), "garbage").unwrap();
+
+    assert!(tls.reload().is_err());
+}
+
+#[test]
+fn new_fails_without_certificates() {
+    let dir = tempdir().unwrap();
+
+    assert!(Tls::new(TlsOptions {
+        cert: dir.path().join("cert.pem"),
+        key: dir.path().join("key.pem"),
+        client_ca: None,
+    })
+    .is_err());
+}